@@ -0,0 +1,169 @@
+/*  
+    miner.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Reference miner for the mining server of a core node.
+//! usage: miner <node ip:mining port> [payout address] [threads] [pool <secret key file> <public key file>]
+//! With "pool", the miner registers the address of a minisign key pair to the pool of the node and submits shares.
+//! The password of the secret key is read from MINER_KEY_PASSWORD.
+
+// the code base compares booleans with `== true`, spells out struct fields, returns
+// explicitly and gives its types `create()`/`clone()`/`to_string()` methods
+#![allow(clippy::bool_comparison, clippy::redundant_field_names, clippy::len_zero, clippy::needless_return)]
+#![allow(clippy::should_implement_trait, clippy::inherent_to_string, clippy::len_without_is_empty)]
+#![allow(clippy::ptr_arg, clippy::too_many_arguments)]
+
+use blockchain_rs::client_server_core;
+
+use client_server_core::consensus::pow::algorithm::create_pow_algorithm;
+use client_server_core::consensus::pow::miner;
+use client_server_core::block_chain::keymanager::KeyManager;
+use client_server_core::mining_server::{BlockSubmission, MiningClient};
+
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The time interval for checking whether the template became stale
+pub const TEMPLATE_CHECK_INTERVAL: u64 = 5;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let node_addr = if args.len() > 1 { args[1].clone() } else { "127.0.0.1:50090".to_string() };
+    let mut address = if args.len() > 2 { args[2].clone() } else { String::new() };
+    let threads: usize = if args.len() > 3 { args[3].parse().unwrap() } else { miner::get_default_threads() };
+    let pool_mode = args.len() > 4 && args[4] == "pool";
+    let km = if pool_mode == true {
+        match load_key(&args) {
+            Some(km) => Some(km),
+            None => {
+                println!("pool mining needs a minisign secret key file, its public key file and MINER_KEY_PASSWORD");
+                return;
+            }
+        }
+    }
+    else {
+        None
+    };
+
+    println!("mining for {} with {} threads", node_addr, threads);
+    let mut client = match MiningClient::connect(&node_addr) {
+        Some(client) => client,
+        None => return,
+    };
+    if let Some(km) = &km {
+        address = km.my_address();
+        let (result, flag) = client.register(km);
+        if flag == false {
+            println!("registration failed: {}", result);
+            return;
+        }
+    }
+
+    loop {
+        let template = match client.get_block_template(&address) {
+            Some(template) => template,
+            None => {
+                thread::sleep(Duration::from_secs(TEMPLATE_CHECK_INTERVAL));
+                continue;
+            }
+        };
+        if template.transactions.len() == 0 {
+            // the node mines only when it has transactions to register
+            thread::sleep(Duration::from_secs(TEMPLATE_CHECK_INTERVAL));
+            continue;
+        }
+        let algorithm = match create_pow_algorithm(&template.algorithm) {
+            Some(algorithm) => algorithm,
+            None => {
+                println!("unknown proof-of-work algorithm {}", template.algorithm);
+                return;
+            }
+        };
+        // a pool accepts any hash meeting the share difficulty. a new template is taken after each share.
+        let difficulty = if pool_mode == true { template.share_difficulty } else { template.difficulty };
+        println!("new template {} at height {}", template.template_id, template.height);
+
+        // stop mining when another block is registered on top of the same previous block
+        let to_stop = Arc::new(AtomicBool::new(false));
+        let finished = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let to_stop = Arc::clone(&to_stop);
+            let finished = Arc::clone(&finished);
+            let node_addr = node_addr.clone();
+            let address = address.clone();
+            let previous_block = template.previous_block.clone();
+            thread::spawn(move || {
+                let mut watcher_client = match MiningClient::connect(&node_addr) {
+                    Some(client) => client,
+                    None => return,
+                };
+                let mut iter = 0;
+                while finished.load(Ordering::Relaxed) == false {
+                    thread::sleep(Duration::from_millis(100));
+                    iter += 1;
+                    if iter < TEMPLATE_CHECK_INTERVAL * 10 {
+                        continue;
+                    }
+                    iter = 0;
+                    if let Some(latest) = watcher_client.get_block_template(&address) {
+                        if latest.previous_block != previous_block {
+                            println!("template is stale");
+                            to_stop.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                }
+            })
+        };
+
+        let mut block = template.create_block(0, 0);
+        let (flag, hash_rate) = miner::mine_parallel_with_difficulty(&mut block, algorithm.as_ref(), threads, difficulty, &to_stop);
+        finished.store(true, Ordering::Relaxed);
+        println!("hash rate: {:.0} hashes/s", hash_rate);
+
+        if flag == true {
+            let submission = BlockSubmission {
+                template_id: template.template_id.clone(),
+                nonce: block.nonce,
+                extra_nonce: miner::get_extra_nonce(&block)
+            };
+            let (result, accepted) = if pool_mode == true { client.submit_share(&submission) } else { client.submit_block(&submission) };
+            if accepted == true {
+                println!("accepted: {}", result);
+            }
+            else {
+                println!("rejected: {}", result);
+            }
+        }
+        watcher.join().unwrap();
+    }
+}
+
+fn load_key(args: &Vec<String>) -> Option<KeyManager> {
+    if args.len() < 7 {
+        return None;
+    }
+    let private_key_str = fs::read_to_string(&args[5]).ok()?;
+    let public_key_str = fs::read_to_string(&args[6]).ok()?;
+    let password = env::var("MINER_KEY_PASSWORD").ok()?;
+    KeyManager::load(&private_key_str, &public_key_str, &password)
+}
@@ -19,32 +19,61 @@
 
 pub mod connection_manager;
 pub mod block_chain;
+pub mod consensus;
+pub mod chain_spec;
+pub mod mining_server;
+#[cfg(test)]
+mod fuzz;
+pub mod bench;
+#[cfg(test)]
+mod abuse;
+#[cfg(test)]
+mod pool;
+#[cfg(test)]
+mod reorg;
 
 use crate::client_server_core;
 use crate::client_server_core::block_chain::Transaction;
 use crate::client_server_core::block_chain::TransactionPool;
 use crate::client_server_core::block_chain::Block;
 use crate::client_server_core::block_chain::BlockChain;
+use crate::client_server_core::block_chain::BlockHeader;
 use crate::client_server_core::connection_manager::ConnectionManager;
 use crate::client_server_core::connection_manager::message_manager;
 
-use std::thread;
 use std::time::Duration;
 use std::sync::mpsc;
-use std::net::{TcpListener, TcpStream};
-use std::net::UdpSocket;
-use std::collections::HashSet;
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use chrono::Local;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::task::JoinHandle;
 
 use self::block_chain::UTXOManager;
+use self::block_chain::fee_estimator::{self, FeeEstimate, FeeEstimator};
+use self::block_chain::merkle;
+use self::block_chain::merkle::MerkleProof;
+use self::block_chain::spv::HeaderChain;
+use self::block_chain::block_filter::{BlockFilterIndex, FilterHeaderChain};
+use self::block_chain::utxo_set::{UTXOSet, UTXOView};
+use self::block_chain::utxo_snapshot::UTXOSnapshot;
+use self::block_chain::bootstrap;
+use self::block_chain::chain_sync::{self, ChainSync};
+use self::block_chain::compact_block::{BlockTransactions, BlockTransactionsRequest, CompactBlock, PartialBlock};
+use self::consensus::Consensus;
+use self::chain_spec::ChainSpec;
 use self::connection_manager::ConnectionManager4Edge;
+use self::connection_manager::message;
+use self::connection_manager::message::{LocalCommand, Message, MessageHandler, NodeEvent, Peer};
+use self::connection_manager::limits::ResourceLimits;
+use self::connection_manager::ban_list::{BanEntry, MISBEHAVIOUR_BAD_SIGNATURE, MISBEHAVIOUR_INVALID_BLOCK, MISBEHAVIOUR_INVALID_CHAIN};
+use self::mining_server::MiningState;
 
 extern crate minisign;
-use minisign::{KeyPair, PublicKeyBox, SecretKeyBox, SignatureBox};
-use openssl::sign;
+use minisign::{PublicKeyBox, SignatureBox};
 use std::io::Cursor;
 
 extern crate rand;
@@ -63,19 +92,69 @@ pub const STATE_SHUTTING_DOWN_4EDGE: u64 = 2;
 pub const MINING_INTERVAL: i64 = 60;
 /// The time interval for checking peers connections
 pub const CHECK_PEERS_CONNECTION_INTERVAL: i64 = 20;
+/// The time interval of the timers of the event loops in milliseconds. Messages are handled as soon as they arrive.
+pub const EVENT_LOOP_TICK_MS: u64 = 1000;
+/// The number of recent slots whose sealers are remembered for detecting equivocation
+pub const SEEN_SEALS_SLOTS: u64 = 1000;
+/// The number of remembered sealers. The sealer of the oldest slot is forgotten first.
+pub const MAX_SEEN_SEALS: usize = 10000;
+/// The number of recently received transactions kept for rebuilding compact blocks
+pub const RECENT_TRANSACTIONS_SIZE: usize = 1000;
+/// The number of pooled transactions. A full pool evicts the transaction of the lowest fee rate for a better one.
+pub const MAX_POOL_TRANSACTIONS: usize = 5000;
+/// The number of compact blocks waiting for their missing transactions
+pub const MAX_PENDING_COMPACT_BLOCKS: usize = 16;
+/// The states of the background validation of the history below a loaded UTXO snapshot
+pub const SNAPSHOT_UNVERIFIED: usize = 0;
+pub const SNAPSHOT_VERIFYING: usize = 1;
+pub const SNAPSHOT_VERIFIED: usize = 2;
+pub const SNAPSHOT_INVALID: usize = 3;
+/// The minimum number of latest blocks whose transactions a pruned node keeps for reorganizations and compact blocks
+pub const MIN_BLOCKS_TO_KEEP: usize = 10;
+/// The block reward paid to the producer of a block in addition to the fees
+pub const MINING_REWARD: i64 = 30;
 pub struct Worker {
     to_stop: Arc<AtomicBool>,
-}
-
-trait WorkerTrait {
-    fn run(&self);
-    fn stop(&self);
+    /// the task producing a block, which is aborted when the block is not needed any more
+    task: Option<JoinHandle<()>>
 }
 
 impl Worker {
     fn new() -> Worker {
         Worker {
             to_stop: Arc::new(AtomicBool::new(false)),
+            task: None
+        }
+    }
+
+    fn stop(&mut self) -> bool {
+        //! cancel the running task. Sealing runs on a blocking thread, so it is stopped by to_stop.
+        //! It returns true if a task was cancelled before it finished.
+        self.to_stop.store(true, Ordering::Relaxed);
+        match self.task.take() {
+            Some(task) => {
+                let running = task.is_finished() == false;
+                task.abort();
+                running
+            },
+            None => false,
+        }
+    }
+}
+
+/// The block production of a core node, changed by the messages it handles
+pub struct ProducerState {
+    locked: bool,
+    cur_time: i64,
+    w: Worker
+}
+
+impl ProducerState {
+    fn stop(&mut self) {
+        //! stop producing a block. A cancelled task does not send MSG_UNLOCKED, so the lock is released here.
+        if self.w.stop() == true {
+            self.locked = false;
+            self.cur_time = Local::now().timestamp();
         }
     }
 }
@@ -91,17 +170,49 @@ pub struct ServerCore {
     bc: BlockChain,
     previous_block_hash: String, 
     km: block_chain::keymanager::KeyManager, 
-    um: UTXOManager
+    um: UTXOManager,
+    fe: FeeEstimator,
+    consensus: Arc<dyn Consensus>,
+    seen_seals: HashMap<(String, u64), String>,
+    mining_port: String,
+    mining_state: Arc<Mutex<MiningState>>,
+    filter_index: BlockFilterIndex,
+    recent_transactions: VecDeque<Transaction>,
+    pending_compact_blocks: HashMap<String, PartialBlock>,
+    chain_spec: ChainSpec,
+    utxo_set: UTXOSet,
+    snapshot: Option<UTXOSnapshot>,
+    snapshot_status: Arc<AtomicUsize>,
+    node_tx: Option<UnboundedSender<NodeEvent>>,
+    blocks_to_keep: usize,
+    sync: Option<ChainSync>
 }
 
 impl ServerCore {
     pub fn create(my_ip: &str, my_port: &str, core_node_ip: &str, core_node_port: &str) -> ServerCore {
+        ServerCore::create_with_consensus(my_ip, my_port, core_node_ip, core_node_port, Arc::new(consensus::pow::ProofOfWork::create()))
+    }
+
+    pub fn create_with_chain_spec(my_ip: &str, my_port: &str, core_node_ip: &str, core_node_port: &str, spec: &ChainSpec) -> ServerCore {
+        spec.print();
+        let consensus = consensus::create_consensus(spec).expect("unknown consensus engine in the chain spec");
+        let mut server_core = ServerCore::create_with_consensus(my_ip, my_port, core_node_ip, core_node_port, consensus);
+        server_core.chain_spec = spec.clone();
+        server_core.connection_manager.set_chain_id(&spec.get_chain_id());
+        server_core.connection_manager.set_seed_nodes(&spec.seed_nodes);
+        server_core
+    }
+
+    pub fn create_with_consensus(my_ip: &str, my_port: &str, core_node_ip: &str, core_node_port: &str, consensus: Arc<dyn Consensus>) -> ServerCore {
         println!("Initializing server...");
+        println!("Consensus engine is set to {}", consensus.name());
         println!("Server IP address is set to {}", my_ip);
 
         let gc = Block::create_genesis_block();
         let gc_hash = gc.get_hash();
         println!("initial hash value: {}", gc_hash);
+        let mut utxo_set = UTXOSet::create();
+        utxo_set.apply_block(&gc, false);
         let mut bc = BlockChain::create();
         bc.set_new_block(gc);
 
@@ -110,6 +221,7 @@ impl ServerCore {
         let km = client_server_core::block_chain::keymanager::KeyManager::create(rand_num);
         let my_address = km.my_address();
         let um = UTXOManager::create(&my_address);
+        let km_public_key = km.public_key_str.clone();
 
         ServerCore {
             server_state: STATE_INIT, 
@@ -117,12 +229,27 @@ impl ServerCore {
             my_port: my_port.to_string(), 
             core_node_ip: core_node_ip.to_string(), 
             core_node_port: core_node_port.to_string(),
-            connection_manager: ConnectionManager::create(my_ip, my_port, &core_node_ip, &core_node_port),
+            connection_manager: ConnectionManager::create(my_ip, my_port, core_node_ip, core_node_port),
             tp: TransactionPool::create(),
             bc: bc,
             previous_block_hash: gc_hash,
             km: km, 
-            um: um
+            um: um,
+            fe: FeeEstimator::create(),
+            consensus: consensus,
+            seen_seals: HashMap::new(),
+            mining_port: String::new(),
+            mining_state: Arc::new(Mutex::new(MiningState::create(&km_public_key))),
+            filter_index: BlockFilterIndex::create(),
+            recent_transactions: VecDeque::new(),
+            pending_compact_blocks: HashMap::new(),
+            chain_spec: ChainSpec::create_default(),
+            utxo_set: utxo_set,
+            snapshot: None,
+            snapshot_status: Arc::new(AtomicUsize::new(SNAPSHOT_UNVERIFIED)),
+            node_tx: None,
+            blocks_to_keep: 0,
+            sync: None
         }
     }
 
@@ -130,7 +257,7 @@ impl ServerCore {
         println!("Cloning server...");
 
         ServerCore {
-            server_state: self.server_state.clone(), 
+            server_state: self.server_state, 
             my_ip: self.my_ip.clone(),
             my_port: self.my_port.clone(),
             core_node_ip: self.core_node_ip.clone(),
@@ -140,21 +267,263 @@ impl ServerCore {
             bc: self.bc.clone(),
             previous_block_hash: self.previous_block_hash.clone(),
             km: self.km.clone(),
-            um: self.um.clone()
+            um: self.um.clone(),
+            fe: self.fe.clone(),
+            consensus: Arc::clone(&self.consensus),
+            seen_seals: self.seen_seals.clone(),
+            mining_port: self.mining_port.clone(),
+            mining_state: Arc::clone(&self.mining_state),
+            filter_index: self.filter_index.clone(),
+            recent_transactions: self.recent_transactions.iter().map(|t| t.clone()).collect(),
+            pending_compact_blocks: self.pending_compact_blocks.iter().map(|(h, p)| (h.clone(), p.clone())).collect(),
+            chain_spec: self.chain_spec.clone(),
+            utxo_set: self.utxo_set.clone(),
+            snapshot: self.snapshot.as_ref().map(|s| s.clone()),
+            snapshot_status: Arc::clone(&self.snapshot_status),
+            node_tx: self.node_tx.clone(),
+            blocks_to_keep: self.blocks_to_keep,
+            sync: self.sync.as_ref().map(|s| s.clone())
+        }
+    }
+
+    pub fn set_key_manager(&mut self, km: block_chain::keymanager::KeyManager) {
+        //! replace the random key of this node, e.g. with the key of a signer
+        self.um = UTXOManager::create(&km.my_address());
+        self.mining_state.lock().unwrap().reward_address = km.public_key_str.clone();
+        self.km = km;
+    }
+
+    pub fn enable_external_mining(&mut self, mining_port: &str) {
+        //! serve block templates to external miners at mining_port of 127.0.0.1 instead of mining in this process
+        self.mining_port = mining_port.to_string();
+    }
+
+    pub fn enable_pool_mining(&mut self, mining_port: &str, payout_scheme: &str) {
+        //! run a mining pool at mining_port. The rest of a block reward after paying the miners goes to this node.
+        self.enable_external_mining(mining_port);
+        self.mining_state.lock().unwrap().pool = Some(mining_server::pool::MiningPool::create(payout_scheme));
+    }
+
+    pub fn enable_pruning(&mut self, blocks_to_keep: usize) -> bool {
+        //! keep the headers, the UTXO set and only the transactions of the latest blocks_to_keep blocks
+        if self.consensus.needs_block_bodies() == true {
+            println!("{} cannot validate blocks without the transactions of their ancestors", self.consensus.name());
+            return false;
+        }
+        if blocks_to_keep < MIN_BLOCKS_TO_KEEP {
+            println!("a pruned node must keep at least {} blocks", MIN_BLOCKS_TO_KEEP);
+            return false;
+        }
+        self.blocks_to_keep = blocks_to_keep;
+        self.prune_blocks();
+        true
+    }
+
+    pub fn export_chain(&self, start_height: usize, end_height: usize, path: &str) {
+        //! write the blocks from start_height to end_height (exclusive) to a bootstrap file
+        match &self.node_tx {
+            Some(node_tx) => {
+                node_tx.send(NodeEvent::Local(LocalCommand::ExportChain(start_height, end_height, path.to_string()))).unwrap();
+            },
+            None => {
+                bootstrap::export_blocks(&self.bc, start_height, end_height, path);
+            }
+        }
+    }
+
+    pub async fn import_chain(&mut self, path: &str) {
+        //! validate and add the blocks of a bootstrap file
+        match &self.node_tx {
+            Some(node_tx) => {
+                node_tx.send(NodeEvent::Local(LocalCommand::ImportChain(path.to_string()))).unwrap();
+            },
+            None => {
+                self.import_bootstrap_file(path).await;
+            }
         }
     }
 
-    pub fn start(&mut self) {
+    pub fn load_utxo_snapshot(&mut self, path: &str) -> bool {
+        //! start from a UTXO snapshot listed in the chain spec instead of replaying the chain. It must be called before start.
+        let snapshot = match UTXOSnapshot::load_from_file(path) {
+            Some(snapshot) => snapshot,
+            None => return false,
+        };
+        if snapshot.is_valid() == false {
+            println!("snapshot does not match its commitment");
+            return false;
+        }
+        if self.chain_spec.is_trusted_snapshot(snapshot.height, &snapshot.commitment) == false {
+            println!("snapshot at {} is not listed in the chain spec", snapshot.height);
+            return false;
+        }
+        println!("UTXO snapshot at {} ({}) is loaded", snapshot.height, snapshot.block_hash);
+        self.utxo_set = snapshot.to_utxo_set();
+        self.snapshot = Some(snapshot);
+        self.snapshot_status.store(SNAPSHOT_UNVERIFIED, Ordering::Relaxed);
+        true
+    }
+
+    pub fn dump_utxo_snapshot(&self, height: usize, path: &str) {
+        //! write the UTXO set at height to path. A running node writes it in its event loop.
+        match &self.node_tx {
+            Some(node_tx) => {
+                node_tx.send(NodeEvent::Local(LocalCommand::DumpSnapshot(height, path.to_string()))).unwrap();
+            },
+            None => {
+                self.write_utxo_snapshot(height, path);
+            }
+        }
+    }
+
+    fn write_utxo_snapshot(&self, height: usize, path: &str) -> bool {
+        if self.utxo_set.height != self.bc.chain.len() {
+            println!("the chain is not fully validated yet");
+            return false;
+        }
+        if self.bc.get_pruned_height() > 0 {
+            println!("the blocks below {} are pruned", self.bc.get_pruned_height());
+            return false;
+        }
+        match UTXOSnapshot::create_from_chain(&self.bc, height) {
+            Some(snapshot) => {
+                println!("UTXO snapshot at {} with commitment {}", height, snapshot.commitment);
+                snapshot.save_to_file(path)
+            },
+            None => {
+                println!("no UTXO snapshot at {}", height);
+                false
+            }
+        }
+    }
+
+    async fn handle_received_message(&mut self, sender: &Peer, msg: &str, producer: &mut ProducerState) {
+        println!("received msg: {}", msg);
+        match message::decode(msg) {
+            Ok(message) => {
+                self.connection_manager.handle_message(sender, &message, &mut ()).await;
+                self.handle_message(sender, &message, producer).await;
+            },
+            Err(e) => {
+                println!("{}", e);
+                self.connection_manager.handle_protocol_error(sender, &e).await;
+            },
+        }
+    }
+
+    async fn handle_local_command(&mut self, command: LocalCommand, producer: &mut ProducerState) {
+        //! run a command of the node itself. Its messages are handled with the node as the sender.
+        match command {
+            LocalCommand::Message(msg) => {
+                let me = self.connection_manager.get_my_peer();
+                self.handle_received_message(&me, &msg, producer).await;
+            },
+            LocalCommand::DumpSnapshot(height, path) => {
+                self.write_utxo_snapshot(height, &path);
+            },
+            LocalCommand::ExportChain(start_height, end_height, path) => {
+                bootstrap::export_blocks(&self.bc, start_height, end_height, &path);
+            },
+            LocalCommand::ImportChain(path) => {
+                producer.stop();
+                self.import_bootstrap_file(&path).await;
+            },
+            LocalCommand::Ban(ip, port, ban_secs) => {
+                self.connection_manager.ban_peer(&ip, &port, ban_secs, "banned by the operator").await;
+            },
+        }
+    }
+
+    fn update_mining_state(&self) {
+        //! pass the current tip and the valid pooled transactions to the mining server
+        let (transactions, total_fee) = self.utxo_set.filter_transactions(&self.tp.transactions);
+        let mut state = self.mining_state.lock().unwrap();
+        state.tp = TransactionPool { transactions: transactions };
+        state.total_fee = total_fee;
+        state.tip = self.bc.chain[self.bc.chain.len() - 1].clone();
+        state.height = self.bc.chain.len();
+    }
+
+    pub async fn start(&mut self) {
         println!("start");
         self.server_state = STATE_STANDBY;
-        self.wait_for_access();
+        self.wait_for_access().await;
+
+    }
+
+    pub fn load_peer_db(&mut self, path: &str) {
+        //! keep the known core nodes in the file at path across restarts
+        self.connection_manager.load_peer_db(path);
+    }
+
+    pub fn load_node_key(&mut self, path: &str) {
+        //! keep the static key of the encrypted connections in the file at path, so that edge nodes can pin it
+        self.connection_manager.load_node_key(path);
+    }
+
+    pub fn pin_node_key(&mut self, ip: &str, port: &str, public_key: &str) -> bool {
+        //! accept the core node at ip and port only if it has the static key
+        self.connection_manager.pin_node_key(ip, port, public_key)
+    }
 
+    pub fn load_pinned_keys(&mut self, path: &str) -> usize {
+        //! pin the keys of the core nodes listed in the configuration file at path
+        self.connection_manager.load_pinned_keys(path)
     }
 
-    pub fn join_network(&mut self) {
+    pub fn get_node_public_key(&self) -> String {
+        self.connection_manager.get_public_key()
+    }
+
+    pub fn load_ban_list(&mut self, path: &str) {
+        //! keep the banned peers in the file at path across restarts
+        self.connection_manager.load_ban_list(path);
+    }
+
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        //! the connection slots and the rate limits for the connections made from now on
+        self.connection_manager.set_resource_limits(limits);
+    }
+
+    pub fn get_connection_counts(&self) -> (usize, usize) {
+        //! the numbers of inbound and outbound connections
+        self.connection_manager.get_connection_counts()
+    }
+
+    pub fn set_ban_time(&mut self, ban_secs: i64) {
+        //! the time in seconds a misbehaving peer is banned for
+        self.connection_manager.set_ban_secs(ban_secs);
+    }
+
+    pub async fn ban_peer(&mut self, ip: &str, port: &str, ban_secs: i64) {
+        //! ban the node at ip and port, or all nodes at ip if port is empty. A running node disconnects them in its event loop.
+        match &self.node_tx {
+            Some(node_tx) => {
+                node_tx.send(NodeEvent::Local(LocalCommand::Ban(ip.to_string(), port.to_string(), ban_secs))).unwrap();
+            },
+            None => {
+                self.connection_manager.ban_peer(ip, port, ban_secs, "banned by the operator").await;
+            }
+        }
+    }
+
+    pub fn unban_peer(&mut self, ip: &str, port: &str) -> bool {
+        self.connection_manager.unban_peer(ip, port)
+    }
+
+    pub fn unban_key(&mut self, key: &str) -> bool {
+        //! lift the ban of a node which was banned for its misbehaviour by its static key
+        self.connection_manager.unban_key(key)
+    }
+
+    pub fn get_banned_peers(&self) -> Vec<BanEntry> {
+        self.connection_manager.get_bans()
+    }
+
+    pub async fn join_network(&mut self) {
         println!("start_join_network");
         self.server_state = STATE_CONNECTED_TO_NETWORK;
-        connection_manager::join_network(&self.my_ip, &self.my_port, &self.core_node_ip, &self.core_node_port);
+        self.connection_manager.join_network().await;
     }
 
     pub fn shutdown(&mut self) {
@@ -166,333 +535,900 @@ impl ServerCore {
         self.server_state
     }
 
-    fn handle_message(&mut self, msg: &str, locked: &mut bool, cur_time: &mut i64, w: &Worker) {
-        let res = connection_manager::message_manager::parse(&msg);
-
-        println!("received message: {} {} {} {} {} {}", res[0], res[1], res[2], res[3], res[4], res[5]);
-
-        let cmd: usize       = res[2].parse().unwrap();
-        let ip      = &res[3];
-        let port    = &res[4];
-        let payload = &res[5];
-        
-        println!("received msg cmd ip port payload {} {} {} {}", cmd, ip, port, payload);
-
-        if cmd == connection_manager::message_manager::MSG_REQUEST_FULL_CHAIN {
-            println!("Send our latest blockchain to : {}:{}", ip, port);
-            let bc_str = self.bc.to_string();
-            let new_msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_FULL_CHAIN, &self.my_ip, &self.my_port, &bc_str);
-            connection_manager::send_msg(&ip, &port, &new_msg);
+    async fn add_new_block(&mut self, new_block: Block) -> bool {
+        //! validate a new block and set it on top of the chain
+        new_block.print();
+        // only a seal of a sealer with stake is remembered, so that forged blocks cannot fill the memory
+        if new_block.slot > 0 && self.consensus.is_valid_header(&new_block.get_header(), &self.bc.chain) == true {
+            self.check_equivocation(&new_block).await;
         }
-        else if cmd == connection_manager::message_manager::MSG_NEW_TRANSACTION {
-            let new_transaction: Transaction = Transaction::from_str(&payload);
 
-            println!("received transaction: ");
-            new_transaction.print();
+        if self.chain_spec.is_checkpoint_block(self.bc.chain.len(), &new_block.get_hash()) == false {
+            println!("block conflicts with a checkpoint");
+            return false;
+        }
+        if self.utxo_set.height != self.bc.chain.len() {
+            println!("the UTXO set is not on the tip of the chain");
+            return false;
+        }
+        if self.consensus.is_valid_block(&new_block, &self.bc.chain) == true && self.utxo_set.apply_block(&new_block, true) == true {
+            println!("valid block is received and blockchain is updated");
+            let block_hash = new_block.get_hash();
+            self.previous_block_hash = block_hash.clone();
+            self.tp.remove_transactions_in_block(&new_block);
+            self.bc.set_new_block(new_block);
+            self.mining_state.lock().unwrap().block_accepted(&block_hash);
+            self.fe.process_block(&self.bc.chain[self.bc.chain.len() - 1], self.bc.chain.len());
+            self.prune_blocks();
+            return true;
+        }
+        false
+    }
 
-            if self.tp.contain(&new_transaction) {
-                println!("this has already been pooled transaction!");
-                return;
-            }
+    fn is_on_tip(&self, block: &Block) -> bool {
+        //! true if the block extends the tip of the chain. Such a block is rejected only if it is invalid.
+        block.previous_block == self.previous_block_hash && self.utxo_set.height == self.bc.chain.len()
+    }
 
-            if self.connection_manager.is_core(&ip, &port) == false {
-                self.tp.push(new_transaction);
-                println!("current transaction pool!");
-                self.tp.print();
-                let new_msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_NEW_TRANSACTION, &self.my_ip, &self.my_port, payload);
-                self.connection_manager.send_to_all_peer(&new_msg);
+    fn replace_chain(&mut self, new_block_chain: BlockChain) -> bool {
+        //! switch to a better valid chain and return the transactions of the orphan blocks to the pool.
+        //! Only the blocks after the fork point are validated and applied to the UTXO set.
+        let fork_height = self.bc.get_fork_height(&new_block_chain);
+        if self.consensus.choose_fork(&self.bc, &new_block_chain) == true && self.chain_spec.has_checkpoints(&new_block_chain) == true && self.consensus.is_valid_chain(&new_block_chain, fork_height) == true {
+            let (utxo_set, valid_transactions) = self.get_utxo_set_of_chain(&new_block_chain, fork_height);
+            if valid_transactions == false {
+                println!("Received blockchain has invalid transactions");
+                return false;
             }
-            else if self.tp.contain(&new_transaction) == false {
-                self.tp.push(new_transaction);
-                println!("current transaction pool!");
-                self.tp.print();
+            let orphan_blocks = self.bc.get_orphan_blocks(&new_block_chain);
+            let (previous_block_hash, flag)= self.bc.renew(new_block_chain);
+            if flag == true {
+                self.previous_block_hash = previous_block_hash;
+                self.utxo_set = utxo_set;
+                self.start_snapshot_validation();
+                self.prune_blocks();
+                if orphan_blocks.chain.len() != 0 {
+                    let new_transactions = self.bc.get_transactions_from_orphan_blocks(&orphan_blocks);
+
+                    for t in new_transactions.transactions {
+                        self.tp.set_new_transaction(t);
+                    }
+                }
             }
+            return flag;
         }
-        else if cmd == connection_manager::message_manager::MSG_NEW_BLOCK {
-            println!("MSG_NEW_BLOCK");
-            // if sender and receiver have the same ip and port, do nothing!
-            if self.my_ip.eq(ip) == true && self.my_port.eq(port) == true {
-                println!("sender and receiver have the same ip and port");
-                return;
+        println!("Received transaction is useless!");
+        false
+    }
+
+    async fn import_bootstrap_file(&mut self, path: &str) -> bool {
+        //! validate the blocks of a bootstrap file like blocks from peers. Blocks on top of the tip are added
+        //! one by one, and blocks forking from the chain replace it only if the consensus prefers them.
+        let (header, blocks) = match bootstrap::import_blocks(path) {
+            Some(result) => result,
+            None => return false,
+        };
+        let start_height = header.start_height;
+        if start_height > self.bc.chain.len() {
+            println!("the blocks start at {} above our chain of {} blocks", start_height, self.bc.chain.len());
+            return false;
+        }
+        if start_height == self.bc.chain.len() {
+            for block in blocks {
+                if self.add_new_block(block).await == false {
+                    println!("imported block is invalid");
+                    return false;
+                }
             }
-            if self.connection_manager.is_core(&ip, &port) == false {
-                println!("block received from unknown core node");
-                return;
+            println!("{} blocks are imported", header.block_count);
+            return true;
+        }
+
+        if start_height > 0 && self.bc.chain[start_height - 1].get_hash() != blocks[0].previous_block {
+            println!("the blocks are not linked to our chain at {}", start_height);
+            return false;
+        }
+        let mut new_block_chain = BlockChain { chain: self.bc.chain[..start_height].iter().map(|b| b.clone()).collect() };
+        for block in blocks {
+            new_block_chain.set_new_block(block);
+        }
+        self.replace_chain(new_block_chain)
+    }
+
+    fn get_utxo_set_of_chain(&self, bc: &BlockChain, fork_height: usize) -> (UTXOSet, bool) {
+        //! apply the transactions of a chain which has the first fork_height blocks of our chain. Our UTXO set is rolled
+        //! back to the fork point with its undo data, or else the blocks after a UTXO snapshot on the chain or all blocks
+        //! are replayed. Signatures up to the assume-valid block are not checked.
+        let assume_valid_height = self.chain_spec.get_assume_valid_height(bc);
+        let mut start_height = 0;
+        let mut utxo_set = UTXOSet::create();
+        if self.utxo_set.height == self.bc.chain.len() && self.utxo_set.can_undo_to(fork_height) == true {
+            utxo_set = self.utxo_set.clone();
+            utxo_set.undo_to(fork_height);
+            start_height = fork_height;
+        }
+        else if let Some(snapshot) = &self.snapshot {
+            if bc.chain.len() > snapshot.height && bc.chain[snapshot.height].get_hash() == snapshot.block_hash {
+                utxo_set = snapshot.to_utxo_set();
+                start_height = snapshot.height + 1;
             }
+        }
+        // a pruned block has no transactions to replay
+        if let Some(block) = bc.chain[start_height..].iter().find(|b| b.pruned == true) {
+            println!("block {} is pruned and cannot be replayed", block.get_hash());
+            return (utxo_set, false);
+        }
+        let flag = utxo_set.apply_blocks(&bc.chain[start_height..], assume_valid_height);
+        (utxo_set, flag)
+    }
+
+    fn prune_blocks(&mut self) {
+        //! delete the transactions of old blocks on a pruned node. Their block filters are built before.
+        if self.blocks_to_keep == 0 || self.bc.chain.len() <= self.blocks_to_keep {
+            return;
+        }
+        self.filter_index.get_filters(&self.bc.chain, self.bc.get_pruned_height());
+        let count = self.bc.prune(self.blocks_to_keep);
+        if count > 0 {
+            println!("{} blocks are pruned. blocks below {} have no transactions", count, self.bc.get_pruned_height());
+        }
+    }
 
-            w.to_stop.store(true, Ordering::Relaxed);
+    fn update_local_status(&self) {
+        //! tell new peers our services and height in the handshake
+        let mut services = connection_manager::handshake::NODE_MINER;
+        if self.blocks_to_keep > 0 {
+            services |= connection_manager::handshake::NODE_PRUNED;
+        }
+        else {
+            services |= connection_manager::handshake::NODE_FULL;
+        }
+        self.connection_manager.set_local_status(services, self.bc.chain.len() - 1);
+    }
 
-            let new_block = Block::from_string(&payload);
-            new_block.print();
+    async fn announce_pruned(&self) {
+        //! tell the peers not to request the full chain from this node
+        let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_PRUNED, &self.my_ip, &self.my_port, &self.bc.get_pruned_height().to_string());
+        self.connection_manager.send_to_all_peer(&msg).await;
+    }
 
-            if new_block.is_valid_block(&self.previous_block_hash) == true {
-                println!("valid block is received and blockchain is updated");
-                self.previous_block_hash = new_block.get_hash();
-                self.bc.set_new_block(new_block);
+    fn start_snapshot_validation(&self) {
+        //! verify the history up to the snapshot in the background
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot.clone(),
+            None => return,
+        };
+        if self.snapshot_status.load(Ordering::Relaxed) != SNAPSHOT_UNVERIFIED || self.bc.chain.len() <= snapshot.height {
+            return;
+        }
+        self.snapshot_status.store(SNAPSHOT_VERIFYING, Ordering::Relaxed);
+
+        let blocks: Vec<Block> = self.bc.chain[..snapshot.height + 1].iter().map(|b| b.clone()).collect();
+        let assume_valid_height = self.chain_spec.get_assume_valid_height(&self.bc);
+        let snapshot_status = Arc::clone(&self.snapshot_status);
+        tokio::task::spawn_blocking(move || {
+            println!("start validating the history up to the snapshot at {}", snapshot.height);
+            let mut utxo_set = UTXOSet::create();
+            let flag = utxo_set.apply_blocks(&blocks, assume_valid_height);
+            if flag == true && UTXOSnapshot::create(&utxo_set, &snapshot.block_hash).commitment == snapshot.commitment {
+                println!("the history up to the snapshot is valid");
+                snapshot_status.store(SNAPSHOT_VERIFIED, Ordering::Relaxed);
             }
             else {
-                // request all chains for resolving conflicts
-                println!("received blockchain is invalid. full chain is requested.");
-                let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_FULL_CHAIN, &self.my_ip, &self.my_port, "");
-                self.connection_manager.send_to_all_peer(&msg);
+                println!("the history does not match the snapshot");
+                snapshot_status.store(SNAPSHOT_INVALID, Ordering::Relaxed);
             }
+        });
+    }
+
+    async fn discard_snapshot(&mut self) {
+        //! go back to the genesis block and validate a full chain from scratch
+        println!("discard the UTXO snapshot");
+        self.snapshot = None;
+        let gc = Block::create_genesis_block();
+        self.previous_block_hash = gc.get_hash();
+        self.utxo_set = UTXOSet::create();
+        self.utxo_set.apply_block(&gc, false);
+        self.bc = BlockChain::create();
+        self.bc.set_new_block(gc);
+        self.sync = None;
+        self.request_headers().await;
+    }
+
+    async fn add_partial_block(&mut self, sender: &Peer, partial_block: &PartialBlock) -> bool {
+        //! add the block rebuilt from a compact block on our tip. An invalid block on our tip is misbehaviour of
+        //! the sender. A block which does not match its header after a short ID collision is dropped, and the
+        //! next compact block, whose parent is then unknown, brings the chain.
+        let new_block = match partial_block.to_block() {
+            Some(new_block) => new_block,
+            None => return false,
+        };
+        let on_tip = self.is_on_tip(&new_block);
+        if self.add_new_block(new_block).await == true {
+            return true;
+        }
+        if on_tip == true {
+            self.connection_manager.misbehave(sender, MISBEHAVIOUR_INVALID_BLOCK, "invalid compact block").await;
         }
-        else if cmd == connection_manager::message_manager::MSG_NEW_BLOCK_TO_ALL {
-            if self.connection_manager.is_core(&ip, &port) == false {
-                println!("block received from unknown core node");
+        false
+    }
+
+    async fn request_headers(&mut self) {
+        //! ask the full nodes for the headers after our tip. The chain is synced from the first node with new headers.
+        if let Some(sync) = &self.sync {
+            if sync.is_timed_out() == false {
                 return;
             }
+            println!("sync with {}:{} timed out", sync.ip, sync.port);
+            self.sync = None;
+        }
+        println!("the headers after our tip are requested.");
+        let tip_hash = self.bc.chain[self.bc.chain.len() - 1].get_hash();
+        let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_HEADERS, &self.my_ip, &self.my_port, &tip_hash);
+        self.connection_manager.send_to_all_full_node(&msg).await;
+    }
 
-            println!("MSG_NEW_BLOCK_TO_ALL");
-            w.to_stop.store(true, Ordering::Relaxed);
-
-            println!("msg payload: {}", payload);
-            let new_block = Block::from_string(&payload);
-            new_block.print();
-
-            if new_block.is_valid_block(&self.previous_block_hash) == true {
-                println!("valid block is received and blockchain is updated");
-                self.previous_block_hash = new_block.get_hash();
-                self.bc.set_new_block(new_block);
-                let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_NEW_BLOCK, &ip, &port, &payload);
-                self.connection_manager.send_to_all_peer(&msg);
+    async fn start_sync(&mut self, sender: &Peer, headers: &Vec<BlockHeader>) {
+        //! start a sync with the sender if its headers are not in our chain, or continue the sync with the next headers
+        let more_headers = headers.len() >= chain_sync::MAX_HEADERS_PER_MESSAGE;
+        if let Some(sync) = &mut self.sync {
+            if headers.len() == 0 {
+                self.finish_sync(sender).await;
+                return;
             }
-            else {
-                // request all chains for resolving conflicts
-                println!("received blockchain is invalid. full chain is requested.");
-                let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_FULL_CHAIN, &self.my_ip, &self.my_port, "");
-                self.connection_manager.send_to_all_peer(&msg);
+            if sync.add_headers(headers, more_headers) == false {
+                println!("headers from {}:{} do not follow the last header", sender.ip, sender.port);
+                self.sync = None;
+                return;
             }
         }
-        else if cmd == connection_manager::message_manager::RSP_FULL_CHAIN {
-            if self.connection_manager.is_core(&ip, &port) == false {
-                println!("block received from unknown core node");
+        else {
+            let (fork_height, known) = match chain_sync::find_fork(&self.bc, headers) {
+                Some(fork) => fork,
+                None => {
+                    println!("headers from {}:{} are not linked to our chain", sender.ip, sender.port);
+                    return;
+                }
+            };
+            if known == headers.len() {
+                if more_headers == true {
+                    let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_HEADERS, &self.my_ip, &self.my_port, &headers[known - 1].get_hash());
+                    self.connection_manager.send_msg(&sender.ip, &sender.port, &msg).await;
+                }
                 return;
             }
-            let new_block_chain = BlockChain::from_string(&payload);
-            let (new_bc, orphan_blocks, valid_flag) = self.bc.resolve_conflicts(new_block_chain);
-            if valid_flag == true {
-                let (previous_block_hash, flag)= self.bc.renew(new_bc);
-                if flag == true {
-                    self.previous_block_hash = previous_block_hash;
-                    if orphan_blocks.chain.len() != 0 {
-                        let new_transactions = self.bc.get_transactions_from_orphan_blocks(&orphan_blocks);
+            if more_headers == false && fork_height + headers.len() - known <= self.bc.chain.len() {
+                println!("the chain of {}:{} is not longer than ours", sender.ip, sender.port);
+                return;
+            }
+            println!("sync with {}:{} from {}", sender.ip, sender.port, fork_height);
+            self.sync = Some(ChainSync::create(&sender.ip, &sender.port, fork_height, &headers[known..], more_headers));
+        }
+        self.request_sync_blocks(sender).await;
+    }
 
-                        for t in new_transactions.transactions {
-                            self.tp.set_new_transaction(t);
-                        }
-                    }
+    async fn request_sync_blocks(&mut self, sender: &Peer) {
+        //! request the next batch of blocks, the next headers once all blocks are received, or finish the sync
+        let sync = match &mut self.sync {
+            Some(sync) => sync,
+            None => return,
+        };
+        let hashes = sync.next_batch();
+        let msg = if hashes.len() > 0 {
+            connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_BLOCKS, &self.my_ip, &self.my_port, &serde_json::to_string(&hashes).unwrap())
+        }
+        else if sync.more_headers == true {
+            connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_HEADERS, &self.my_ip, &self.my_port, &sync.get_last_hash())
+        }
+        else {
+            self.finish_sync(sender).await;
+            return;
+        };
+        let (ip, port) = (sync.ip.clone(), sync.port.clone());
+        self.connection_manager.send_msg(&ip, &port, &msg).await;
+    }
+
+    async fn add_sync_blocks(&mut self, sender: &Peer, blocks: Vec<Block>) -> bool {
+        //! add the blocks of the sync on our tip, or keep the blocks of a fork until all of them are received
+        let sync = match &mut self.sync {
+            Some(sync) => sync,
+            None => return false,
+        };
+        if sync.fork_height != self.bc.chain.len() || sync.blocks.len() > 0 {
+            sync.blocks.extend(blocks);
+            return true;
+        }
+        for block in blocks {
+            let on_tip = self.is_on_tip(&block);
+            if self.add_new_block(block).await == false {
+                if on_tip == true {
+                    self.connection_manager.misbehave(sender, MISBEHAVIOUR_INVALID_BLOCK, "invalid block").await;
                 }
+                return false;
             }
-            else {
-                println!("Received transaction is useless!");
-            }
         }
-        else if cmd == connection_manager::message_manager::MSG_ENHANCED {
-            // pass
+        self.connection_manager.mark_useful(sender);
+        if let Some(sync) = &mut self.sync {
+            sync.fork_height = self.bc.chain.len();
+        }
+        true
+    }
+
+    async fn finish_sync(&mut self, sender: &Peer) {
+        //! switch to the fork of the sync once all of its blocks are received
+        let sync = match self.sync.take() {
+            Some(sync) => sync,
+            None => return,
+        };
+        println!("sync with {}:{} is finished", sync.ip, sync.port);
+        if sync.blocks.len() == 0 || sync.fork_height > self.bc.chain.len() {
+            return;
         }
-        else if cmd == connection_manager::message_manager::MSG_UNLOCKED {
-            *locked = false;
-            *cur_time = Local::now().timestamp();
+        // a shorter chain is not misbehaviour, a longer chain which fails validation is
+        let new_block_chain = sync.get_fork_chain(&self.bc);
+        let better = self.consensus.choose_fork(&self.bc, &new_block_chain);
+        if self.replace_chain(new_block_chain) == true {
+            self.connection_manager.mark_useful(sender);
+        }
+        else if better == true && self.bc.is_valid() == true {
+            self.connection_manager.misbehave(sender, MISBEHAVIOUR_INVALID_CHAIN, "invalid chain").await;
+        }
+    }
+
+    async fn check_equivocation(&mut self, block: &Block) {
+        //! remember the sealer of each slot, and report a sealer which sealed two different blocks in one slot.
+        //! The seal of the block must have been verified.
+        if block.sealer.len() == 0 || block.slot == 0 {
+            return;
+        }
+        let key = (block.sealer.clone(), block.slot);
+        let first = match self.seen_seals.get(&key) {
+            Some(first) => match Block::from_string(first) {
+                Some(first) => first,
+                None => return,
+            },
+            None => {
+                self.seen_seals.retain(|k, _| k.1.saturating_add(SEEN_SEALS_SLOTS) > block.slot);
+                if self.seen_seals.len() >= MAX_SEEN_SEALS {
+                    let oldest = self.seen_seals.keys().min_by_key(|k| k.1).cloned();
+                    if let Some(oldest) = oldest {
+                        self.seen_seals.remove(&oldest);
+                    }
+                }
+                self.seen_seals.insert(key, block.to_string());
+                return;
+            }
+        };
+        if first.get_hash() == block.get_hash() {
+            return;
         }
-        else if cmd == connection_manager::message_manager::MSG_SENDMSGALLPEAR {
-            println!("send_msg_to_all_pear1: {}", payload);
-            self.connection_manager.send_to_all_peer(&payload);
+
+        let evidence = Transaction::create_evidence_transaction(&first, block);
+        if consensus::pos::get_equivocator(&evidence).is_none() {
+            return;
         }
+        println!("equivocation of {} in slot {} is detected", block.sealer, block.slot);
+        let new_msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_NEW_TRANSACTION, &self.my_ip, &self.my_port, &evidence.to_string());
+        self.tp.push(evidence);
+        self.connection_manager.send_to_all_peer(&new_msg).await;
     }
 
-    fn check_availability_of_transaction(&self, transaction: &Transaction) -> bool {
-        let (result, used_outputs) = verify_sbc_transaction_sig(&transaction);
+    fn get_fee_for_pool(&self, transaction: &Transaction) -> Option<i64> {
+        //! a transaction is pooled if it is valid on top of the chain and the pooled transactions.
+        //! It returns the fee computed from the spent outputs.
+        if self.utxo_set.height != self.bc.chain.len() {
+            println!("the UTXO set is not on the tip of the chain");
+            return None;
+        }
+        let mut view = UTXOView::create(&self.utxo_set);
+        for t in self.tp.transactions.iter() {
+            view.apply_transaction(t, false);
+        }
+        view.check_transaction(transaction, true)
+    }
 
-        if result == false {
-            println!("signature verification error on new transaction");
+    fn make_room_in_pool(&mut self, fee_rate: f64) -> bool {
+        //! a full pool evicts its transaction of the lowest fee rate, if it pays less than fee_rate, with the pooled
+        //! transactions spending its outputs. Votes and evidence pay no fee, so they are evicted first.
+        if self.tp.len() < MAX_POOL_TRANSACTIONS {
+            return true;
+        }
+        let mut view = UTXOView::create(&self.utxo_set);
+        let mut lowest: Option<(usize, f64)> = None;
+        for (i, t) in self.tp.transactions.iter().enumerate() {
+            let rate = match view.apply_transaction(t, false) {
+                Some(fee) if t.inputs.len() > 0 => fee_estimator::get_fee_rate(t, fee),
+                _ => 0.0,
+            };
+            let is_lower = match lowest {
+                Some((_, lowest_rate)) => rate < lowest_rate,
+                None => true,
+            };
+            if is_lower == true {
+                lowest = Some((i, rate));
+            }
+        }
+        let (index, lowest_rate) = match lowest {
+            Some(lowest) => lowest,
+            None => return false,
+        };
+        if lowest_rate >= fee_rate {
             return false;
         }
+        println!("evict a pooled transaction of fee rate {}", lowest_rate);
+        self.tp.transactions.remove(index);
 
-        for used_o in used_outputs.iter() { 
-            let bv_v_result = self.bc.has_this_output_in_my_chain(used_o);
+        let mut view = UTXOView::create(&self.utxo_set);
+        self.tp.transactions.retain(|t| view.apply_transaction(t, false).is_some());
+        true
+    }
 
-            if bv_v_result == true {
-                println!("This transactionOutput is already used");
-                return false;
-            }
+    fn get_valid_pool(&mut self) -> (TransactionPool, i64) {
+        //! drop the pooled transactions which are no longer valid on top of the chain, and return the rest
+        //! with their total fee. The pool keeps them until a block with them is accepted.
+        let (transactions, total_fee) = self.utxo_set.filter_transactions(&self.tp.transactions);
+        if transactions.len() < self.tp.len() {
+            println!("{} pooled transactions are no longer valid", self.tp.len() - transactions.len());
         }
-        return true;
+        self.tp.transactions = transactions;
+        (self.tp.clone(), total_fee)
     }
 
-    pub fn generate_block_with_tp(transaction_pool: TransactionPool, km: block_chain::keymanager::KeyManager, previous_block_hash: String, my_ip: String, my_port: String, tx: mpsc::Sender<String>, to_stop: &Arc<AtomicBool>) {
-        //! It generates a block with a transaction pool by the proof of work algorithm.
+    pub async fn generate_block_with_tp(transaction_pool: TransactionPool, total_fee: i64, km: block_chain::keymanager::KeyManager, previous_block_hash: String, my_ip: String, my_port: String, tx: UnboundedSender<NodeEvent>, to_stop: Arc<AtomicBool>, consensus: Arc<dyn Consensus>) {
+        //! It generates a block with the valid pooled transactions and their total fee, and seals it with the consensus engine.
+        //! Sealing may take long, so it runs on a blocking thread and is stopped by to_stop.
 
         if transaction_pool.len() == 0 {
             return;
         }
 
-        let mut total_fee = transaction_pool.get_total_fee_from_tp();
-        total_fee += 30;
+        let total_fee = total_fee + MINING_REWARD;
 
         let my_coinbase_t = Transaction::create_coinbase_transaction(&km.public_key_str, total_fee);
 
         let mut new_transaction_pool = transaction_pool.clone();
         new_transaction_pool.set_new_transaction(my_coinbase_t);
         let mut new_block = Block::create(new_transaction_pool, previous_block_hash);
-        let flag = new_block.compute_nonce_for_pow(to_stop);
-        if flag == true {
+        let sealed = tokio::task::spawn_blocking(move || {
+            let flag = consensus.seal(&mut new_block, &km, &to_stop);
+            (flag, new_block)
+        }).await;
+        if let Ok((true, new_block)) = sealed {
             let new_block_string = new_block.to_string();
             let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_NEW_BLOCK_TO_ALL, &my_ip, &my_port, &new_block_string);
             println!("created transaction msg: {}", msg);
-            tx.send(msg).unwrap();  
+            tx.send(NodeEvent::Local(LocalCommand::Message(msg))).unwrap();
         }
         let cur_time = Local::now().timestamp();
         let msg2 = connection_manager::get_message_text(connection_manager::message_manager::MSG_UNLOCKED, &my_ip, &my_port, &cur_time.to_string());
-        tx.send(msg2).unwrap();
+        tx.send(NodeEvent::Local(LocalCommand::Message(msg2))).unwrap();
     }
 
-    pub fn get_total_fee_on_block(&self, block: &Block) -> i64 {
-        println!("get_total_fee_on_block is called!");
+    async fn wait_for_access(&mut self) {
+        println!("execute wait_for_access");
+        let addr = self.my_ip.to_string() + ":" + &self.my_port;
 
-        let transactions = &block.transaction_pool.transactions;
-        let mut result = 0;
-        for t in transactions.iter() {
-            let mut total_in = 0;
-            for i in t.inputs.iter() {
-                total_in += i.transaction.outputs[i.output_index].value;
-            }
-            let mut total_out = 0;
-            for o in t.outputs.iter() {
-                total_out += o.value;
+        println!("starting server at {}", addr);
+        let server = TcpListener::bind(addr).await.expect("Faiulre in starting server");
+
+        let (tx, mut rx) = unbounded_channel::<NodeEvent>();
+        self.node_tx = Some(tx.clone());
+
+        // accept in its own task so that a handshake with a peer never waits for this node's loop
+        let listener_cm = self.connection_manager.clone();
+        let listener_tx = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Ok((client, _)) = server.accept().await {
+                    println!("Connected by {:?}", client.peer_addr());
+                    listener_cm.receiver(client, listener_tx.clone());
+                }
             }
-            let delta = total_in - total_out;
-            result += delta;
-        }
-        return result;
-    }
+        });
+
+        let mut server_core = self.clone();
 
-    pub fn check_transactions_in_new_block(&self, block: &Block) -> bool {
-        let mut fee_for_block: i64 = self.get_total_fee_on_block(block);
-        fee_for_block += 30;
-        
-        println!("fee_for_block: {}", fee_for_block);
+        let mut producer = ProducerState {
+            locked: false,
+            cur_time: Local::now().timestamp(),
+            w: Worker::new()
+        };
+        let mut iter = 0;
 
-        let transactions = &block.transaction_pool.transactions;
+        let external_mining = self.mining_port.len() > 0;
+        if external_mining == true {
+            // miners connect from the same host. The port is not exposed to the network.
+            let mining_addr = "127.0.0.1:".to_string() + &self.mining_port;
+            mining_server::start_mining_server(&mining_addr, Arc::clone(&self.mining_state), Arc::clone(&self.consensus), tx.clone(), &self.my_ip, &self.my_port).await;
+        }
 
-        let mut counter = 0;
+        let mut ticker = tokio::time::interval(Duration::from_millis(EVENT_LOOP_TICK_MS));
+        tokio::spawn(async move {
+            loop {
+                // wait until a message arrives or the timers are due
+                tokio::select! {
+                    event = rx.recv() => {
+                        match event {
+                            Some(NodeEvent::Remote(sender, msg)) => {
+                                if server_core.connection_manager.is_peer_banned(&sender) == true {
+                                    println!("message from banned peer {}:{} is dropped", sender.ip, sender.port);
+                                    continue;
+                                }
+                                server_core.handle_received_message(&sender, &msg, &mut producer).await;
+                            },
+                            Some(NodeEvent::Local(command)) => server_core.handle_local_command(command, &mut producer).await,
+                            None => break,
+                        }
+                        continue;
+                    },
+                    _ = ticker.tick() => {},
+                }
 
-        for t in transactions.iter() {
-            if t.tx_type == false { // for general transaction
-                let flag = self.check_availability_of_transaction(t);
-                if flag == false {
-                    println!("Bad block. Having invalid Transaction");
-                    return false;
+                server_core.bc.print();
+                server_core.update_local_status();
+                if external_mining == true {
+                    server_core.update_mining_state();
                 }
-            }
-            else { // for coinbase transaction
-                if counter != 0 {
-                    println!("Coinbase Transaction is only for BlockBuilder!");
-                    return false;
+                else if producer.locked == false && server_core.tp.len() > 0 && server_core.consensus.is_ready_to_produce(&server_core.bc, &server_core.km, producer.cur_time, Local::now().timestamp()) == true {
+                    // one block at a time. MSG_UNLOCKED from the producer task releases the lock.
+                    producer.locked = true;
+                    let (transaction_pool_clone, total_fee) = server_core.get_valid_pool();
+                    let km_clone = server_core.km.clone();
+                    let previous_block_hash_clone = server_core.previous_block_hash.clone();
+                    let my_ip_tmp = server_core.my_ip.clone();
+                    let my_port_tmp = server_core.my_port.clone();
+                    let tx_clone = tx.clone();
+                    producer.cur_time = Local::now().timestamp();
+                    // a stopped sealer may still be running, so each task has its own flag
+                    producer.w.to_stop = Arc::new(AtomicBool::new(false));
+                    let to_stop = Arc::clone(&producer.w.to_stop);
+                    let consensus_clone = Arc::clone(&server_core.consensus);
+
+                    producer.w.task = Some(tokio::spawn(ServerCore::generate_block_with_tp(transaction_pool_clone, total_fee, km_clone, previous_block_hash_clone, my_ip_tmp, my_port_tmp, tx_clone, to_stop, consensus_clone)));
                 }
-                else {
-                    let insentive = t.outputs[0].value;
-                    println!("insentive: {}", insentive);
-                    if insentive != fee_for_block {
-                        println!("Invalid value in fee for Coinbase Transaction {}", insentive);
-                        return false;
+                if server_core.snapshot.is_some() && server_core.snapshot_status.load(Ordering::Relaxed) == SNAPSHOT_INVALID {
+                    server_core.discard_snapshot().await;
+                }
+                if iter == CHECK_PEERS_CONNECTION_INTERVAL {
+                    server_core.connection_manager.check_peers_connection(tx.clone()).await;
+                    if server_core.blocks_to_keep > 0 {
+                        server_core.announce_pruned().await;
                     }
+                    iter = 0;
                 }
+                iter += 1;
             }
-        }
-        println!("ok. this block is acceptable.");
-        return true;
+        });
     }
+}
 
-    pub fn check_availability_of_transaction_in_block(&self, transaction: &Transaction) -> bool {
-        let (result, used_outputs) = verify_sbc_transaction_sig(transaction);
-
-        if result == false {
-            println!("signature verification error on new transaction");
-            return false;
-        }
+impl MessageHandler for ServerCore {
+    type Context = ProducerState;
 
-        for used_o in used_outputs.iter() {
-            println!("used outputs: ");
-            used_o.print();
+    async fn handle_message(&mut self, sender: &Peer, message: &Message, producer: &mut ProducerState) {
+        let ip = &sender.ip;
+        let port = &sender.port;
 
-            let bm_v_result = self.bc.has_this_output_in_my_chain(used_o);
-//            let bm_v_result2 = self.bc.is_valid
+        println!("received msg cmd ip port {} {} {}", message.get_type(), ip, port);
 
-            if bm_v_result == false {
-                println!("This transaction output is unknown.");
-                return false;
-            }
-        }
-        return true;
-    }
+        match message {
+            Message::RequestFullChain => {
+                let pruned_height = self.bc.get_pruned_height();
+                if pruned_height > 0 {
+                    println!("Tell {}:{} that our blocks below {} are pruned", ip, port, pruned_height);
+                    let new_msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_PRUNED, &self.my_ip, &self.my_port, &pruned_height.to_string());
+                    self.connection_manager.send_msg(ip, port, &new_msg).await;
+                    return;
+                }
+                println!("Send our latest blockchain to : {}:{}", ip, port);
+                let bc_str = self.bc.to_string();
+                let new_msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_FULL_CHAIN, &self.my_ip, &self.my_port, &bc_str);
+                self.connection_manager.send_msg(ip, port, &new_msg).await;
+            },
+            Message::NewTransaction(new_transaction) => {
+                println!("received transaction: ");
+                new_transaction.print();
+
+                if self.tp.contain(new_transaction) {
+                    println!("this has already been pooled transaction!");
+                    return;
+                }
+                if new_transaction.inputs.len() > 0 && verify_sbc_transaction_sig(new_transaction).0 == false {
+                    println!("transaction signature is invalid");
+                    self.connection_manager.misbehave(sender, MISBEHAVIOUR_BAD_SIGNATURE, "invalid transaction signature").await;
+                    return;
+                }
+                if new_transaction.inputs.len() == 0 && self.consensus.is_valid_payload(new_transaction, &self.bc) == false {
+                    println!("transaction is rejected: it has no inputs and no valid governance vote or evidence");
+                    return;
+                }
+                let fee = match self.get_fee_for_pool(new_transaction) {
+                    Some(fee) => fee,
+                    None => {
+                        println!("transaction is rejected: it is a coinbase, double spend or overspend, or spends unknown outputs");
+                        return;
+                    }
+                };
+                // votes and evidence pay no fee
+                let fee_rate = if new_transaction.inputs.len() == 0 { 0.0 } else { fee_estimator::get_fee_rate(new_transaction, fee) };
+                if new_transaction.inputs.len() > 0 && fee_rate < fee_estimator::MIN_FEE_RATE {
+                    println!("transaction is rejected: fee rate {} is below {}", fee_rate, fee_estimator::MIN_FEE_RATE);
+                    return;
+                }
+                if self.make_room_in_pool(fee_rate) == false {
+                    println!("transaction is rejected: the pool is full of transactions paying more");
+                    return;
+                }
+                self.connection_manager.mark_useful(sender);
 
-    fn wait_for_access(&self) {
-        println!("execute wait_for_access");
-        let addr = self.my_ip.to_string() + ":" + &self.my_port;
+                self.fe.process_transaction(new_transaction, fee, self.bc.chain.len());
+                self.recent_transactions.push_back(new_transaction.clone());
+                if self.recent_transactions.len() > RECENT_TRANSACTIONS_SIZE {
+                    self.recent_transactions.pop_front();
+                }
 
-        println!("starting server at {}", addr);
-        let server = TcpListener::bind(addr).expect("Faiulre in starting server");
-        server.set_nonblocking(true).expect("unusable");
+                if self.connection_manager.is_core(ip, port) == false {
+                    self.tp.push(new_transaction.clone());
+                    println!("current transaction pool!");
+                    self.tp.print();
+                    let new_msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_NEW_TRANSACTION, &self.my_ip, &self.my_port, &new_transaction.to_string());
+                    self.connection_manager.send_to_all_peer(&new_msg).await;
+                }
+                else if self.tp.contain(new_transaction) == false {
+                    self.tp.push(new_transaction.clone());
+                    println!("current transaction pool!");
+                    self.tp.print();
+                }
+            },
+            Message::NewBlock(new_block) => {
+                println!("MSG_NEW_BLOCK");
+                // if sender and receiver have the same ip and port, do nothing!
+                if self.my_ip.eq(ip) == true && self.my_port.eq(port) == true {
+                    println!("sender and receiver have the same ip and port");
+                    return;
+                }
+                if self.connection_manager.is_core(ip, port) == false {
+                    println!("block received from unknown core node");
+                    return;
+                }
 
-        let (tx, rx) = mpsc::channel::<String>();
+                let on_tip = self.is_on_tip(new_block);
+                if self.add_new_block(new_block.clone()).await == false {
+                    if on_tip == true {
+                        self.connection_manager.misbehave(sender, MISBEHAVIOUR_INVALID_BLOCK, "invalid block").await;
+                    }
+                    self.request_headers().await;
+                }
+                else {
+                    // the block being produced is on the old tip. An invalid block must not stop it.
+                    producer.stop();
+                    self.connection_manager.mark_useful(sender);
+                }
+            },
+            Message::NewBlockToAll(new_block) => {
+                if self.connection_manager.is_core(ip, port) == false {
+                    println!("block received from unknown core node");
+                    return;
+                }
 
-        let mut server_core = self.clone();
+                println!("MSG_NEW_BLOCK_TO_ALL");
+                let compact_block = CompactBlock::create(new_block);
+                let on_tip = self.is_on_tip(new_block);
+                if self.add_new_block(new_block.clone()).await == true {
+                    producer.stop();
+                    // announce the block by a compact block. peers rebuild it from their transaction pools.
+                    // peers which did not negotiate compact blocks receive the full block.
+                    let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_COMPACT_BLOCK, &self.my_ip, &self.my_port, &compact_block.to_string());
+                    let fallback_msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_NEW_BLOCK, &self.my_ip, &self.my_port, &new_block.to_string());
+                    self.connection_manager.send_to_all_peer_with_feature(connection_manager::handshake::FEATURE_COMPACT_BLOCKS, &msg, &fallback_msg).await;
+                }
+                else {
+                    if on_tip == true {
+                        self.connection_manager.misbehave(sender, MISBEHAVIOUR_INVALID_BLOCK, "invalid block").await;
+                    }
+                    self.request_headers().await;
+                }
+            },
+            Message::CompactBlock(compact_block) => {
+                println!("MSG_COMPACT_BLOCK");
+                if self.my_ip.eq(ip) == true && self.my_port.eq(port) == true {
+                    println!("sender and receiver have the same ip and port");
+                    return;
+                }
+                if self.connection_manager.is_core(ip, port) == false {
+                    println!("block received from unknown core node");
+                    return;
+                }
 
-        let mut cur_time = Local::now().timestamp();
-        let mut locked: bool = false; 
-        let w = Worker::new();
-        let mut iter = 0;
-        thread::spawn(move || loop {
-            server_core.bc.print();
-            if let Ok((client, addr)) = server.accept() {
-                println!("Connected by {}", addr);
-                connection_manager::receiver(client, tx.clone());
-            }
-            if let Ok(msg) = rx.try_recv() {
-                println!("received msg: {}", msg);
-                server_core.connection_manager.handle_message(&msg);
-                server_core.handle_message(&msg, &mut locked, &mut cur_time, &w);
-            }
-            if locked == false && Local::now().timestamp() - cur_time > MINING_INTERVAL{
-                let transaction_pool_clone = server_core.tp.clone();
-                let km_clone = server_core.km.clone();
-                server_core.tp.clear();
-                let previous_block_hash_clone = server_core.previous_block_hash.clone();
-                let my_ip_tmp = server_core.my_ip.clone();
-                let my_port_tmp = server_core.my_port.clone();
-                let tx_clone = tx.clone();
-                cur_time = Local::now().timestamp();
-                let to_stop = Arc::clone(&w.to_stop);
-
-                thread::spawn(move || {
-                    ServerCore::generate_block_with_tp(transaction_pool_clone, km_clone, previous_block_hash_clone, my_ip_tmp, my_port_tmp, tx_clone, &to_stop);
-                });
-            }
-            if iter == CHECK_PEERS_CONNECTION_INTERVAL {
-                server_core.connection_manager.check_peers_connection(tx.clone());
-                iter = 0;
-            }
-            iter += 1;
+                let block_hash = compact_block.get_hash();
+                if self.bc.chain.iter().any(|b| b.get_hash() == block_hash) == true {
+                    println!("block is already in the chain");
+                    return;
+                }
+                // anybody can make a header, so it is checked before any work or request for it
+                if self.consensus.is_valid_header(&compact_block.header, &self.bc.chain) == false {
+                    println!("compact block has an invalid header");
+                    self.connection_manager.misbehave(sender, MISBEHAVIOUR_INVALID_BLOCK, "invalid compact block header").await;
+                    return;
+                }
+                if compact_block.header.previous_block != self.previous_block_hash {
+                    if self.bc.chain.iter().any(|b| b.get_hash() == compact_block.header.previous_block) == true {
+                        println!("compact block is on a fork below our tip");
+                    }
+                    else {
+                        println!("parent of the compact block is unknown");
+                        self.request_headers().await;
+                    }
+                    return;
+                }
 
-            thread::sleep(Duration::from_millis(1000));
-        });
+                let mut candidates: Vec<Transaction> = self.tp.transactions.iter().map(|t| t.clone()).collect();
+                for t in self.recent_transactions.iter() {
+                    candidates.push(t.clone());
+                }
+                let partial_block = compact_block.to_partial_block(&candidates);
+                let missing = partial_block.get_missing();
+                if missing.len() == 0 {
+                    if self.add_partial_block(sender, &partial_block).await == true {
+                        producer.stop();
+                        self.connection_manager.mark_useful(sender);
+                    }
+                }
+                else {
+                    println!("{} transactions are missing. request them to {}:{}", missing.len(), ip, port);
+                    if self.pending_compact_blocks.len() >= MAX_PENDING_COMPACT_BLOCKS {
+                        self.pending_compact_blocks.clear();
+                    }
+                    self.pending_compact_blocks.insert(block_hash.clone(), partial_block);
+                    let request = BlockTransactionsRequest { block_hash: block_hash, indexes: missing };
+                    let msg = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_BLOCK_TRANSACTIONS, &self.my_ip, &self.my_port, &serde_json::to_string(&request).unwrap());
+                    self.connection_manager.send_msg(ip, port, &msg).await;
+                }
+            },
+            Message::RequestBlockTransactions(request) => {
+                for block in self.bc.chain.iter() {
+                    if block.get_hash() == request.block_hash && block.pruned == false {
+                        let response = BlockTransactions::create(block, request);
+                        println!("Send {} block transactions to : {}:{}", response.transactions.len(), ip, port);
+                        let msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_BLOCK_TRANSACTIONS, &self.my_ip, &self.my_port, &serde_json::to_string(&response).unwrap());
+                        self.connection_manager.send_msg(ip, port, &msg).await;
+                        break;
+                    }
+                }
+            },
+            Message::BlockTransactions(response) => {
+                match self.pending_compact_blocks.remove(&response.block_hash) {
+                    Some(mut partial_block) => {
+                        partial_block.fill(&response.transactions);
+                        if self.add_partial_block(sender, &partial_block).await == true {
+                            producer.stop();
+                            self.connection_manager.mark_useful(sender);
+                        }
+                    },
+                    None => println!("block transactions for an unknown compact block"),
+                }
+            },
+            Message::Headers(headers) => {
+                if self.connection_manager.is_core(ip, port) == false {
+                    println!("headers received from unknown core node");
+                    return;
+                }
+                if let Some(sync) = &self.sync {
+                    if sync.is_peer(ip, port) == false {
+                        println!("headers from {}:{} are dropped during the sync with {}:{}", ip, port, sync.ip, sync.port);
+                        return;
+                    }
+                }
+                // the sync may finish on these headers. The block being produced is stopped only if our tip moved.
+                let tip = self.previous_block_hash.clone();
+                self.start_sync(sender, headers).await;
+                if self.previous_block_hash != tip {
+                    producer.stop();
+                }
+            },
+            Message::Blocks(blocks) => {
+                let blocks = match &mut self.sync {
+                    Some(sync) if sync.is_peer(ip, port) == true => match sync.take_blocks(blocks) {
+                        Some(blocks) => blocks,
+                        None => {
+                            println!("{}:{} did not send the requested blocks", ip, port);
+                            self.sync = None;
+                            return;
+                        }
+                    },
+                    _ => {
+                        println!("blocks from {}:{} are not synced", ip, port);
+                        return;
+                    }
+                };
+                let tip = self.previous_block_hash.clone();
+                if self.add_sync_blocks(sender, blocks).await == false {
+                    self.sync = None;
+                }
+                else {
+                    self.request_sync_blocks(sender).await;
+                }
+                if self.previous_block_hash != tip {
+                    producer.stop();
+                }
+            },
+            Message::RequestFeeEstimate(target_blocks) => {
+                let fee_estimate = FeeEstimate {
+                    target_blocks: *target_blocks,
+                    fee_rate: self.fe.estimate_fee_rate(*target_blocks)
+                };
+                println!("Send fee estimate {} for {} blocks to : {}:{}", fee_estimate.fee_rate, target_blocks, ip, port);
+                let new_msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_FEE_ESTIMATE, &self.my_ip, &self.my_port, &fee_estimate.to_string());
+                self.connection_manager.send_msg(ip, port, &new_msg).await;
+            },
+            Message::RequestHeaders(last_hash) => {
+                let mut headers = self.bc.get_headers_after(last_hash);
+                headers.truncate(chain_sync::MAX_HEADERS_PER_MESSAGE);
+                println!("Send {} headers to : {}:{}", headers.len(), ip, port);
+                let new_msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_HEADERS, &self.my_ip, &self.my_port, &serde_json::to_string(&headers).unwrap());
+                self.connection_manager.send_msg(ip, port, &new_msg).await;
+            },
+            Message::RequestMerkleProofs(address) => {
+                let proofs = merkle::get_merkle_proofs_for_address(&self.bc.chain, address);
+                println!("Send {} merkle proofs to : {}:{}", proofs.len(), ip, port);
+                let new_msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_MERKLE_PROOFS, &self.my_ip, &self.my_port, &serde_json::to_string(&proofs).unwrap());
+                self.connection_manager.send_msg(ip, port, &new_msg).await;
+            },
+            Message::RequestFilters(start_height) => {
+                let filters = self.filter_index.get_filters(&self.bc.chain, *start_height);
+                println!("Send {} block filters to : {}:{}", filters.len(), ip, port);
+                let new_msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_FILTERS, &self.my_ip, &self.my_port, &serde_json::to_string(&filters).unwrap());
+                self.connection_manager.send_msg(ip, port, &new_msg).await;
+            },
+            Message::RequestBlocks(hashes) => {
+                // the blocks are escaped in the message, so they may fill half of a frame
+                let mut blocks: Vec<&Block> = Vec::new();
+                let mut size = 0;
+                for block in self.bc.chain.iter().filter(|b| b.pruned == false && hashes.contains(&b.get_hash())) {
+                    size += block.to_string().len();
+                    if size > connection_manager::frame::MAX_PAYLOAD_SIZE / 2 {
+                        break;
+                    }
+                    blocks.push(block);
+                }
+                println!("Send {} blocks to : {}:{}", blocks.len(), ip, port);
+                let new_msg = connection_manager::get_message_text(connection_manager::message_manager::RSP_BLOCKS, &self.my_ip, &self.my_port, &serde_json::to_string(&blocks).unwrap());
+                self.connection_manager.send_msg(ip, port, &new_msg).await;
+            },
+            Message::Enhanced(_) => {
+                // pass
+            },
+            Message::Unlocked(_) => {
+                producer.locked = false;
+                producer.cur_time = Local::now().timestamp();
+            },
+            Message::SendMsgAllPeer(msg) => {
+                println!("send_msg_to_all_pear1: {}", msg);
+                self.connection_manager.send_to_all_peer(msg).await;
+            },
+            _ => {},
+        }
     }
 }
 
 pub fn verify_signature(message: &str, signature_box_str: &str, sender_public_key_box_str: &str) -> bool {
-    let signature_box = SignatureBox::from_string(signature_box_str).unwrap();
+    //! signatures and keys come from peers, so a malformed one is an invalid signature
+    let signature_box = match SignatureBox::from_string(signature_box_str) {
+        Ok(signature_box) => signature_box,
+        Err(_) => return false,
+    };
        
-    let pk_box = PublicKeyBox::from_string(&sender_public_key_box_str).unwrap();
-    let pk = pk_box.into_public_key().unwrap();
+    let pk = match PublicKeyBox::from_string(sender_public_key_box_str).and_then(|pk_box| pk_box.into_public_key()) {
+        Ok(pk) => pk,
+        Err(_) => return false,
+    };
 
     let msg_reader = Cursor::new(message);
     let verified = minisign::verify(&pk, &signature_box, msg_reader, true, false, false);
 
-    let flag: bool;
-    match verified {
-        Ok(()) => flag = true,
-        Err(_) => flag = false,
+    let flag = match verified {
+        Ok(()) => true,
+        Err(_) => false,
     };
     return flag;
 }
@@ -500,31 +1436,42 @@ pub fn verify_signature(message: &str, signature_box_str: &str, sender_public_ke
 pub fn verify_sbc_transaction_sig(transaction: &Transaction) -> (bool, Vec<block_chain::TransactionOutput>){
     println!("verify_sbc_transaction_sig was called");
 
-    let (sender_pubkey_text, used_outputs) = get_pubkey_from_sbc_transaction(transaction);
+    let used_outputs = get_used_outputs_of_sbc_transaction(transaction);
+    let result = used_outputs.len() > 0 && verify_transaction_sig(transaction, &used_outputs);
+
+    return (result, used_outputs);
+}
+
+pub fn verify_transaction_sig(transaction: &Transaction, used_outputs: &[block_chain::TransactionOutput]) -> bool {
+    //! A transaction has one signature, so all the outputs it spends must have the same owner, who signed it.
+    let sender_pubkey_text = match used_outputs.first() {
+        Some(output) => &output.recipient,
+        None => return false,
+    };
+    if used_outputs.iter().any(|o| o.recipient.eq(sender_pubkey_text) == false) {
+        println!("inputs of a transaction have different owners");
+        return false;
+    }
     let signature = &transaction.signature;
     let mut c_transaction = transaction.clone();
     c_transaction.signature = "".to_string();
     let target_txt = c_transaction.to_string();
 
-    let result = verify_signature(&target_txt, &signature, &sender_pubkey_text);
-
-    return (result, used_outputs);
+    verify_signature(&target_txt, signature, sender_pubkey_text)
 }
 
-fn get_pubkey_from_sbc_transaction(transaction: &Transaction) -> (String, Vec<block_chain::TransactionOutput>){
-    println!("get_public_key_from_sbc_transaction was called");
-
-    let input_t_list = &transaction.inputs;
+fn get_used_outputs_of_sbc_transaction(transaction: &Transaction) -> Vec<block_chain::TransactionOutput> {
+    //! the outputs the inputs of a transaction refer to, or nothing if one of them does not exist
     let mut used_output: Vec<block_chain::TransactionOutput> = Vec::new();
-    let mut sender_pubkey: &String = &"".to_string();
-    for i in input_t_list.iter() {
+    for i in transaction.inputs.iter() {
         let idx = i.output_index;        
-        let tx = &i.transaction.outputs[idx];
+        let tx = match i.transaction.outputs.get(idx) {
+            Some(tx) => tx,
+            None => return Vec::new(),
+        };
         used_output.push(tx.clone());
-        sender_pubkey = &tx.recipient;
     }
-
-    (sender_pubkey.clone(), used_output)
+    used_output
 }
 
 pub struct ClientCore {
@@ -535,7 +1482,12 @@ pub struct ClientCore {
     pub core_node_port: String, 
     pub bc: BlockChain,
     pub prev_block_hash: String, 
-    pub cm: ConnectionManager4Edge
+    pub cm: ConnectionManager4Edge,
+    /// true if the edge node keeps headers only and verifies Merkle proofs of its transactions
+    pub spv: bool,
+    pub headers: HeaderChain,
+    /// the filter headers of the blocks in headers whose filters were received
+    pub filter_headers: FilterHeaderChain
 }
 
 impl ClientCore {
@@ -551,28 +1503,34 @@ impl ClientCore {
             core_node_port: core_node_port.to_string(),
             bc: BlockChain::create(),
             prev_block_hash: String::new(),
-            cm: ConnectionManager4Edge::create(my_ip, my_port, core_node_ip, core_node_port)
+            cm: ConnectionManager4Edge::create(my_ip, my_port, core_node_ip, core_node_port),
+            spv: false,
+            headers: HeaderChain::create(),
+            filter_headers: FilterHeaderChain::create()
         }
     }
 
     pub fn clone(&self) -> ClientCore {
         ClientCore {
-            client_state: self.client_state.clone(),
+            client_state: self.client_state,
             my_ip: self.my_ip.clone(),
             my_port: self.my_port.clone(),
             core_node_ip: self.core_node_ip.clone(),
             core_node_port: self.core_node_port.clone(),
             bc: self.bc.clone(),
             prev_block_hash: self.prev_block_hash.clone(),
-            cm: self.cm.clone()
+            cm: self.cm.clone(),
+            spv: self.spv,
+            headers: self.headers.clone(),
+            filter_headers: self.filter_headers.clone()
         }
     }
 
-    pub fn start(&mut self, tx: &mpsc::Sender<String>) {
+    pub async fn start(&mut self, tx: &mpsc::Sender<String>) {
         println!("Start edge node ....");
         self.client_state = STATE_ACTIVE_4EDGE;
-        self.wait_for_access_4edge(tx.clone());
-        self.cm.connect_to_core_node_4edge();
+        self.wait_for_access_4edge(tx.clone()).await;
+        self.cm.connect_to_core_node_4edge().await;
     }
 
     pub fn shutdown(&mut self) {
@@ -584,81 +1542,250 @@ impl ClientCore {
         self.client_state
     }
 
-    pub fn send_message_to_my_core_node(&mut self, msg_type: usize, msg: &str) {
-        let msg = connection_manager::get_message_text(msg_type, &self.core_node_ip, &self.core_node_port, &msg);
+    pub fn enable_spv(&mut self) {
+        //! keep headers only instead of the full chain. It must be called before start.
+        self.spv = true;
+    }
+
+    pub fn set_chain_spec(&mut self, spec: &ChainSpec) -> bool {
+        //! connect only to the core nodes of the chain and check headers by its consensus. It must be called before start.
+        match HeaderChain::create_with_chain_spec(spec) {
+            Some(headers) => self.headers = headers,
+            None => {
+                println!("light clients cannot follow the consensus engine of the chain spec");
+                return false;
+            }
+        }
+        self.cm.set_chain_id(&spec.get_chain_id());
+        true
+    }
+
+    pub fn pin_core_node_key(&mut self, ip: &str, port: &str, public_key: &str) -> bool {
+        //! connect to the core node at ip and port only if it has the static key
+        self.cm.pin_core_node_key(ip, port, public_key)
+    }
+
+    pub fn load_pinned_keys(&mut self, path: &str) -> usize {
+        //! pin the keys of the core nodes listed in the configuration file at path
+        self.cm.load_pinned_keys(path)
+    }
+
+    pub async fn send_message_to_my_core_node(&mut self, msg_type: usize, msg: &str) {
+        let msg = connection_manager::get_message_text(msg_type, &self.core_node_ip, &self.core_node_port, msg);
         println!("msgtxt: {}", msg);
-        self.cm.send_msg(&msg);
+        self.cm.send_msg(&msg).await;
     }
 
-    pub fn send_req_full_chain_to_my_core_node(&mut self) {
+    pub async fn send_req_full_chain_to_my_core_node(&mut self) {
         println!("send a request of the full chain to my core node");
         let new_message = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_FULL_CHAIN, &self.my_ip, &self.my_port, "");
-        self.cm.send_msg(&new_message);
+        self.cm.send_msg(&new_message).await;
     }
 
-    pub fn update_callback(&self) {
-        println!("update callback was called!");
-//        let s_transactions = self.
+    pub async fn send_req_fee_estimate_to_my_core_node(&mut self, target_blocks: usize) {
+        println!("send a request of the fee estimate to my core node");
+        let new_message = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_FEE_ESTIMATE, &self.my_ip, &self.my_port, &target_blocks.to_string());
+        self.cm.send_msg(&new_message).await;
     }
 
-    pub fn handle_message(&mut self, msg: &str, tx: mpsc::Sender<String>)  {
-        let res = connection_manager::message_manager::parse(&msg);
+    pub async fn send_req_headers_to_my_core_node(&mut self, tip_hash: &str) {
+        //! request the headers after the tip of the header chain
+        println!("send a request of the headers to my core node");
+        let new_message = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_HEADERS, &self.my_ip, &self.my_port, tip_hash);
+        self.cm.send_msg(&new_message).await;
+    }
 
-        println!("received message: {} {} {} {} {} {}", res[0], res[1], res[2], res[3], res[4], res[5]);
+    pub fn core_node_supports(&self, feature: u64) -> bool {
+        self.cm.core_node_supports(feature)
+    }
 
+    pub async fn send_req_merkle_proofs_to_my_core_node(&mut self, address: &str) {
+        println!("send a request of the merkle proofs to my core node");
+        let new_message = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_MERKLE_PROOFS, &self.my_ip, &self.my_port, address);
+        self.cm.send_msg(&new_message).await;
+    }
 
-        let cmd: usize       = res[2].parse().unwrap();
-        let ip      = &res[3];
-        let port    = &res[4];
-        let payload = &res[5];
-        
-        if cmd == message_manager::RSP_FULL_CHAIN {
-            let new_block_chain: BlockChain = serde_json::from_str(payload).unwrap();
-            let (new_block_chain, ortphan_blocks, flag) = self.bc.resolve_conflicts(new_block_chain);
-            println!("blockchain received from central");
-            new_block_chain.print();
-            if flag == true {
-                println!("obtained bc");
-                new_block_chain.print();
-                self.bc = new_block_chain;
-                let msg = serde_json::to_string(&self.bc).unwrap();
-                tx.send(msg).unwrap();
-            }
-            else {
-                println!("Received blockchain is useless");
-            }
-        }
+    pub async fn send_req_filters_to_my_core_node(&mut self, start_height: usize) {
+        println!("send a request of the block filters to my core node");
+        let new_message = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_FILTERS, &self.my_ip, &self.my_port, &start_height.to_string());
+        self.cm.send_msg(&new_message).await;
+    }
+
+    pub async fn send_req_blocks_to_my_core_node(&mut self, block_hashes: &Vec<String>) {
+        println!("send a request of {} blocks to my core node", block_hashes.len());
+        let new_message = connection_manager::get_message_text(connection_manager::message_manager::MSG_REQUEST_BLOCKS, &self.my_ip, &self.my_port, &serde_json::to_string(block_hashes).unwrap());
+        self.cm.send_msg(&new_message).await;
+    }
 
+    pub fn update_callback(&self) {
+        println!("update callback was called!");
+//        let s_transactions = self.
     }
 
-    pub fn wait_for_access_4edge(&mut self, tx_main: mpsc::Sender<String>) {
+    pub async fn wait_for_access_4edge(&mut self, mut tx_main: mpsc::Sender<String>) {
         println!("execute __wait_for_access");
 
         let mut client_core = client_server_core::ClientCore::create(&self.my_ip, &self.my_port, &self.core_node_ip, &self.core_node_port);
+        client_core.spv = self.spv;
+        client_core.headers = self.headers.clone();
+        // the connections of the edge node share its key and its pinned core node keys
+        client_core.cm = self.cm.clone();
 
         let addr = self.cm.ip.to_string() + ":" + &self.cm.port.to_string();
         println!("starting server at {}", addr);
-        let server = TcpListener::bind(addr).expect("Faiulre in starting server");
-        server.set_nonblocking(true).expect("unusable");
-        let (tx, rx) = mpsc::channel::<String>();
+        let server = TcpListener::bind(addr).await.expect("Faiulre in starting server");
+        let (tx, mut rx) = unbounded_channel::<NodeEvent>();
         let mut iter = 0;
 
-        thread::spawn(move || loop {
-            if let Ok((client, addr)) = server.accept() {
-                println!("Connected by {}", addr);
-                connection_manager::ConnectionManager4Edge::receiver_4edge(client, tx.clone());
-            }
-            if let Ok(msg) = rx.try_recv() {
-                println!("received msg: {}", msg.trim());
-                client_core.cm.handle_message(&msg);
-                client_core.handle_message(&msg, tx_main.clone());
+        let listener_cm = client_core.cm.clone();
+        let listener_tx = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Ok((client, _)) = server.accept().await {
+                    println!("Connected by {:?}", client.peer_addr());
+                    listener_cm.receiver_4edge(client, listener_tx.clone());
+                }
             }
-            if iter == 20 {
-                client_core.cm.send_ping();
-                iter = 0;
+        });
+
+        let mut ticker = tokio::time::interval(Duration::from_millis(EVENT_LOOP_TICK_MS));
+        tokio::spawn(async move {
+            // the loop keeps a sender, so that the channel stays open without connections
+            let _tx = tx;
+            loop {
+                tokio::select! {
+                    event = rx.recv() => {
+                        match event {
+                            Some(NodeEvent::Remote(sender, msg)) => {
+                                println!("received msg: {}", msg.trim());
+                                match message::decode(&msg) {
+                                    Ok(message) => {
+                                        client_core.cm.handle_message(&sender, &message, &mut ()).await;
+                                        client_core.handle_message(&sender, &message, &mut tx_main).await;
+                                    },
+                                    Err(e) => println!("{}", e),
+                                }
+                            },
+                            Some(NodeEvent::Local(_)) => {},
+                            None => break,
+                        }
+                        continue;
+                    },
+                    _ = ticker.tick() => {},
+                }
+
+                if iter == 20 {
+                    client_core.cm.send_ping().await;
+                    iter = 0;
+                }
+                iter += 1;
             }
-            iter += 1;
-            thread::sleep(Duration::from_millis(1000));
         });
     }
-}
\ No newline at end of file
+}
+
+impl MessageHandler for ClientCore {
+    /// the channel to the wallet
+    type Context = mpsc::Sender<String>;
+
+    async fn handle_message(&mut self, sender: &Peer, message: &Message, tx: &mut mpsc::Sender<String>) {
+        let ip = &sender.ip;
+        let port = &sender.port;
+
+        match message {
+            Message::FullChain(new_block_chain) => {
+                let (new_block_chain, _ortphan_blocks, flag) = self.bc.resolve_conflicts(new_block_chain.clone());
+                println!("blockchain received from central");
+                new_block_chain.print();
+                if flag == true {
+                    println!("obtained bc");
+                    new_block_chain.print();
+                    self.bc = new_block_chain;
+                    let bc_str = serde_json::to_string(&self.bc).unwrap();
+                    let msg = connection_manager::get_message_text(message_manager::RSP_FULL_CHAIN, ip, port, &bc_str);
+                    tx.send(msg).unwrap();
+                }
+                else {
+                    println!("Received blockchain is useless");
+                }
+            },
+            Message::FeeEstimate(fee_estimate) => {
+                println!("fee estimate received from central: {}", fee_estimate.to_string());
+                let msg = connection_manager::get_message_text(message_manager::RSP_FEE_ESTIMATE, ip, port, &fee_estimate.to_string());
+                tx.send(msg).unwrap();
+            },
+            Message::Headers(headers) => {
+                println!("{} headers received from central", headers.len());
+                if self.headers.add_headers(headers.iter().map(|h| h.clone()).collect()) == false {
+                    return;
+                }
+                // the wallet requests the headers after the new tip
+                let tip = vec![self.headers.headers[self.headers.len() - 1].clone()];
+                let msg = connection_manager::get_message_text(message_manager::RSP_HEADERS, ip, port, &serde_json::to_string(&tip).unwrap());
+                tx.send(msg).unwrap();
+                if headers.len() >= chain_sync::MAX_HEADERS_PER_MESSAGE {
+                    let tip_hash = self.headers.get_tip_hash();
+                    self.send_req_headers_to_my_core_node(&tip_hash).await;
+                }
+                if self.filter_headers.rewind(&self.headers) == true {
+                    // the filters of the blocks on the new branch are needed again
+                    let start_height = self.filter_headers.len();
+                    self.send_req_filters_to_my_core_node(start_height).await;
+                }
+            },
+            Message::MerkleProofs(proofs) => {
+                // pass only the proofs which are included in the header chain to the wallet
+                let mut verified: Vec<MerkleProof> = Vec::new();
+                for proof in proofs.iter() {
+                    let (confirmations, flag) = self.headers.verify_merkle_proof(proof);
+                    if flag == true {
+                        println!("transaction in {} with {} confirmations", proof.block_hash, confirmations);
+                        verified.push(proof.clone());
+                    }
+                }
+                let msg = connection_manager::get_message_text(message_manager::RSP_MERKLE_PROOFS, ip, port, &serde_json::to_string(&verified).unwrap());
+                tx.send(msg).unwrap();
+            },
+            Message::Filters(filters) => {
+                // the wallet tests the filters of the header chain which connect to the filter headers with its addresses
+                println!("block filters received from central");
+                if filters.len() > 0 && filters[0].height > self.filter_headers.len() {
+                    let start_height = self.filter_headers.len();
+                    self.send_req_filters_to_my_core_node(start_height).await;
+                    return;
+                }
+                let added = self.filter_headers.add_filters(filters, &self.headers);
+                println!("{} of {} block filters are added, filter tip {}", added.len(), filters.len(), self.filter_headers.get_tip_header());
+                let msg = connection_manager::get_message_text(message_manager::RSP_FILTERS, ip, port, &serde_json::to_string(&added).unwrap());
+                tx.send(msg).unwrap();
+            },
+            Message::Blocks(blocks) => {
+                // pass only the blocks which are in the header chain and match their filters and Merkle roots
+                let mut verified: Vec<Block> = Vec::new();
+                for block in blocks.iter() {
+                    if self.spv == true {
+                        let height = match self.headers.get_height(&block.get_hash()) {
+                            Some(height) => height,
+                            None => {
+                                println!("block {} is not in the header chain", block.get_hash());
+                                continue;
+                            }
+                        };
+                        if self.filter_headers.matches_block(block, height) == false {
+                            println!("block {} does not match its filter", block.get_hash());
+                            continue;
+                        }
+                    }
+                    if block.has_valid_merkle_root() == false {
+                        println!("block {} has an invalid merkle root", block.get_hash());
+                        continue;
+                    }
+                    verified.push(block.clone());
+                }
+                let msg = connection_manager::get_message_text(message_manager::RSP_BLOCKS, ip, port, &serde_json::to_string(&verified).unwrap());
+                tx.send(msg).unwrap();
+            },
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,266 @@
+/*  
+    abuse.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Tests of abusive peers against a core node with the default resource limits: a peer flooding expensive
+//! requests, a peer flooding cheap requests until it is disconnected, a peer announcing an oversized message,
+//! a flood of idle connections which must not evict a peer that relays transactions, an idle connection and
+//! a flood of connections claiming addresses of other nodes, which must not make the node dial anything.
+
+use crate::client_server_core::ServerCore;
+use crate::client_server_core::block_chain::Transaction;
+use crate::client_server_core::connection_manager::frame;
+use crate::client_server_core::connection_manager::handshake::{self, VersionMessage};
+use crate::client_server_core::connection_manager::limits::ResourceLimits;
+use crate::client_server_core::connection_manager::message::NodeEvent;
+use crate::client_server_core::connection_manager::message_manager;
+use crate::client_server_core::connection_manager::noise::{self, NodeKey, SecureStream};
+use crate::client_server_core::connection_manager::peer_connection::{self, PeerConnections};
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// The number of requests an abusive peer sends at once
+pub const FLOOD_MESSAGES: usize = 2000;
+/// The number of idle connections opened against the inbound slots
+pub const FLOOD_CONNECTIONS: usize = 20;
+/// The inbound slots of the core node in the connection flood
+pub const FLOOD_INBOUND_SLOTS: usize = 4;
+/// The number of connections claiming addresses they do not listen at
+pub const SPOOFED_CONNECTIONS: usize = 50;
+/// The time within which the core node must connect and send to a new peer during the spoofed flood
+pub const MAX_SEND_LATENCY_MS: u128 = 500;
+
+async fn connect_raw(core_port: &str, my_port: &str, node_key: &NodeKey) -> Option<SecureStream> {
+    //! a connection which writes frames directly, bypassing the checks of PeerConnections
+    let stream = TcpStream::connect("127.0.0.1:".to_string() + core_port).await.ok()?;
+    let mut stream = noise::handshake_as_initiator(stream, node_key).await.ok()?;
+    let local = VersionMessage::create("127.0.0.1", my_port, handshake::NODE_LIGHT);
+    handshake::handshake_as_dialer(&mut stream, &local).await.ok()?;
+    Some(stream)
+}
+
+async fn send_frames(stream: &mut SecureStream, msg_type: usize, my_port: &str, payload: &str, count: usize) -> usize {
+    //! write the message count times and return how many frames were written before the node closed the connection
+    let msg = message_manager::build(msg_type, "127.0.0.1", my_port, payload);
+    let data = frame::encode_frame(msg_type as u32, msg.as_bytes());
+    for i in 0..count {
+        if stream.write_all(&data).await.is_err() {
+            return i;
+        }
+    }
+    count
+}
+
+async fn is_closed(stream: &mut SecureStream) -> bool {
+    //! true if the node closed the connection. A node never writes to an inbound connection after the handshake,
+    //! so a read which is still waiting after the timeout means that the connection is open.
+    let mut buf = [0u8; 1];
+    match tokio::time::timeout(Duration::from_millis(500), stream.read_exact(&mut buf)).await {
+        Ok(Ok(_)) => false,
+        Ok(Err(_)) => true,
+        Err(_) => false,
+    }
+}
+
+async fn count_replies(rx: &mut mpsc::UnboundedReceiver<NodeEvent>, msg_type: usize) -> usize {
+    let mut count = 0;
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(2000), rx.recv()).await {
+        if let NodeEvent::Remote(_, msg) = event {
+            if message_manager::get_msg_type(&msg) == Some(msg_type) {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+async fn start_listener(port: &str) -> (mpsc::UnboundedReceiver<NodeEvent>, NodeKey) {
+    //! receive the replies of the core node without limits, so that every reply is counted. The raw
+    //! connections of the abusive peer use the key of the listener, so the core node answers them there.
+    //! The requests bypass the listener, so it accepts the replies which it did not request.
+    let listener = TcpListener::bind("127.0.0.1:".to_string() + port).await.unwrap();
+    let peers = Arc::new(PeerConnections::create("127.0.0.1", port, handshake::NODE_LIGHT));
+    let mut limits = ResourceLimits::unlimited();
+    limits.drop_unrequested = false;
+    peers.set_limits(limits);
+    let (tx, rx) = mpsc::unbounded_channel::<NodeEvent>();
+    let node_key = peers.get_node_key();
+    peer_connection::start_listener(peers, listener, tx);
+    (rx, node_key)
+}
+
+async fn start_core(port: &str, limits: ResourceLimits) -> ServerCore {
+    let mut server_core = ServerCore::create("127.0.0.1", port, "", "");
+    server_core.set_resource_limits(limits);
+    server_core.start().await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    server_core
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_chain_flood_is_limited_to_a_burst() {
+    //! expensive requests are limited to a small burst
+    let (core_port, abuser_port) = ("50200", "50201");
+    let _core = start_core(core_port, ResourceLimits::create()).await;
+    let (mut rx, abuser_key) = start_listener(abuser_port).await;
+    let mut stream = connect_raw(core_port, abuser_port, &abuser_key).await.unwrap();
+    let sent = send_frames(&mut stream, message_manager::MSG_REQUEST_FULL_CHAIN, abuser_port, "", 100).await;
+    let replies = count_replies(&mut rx, message_manager::RSP_FULL_CHAIN).await;
+    println!("full chain flood: {} requests sent, {} chains sent back", sent, replies);
+    assert_eq!(sent, 100);
+    assert!((1..=6).contains(&replies));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cheap_flood_is_disconnected() {
+    //! cheap requests pass up to the burst, and a peer which keeps sending over the limits is disconnected
+    let (core_port, abuser_port) = ("50204", "50205");
+    let limits = ResourceLimits::create();
+    let burst = limits.get_rate(message_manager::MSG_REQUEST_FEE_ESTIMATE).burst as usize;
+    let _core = start_core(core_port, limits).await;
+    let (mut rx, abuser_key) = start_listener(abuser_port).await;
+    let mut stream = connect_raw(core_port, abuser_port, &abuser_key).await.unwrap();
+    let sent = send_frames(&mut stream, message_manager::MSG_REQUEST_FEE_ESTIMATE, abuser_port, "6", FLOOD_MESSAGES).await;
+    let replies = count_replies(&mut rx, message_manager::RSP_FEE_ESTIMATE).await;
+    let closed = is_closed(&mut stream).await;
+    println!("fee estimate flood: {} requests sent, {} answered, disconnected: {}", sent, replies, closed);
+    assert!(replies >= burst && replies < burst * 2);
+    assert!(closed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_request_is_disconnected() {
+    //! the size cap of the command is checked before the payload is read
+    let (core_port, abuser_port) = ("50206", "50207");
+    let _core = start_core(core_port, ResourceLimits::create()).await;
+    let (_rx, abuser_key) = start_listener(abuser_port).await;
+    let mut stream = connect_raw(core_port, abuser_port, &abuser_key).await.unwrap();
+    let mut oversized = frame::encode_frame(message_manager::MSG_REQUEST_FEE_ESTIMATE as u32, b"");
+    oversized[8..12].copy_from_slice(&((frame::MAX_SMALL_PAYLOAD_SIZE + 1) as u32).to_le_bytes());
+    stream.write_all(&oversized).await.unwrap();
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_connections_do_not_evict_a_relaying_peer() {
+    //! idle connections evict each other, not the peer whose transaction was accepted
+    let (slots_port, relay_port) = ("50202", "50203");
+    let mut limits = ResourceLimits::create();
+    limits.max_inbound = FLOOD_INBOUND_SLOTS;
+    let slots_core = start_core(slots_port, limits).await;
+
+    let (_relay_rx, relay_key) = start_listener(relay_port).await;
+    let mut relay = connect_raw(slots_port, relay_port, &relay_key).await.unwrap();
+    let t = Transaction::create();
+    send_frames(&mut relay, message_manager::MSG_NEW_TRANSACTION, relay_port, &t.to_string(), 1).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut idle: Vec<SecureStream> = Vec::new();
+    for i in 0..FLOOD_CONNECTIONS {
+        let idle_port = (50210 + i).to_string();
+        let (_, idle_key) = start_listener(&idle_port).await;
+        if let Some(stream) = connect_raw(slots_port, &idle_port, &idle_key).await {
+            idle.push(stream);
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut closed = 0;
+    for stream in idle.iter_mut() {
+        if is_closed(stream).await == true {
+            closed += 1;
+        }
+    }
+    let (inbound, outbound) = slots_core.get_connection_counts();
+    let relay_connected = is_closed(&mut relay).await == false;
+    println!("connection flood: {} of {} idle connections evicted, relaying peer connected: {}, {} inbound and {} outbound connections", closed, idle.len(), relay_connected, inbound, outbound);
+    assert!(relay_connected);
+    assert!(closed >= idle.len() - (FLOOD_INBOUND_SLOTS - 1));
+    assert!(inbound <= FLOOD_INBOUND_SLOTS);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_connection_is_closed() {
+    //! a connection which sends nothing after the handshake is closed after the idle timeout
+    let (core_port, idle_port) = ("50208", "50209");
+    let mut limits = ResourceLimits::create();
+    limits.idle_timeout_secs = 1;
+    let _core = start_core(core_port, limits).await;
+    let (_rx, idle_key) = start_listener(idle_port).await;
+    let mut stream = connect_raw(core_port, idle_port, &idle_key).await.unwrap();
+    assert!(is_closed(&mut stream).await == false);
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(is_closed(&mut stream).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn spoofed_addresses_take_no_outbound_slot() {
+    //! connections claiming the addresses of other nodes or of a host which never answers are not dialed back,
+    //! so they neither take outbound slots nor hold up the connections the node makes itself
+    let (core_port, relay_port, peer_port, silent_port) = ("50230", "50231", "50232", "50233");
+    let core = start_core(core_port, ResourceLimits::create()).await;
+    let (mut relay_rx, _) = start_listener(relay_port).await;
+    let (mut peer_rx, _) = start_listener(peer_port).await;
+    let mut other_ports: Vec<String> = Vec::new();
+    for i in 0..4 {
+        let port = (50234 + i).to_string();
+        start_listener(&port).await;
+        other_ports.push(port);
+    }
+    // a dial-back to the silent host would wait for the handshake until it times out
+    let silent = TcpListener::bind("127.0.0.1:".to_string() + silent_port).await.unwrap();
+    tokio::spawn(async move {
+        let mut held: Vec<TcpStream> = Vec::new();
+        while let Ok((stream, _)) = silent.accept().await {
+            held.push(stream);
+        }
+    });
+    let msg = message_manager::build(message_manager::MSG_GET_ADDR, "127.0.0.1", core_port, "");
+    assert!(core.connection_manager.send_msg("127.0.0.1", relay_port, &msg).await);
+    assert!(tokio::time::timeout(Duration::from_secs(5), relay_rx.recv()).await.is_ok());
+
+    let core_port_clone = core_port.to_string();
+    let flood = tokio::spawn(async move {
+        let mut streams: Vec<SecureStream> = Vec::new();
+        for i in 0..SPOOFED_CONNECTIONS {
+            // the connections claim the address of the relay, whose key the core node knows, of the silent host and of other nodes
+            let claimed_port = match i % 3 {
+                0 => relay_port.to_string(),
+                1 => silent_port.to_string(),
+                _ => other_ports[i % other_ports.len()].clone(),
+            };
+            if let Some(stream) = connect_raw(&core_port_clone, &claimed_port, &NodeKey::generate()).await {
+                streams.push(stream);
+            }
+        }
+        streams
+    });
+    let streams = flood.await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let start = std::time::Instant::now();
+    let sent = core.connection_manager.send_msg("127.0.0.1", peer_port, &msg).await;
+    let latency = start.elapsed().as_millis();
+    let received = tokio::time::timeout(Duration::from_secs(5), peer_rx.recv()).await.is_ok();
+    let (inbound, outbound) = core.get_connection_counts();
+    println!("spoofed flood: {} connections, {} inbound and {} outbound connections, send to a new peer in {} ms", streams.len(), inbound, outbound, latency);
+    assert!(sent && received);
+    assert!(latency < MAX_SEND_LATENCY_MS);
+    // the relay and the new peer are the only nodes the core node has dialed
+    assert_eq!(outbound, 2);
+}
@@ -0,0 +1,76 @@
+/*  
+    bench.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! A benchmark of the message throughput of a core node. A client sends fee estimate requests to a core node
+//! and measures how fast the estimates come back, which is bound by the event loop of the core node.
+//! Run it with `cargo bench --bench message_throughput`.
+
+use crate::client_server_core::ServerCore;
+use crate::client_server_core::connection_manager::handshake;
+use crate::client_server_core::connection_manager::limits::ResourceLimits;
+use crate::client_server_core::connection_manager::message::NodeEvent;
+use crate::client_server_core::connection_manager::message_manager;
+use crate::client_server_core::connection_manager::peer_connection::{self, PeerConnections};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// The number of requests sent to the core node
+pub const BENCH_MESSAGES: usize = 1000;
+/// The time after which the benchmark stops waiting for replies
+pub const BENCH_TIMEOUT_SECS: u64 = 30;
+
+pub async fn run() {
+    //! start a core node, send BENCH_MESSAGES requests at once and print the replies per second
+    let core_port = "50190";
+    let client_port = "50191";
+    let mut server_core = ServerCore::create("127.0.0.1", core_port, "", "");
+    // the client floods one connection on purpose
+    server_core.set_resource_limits(ResourceLimits::unlimited());
+    server_core.start().await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let listener = TcpListener::bind("127.0.0.1:".to_string() + client_port).await.unwrap();
+    let peers = Arc::new(PeerConnections::create("127.0.0.1", client_port, handshake::NODE_LIGHT));
+    peers.set_limits(ResourceLimits::unlimited());
+    let (tx, mut rx) = mpsc::unbounded_channel::<NodeEvent>();
+    peer_connection::start_listener(Arc::clone(&peers), listener, tx);
+
+    let request = message_manager::build(message_manager::MSG_REQUEST_FEE_ESTIMATE, "127.0.0.1", client_port, "6");
+    let start = Instant::now();
+    for _ in 0..BENCH_MESSAGES {
+        peers.send("127.0.0.1", core_port, &request).await;
+    }
+    let deadline = start + Duration::from_secs(BENCH_TIMEOUT_SECS);
+    let mut received = 0;
+    while received < BENCH_MESSAGES {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match tokio::time::timeout(deadline - now, rx.recv()).await {
+            Ok(Some(_)) => received += 1,
+            _ => break,
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("{} of {} messages are handled in {:.2} s: {:.1} messages/s", received, BENCH_MESSAGES, elapsed, received as f64 / elapsed);
+}
@@ -22,24 +22,33 @@
 use chrono::prelude::*;
 
 extern crate crypto;
-use crypto::ed25519::keypair;
 use crypto::sha2::Sha256;
 use crypto::digest::Digest;
 
 //use std::collections::linked_list::CursorMut;
-use std::thread;
-use std::time::Duration;
-use std::collections::HashMap;
+use std::collections::HashSet;
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 extern crate serde;
 extern crate serde_json;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::client_server_core::consensus::pow::algorithm::{DoubleSha256, PowAlgorithm};
 
 
 pub mod keymanager;
+pub mod coin_selection;
+pub mod fee_estimator;
+pub mod merkle;
+pub mod spv;
+pub mod block_filter;
+pub mod compact_block;
+pub mod utxo_set;
+pub mod utxo_snapshot;
+pub mod bootstrap;
+pub mod chain_sync;
 
 pub const DIFFICULTY: usize = 3;
 
@@ -68,8 +77,8 @@ impl TransactionOutput {
         serde_json::to_string(self).unwrap()
     }
 
-    pub fn from_string(msg: &str) -> TransactionOutput {
-        serde_json::from_str(&msg).unwrap()
+    pub fn from_string(msg: &str) -> Option<TransactionOutput> {
+        serde_json::from_str(msg).ok()
     }
 
     pub fn equal(&self, tx_out: &TransactionOutput) -> bool {
@@ -104,8 +113,8 @@ impl TransactionInput {
         serde_json::to_string(self).unwrap()
     }
 
-    pub fn from_str(msg: &str) -> TransactionInput {
-        serde_json::from_str(msg).unwrap()
+    pub fn from_str(msg: &str) -> Option<TransactionInput> {
+        serde_json::from_str(msg).ok()
     }
 
     pub fn clone(&self) -> TransactionInput {
@@ -129,13 +138,58 @@ impl TransactionInput {
     }
 }
 
+/// A governance action carried by a transaction. It proposes to add or remove a signer of a permissioned network.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GovernanceAction {
+    pub action: String,
+    pub signer: String,
+    pub proposer: String,
+    /// the epoch the vote is cast in. A vote counts only in a block of this epoch.
+    #[serde(default)]
+    pub epoch: u64
+}
+
+impl GovernanceAction {
+    pub fn clone(&self) -> GovernanceAction {
+        GovernanceAction {
+            action: self.action.clone(),
+            signer: self.signer.clone(),
+            proposer: self.proposer.clone(),
+            epoch: self.epoch
+        }
+    }
+}
+
+/// Two blocks sealed by the same sealer in the same slot. It proves the equivocation of the sealer.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EquivocationEvidence {
+    pub first: String,
+    pub second: String
+}
+
+impl EquivocationEvidence {
+    pub fn clone(&self) -> EquivocationEvidence {
+        EquivocationEvidence {
+            first: self.first.clone(),
+            second: self.second.clone()
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Transaction {
     pub timestamp: String,
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>, 
     pub signature: String, 
-    pub tx_type: bool
+    pub tx_type: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub governance: Option<GovernanceAction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evidence: Option<EquivocationEvidence>,
+    /// the extra nonce of a coinbase transaction. Miners change it when the nonce space of a block is exhausted.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub extra_nonce: u64
 }
 
 impl Transaction {
@@ -145,7 +199,10 @@ impl Transaction {
             inputs: Vec::new(), 
             outputs: Vec::new(),
             signature: String::new(), 
-            tx_type: false
+            tx_type: false,
+            governance: None,
+            evidence: None,
+            extra_nonce: 0
         }
     }
 
@@ -155,7 +212,46 @@ impl Transaction {
             inputs: Vec::new(),
             outputs: Vec::new(), 
             signature: String::new(), 
-            tx_type: false
+            tx_type: false,
+            governance: None,
+            evidence: None,
+            extra_nonce: 0
+        }
+    }
+
+    pub fn create_governance_transaction(action: &str, signer: &str, proposer: &str, epoch: u64) -> Transaction {
+        //! create an unsigned transaction which proposes to add or remove a signer in the epoch. The proposer has to sign it.
+        Transaction {
+            timestamp: Utc::now().to_string(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            signature: String::new(),
+            tx_type: false,
+            governance: Some(GovernanceAction {
+                action: action.to_string(),
+                signer: signer.to_string(),
+                proposer: proposer.to_string(),
+                epoch: epoch
+            }),
+            evidence: None,
+            extra_nonce: 0
+        }
+    }
+
+    pub fn create_evidence_transaction(first: &Block, second: &Block) -> Transaction {
+        //! create a transaction which reports two blocks sealed by the same sealer in the same slot
+        Transaction {
+            timestamp: Utc::now().to_string(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            signature: String::new(),
+            tx_type: false,
+            governance: None,
+            evidence: Some(EquivocationEvidence {
+                first: first.to_string(),
+                second: second.to_string()
+            }),
+            extra_nonce: 0
         }
     }
 
@@ -175,21 +271,33 @@ impl Transaction {
             inputs: inputs_clone, 
             outputs: outputs_clone, 
             signature: self.signature.clone(), 
-            tx_type: self.tx_type
+            tx_type: self.tx_type,
+            governance: self.governance.as_ref().map(|g| g.clone()),
+            evidence: self.evidence.as_ref().map(|e| e.clone()),
+            extra_nonce: self.extra_nonce
         }
     }
 
     pub fn create_coinbase_transaction(recipient_address: &str, value: i64) -> Transaction {
-        let output   = TransactionOutput::create(recipient_address, value);
+        Transaction::create_coinbase_transaction_with_outputs(&vec![(recipient_address.to_string(), value)])
+    }
+
+    pub fn create_coinbase_transaction_with_outputs(payouts: &Vec<(String, i64)>) -> Transaction {
+        //! create a coinbase transaction which splits the reward into outputs of (recipient, value)
         let mut outputs: Vec<TransactionOutput> = Vec::new();
-        outputs.push(output);
+        for (recipient_address, value) in payouts.iter() {
+            outputs.push(TransactionOutput::create(recipient_address, *value));
+        }
 
         Transaction {
             timestamp: Utc::now().to_string(),
             inputs: Vec::new(), 
             outputs: outputs, 
             signature: "".to_string(), 
-            tx_type: true
+            tx_type: true,
+            governance: None,
+            evidence: None,
+            extra_nonce: 0
         }
     }
 
@@ -230,6 +338,24 @@ impl Transaction {
         return delta;
     }
 
+    pub fn get_fee(&self) -> i64 {
+        //! return the total value of the inputs minus the total value of the outputs
+        if self.tx_type == true {
+            return 0;
+        }
+
+        let mut total_in: i64 = 0;
+        for i in self.inputs.iter() {
+            total_in += i.transaction.outputs[i.output_index].value;
+        }
+
+        let mut total_out: i64 = 0;
+        for o in self.outputs.iter() {
+            total_out += o.value;
+        }
+        total_in - total_out
+    }
+
     pub fn equal(&self, transaction: &Transaction) -> bool {
         if self.to_string() == transaction.to_string() {
             return true;
@@ -241,8 +367,39 @@ impl Transaction {
         serde_json::to_string(self).unwrap()
     }
 
-    pub fn from_str(msg: &str) -> Transaction {
-        serde_json::from_str(msg).unwrap()
+    pub fn from_str(msg: &str) -> Option<Transaction> {
+        serde_json::from_str(msg).ok()
+    }
+
+    pub fn is_well_formed(&self) -> bool {
+        //! check what the validation code indexes and sums without checking, so that a transaction from
+        //! a peer cannot make the node panic: every input points at an existing output of its transaction,
+        //! the inputs are well-formed themselves and no value is negative or overflows.
+        let mut total_in: i64 = 0;
+        for i in self.inputs.iter() {
+            if i.transaction.is_well_formed() == false {
+                return false;
+            }
+            match i.transaction.outputs.get(i.output_index) {
+                Some(o) => match total_in.checked_add(o.value) {
+                    Some(total) => total_in = total,
+                    None => return false,
+                },
+                None => return false,
+            }
+        }
+
+        let mut total_out: i64 = 0;
+        for o in self.outputs.iter() {
+            if o.value < 0 {
+                return false;
+            }
+            match total_out.checked_add(o.value) {
+                Some(total) => total_out = total,
+                None => return false,
+            }
+        }
+        true
     }
 
     pub fn print(&self) {
@@ -298,6 +455,11 @@ impl TransactionPool {
         return false;
 
     }
+
+    pub fn remove_transactions_in_block(&mut self, block: &Block) {
+        //! remove the transactions which are registered in block
+        self.transactions.retain(|t| block.transaction_pool.contain(t) == false);
+    }
 /*
     pub fn has_this_output_in_my_tp(&self, transaction_output: TransactionOutput) -> bool {
         println!("has_this_output_in_my_tp is called!");
@@ -331,8 +493,8 @@ impl TransactionPool {
         serde_json::to_string(self).unwrap()
     }
 
-    pub fn from_string(msg: &str) -> TransactionPool {
-        serde_json::from_str(msg).unwrap()
+    pub fn from_string(msg: &str) -> Option<TransactionPool> {
+        serde_json::from_str(msg).ok()
     }
 
     pub fn equal(&self, tp: &TransactionPool) -> bool {
@@ -377,24 +539,104 @@ impl TransactionPool {
 
 }
 
+/// The header of a block. The hash of a block is the hash of its header, which commits to the transactions by the Merkle root.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockHeader {
+    pub timestamp: String,
+    pub previous_block: String,
+    pub merkle_root: String,
+    pub nonce: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sealer: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub seal: String,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub slot: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub vrf_proof: String
+}
+
+impl BlockHeader {
+    pub fn clone(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp.clone(),
+            previous_block: self.previous_block.clone(),
+            merkle_root: self.merkle_root.clone(),
+            nonce: self.nonce,
+            sealer: self.sealer.clone(),
+            seal: self.seal.clone(),
+            slot: self.slot,
+            vrf_proof: self.vrf_proof.clone()
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_string(msg: &str) -> Option<BlockHeader> {
+        serde_json::from_str(msg).ok()
+    }
+
+    pub fn get_hash(&self) -> String {
+        get_double_sha256(&self.to_string())
+    }
+
+    pub fn get_pow_hash(&self, algorithm: &dyn PowAlgorithm) -> String {
+        algorithm.hash(&self.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Block {
 //    pub timestamp: DateTime<Utc>,
     pub timestamp: String,
     pub transaction_pool: TransactionPool,
     pub previous_block: String,
-    pub nonce: u64
+    /// the Merkle root of the transactions
+    #[serde(default)]
+    pub merkle_root: String,
+    pub nonce: u64,
+    /// the address of the node which sealed the block (empty under proof of work)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sealer: String,
+    /// the signature of the sealer over the block with an empty seal (empty under proof of work)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub seal: String,
+    /// the slot in which the block was sealed (0 except under proof of stake)
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub slot: u64,
+    /// the VRF proof of the sealer that it won the lottery of the slot (empty except under proof of stake)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub vrf_proof: String,
+    /// true if the transactions were deleted by a pruned node. The header is kept.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub pruned: bool
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    *value == false
 }
 
 impl Block {
     pub fn create(transaction_pool: TransactionPool, previous_block: String) -> Block {
-        let mut block = Block {
+        let merkle_root = merkle::get_merkle_root_of_transactions(&transaction_pool.transactions);
+        Block {
             timestamp: Utc::now().to_string(), 
             transaction_pool: transaction_pool, 
             previous_block: previous_block,
-            nonce: 0
-        };
-        block
+            merkle_root: merkle_root,
+            nonce: 0,
+            sealer: String::new(),
+            seal: String::new(),
+            slot: 0,
+            vrf_proof: String::new(),
+            pruned: false
+        }
     }
 
     pub fn clone(&self) -> Block {
@@ -402,7 +644,13 @@ impl Block {
             timestamp: self.timestamp.clone(), 
             transaction_pool: self.transaction_pool.clone(),
             previous_block: self.previous_block.clone(), 
-            nonce: self.nonce
+            merkle_root: self.merkle_root.clone(),
+            nonce: self.nonce,
+            sealer: self.sealer.clone(),
+            seal: self.seal.clone(),
+            slot: self.slot,
+            vrf_proof: self.vrf_proof.clone(),
+            pruned: self.pruned
         }
     }
 
@@ -410,29 +658,86 @@ impl Block {
         serde_json::to_string(self).unwrap()
     }
 
-    pub fn from_string(msg: &str) -> Block {
-        serde_json::from_str(msg).unwrap()
+    pub fn from_string(msg: &str) -> Option<Block> {
+        serde_json::from_str(msg).ok()
+    }
+
+    pub fn is_well_formed(&self) -> bool {
+        self.transaction_pool.transactions.iter().all(|t| t.is_well_formed())
+    }
+
+    pub fn get_header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp.clone(),
+            previous_block: self.previous_block.clone(),
+            merkle_root: self.merkle_root.clone(),
+            nonce: self.nonce,
+            sealer: self.sealer.clone(),
+            seal: self.seal.clone(),
+            slot: self.slot,
+            vrf_proof: self.vrf_proof.clone()
+        }
     }
 
     pub fn get_hash(&self) -> String {
-        get_double_sha256(&self.to_string())
+        self.get_header().get_hash()
+    }
+
+    pub fn update_merkle_root(&mut self) {
+        //! recompute the Merkle root after the transactions are changed, e.g. by an extra nonce
+        self.merkle_root = merkle::get_merkle_root_of_transactions(&self.transaction_pool.transactions);
+    }
+
+    pub fn has_valid_merkle_root(&self) -> bool {
+        if self.has_duplicate_transactions() == true {
+            return false;
+        }
+        self.merkle_root == merkle::get_merkle_root_of_transactions(&self.transaction_pool.transactions)
+    }
+
+    pub fn has_duplicate_transactions(&self) -> bool {
+        //! the Merkle tree pairs the last hash of an odd level with itself, so a block which repeats its last transactions
+        //! has the Merkle root of the block without them (CVE-2012-2459). Such a block is invalid.
+        let mut hashes: HashSet<String> = HashSet::new();
+        self.transaction_pool.transactions.iter().any(|t| hashes.insert(merkle::get_transaction_hash(t)) == false)
     }
 
     pub fn create_genesis_block() -> Block {
         let transaction = Transaction::create_for_genesis_block();
         let mut transaction_pool = TransactionPool::create();
         transaction_pool.transactions.push(transaction);
-        let block = Block {
+        let merkle_root = merkle::get_merkle_root_of_transactions(&transaction_pool.transactions);
+        Block {
             timestamp: "0".to_string(),
             transaction_pool: transaction_pool, 
             previous_block: "".to_string(),
-            nonce: 0
-        };
-        block
+            merkle_root: merkle_root,
+            nonce: 0,
+            sealer: String::new(),
+            seal: String::new(),
+            slot: 0,
+            vrf_proof: String::new(),
+            pruned: false
+        }
+    }
+
+    pub fn prune(&mut self) {
+        //! delete the transactions. The hash does not change since it covers only the header.
+        self.transaction_pool.clear();
+        self.pruned = true;
     }
 
     pub fn compute_nonce_for_pow(&mut self, to_stop: &Arc<AtomicBool>) -> bool {
-        println!("start computing nonce");
+        self.compute_nonce_for_pow_with(&DoubleSha256 {}, to_stop)
+    }
+
+    pub fn get_pow_hash(&self, algorithm: &dyn PowAlgorithm) -> String {
+        //! return the hash of the block header by the proof-of-work hash function
+        self.get_header().get_pow_hash(algorithm)
+    }
+
+    pub fn compute_nonce_for_pow_with(&mut self, algorithm: &dyn PowAlgorithm, to_stop: &Arc<AtomicBool>) -> bool {
+        println!("start computing nonce with {}", algorithm.name());
         let mut nonce: u64 = 0;
         let suffix: String = "0".to_string().repeat(DIFFICULTY);
         loop {
@@ -442,7 +747,7 @@ impl Block {
             }
 
             self.nonce = nonce;
-            let digest = self.get_hash();
+            let digest = self.get_pow_hash(algorithm);
             if digest.ends_with(&suffix) {
                 break;
         }
@@ -452,14 +757,22 @@ impl Block {
     }
 
     pub fn is_valid_block(&self, previous_block_hash: &str) -> bool {
+        self.is_valid_block_with(previous_block_hash, &DoubleSha256 {})
+    }
+
+    pub fn is_valid_block_with(&self, previous_block_hash: &str, algorithm: &dyn PowAlgorithm) -> bool {
         println!("compre previous hash: {} {}", self.previous_block, previous_block_hash);
         if self.previous_block.eq(previous_block_hash) == false {
             println!("Invalid block (bad previous_block)");
             println!("{} {}", self.previous_block, previous_block_hash);
             return false;
         }
+        if self.has_valid_merkle_root() == false {
+            println!("Invalid block (bad merkle_root)");
+            return false;
+        }
 
-        let digest = self.get_hash();
+        let digest = self.get_pow_hash(algorithm);
         println!("is_valid_block digest: {}", digest);
         self.print();
         let suffix: String = "0".to_string().repeat(DIFFICULTY);
@@ -470,7 +783,7 @@ impl Block {
     }
 
     pub fn equal(&self, block: &Block) -> bool {
-        if self.timestamp == block.timestamp && self.transaction_pool.equal(&block.transaction_pool) && self.previous_block == block.previous_block && self.nonce == block.nonce {
+        if self.timestamp == block.timestamp && self.transaction_pool.equal(&block.transaction_pool) && self.previous_block == block.previous_block && self.merkle_root == block.merkle_root && self.nonce == block.nonce && self.sealer == block.sealer && self.seal == block.seal && self.slot == block.slot && self.vrf_proof == block.vrf_proof {
             return true;
         }
         return false;
@@ -478,10 +791,20 @@ impl Block {
 
     pub fn print(&self) {
         println!("print block");
-        println!("timestamp: {}", self.timestamp.to_string());
+        println!("timestamp: {}", self.timestamp);
         self.transaction_pool.print();
         println!("previous_block: {}", self.previous_block);
+        println!("merkle_root: {}", self.merkle_root);
         println!("nonce: {}", self.nonce);
+        if self.sealer.len() > 0 {
+            println!("sealer: {}", self.sealer);
+        }
+        if self.slot > 0 {
+            println!("slot: {}", self.slot);
+        }
+        if self.pruned == true {
+            println!("pruned");
+        }
     }
 }
 
@@ -525,6 +848,39 @@ impl BlockChain {
         }
     }
 
+    pub fn prune(&mut self, blocks_to_keep: usize) -> usize {
+        //! delete the transactions of all blocks except the latest blocks_to_keep ones and return the number of newly pruned blocks
+        let mut count = 0;
+        if self.chain.len() <= blocks_to_keep {
+            return count;
+        }
+        let end = self.chain.len() - blocks_to_keep;
+        for block in self.chain[..end].iter_mut() {
+            if block.pruned == false {
+                block.prune();
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn get_pruned_height(&self) -> usize {
+        //! return the number of blocks from the genesis block whose transactions are deleted
+        self.chain.iter().take_while(|b| b.pruned == true).count()
+    }
+
+    pub fn get_headers_after(&self, block_hash: &str) -> Vec<BlockHeader> {
+        //! return the headers after the block of block_hash, or all headers if the block is not in the chain
+        let mut start = 0;
+        for (i, block) in self.chain.iter().enumerate() {
+            if block.get_hash() == block_hash {
+                start = i + 1;
+                break;
+            }
+        }
+        self.chain[start..].iter().map(|b| b.get_header()).collect()
+    }
+
     pub fn is_valid(&self) -> bool{
         let mut current_index: usize = 1;
 
@@ -541,8 +897,13 @@ impl BlockChain {
         serde_json::to_string(self).unwrap()
     }
 
-    pub fn from_string(msg: &str) -> BlockChain {
-        serde_json::from_str(msg).unwrap()
+    pub fn from_string(msg: &str) -> Option<BlockChain> {
+        serde_json::from_str(msg).ok()
+    }
+
+    pub fn is_well_formed(&self) -> bool {
+        //! a chain needs at least its genesis block
+        self.chain.len() > 0 && self.chain.iter().all(|b| b.is_well_formed())
     }
 
     pub fn get_transactions_from_orphan_blocks(&self, orphan_blocks: &BlockChain) -> TransactionPool {
@@ -573,7 +934,7 @@ impl BlockChain {
                 
                 for t2 in &transaction_pool.transactions {
                     for t in transactions {
-                        if t.equal(&t2)  == false {
+                        if t.equal(t2)  == false {
                             new_transaction_pool.transactions.push(t2.clone());
                         }
                     }
@@ -588,21 +949,32 @@ impl BlockChain {
         }
     }
 
-    pub fn resolve_conflicts(&mut self, new_block_chain: BlockChain) -> (BlockChain, BlockChain, bool){
-        if new_block_chain.chain.len() > self.chain.len() {
-            let mut orphan_blocks = BlockChain::create();
-            for b1 in &self.chain {
-                let mut contain_flag = false;
-                for b2 in &new_block_chain.chain {
-                    if b1.equal(b2) == true {
-                        contain_flag = true;
-                        break;
-                    }
-                }
-                if contain_flag == false {
-                    orphan_blocks.set_new_block(b1.clone());
+    pub fn get_fork_height(&self, new_block_chain: &BlockChain) -> usize {
+        //! return the number of blocks from the genesis block which this chain and new_block_chain have in common
+        self.chain.iter().zip(new_block_chain.chain.iter()).take_while(|(b1, b2)| b1.get_hash() == b2.get_hash()).count()
+    }
+
+    pub fn get_orphan_blocks(&self, new_block_chain: &BlockChain) -> BlockChain {
+        //! return the blocks of this chain which are not included in new_block_chain
+        let mut orphan_blocks = BlockChain::create();
+        for b1 in &self.chain {
+            let mut contain_flag = false;
+            for b2 in &new_block_chain.chain {
+                if b1.equal(b2) == true {
+                    contain_flag = true;
+                    break;
                 }
             }
+            if contain_flag == false {
+                orphan_blocks.set_new_block(b1.clone());
+            }
+        }
+        orphan_blocks
+    }
+
+    pub fn resolve_conflicts(&mut self, new_block_chain: BlockChain) -> (BlockChain, BlockChain, bool){
+        if new_block_chain.chain.len() > self.chain.len() {
+            let orphan_blocks = self.get_orphan_blocks(&new_block_chain);
             println!("valid chain is set");
             new_block_chain.print();
             return (new_block_chain, orphan_blocks, true);
@@ -644,7 +1016,7 @@ impl BlockChain {
             for t in transactions.transactions.iter() {
                 if t.tx_type == true && t.inputs.len() != 0{ // for coinbase transaction
                     for it in t.inputs.iter() {
-                        if it.transaction.outputs[it.output_index].equal(&transaction_output) == true {
+                        if it.transaction.outputs[it.output_index].equal(transaction_output) == true {
                             println!("This transaction was already used!");
                             return true;
                         }
@@ -656,7 +1028,7 @@ impl BlockChain {
         return false;
     }
 
-    pub fn is_valid_output_in_my_chain(&self, transaction_output: &TransactionOutput) {
+    pub fn is_valid_output_in_my_chain(&self, _transaction_output: &TransactionOutput) {
         // to be implemented
     }
 
@@ -669,7 +1041,7 @@ impl BlockChain {
 
 pub fn get_double_sha256(msg: &str) -> String {
     let mut sha256 = Sha256::new();
-    sha256.input_str(&msg);
+    sha256.input_str(msg);
     let res = sha256.result_str();
     let mut sha256_2 = Sha256::new();
     sha256_2.input_str(&res);
@@ -710,7 +1082,6 @@ impl UTXOManager {
         let mut outputs: Vec<Transaction> = Vec::new();
         let mut inputs: Vec<Transaction> = Vec::new();
 
-        let mut idx: usize = 0;
         for t in txs.iter() {
             for txout in t.outputs.iter() {
                 let recipient = &txout.recipient;
@@ -723,7 +1094,7 @@ impl UTXOManager {
 
             for txin in t.inputs.iter() {
                 let t_in_txin = &txin.transaction;
-                idx = txin.output_index;
+                let idx = txin.output_index;
                 let o_recipient = &t_in_txin.outputs[idx].recipient;
                 println!("keys2 {} {}", self.my_address, o_recipient);
                 t.print();
@@ -767,24 +1138,20 @@ impl UTXOManager {
         for tx in txs {
             println!("tx:");
             tx.print();
-            self.put_utxo_tx(&tx);
+            self.put_utxo_tx(tx);
         }
     }
 
     pub fn put_utxo_tx(&mut self, tx: &Transaction) {
         println!("put_utxo_tx was called");
-        let mut idx: usize = 0;
 
-        for txout in &tx.outputs {
+        for (idx, txout) in tx.outputs.iter().enumerate() {
             println!("txout.recipient: {}", txout.recipient);
             println!("self.my_address: {}", self.my_address);
             if self.my_address.eq(&txout.recipient) == true {
                 tx.print();
                 self.utxo_txs.push((tx.clone(), idx));
             }
-            else {
-                idx += 1;
-            }
         }
 
         self.compute_my_balance();
@@ -794,6 +1161,12 @@ impl UTXOManager {
         return &self.utxo_txs[idx];
     }
 
+    pub fn get_utxo_value(&self, idx: usize) -> i64 {
+        //! return the value of the idx-th UTXO
+        let (t, output_index) = &self.utxo_txs[idx];
+        t.outputs[*output_index].value
+    }
+
     pub fn remove_utxo_tx(&mut self, idx: usize) {
         self.utxo_txs.remove(idx);
         self.compute_my_balance();
@@ -803,16 +1176,33 @@ impl UTXOManager {
         println!("compute_my_balance was called");
 
         let mut balance: i64 = 0;
-        for t in self.utxo_txs.iter() {
-            for txout in t.0.outputs.iter() {
-                if self.my_address.eq(&txout.recipient) == true {
-                    balance += txout.value;
-                }
-            }
+        for idx in 0..self.utxo_txs.len() {
+            balance += self.get_utxo_value(idx);
         }
         self.my_balance = balance;
     }
 
+    pub fn get_filter_items(&self) -> Vec<String> {
+        //! my address and the outpoints of my UTXOs. Spending transactions usually pay the change to my address as well.
+        let mut items: Vec<String> = vec![self.my_address.clone()];
+        for (t, output_index) in self.utxo_txs.iter() {
+            items.push(block_filter::get_outpoint(t, *output_index));
+        }
+        items
+    }
+
+    pub fn get_matched_blocks(&self, filters: &Vec<block_filter::BlockFilter>) -> Vec<String> {
+        //! return the hashes of the blocks whose filters match my items
+        let items = self.get_filter_items();
+        let mut hashes: Vec<String> = Vec::new();
+        for filter in filters.iter() {
+            if filter.match_any(&items) == true {
+                hashes.push(filter.block_hash.clone());
+            }
+        }
+        hashes
+    }
+
     pub fn get_txs_to_my_address(&self, txs: &Vec<Transaction>) -> Vec<TransactionOutput> {
         let mut my_txs: Vec<TransactionOutput> = Vec::new();
 
@@ -849,32 +1239,28 @@ impl UTXOManager {
 }
 
 pub fn run() {
-    let mut k_m = keymanager::KeyManager::create(20);
-    let mut my_address = k_m.my_address();
+    let k_m = keymanager::KeyManager::create(20);
+    let my_address = k_m.my_address();
     let mut um = UTXOManager::create(&my_address);
 
-    let mut i_k_m = keymanager::KeyManager::create(30);
-    let mut u_k_m = keymanager::KeyManager::create(40);
+    let i_k_m = keymanager::KeyManager::create(30);
+    let u_k_m = keymanager::KeyManager::create(40);
 
-    let mut t1 = Transaction::create_coinbase_transaction(&k_m.my_address(), 30);
-    let mut t2 = Transaction::create_coinbase_transaction(&k_m.my_address(), 30);
-    let mut t3 = Transaction::create_coinbase_transaction(&k_m.my_address(), 30);
+    let t1 = Transaction::create_coinbase_transaction(&k_m.my_address(), 30);
+    let t2 = Transaction::create_coinbase_transaction(&k_m.my_address(), 30);
+    let t3 = Transaction::create_coinbase_transaction(&k_m.my_address(), 30);
 
     let mut t4 = Transaction::create();
 
-    let mut t_in = TransactionInput::create(t1.clone(), 0);
-    let mut t_out_1 = TransactionOutput::create(&u_k_m.my_address(), 10);
-    let mut t_out_2 = TransactionOutput::create(&i_k_m.my_address(), 20);
+    let t_in = TransactionInput::create(t1.clone(), 0);
+    let t_out_1 = TransactionOutput::create(&u_k_m.my_address(), 10);
+    let t_out_2 = TransactionOutput::create(&i_k_m.my_address(), 20);
 
     t4.inputs.push(t_in);
     t4.outputs.push(t_out_1);
     t4.outputs.push(t_out_2);
 
-    let mut transactions: Vec<Transaction> = Vec::new();
-    transactions.push(t1);
-    transactions.push(t2);
-    transactions.push(t3);
-    transactions.push(t4);
+    let transactions: Vec<Transaction> = vec![t1, t2, t3, t4];
 
     um.extract_utxo(&transactions);
 
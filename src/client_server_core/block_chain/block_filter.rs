@@ -0,0 +1,431 @@
+/*  
+    block_filter.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Compact block filters in the style of BIP158. A filter is a Golomb-coded set of the output recipients and
+//! the spent outpoints of a block. Light clients test filters locally and fetch only the matching blocks,
+//! so the core node never learns their addresses. Items are hashed by SHA-256 keyed with the block hash
+//! instead of SipHash.
+
+use crate::client_server_core::block_chain::{Block, Transaction, get_double_sha256};
+use crate::client_server_core::block_chain::merkle::get_transaction_hash;
+use crate::client_server_core::block_chain::spv::HeaderChain;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+/// The number of bits of the remainder of the Golomb-Rice coding
+pub const FILTER_P: u8 = 19;
+/// The inverse of the false positive rate
+pub const FILTER_M: u64 = 784931;
+
+pub fn get_outpoint(transaction: &Transaction, output_index: usize) -> String {
+    //! the identifier of an output, which is spent by an input referring to it
+    get_transaction_hash(transaction) + ":" + &output_index.to_string()
+}
+
+fn hash_item(key: &str, item: &str) -> u64 {
+    let mut sha256 = Sha256::new();
+    sha256.input_str(key);
+    sha256.input_str(item);
+    let mut digest = [0u8; 32];
+    sha256.result(&mut digest);
+    let mut value: u64 = 0;
+    for b in digest[..8].iter() {
+        value = (value << 8) | *b as u64;
+    }
+    value
+}
+
+fn hash_to_range(key: &str, item: &str, f: u64) -> u64 {
+    ((hash_item(key, item) as u128 * f as u128) >> 64) as u64
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    nbits: usize
+}
+
+impl BitWriter {
+    fn create() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            nbits: 0
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.nbits.is_multiple_of(8) {
+            self.bytes.push(0);
+        }
+        if bit == true {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 0x80 >> (self.nbits % 8);
+        }
+        self.nbits += 1;
+    }
+
+    fn write_bits(&mut self, value: u64, n: u8) {
+        for i in (0..n).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> BitReader<'a> {
+    fn read_bit(&mut self) -> Option<bool> {
+        if self.pos >= self.bytes.len() * 8 {
+            return None;
+        }
+        let bit = self.bytes[self.pos / 8] & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_bits(&mut self, n: u8) -> Option<u64> {
+        let mut value: u64 = 0;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Some(value)
+    }
+}
+
+fn get_sorted_values(key: &str, items: &Vec<String>, f: u64) -> Vec<u64> {
+    let mut values: Vec<u64> = items.iter().map(|item| hash_to_range(key, item, f)).collect();
+    values.sort();
+    values
+}
+
+pub fn build_gcs(key: &str, items: &Vec<String>) -> (usize, Vec<u8>) {
+    //! encode the items into a Golomb-coded set. It returns the number of items and the encoded bits.
+    let mut unique = items.clone();
+    unique.sort();
+    unique.dedup();
+    let n = unique.len();
+    let values = get_sorted_values(key, &unique, n as u64 * FILTER_M);
+
+    let mut writer = BitWriter::create();
+    let mut last: u64 = 0;
+    for value in values.iter() {
+        let delta = value - last;
+        let quotient = delta >> FILTER_P;
+        for _ in 0..quotient {
+            writer.write_bit(true);
+        }
+        writer.write_bit(false);
+        writer.write_bits(delta & ((1 << FILTER_P) - 1), FILTER_P);
+        last = *value;
+    }
+    (n, writer.bytes)
+}
+
+pub fn match_any_gcs(key: &str, n: usize, data: &[u8], items: &Vec<String>) -> bool {
+    //! return true if any of items is probably in the set. False positives occur at the rate of 1/FILTER_M.
+    if n == 0 || items.len() == 0 {
+        return false;
+    }
+    let targets = get_sorted_values(key, items, n as u64 * FILTER_M);
+
+    let mut reader = BitReader { bytes: data, pos: 0 };
+    let mut value: u64 = 0;
+    let mut t = 0;
+    for _ in 0..n {
+        let mut quotient: u64 = 0;
+        loop {
+            match reader.read_bit() {
+                Some(true) => quotient += 1,
+                Some(false) => break,
+                None => return false,
+            }
+        }
+        let remainder = match reader.read_bits(FILTER_P) {
+            Some(remainder) => remainder,
+            None => return false,
+        };
+        value += (quotient << FILTER_P) | remainder;
+
+        while t < targets.len() && targets[t] < value {
+            t += 1;
+        }
+        if t == targets.len() {
+            return false;
+        }
+        if targets[t] == value {
+            return true;
+        }
+    }
+    false
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockFilter {
+    pub block_hash: String,
+    pub height: usize,
+    /// the number of items in the filter
+    pub n: usize,
+    /// the Golomb-coded set in hex
+    pub data: String
+}
+
+impl BlockFilter {
+    pub fn create(block: &Block, height: usize) -> BlockFilter {
+        let block_hash = block.get_hash();
+        let items = get_filter_items(block);
+        let (n, data) = build_gcs(&get_filter_key(&block_hash), &items);
+        BlockFilter {
+            block_hash: block_hash,
+            height: height,
+            n: n,
+            data: hex::encode(data)
+        }
+    }
+
+    pub fn clone(&self) -> BlockFilter {
+        BlockFilter {
+            block_hash: self.block_hash.clone(),
+            height: self.height,
+            n: self.n,
+            data: self.data.clone()
+        }
+    }
+
+    pub fn get_filter_hash(&self) -> String {
+        get_double_sha256(&self.data)
+    }
+
+    pub fn match_any(&self, items: &Vec<String>) -> bool {
+        let data = match hex::decode(&self.data) {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
+        match_any_gcs(&get_filter_key(&self.block_hash), self.n, &data, items)
+    }
+}
+
+fn get_filter_key(block_hash: &str) -> String {
+    //! the first 16 bytes of the block hash, as in BIP158
+    block_hash.chars().take(32).collect()
+}
+
+pub fn get_filter_items(block: &Block) -> Vec<String> {
+    //! the recipients of the outputs and the outpoints spent by the inputs of a block
+    let mut items: Vec<String> = Vec::new();
+    for t in block.transaction_pool.transactions.iter() {
+        for o in t.outputs.iter() {
+            items.push(o.recipient.clone());
+        }
+        for i in t.inputs.iter() {
+            items.push(get_outpoint(&i.transaction, i.output_index));
+        }
+    }
+    items
+}
+
+pub fn get_filter_header(filter_hash: &str, previous_filter_header: &str) -> String {
+    //! the header of a filter commits to the filter and, through the previous header, to all the filters before it
+    get_double_sha256(&(filter_hash.to_string() + previous_filter_header))
+}
+
+/// The filter headers of a light client, as in BIP157. Once a core node sent the filter of a block,
+/// it can neither change it nor leave blocks out of the filters sent later.
+pub struct FilterHeaderChain {
+    block_hashes: Vec<String>,
+    filter_hashes: Vec<String>,
+    filter_headers: Vec<String>
+}
+
+impl FilterHeaderChain {
+    pub fn create() -> FilterHeaderChain {
+        FilterHeaderChain {
+            block_hashes: Vec::new(),
+            filter_hashes: Vec::new(),
+            filter_headers: Vec::new()
+        }
+    }
+
+    pub fn clone(&self) -> FilterHeaderChain {
+        FilterHeaderChain {
+            block_hashes: self.block_hashes.clone(),
+            filter_hashes: self.filter_hashes.clone(),
+            filter_headers: self.filter_headers.clone()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.filter_headers.len()
+    }
+
+    pub fn get_tip_header(&self) -> String {
+        match self.filter_headers.last() {
+            Some(header) => header.clone(),
+            None => String::new(),
+        }
+    }
+
+    pub fn rewind(&mut self, headers: &HeaderChain) -> bool {
+        //! drop the filters of the blocks which left the header chain. It returns true if any was dropped.
+        let mut height = 0;
+        while height < self.len() && height < headers.len() && self.block_hashes[height] == headers.headers[height].get_hash() {
+            height += 1;
+        }
+        if height == self.len() {
+            return false;
+        }
+        self.block_hashes.truncate(height);
+        self.filter_hashes.truncate(height);
+        self.filter_headers.truncate(height);
+        true
+    }
+
+    pub fn add_filters(&mut self, filters: &Vec<BlockFilter>, headers: &HeaderChain) -> Vec<BlockFilter> {
+        //! connect filters from a core node and return the new ones. The filters have to follow each other from
+        //! a height not above the tip, be of the blocks of the header chain and match the filters received before.
+        //! Filters beyond the header chain are left for the next request.
+        let mut added: Vec<BlockFilter> = Vec::new();
+        self.rewind(headers);
+        if filters.len() == 0 || filters[0].height > self.len() {
+            println!("block filters do not connect to the filter headers");
+            return added;
+        }
+        for (i, filter) in filters.iter().enumerate() {
+            let height = filter.height;
+            if height != filters[0].height + i {
+                println!("block filter at {} is out of order", height);
+                return added;
+            }
+            if height >= headers.len() {
+                break;
+            }
+            if filter.block_hash != headers.headers[height].get_hash() {
+                println!("block filter at {} is not of the block in the header chain", height);
+                return added;
+            }
+            let filter_hash = filter.get_filter_hash();
+            if height < self.len() {
+                if self.filter_hashes[height] != filter_hash {
+                    println!("block filter at {} differs from the one received before", height);
+                    return added;
+                }
+                continue;
+            }
+            let previous_filter_header = self.get_tip_header();
+            self.block_hashes.push(filter.block_hash.clone());
+            self.filter_headers.push(get_filter_header(&filter_hash, &previous_filter_header));
+            self.filter_hashes.push(filter_hash);
+            added.push(filter.clone());
+        }
+        added
+    }
+
+    pub fn matches_block(&self, block: &Block, height: usize) -> bool {
+        //! check that a block fetched for a matched filter has the filter received before,
+        //! so that a core node cannot hide transactions from the filters
+        if height >= self.len() || self.block_hashes[height] != block.get_hash() {
+            return false;
+        }
+        BlockFilter::create(block, height).get_filter_hash() == self.filter_hashes[height]
+    }
+}
+
+/// The filters of a core node, built once per block
+pub struct BlockFilterIndex {
+    filters: HashMap<String, BlockFilter>
+}
+
+impl BlockFilterIndex {
+    pub fn create() -> BlockFilterIndex {
+        BlockFilterIndex {
+            filters: HashMap::new()
+        }
+    }
+
+    pub fn clone(&self) -> BlockFilterIndex {
+        let mut filters: HashMap<String, BlockFilter> = HashMap::new();
+        for (hash, filter) in self.filters.iter() {
+            filters.insert(hash.clone(), filter.clone());
+        }
+        BlockFilterIndex {
+            filters: filters
+        }
+    }
+
+    pub fn get_filters(&mut self, blocks: &Vec<Block>, start_height: usize) -> Vec<BlockFilter> {
+        //! return the filters of the blocks from start_height, building the missing ones
+        let mut result: Vec<BlockFilter> = Vec::new();
+        for (height, block) in blocks.iter().enumerate().skip(start_height) {
+            let hash = block.get_hash();
+            let filter = self.filters.entry(hash).or_insert_with(|| BlockFilter::create(block, height));
+            result.push(filter.clone());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::block_chain::TransactionPool;
+
+    const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+    fn get_items() -> Vec<String> {
+        (0..100).map(|i| format!("address-{}", i)).collect()
+    }
+
+    #[test]
+    fn filter_matches_every_item() {
+        let items = get_items();
+        let (n, data) = build_gcs(KEY, &items);
+        assert_eq!(n, items.len());
+        // a Golomb-Rice code takes about FILTER_P + 2 bits per item
+        assert!(data.len() * 8 <= n * (FILTER_P as usize + 3));
+        for item in items.iter() {
+            assert!(match_any_gcs(KEY, n, &data, &vec![item.clone()]));
+        }
+    }
+
+    #[test]
+    fn false_positives_are_rare() {
+        let (n, data) = build_gcs(KEY, &get_items());
+        let trials = 100000;
+        let false_positives = (0..trials).filter(|i| match_any_gcs(KEY, n, &data, &vec![format!("other-{}", i)])).count();
+        // the false positive rate of an item is 1 / FILTER_M, so 0.13 are expected
+        assert!(false_positives <= 3, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn block_filter_matches_the_recipients_of_the_block() {
+        let mut transaction_pool = TransactionPool::create();
+        transaction_pool.push(Transaction::create_coinbase_transaction("alice", 30));
+        let block = Block::create(transaction_pool, Block::create_genesis_block().get_hash());
+        let filter = BlockFilter::create(&block, 1);
+        assert!(filter.match_any(&vec!["bob".to_string(), "alice".to_string()]));
+        assert!(filter.match_any(&vec!["bob".to_string()]) == false);
+        assert!(filter.match_any(&Vec::new()) == false);
+    }
+}
@@ -0,0 +1,221 @@
+/*  
+    bootstrap.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Bootstrap files for moving a chain without a network. The first line describes the file and
+//! each following line holds one block, so that a node can be seeded from a range of a chain.
+
+use crate::client_server_core::block_chain::{Block, BlockChain, get_double_sha256};
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+
+pub const BOOTSTRAP_FORMAT: &str = "blockchain-rs-bootstrap";
+pub const BOOTSTRAP_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BootstrapHeader {
+    pub format: String,
+    pub version: u64,
+    /// the hash of the genesis block of the chain the blocks belong to
+    pub genesis_hash: String,
+    /// the height of the first block in the file
+    pub start_height: usize,
+    pub block_count: usize,
+    /// the hash of the last block in the file
+    pub tip_hash: String,
+    /// the double SHA-256 of the block lines
+    pub checksum: String
+}
+
+pub fn export_blocks(bc: &BlockChain, start_height: usize, end_height: usize, path: &str) -> bool {
+    //! write the blocks from start_height to end_height (exclusive) to path
+    let end_height = if end_height > bc.chain.len() { bc.chain.len() } else { end_height };
+    if start_height >= end_height {
+        println!("no blocks between {} and {}", start_height, end_height);
+        return false;
+    }
+    if bc.chain[start_height..end_height].iter().any(|b| b.pruned == true) == true {
+        println!("the transactions of some blocks are pruned");
+        return false;
+    }
+
+    let lines: Vec<String> = bc.chain[start_height..end_height].iter().map(|b| b.to_string()).collect();
+    let header = BootstrapHeader {
+        format: BOOTSTRAP_FORMAT.to_string(),
+        version: BOOTSTRAP_VERSION,
+        genesis_hash: bc.chain[0].get_hash(),
+        start_height: start_height,
+        block_count: lines.len(),
+        tip_hash: bc.chain[end_height - 1].get_hash(),
+        checksum: get_double_sha256(&lines.join("\n"))
+    };
+
+    let content = serde_json::to_string(&header).unwrap() + "\n" + &lines.join("\n") + "\n";
+    match fs::write(path, content) {
+        Ok(_) => {
+            println!("{} blocks from {} are exported to {}", header.block_count, start_height, path);
+            true
+        },
+        Err(e) => {
+            println!("cannot write the blocks to {}: {}", path, e);
+            false
+        }
+    }
+}
+
+pub fn import_blocks(path: &str) -> Option<(BootstrapHeader, Vec<Block>)> {
+    //! read the blocks of a bootstrap file. It checks the file is complete and the blocks are linked,
+    //! but the blocks themselves must be validated by the node.
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            println!("cannot read the blocks from {}: {}", path, e);
+            return None;
+        }
+    };
+    let mut lines = content.lines();
+    let header: BootstrapHeader = match serde_json::from_str(lines.next().unwrap_or("")) {
+        Ok(header) => header,
+        Err(e) => {
+            println!("invalid bootstrap header: {}", e);
+            return None;
+        }
+    };
+    if header.format != BOOTSTRAP_FORMAT || header.version != BOOTSTRAP_VERSION {
+        println!("unsupported bootstrap file {} {}", header.format, header.version);
+        return None;
+    }
+    if header.genesis_hash != Block::create_genesis_block().get_hash() {
+        println!("the blocks belong to another chain");
+        return None;
+    }
+
+    let block_lines: Vec<&str> = lines.filter(|l| l.len() > 0).collect();
+    if block_lines.len() != header.block_count || block_lines.len() == 0 {
+        println!("{} blocks are expected but {} are found", header.block_count, block_lines.len());
+        return None;
+    }
+    if get_double_sha256(&block_lines.join("\n")) != header.checksum {
+        println!("the checksum of the blocks is not matched");
+        return None;
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for line in block_lines.iter() {
+        match serde_json::from_str::<Block>(line) {
+            Ok(block) => {
+                if block.is_well_formed() == false {
+                    println!("malformed block at {}", header.start_height + blocks.len());
+                    return None;
+                }
+                blocks.push(block);
+            },
+            Err(e) => {
+                println!("invalid block: {}", e);
+                return None;
+            }
+        }
+    }
+    for i in 1..blocks.len() {
+        if blocks[i].previous_block != blocks[i - 1].get_hash() {
+            println!("the block at {} is not linked to its previous block", header.start_height + i);
+            return None;
+        }
+    }
+    if header.start_height == 0 && blocks[0].get_hash() != header.genesis_hash {
+        println!("the first block is not the genesis block");
+        return None;
+    }
+    if blocks[blocks.len() - 1].get_hash() != header.tip_hash {
+        println!("the last block is not matched with the header");
+        return None;
+    }
+    Some((header, blocks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::block_chain::{TransactionPool, Transaction};
+
+    fn create_chain() -> BlockChain {
+        //! the genesis block and 5 blocks
+        let mut bc = BlockChain::create();
+        bc.set_new_block(Block::create_genesis_block());
+        for i in 0..5 {
+            let mut transaction_pool = TransactionPool::create();
+            transaction_pool.push(Transaction::create_coinbase_transaction("bootstrap", i));
+            let previous_block = bc.chain[bc.chain.len() - 1].get_hash();
+            bc.set_new_block(Block::create(transaction_pool, previous_block));
+        }
+        bc
+    }
+
+    fn get_path(name: &str) -> String {
+        //! a file in the temporary directory which no other test uses
+        let path = std::env::temp_dir().join(format!("{}-{}.dat", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn chain_is_exported_in_two_files() {
+        let bc = create_chain();
+        let path = get_path("bootstrap-export");
+        assert!(export_blocks(&bc, 0, 3, &path));
+        let (header, blocks) = import_blocks(&path).unwrap();
+        assert_eq!((header.start_height, header.block_count), (0, 3));
+        assert_eq!(header.tip_hash, bc.chain[2].get_hash());
+        assert!(blocks.iter().zip(bc.chain.iter()).all(|(b1, b2)| b1.equal(b2)));
+
+        // the end height is capped at the tip
+        assert!(export_blocks(&bc, 3, 100, &path));
+        let (header, blocks) = import_blocks(&path).unwrap();
+        assert_eq!((header.start_height, header.block_count), (3, 3));
+        assert_eq!(blocks[0].previous_block, bc.chain[2].get_hash());
+        assert!(export_blocks(&bc, 6, 100, &path) == false);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tampered_file_is_refused() {
+        let bc = create_chain();
+        let path = get_path("bootstrap-tampered");
+        assert!(export_blocks(&bc, 0, bc.chain.len(), &path));
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("\"bootstrap\"", "\"tampered\"", 1)).unwrap();
+        assert!(import_blocks(&path).is_none());
+
+        // a block left out breaks the checksum and the count
+        let lines: Vec<&str> = content.lines().collect();
+        fs::write(&path, lines[..lines.len() - 1].join("\n")).unwrap();
+        assert!(import_blocks(&path).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pruned_blocks_are_not_exported() {
+        let mut bc = create_chain();
+        bc.prune(2);
+        let path = get_path("bootstrap-pruned");
+        assert!(export_blocks(&bc, 0, bc.chain.len(), &path) == false);
+        assert!(export_blocks(&bc, 4, bc.chain.len(), &path));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+/*  
+    chain_sync.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! The sync of a core node from another core node. The headers after our tip are requested first, and the
+//! blocks of the headers which are not in our chain are downloaded in batches, so that no message has to
+//! carry the whole chain. Blocks on top of our tip are added as they arrive, and the blocks of a fork are
+//! collected until the peer has no more headers.
+
+use crate::client_server_core::block_chain::{Block, BlockChain, BlockHeader, TransactionPool};
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The maximum number of headers in a response to a request for headers
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// The number of blocks requested at once
+pub const SYNC_BATCH_BLOCKS: usize = 100;
+/// The time after which a sync without a response is given up
+pub const SYNC_TIMEOUT_SECS: u64 = 60;
+
+pub fn is_linked(headers: &Vec<BlockHeader>) -> bool {
+    //! true if each header is the parent of the next one
+    headers.windows(2).all(|pair| pair[1].previous_block == pair[0].get_hash())
+}
+
+pub fn find_fork(bc: &BlockChain, headers: &Vec<BlockHeader>) -> Option<(usize, usize)> {
+    //! return the height of the first header which is not in our chain and the number of headers before it,
+    //! which are in our chain already. None if the headers are not linked to our chain.
+    if headers.len() == 0 || is_linked(headers) == false {
+        return None;
+    }
+    let parent_height = bc.chain.iter().position(|b| b.get_hash() == headers[0].previous_block)?;
+    let mut known = 0;
+    while known < headers.len() && parent_height + 1 + known < bc.chain.len() && bc.chain[parent_height + 1 + known].get_hash() == headers[known].get_hash() {
+        known += 1;
+    }
+    Some((parent_height + 1 + known, known))
+}
+
+/// A sync with one core node
+pub struct ChainSync {
+    pub ip: String,
+    pub port: String,
+    /// the height of the first block which is not in our chain
+    pub fork_height: usize,
+    /// the headers whose blocks are not received yet
+    headers: VecDeque<BlockHeader>,
+    /// the hash of the last header received
+    last_hash: String,
+    /// the hashes of the blocks requested last
+    requested: Vec<String>,
+    /// the received blocks of a fork from our chain
+    pub blocks: Vec<Block>,
+    /// true if the last response had the maximum number of headers, so that the peer may have more
+    pub more_headers: bool,
+    last_progress: Instant
+}
+
+impl ChainSync {
+    pub fn create(ip: &str, port: &str, fork_height: usize, headers: &[BlockHeader], more_headers: bool) -> ChainSync {
+        ChainSync {
+            ip: ip.to_string(),
+            port: port.to_string(),
+            fork_height: fork_height,
+            headers: headers.iter().map(|h| h.clone()).collect(),
+            last_hash: headers[headers.len() - 1].get_hash(),
+            requested: Vec::new(),
+            blocks: Vec::new(),
+            more_headers: more_headers,
+            last_progress: Instant::now()
+        }
+    }
+
+    pub fn clone(&self) -> ChainSync {
+        ChainSync {
+            ip: self.ip.clone(),
+            port: self.port.clone(),
+            fork_height: self.fork_height,
+            headers: self.headers.iter().map(|h| h.clone()).collect(),
+            last_hash: self.last_hash.clone(),
+            requested: self.requested.clone(),
+            blocks: self.blocks.iter().map(|b| b.clone()).collect(),
+            more_headers: self.more_headers,
+            last_progress: self.last_progress
+        }
+    }
+
+    pub fn is_peer(&self, ip: &str, port: &str) -> bool {
+        self.ip == ip && self.port == port
+    }
+
+    pub fn is_timed_out(&self) -> bool {
+        self.last_progress.elapsed() > Duration::from_secs(SYNC_TIMEOUT_SECS)
+    }
+
+    pub fn get_last_hash(&self) -> String {
+        self.last_hash.clone()
+    }
+
+    pub fn add_headers(&mut self, headers: &Vec<BlockHeader>, more_headers: bool) -> bool {
+        //! add the next headers of the peer. They must follow the last header.
+        if headers.len() == 0 || headers[0].previous_block != self.last_hash || is_linked(headers) == false {
+            return false;
+        }
+        for header in headers.iter() {
+            self.headers.push_back(header.clone());
+        }
+        self.last_hash = headers[headers.len() - 1].get_hash();
+        self.more_headers = more_headers;
+        self.last_progress = Instant::now();
+        true
+    }
+
+    pub fn next_batch(&mut self) -> Vec<String> {
+        //! the hashes of the next blocks to request. It is empty when all blocks are received.
+        self.requested = self.headers.iter().take(SYNC_BATCH_BLOCKS).map(|h| h.get_hash()).collect();
+        self.requested.clone()
+    }
+
+    pub fn take_blocks(&mut self, blocks: &Vec<Block>) -> Option<Vec<Block>> {
+        //! return the requested blocks in the order of the headers. A peer may send only the first blocks to keep
+        //! its message small, and the rest are requested again. None if the first requested block is missing.
+        let mut taken: Vec<Block> = Vec::new();
+        for hash in self.requested.iter() {
+            match blocks.iter().find(|b| b.pruned == false && b.get_hash() == *hash) {
+                Some(block) => taken.push(block.clone()),
+                None => break,
+            }
+        }
+        if taken.len() == 0 {
+            return None;
+        }
+        for _ in 0..taken.len() {
+            self.headers.pop_front();
+        }
+        self.requested.clear();
+        self.last_progress = Instant::now();
+        Some(taken)
+    }
+
+    pub fn get_fork_chain(&self, bc: &BlockChain) -> BlockChain {
+        //! our chain up to the fork followed by the received blocks
+        let mut new_block_chain = BlockChain { chain: bc.chain[..self.fork_height].iter().map(|b| b.clone()).collect() };
+        for block in self.blocks.iter() {
+            new_block_chain.set_new_block(block.clone());
+        }
+        new_block_chain
+    }
+}
+
+pub fn run() {
+    //! sync a chain of 250 blocks, of which our chain has the first 50, in batches
+    let genesis = Block::create_genesis_block();
+    let mut their_chain = BlockChain::create();
+    their_chain.set_new_block(genesis.clone());
+    for _ in 0..250 {
+        let previous = their_chain.chain[their_chain.chain.len() - 1].get_hash();
+        their_chain.set_new_block(Block::create(TransactionPool::create(), previous));
+    }
+    let mut our_chain = BlockChain { chain: their_chain.chain[..51].iter().map(|b| b.clone()).collect() };
+
+    let mut headers = their_chain.get_headers_after(&genesis.get_hash());
+    headers.truncate(MAX_HEADERS_PER_MESSAGE);
+    let (fork_height, known) = find_fork(&our_chain, &headers).unwrap();
+    println!("fork at {} after {} known headers", fork_height, known);
+    let mut sync = ChainSync::create("127.0.0.1", "0", fork_height, &headers[known..], false);
+    loop {
+        let hashes = sync.next_batch();
+        if hashes.len() == 0 {
+            break;
+        }
+        let blocks: Vec<Block> = their_chain.chain.iter().filter(|b| hashes.contains(&b.get_hash())).map(|b| b.clone()).collect();
+        let blocks = sync.take_blocks(&blocks).unwrap();
+        println!("{} blocks are received", blocks.len());
+        for block in blocks {
+            our_chain.set_new_block(block);
+        }
+    }
+    println!("synced: {}", our_chain.chain[our_chain.chain.len() - 1].get_hash() == their_chain.chain[their_chain.chain.len() - 1].get_hash());
+}
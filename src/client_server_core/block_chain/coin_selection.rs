@@ -0,0 +1,342 @@
+/*  
+    coin_selection.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Coin selection strategies for wallets. A strategy picks UTXOs held by a UTXOManager so that their total covers a target amount.
+
+use crate::client_server_core::block_chain::{Transaction, UTXOManager};
+
+extern crate rand;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+/// The default number of search steps for the branch-and-bound strategy
+pub const BNB_MAX_TRIES: usize = 100000;
+
+pub trait CoinSelection {
+    fn name(&self) -> String;
+
+    /// It returns the indices of the selected UTXOs in um.utxo_txs, their total value and whether the target is covered.
+    fn select(&self, um: &UTXOManager, target: i64) -> (Vec<usize>, i64, bool);
+}
+
+/// It takes the largest UTXOs first. It minimizes the number of inputs.
+pub struct LargestFirst {}
+
+impl LargestFirst {
+    pub fn create() -> LargestFirst {
+        LargestFirst {}
+    }
+}
+
+impl CoinSelection for LargestFirst {
+    fn name(&self) -> String {
+        "largest-first".to_string()
+    }
+
+    fn select(&self, um: &UTXOManager, target: i64) -> (Vec<usize>, i64, bool) {
+        let mut order: Vec<usize> = (0..um.utxo_txs.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(um.get_utxo_value(*i)));
+        accumulate(um, &order, target)
+    }
+}
+
+/// It takes the oldest UTXOs first by the timestamp of the transaction holding them.
+pub struct OldestFirst {}
+
+impl OldestFirst {
+    pub fn create() -> OldestFirst {
+        OldestFirst {}
+    }
+}
+
+impl CoinSelection for OldestFirst {
+    fn name(&self) -> String {
+        "oldest-first".to_string()
+    }
+
+    fn select(&self, um: &UTXOManager, target: i64) -> (Vec<usize>, i64, bool) {
+        // timestamps are written by Utc::now().to_string(), so they sort in lexicographic order.
+        let mut order: Vec<usize> = (0..um.utxo_txs.len()).collect();
+        order.sort_by(|a, b| um.utxo_txs[*a].0.timestamp.cmp(&um.utxo_txs[*b].0.timestamp));
+        accumulate(um, &order, target)
+    }
+}
+
+/// It searches a set of UTXOs whose total matches the target exactly, so that no change output (dust) is created.
+/// If no exact match is found within max_tries steps, it falls back to LargestFirst.
+pub struct BranchAndBound {
+    pub max_tries: usize
+}
+
+impl BranchAndBound {
+    pub fn create(max_tries: usize) -> BranchAndBound {
+        BranchAndBound {
+            max_tries: max_tries
+        }
+    }
+}
+
+impl CoinSelection for BranchAndBound {
+    fn name(&self) -> String {
+        "branch-and-bound".to_string()
+    }
+
+    fn select(&self, um: &UTXOManager, target: i64) -> (Vec<usize>, i64, bool) {
+        let mut order: Vec<usize> = (0..um.utxo_txs.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(um.get_utxo_value(*i)));
+        let values: Vec<i64> = order.iter().map(|i| um.get_utxo_value(*i)).collect();
+
+        // remaining[i] is the total value of values[i..]
+        let mut remaining: Vec<i64> = vec![0; values.len() + 1];
+        for i in (0..values.len()).rev() {
+            remaining[i] = remaining[i + 1] + values[i];
+        }
+
+        let mut selected: Vec<bool> = vec![false; values.len()];
+        let mut tries: usize = 0;
+        if search_exact_match(&values, &remaining, 0, 0, target, &mut selected, &mut tries, self.max_tries) == true {
+            let mut res: Vec<usize> = Vec::new();
+            for i in 0..selected.len() {
+                if selected[i] == true {
+                    res.push(order[i]);
+                }
+            }
+            println!("exact match is found after {} tries", tries);
+            return (res, target, true);
+        }
+
+        println!("no exact match is found. fall back to largest-first");
+        LargestFirst::create().select(um, target)
+    }
+}
+
+fn search_exact_match(values: &Vec<i64>, remaining: &Vec<i64>, depth: usize, current: i64, target: i64, selected: &mut Vec<bool>, tries: &mut usize, max_tries: usize) -> bool {
+    if current == target {
+        return true;
+    }
+    if current > target || depth == values.len() || current + remaining[depth] < target || *tries >= max_tries {
+        return false;
+    }
+    *tries += 1;
+
+    // inclusion branch first, then omission branch
+    selected[depth] = true;
+    if search_exact_match(values, remaining, depth + 1, current + values[depth], target, selected, tries, max_tries) == true {
+        return true;
+    }
+    selected[depth] = false;
+    search_exact_match(values, remaining, depth + 1, current, target, selected, tries, max_tries)
+}
+
+/// It selects UTXOs at random until the target is covered, and then it tries to improve the selection
+/// by adding random UTXOs so that the change gets close to the target amount (total close to 2 * target).
+/// Change outputs of a similar size to payments are harder to tell apart and can be spent later without creating dust.
+pub struct RandomImprove {
+    seed: Option<u64>
+}
+
+impl RandomImprove {
+    pub fn create() -> RandomImprove {
+        RandomImprove {
+            seed: None
+        }
+    }
+
+    pub fn create_with_seed(seed: u64) -> RandomImprove {
+        //! create a RandomImprove whose choices are reproducible
+        RandomImprove {
+            seed: Some(seed)
+        }
+    }
+}
+
+impl CoinSelection for RandomImprove {
+    fn name(&self) -> String {
+        "random-improve".to_string()
+    }
+
+    fn select(&self, um: &UTXOManager, target: i64) -> (Vec<usize>, i64, bool) {
+        let mut rng = match self.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let mut order: Vec<usize> = (0..um.utxo_txs.len()).collect();
+        order.shuffle(&mut rng);
+
+        // random selection phase
+        let mut selected: Vec<usize> = Vec::new();
+        let mut total: i64 = 0;
+        let mut next = 0;
+        while total < target && next < order.len() {
+            selected.push(order[next]);
+            total += um.get_utxo_value(order[next]);
+            next += 1;
+        }
+        if total < target {
+            return (selected, total, false);
+        }
+
+        // improvement phase
+        let ideal = 2 * target;
+        let upper = 3 * target;
+        while next < order.len() {
+            let value = um.get_utxo_value(order[next]);
+            let new_total = total + value;
+            if new_total <= upper && (ideal - new_total).abs() < (ideal - total).abs() {
+                selected.push(order[next]);
+                total = new_total;
+            }
+            next += 1;
+        }
+        (selected, total, true)
+    }
+}
+
+fn accumulate(um: &UTXOManager, order: &Vec<usize>, target: i64) -> (Vec<usize>, i64, bool) {
+    //! take UTXOs in the given order until the target is covered
+    let mut selected: Vec<usize> = Vec::new();
+    let mut total: i64 = 0;
+    for idx in order.iter() {
+        if total >= target {
+            break;
+        }
+        selected.push(*idx);
+        total += um.get_utxo_value(*idx);
+    }
+    (selected, total, total >= target)
+}
+
+pub fn create_coin_selection(name: &str) -> Option<Box<dyn CoinSelection>> {
+    //! create a coin selection strategy by its name
+    match name {
+        "largest-first" => Some(Box::new(LargestFirst::create())),
+        "oldest-first" => Some(Box::new(OldestFirst::create())),
+        "branch-and-bound" => Some(Box::new(BranchAndBound::create(BNB_MAX_TRIES))),
+        "random-improve" => Some(Box::new(RandomImprove::create())),
+        _ => None,
+    }
+}
+
+pub fn run() {
+    let mut um = UTXOManager::create("synthetic address");
+    for value in [5, 30, 12, 7, 50, 3].iter() {
+        let t = Transaction::create_coinbase_transaction("synthetic address", *value);
+        um.put_utxo_tx(&t);
+    }
+
+    let strategies: Vec<Box<dyn CoinSelection>> = vec![
+        Box::new(LargestFirst::create()),
+        Box::new(OldestFirst::create()),
+        Box::new(BranchAndBound::create(BNB_MAX_TRIES)),
+        Box::new(RandomImprove::create_with_seed(7)),
+    ];
+
+    for s in strategies.iter() {
+        let (selected, total, flag) = s.select(&um, 42);
+        println!("{}: selected {:?} total {} covered {}", s.name(), selected, total, flag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The values of the synthetic UTXOs by their indices
+    const VALUES: [i64; 6] = [5, 30, 12, 7, 50, 3];
+
+    fn create_utxo_manager(values: &[i64]) -> UTXOManager {
+        let mut um = UTXOManager::create("synthetic address");
+        for value in values.iter() {
+            um.put_utxo_tx(&Transaction::create_coinbase_transaction("synthetic address", *value));
+        }
+        um
+    }
+
+    #[test]
+    fn branch_and_bound_finds_an_exact_match() {
+        let um = create_utxo_manager(&VALUES);
+        let (selected, total, covered) = BranchAndBound::create(BNB_MAX_TRIES).select(&um, 42);
+        // 30 + 12 leaves no change
+        assert_eq!(selected, vec![1, 2]);
+        assert_eq!(total - 42, 0);
+        assert!(covered);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_largest_first() {
+        let um = create_utxo_manager(&[50, 30]);
+        let (selected, total, covered) = BranchAndBound::create(BNB_MAX_TRIES).select(&um, 42);
+        assert_eq!(selected, vec![0]);
+        assert_eq!(total - 42, 8);
+        assert!(covered);
+    }
+
+    #[test]
+    fn largest_first_takes_the_fewest_inputs() {
+        let um = create_utxo_manager(&VALUES);
+        let (selected, total, covered) = LargestFirst::create().select(&um, 42);
+        assert_eq!(selected, vec![4]);
+        assert_eq!(total - 42, 8);
+        assert!(covered);
+
+        let (selected, total, covered) = LargestFirst::create().select(&um, 85);
+        assert_eq!(selected, vec![4, 1, 2]);
+        assert_eq!(total - 85, 7);
+        assert!(covered);
+    }
+
+    #[test]
+    fn random_improve_makes_change_close_to_the_target() {
+        // whatever the order is, one UTXO covers the target and a second one makes the change equal to it
+        let um = create_utxo_manager(&[10, 10, 10, 10, 10]);
+        let (selected, total, covered) = RandomImprove::create_with_seed(7).select(&um, 10);
+        assert_eq!(selected.len(), 2);
+        assert_ne!(selected[0], selected[1]);
+        assert_eq!(total - 10, 10);
+        assert!(covered);
+
+        // a seed makes the choices reproducible
+        let um = create_utxo_manager(&VALUES);
+        let first = RandomImprove::create_with_seed(7).select(&um, 42);
+        let second = RandomImprove::create_with_seed(7).select(&um, 42);
+        assert_eq!(first, second);
+        assert!(first.1 >= 42 && first.1 <= 3 * 42);
+    }
+
+    #[test]
+    fn insufficient_funds_are_not_covered() {
+        let um = create_utxo_manager(&VALUES);
+        let balance: i64 = VALUES.iter().sum();
+        let strategies: Vec<Box<dyn CoinSelection>> = vec![
+            Box::new(LargestFirst::create()),
+            Box::new(OldestFirst::create()),
+            Box::new(BranchAndBound::create(BNB_MAX_TRIES)),
+            Box::new(RandomImprove::create_with_seed(7)),
+        ];
+        for s in strategies.iter() {
+            let (mut selected, total, covered) = s.select(&um, balance + 1);
+            selected.sort();
+            assert_eq!(selected, vec![0, 1, 2, 3, 4, 5], "{}", s.name());
+            assert_eq!(total, balance, "{}", s.name());
+            assert!(covered == false, "{}", s.name());
+        }
+    }
+}
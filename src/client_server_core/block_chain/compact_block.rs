@@ -0,0 +1,249 @@
+/*  
+    compact_block.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Compact blocks in the style of BIP152. A core node announces a new block by its header and short
+//! transaction IDs, and receivers rebuild the block from the transactions they already have, requesting
+//! only the missing ones.
+
+use crate::client_server_core::block_chain::{Block, BlockHeader, Transaction, TransactionPool, get_double_sha256};
+use crate::client_server_core::block_chain::merkle::get_transaction_hash;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+/// The number of hex characters of a short transaction ID (6 bytes as in BIP152)
+pub const SHORT_ID_LENGTH: usize = 12;
+
+pub fn get_short_id(block_hash: &str, transaction: &Transaction) -> String {
+    //! short IDs are keyed by the block hash so that collisions cannot be prepared for every block
+    let id = get_double_sha256(&(block_hash.to_string() + &get_transaction_hash(transaction)));
+    id[..SHORT_ID_LENGTH].to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// the short IDs of the transactions which are not prefilled
+    pub short_ids: Vec<String>,
+    /// the transactions which receivers cannot have, e.g. the coinbase transaction, with their positions
+    pub prefilled: Vec<(usize, Transaction)>
+}
+
+impl CompactBlock {
+    pub fn create(block: &Block) -> CompactBlock {
+        let block_hash = block.get_hash();
+        let mut short_ids: Vec<String> = Vec::new();
+        let mut prefilled: Vec<(usize, Transaction)> = Vec::new();
+        for (i, t) in block.transaction_pool.transactions.iter().enumerate() {
+            if t.tx_type == true {
+                prefilled.push((i, t.clone()));
+            }
+            else {
+                short_ids.push(get_short_id(&block_hash, t));
+            }
+        }
+        CompactBlock {
+            header: block.get_header(),
+            short_ids: short_ids,
+            prefilled: prefilled
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_string(msg: &str) -> Option<CompactBlock> {
+        serde_json::from_str(msg).ok()
+    }
+
+    pub fn get_hash(&self) -> String {
+        self.header.get_hash()
+    }
+
+    pub fn to_partial_block(&self, candidates: &Vec<Transaction>) -> PartialBlock {
+        //! place the prefilled transactions and the candidates matching short IDs
+        let block_hash = self.get_hash();
+        let mut by_short_id: HashMap<String, &Transaction> = HashMap::new();
+        for t in candidates.iter() {
+            by_short_id.insert(get_short_id(&block_hash, t), t);
+        }
+
+        let len = self.short_ids.len() + self.prefilled.len();
+        let mut transactions: Vec<Option<Transaction>> = (0..len).map(|_| None).collect();
+        for (i, t) in self.prefilled.iter() {
+            if *i < len {
+                transactions[*i] = Some(t.clone());
+            }
+        }
+        let mut short_ids = self.short_ids.iter();
+        for slot in transactions.iter_mut() {
+            if slot.is_none() {
+                if let Some(short_id) = short_ids.next() {
+                    *slot = by_short_id.get(short_id).map(|t| (*t).clone());
+                }
+            }
+        }
+        PartialBlock {
+            header: self.header.clone(),
+            transactions: transactions
+        }
+    }
+}
+
+/// A block being rebuilt from a compact block
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    pub transactions: Vec<Option<Transaction>>
+}
+
+impl PartialBlock {
+    pub fn clone(&self) -> PartialBlock {
+        PartialBlock {
+            header: self.header.clone(),
+            transactions: self.transactions.iter().map(|t| t.as_ref().map(|t| t.clone())).collect()
+        }
+    }
+
+    pub fn get_missing(&self) -> Vec<usize> {
+        let mut missing: Vec<usize> = Vec::new();
+        for (i, t) in self.transactions.iter().enumerate() {
+            if t.is_none() {
+                missing.push(i);
+            }
+        }
+        missing
+    }
+
+    pub fn fill(&mut self, transactions: &Vec<(usize, Transaction)>) {
+        for (i, t) in transactions.iter() {
+            if *i < self.transactions.len() {
+                self.transactions[*i] = Some(t.clone());
+            }
+        }
+    }
+
+    pub fn to_block(&self) -> Option<Block> {
+        //! return the block if every transaction is known and the transactions match the header
+        let mut transaction_pool = TransactionPool::create();
+        for t in self.transactions.iter() {
+            transaction_pool.push(t.as_ref()?.clone());
+        }
+        let mut block = Block::create(transaction_pool, self.header.previous_block.clone());
+        block.timestamp = self.header.timestamp.clone();
+        block.nonce = self.header.nonce;
+        block.sealer = self.header.sealer.clone();
+        block.seal = self.header.seal.clone();
+        block.slot = self.header.slot;
+        block.vrf_proof = self.header.vrf_proof.clone();
+        if block.merkle_root != self.header.merkle_root {
+            // a short ID collided with another transaction
+            println!("rebuilt block does not match the merkle root");
+            return None;
+        }
+        Some(block)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockTransactionsRequest {
+    pub block_hash: String,
+    pub indexes: Vec<usize>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockTransactions {
+    pub block_hash: String,
+    pub transactions: Vec<(usize, Transaction)>
+}
+
+impl BlockTransactions {
+    pub fn create(block: &Block, request: &BlockTransactionsRequest) -> BlockTransactions {
+        let transactions = &block.transaction_pool.transactions;
+        let mut result: Vec<(usize, Transaction)> = Vec::new();
+        for i in request.indexes.iter() {
+            if *i < transactions.len() {
+                result.push((*i, transactions[*i].clone()));
+            }
+        }
+        BlockTransactions {
+            block_hash: request.block_hash.clone(),
+            transactions: result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_block() -> (Block, Vec<Transaction>) {
+        //! a block of 20 payments and a coinbase transaction, and the payments known to a receiver, which misses every fifth one
+        let mut transaction_pool = TransactionPool::create();
+        let mut candidates: Vec<Transaction> = Vec::new();
+        for i in 0..20 {
+            let mut t = Transaction::create_coinbase_transaction(&format!("address-{}", i), i);
+            t.tx_type = false;
+            transaction_pool.push(t.clone());
+            if i % 5 != 0 {
+                candidates.push(t);
+            }
+        }
+        transaction_pool.push(Transaction::create_coinbase_transaction("miner", 30));
+        (Block::create(transaction_pool, "previous".to_string()), candidates)
+    }
+
+    #[test]
+    fn compact_block_is_smaller_than_the_block() {
+        let (block, _) = create_block();
+        let compact = CompactBlock::create(&block);
+        assert_eq!(compact.get_hash(), block.get_hash());
+        assert_eq!(compact.short_ids.len(), 20);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert_eq!(compact.prefilled[0].0, 20);
+        assert!(compact.to_string().len() * 2 < block.to_string().len());
+    }
+
+    #[test]
+    fn block_is_rebuilt_with_the_missing_transactions() {
+        let (block, candidates) = create_block();
+        let compact = CompactBlock::from_string(&CompactBlock::create(&block).to_string()).unwrap();
+        let mut partial = compact.to_partial_block(&candidates);
+        let missing = partial.get_missing();
+        assert_eq!(missing, vec![0, 5, 10, 15]);
+        assert!(partial.to_block().is_none());
+
+        let request = BlockTransactionsRequest { block_hash: compact.get_hash(), indexes: missing };
+        let response = BlockTransactions::create(&block, &request);
+        assert_eq!(response.transactions.len(), 4);
+        partial.fill(&response.transactions);
+        assert_eq!(partial.get_missing().len(), 0);
+        assert_eq!(partial.to_block().unwrap().get_hash(), block.get_hash());
+    }
+
+    #[test]
+    fn transaction_which_does_not_match_the_header_is_refused() {
+        let (block, candidates) = create_block();
+        let mut partial = CompactBlock::create(&block).to_partial_block(&candidates);
+        let wrong: Vec<(usize, Transaction)> = partial.get_missing().iter().map(|i| (*i, candidates[0].clone())).collect();
+        partial.fill(&wrong);
+        assert!(partial.to_block().is_none());
+    }
+}
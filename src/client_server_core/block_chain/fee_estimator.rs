@@ -0,0 +1,220 @@
+/*  
+    fee_estimator.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Fee estimator for core nodes. It tracks fee rates of pooled and recently confirmed transactions
+//! and answers the fee rate required to confirm a transaction within a given number of blocks.
+//! Fee rates are measured in coins per 1000 bytes of the serialized transaction.
+
+use crate::client_server_core::block_chain::{Block, Transaction, get_double_sha256};
+
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+/// The number of recent blocks whose confirmed transactions are kept for estimation
+pub const FEE_ESTIMATOR_HISTORY_BLOCKS: usize = 100;
+/// The ratio of transactions which have to be confirmed within the target for a fee rate to be recommended
+pub const FEE_ESTIMATOR_SUCCESS_THRESHOLD: f64 = 0.85;
+/// The minimum number of confirmed transactions for an estimate based on the history
+pub const FEE_ESTIMATOR_MIN_SAMPLES: usize = 10;
+/// The largest number of pooled transactions tracked. The oldest one is dropped to make room for a new one.
+pub const FEE_ESTIMATOR_MAX_POOL: usize = 10000;
+/// The largest confirmation target which can be requested
+pub const MAX_CONFIRM_TARGET: usize = 25;
+/// The confirmation target used by wallets when nothing is specified
+pub const DEFAULT_CONFIRM_TARGET: usize = 6;
+/// The lowest fee rate returned by the estimator and the lowest fee rate of a transaction pooled by a core node
+pub const MIN_FEE_RATE: f64 = 1.0;
+
+/// The payload of RSP_FEE_ESTIMATE
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FeeEstimate {
+    pub target_blocks: usize,
+    pub fee_rate: f64
+}
+
+impl FeeEstimate {
+    pub fn to_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_string(msg: &str) -> Option<FeeEstimate> {
+        serde_json::from_str(msg).ok()
+    }
+}
+
+pub struct FeeEstimator {
+    /// hash of a pooled transaction -> (fee rate, chain length when it entered the pool)
+    pool: HashMap<String, (f64, usize)>,
+    /// hashes of the pooled transactions from the oldest. Hashes already confirmed are skipped when popped.
+    pool_order: VecDeque<String>,
+    /// (chain length after the confirming block, fee rate, blocks needed to confirm)
+    confirmed: VecDeque<(usize, f64, usize)>
+}
+
+impl FeeEstimator {
+    pub fn create() -> FeeEstimator {
+        FeeEstimator {
+            pool: HashMap::new(),
+            pool_order: VecDeque::new(),
+            confirmed: VecDeque::new()
+        }
+    }
+
+    pub fn clone(&self) -> FeeEstimator {
+        FeeEstimator {
+            pool: self.pool.clone(),
+            pool_order: self.pool_order.clone(),
+            confirmed: self.confirmed.clone()
+        }
+    }
+
+    pub fn process_transaction(&mut self, transaction: &Transaction, fee: i64, height: usize) {
+        //! record a transaction which entered the transaction pool when the chain had the given length.
+        //! fee is the value of its inputs in the UTXO set minus its outputs.
+        if transaction.tx_type == true {
+            return;
+        }
+        let key = get_double_sha256(&transaction.to_string());
+        if self.pool.contains_key(&key) == true {
+            return;
+        }
+        while self.pool.len() >= FEE_ESTIMATOR_MAX_POOL {
+            match self.pool_order.pop_front() {
+                Some(oldest) => {
+                    self.pool.remove(&oldest);
+                },
+                None => break,
+            }
+        }
+        self.pool.insert(key.clone(), (get_fee_rate(transaction, fee), height));
+        self.pool_order.push_back(key);
+    }
+
+    pub fn process_block(&mut self, block: &Block, height: usize) {
+        //! record the transactions confirmed by a block. height is the chain length after the block was set.
+        for t in block.transaction_pool.transactions.iter() {
+            let key = get_double_sha256(&t.to_string());
+            if let Some((fee_rate, entry_height)) = self.pool.remove(&key) {
+                let blocks = if height > entry_height { height - entry_height } else { 1 };
+                self.confirmed.push_back((height, fee_rate, blocks));
+            }
+        }
+
+        while let Some(front) = self.confirmed.front() {
+            if front.0 + FEE_ESTIMATOR_HISTORY_BLOCKS <= height {
+                self.confirmed.pop_front();
+            }
+            else {
+                break;
+            }
+        }
+
+        // a transaction pooled for longer than the history would never count, so it is dropped
+        while let Some(front) = self.pool_order.front() {
+            match self.pool.get(front) {
+                Some((_, entry_height)) if *entry_height + FEE_ESTIMATOR_HISTORY_BLOCKS > height => break,
+                Some(_) => {
+                    self.pool.remove(front);
+                },
+                None => {},
+            }
+            self.pool_order.pop_front();
+        }
+        if self.pool_order.len() > 2 * FEE_ESTIMATOR_MAX_POOL {
+            let pool = &self.pool;
+            self.pool_order.retain(|key| pool.contains_key(key));
+        }
+    }
+
+    pub fn get_pool_fee_rates(&self) -> Vec<f64> {
+        //! return the fee rates of the pooled transactions in descending order
+        let mut rates: Vec<f64> = self.pool.values().map(|v| v.0).collect();
+        rates.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        rates
+    }
+
+    pub fn estimate_fee_rate(&self, target_blocks: usize) -> f64 {
+        //! return the lowest fee rate at which most of the recent transactions paying at least that rate
+        //! were confirmed within target_blocks. It falls back to the median of the pool without enough history.
+        let mut target = target_blocks;
+        if target == 0 {
+            target = 1;
+        }
+        if target > MAX_CONFIRM_TARGET {
+            target = MAX_CONFIRM_TARGET;
+        }
+
+        let mut history: Vec<(f64, usize)> = self.confirmed.iter().map(|c| (c.1, c.2)).collect();
+        history.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+        let mut candidate: Option<f64> = None;
+        let mut count = 0;
+        let mut count_within = 0;
+        for (fee_rate, blocks) in history.iter() {
+            count += 1;
+            if *blocks <= target {
+                count_within += 1;
+            }
+            if count >= FEE_ESTIMATOR_MIN_SAMPLES && count_within as f64 >= FEE_ESTIMATOR_SUCCESS_THRESHOLD * count as f64 {
+                candidate = Some(*fee_rate);
+            }
+        }
+
+        let fee_rate = match candidate {
+            Some(fee_rate) => fee_rate,
+            None => {
+                let rates = self.get_pool_fee_rates();
+                if rates.len() == 0 {
+                    MIN_FEE_RATE
+                }
+                else {
+                    rates[rates.len() / 2]
+                }
+            }
+        };
+
+        if fee_rate < MIN_FEE_RATE {
+            return MIN_FEE_RATE;
+        }
+        fee_rate
+    }
+
+    pub fn print(&self) {
+        println!("fee estimator: {} pooled, {} confirmed", self.pool.len(), self.confirmed.len());
+    }
+}
+
+pub fn get_fee_rate(transaction: &Transaction, fee: i64) -> f64 {
+    //! return the fee rate of a transaction paying fee in coins per 1000 bytes
+    let size = transaction.to_string().len();
+    if size == 0 {
+        return 0.0;
+    }
+    fee as f64 * 1000.0 / size as f64
+}
+
+pub fn get_fee_for_size(fee_rate: f64, size: usize) -> i64 {
+    //! return the fee for a transaction of the given size, which is at least 1
+    let fee = (fee_rate * size as f64 / 1000.0).ceil() as i64;
+    if fee < 1 {
+        return 1;
+    }
+    fee
+}
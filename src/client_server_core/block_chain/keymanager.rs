@@ -23,6 +23,8 @@ extern crate minisign;
 use minisign::{KeyPair, PublicKeyBox, SecretKeyBox, SignatureBox};
 use std::io::Cursor;
 
+use crate::client_server_core::consensus::pos::vrf;
+
 extern crate rand;
 use rand::seq::SliceRandom;
 
@@ -53,6 +55,22 @@ impl KeyManager {
         }
     }
 
+    pub fn load(private_key_str: &str, public_key_str: &str, password: &str) -> Option<KeyManager> {
+        //! use an encrypted minisign secret key and its public key. It returns None unless the password opens the secret key of the public key.
+        let km = KeyManager {
+            private_key_str: private_key_str.to_string(),
+            public_key_str: public_key_str.to_string(),
+            password: password.to_string()
+        };
+        let sk = SecretKeyBox::from_string(private_key_str).and_then(|sk_box| sk_box.into_secret_key(Some(password.to_string()))).ok()?;
+        let signature_box = minisign::sign(None, &sk, Cursor::new("key check"), None, None).ok()?;
+        if km.verify_signature("key check", &signature_box.into_string(), public_key_str) == false {
+            println!("the secret key does not belong to the public key");
+            return None;
+        }
+        Some(km)
+    }
+
     pub fn my_address(&self) -> String {
         //! return the address as a public_key
 
@@ -71,24 +89,34 @@ impl KeyManager {
         signature_box.into_string()
     }
 
+    pub fn compute_vrf_proof(&self, alpha: &str) -> String {
+        //! prove the VRF output of alpha with the key of my address
+        let seed = vrf::get_seed(&self.private_key_str, &self.password).unwrap();
+        hex::encode(vrf::prove(&seed, alpha.as_bytes()))
+    }
+
     pub fn verify_signature(&self, message: &str, signature_box_str: &str, sender_public_key_box_str: &str) -> bool {
-        let signature_box = SignatureBox::from_string(signature_box_str).unwrap();
+        let signature_box = match SignatureBox::from_string(signature_box_str) {
+            Ok(signature_box) => signature_box,
+            Err(_) => return false,
+        };
         
-        let pk_box = PublicKeyBox::from_string(&sender_public_key_box_str).unwrap();
-        let pk = pk_box.into_public_key().unwrap();
+        let pk = match PublicKeyBox::from_string(sender_public_key_box_str).and_then(|pk_box| pk_box.into_public_key()) {
+            Ok(pk) => pk,
+            Err(_) => return false,
+        };
 
         let msg_reader = Cursor::new(message);
         let verified = minisign::verify(&pk, &signature_box, msg_reader, true, false, false);
 
-        let flag: bool;
-        match verified {
-            Ok(()) => flag = true,
-            Err(_) => flag = false,
+        let flag = match verified {
+            Ok(()) => true,
+            Err(_) => false,
         };
         return flag;
     }
 
-    pub fn export_key_pair(&mut self, key_data: &str, pass_phrase: &str) -> (String, String) {
+    pub fn export_key_pair(&mut self, _key_data: &str, _pass_phrase: &str) -> (String, String) {
         return (self.private_key_str.clone(), self.public_key_str.clone());
     }
 
@@ -125,7 +153,7 @@ pub fn run() {
     println!("my_address: {}", my_address);
 
     let msg = "The first message";
-    let signature = km.compute_digital_signature(&msg);
+    let signature = km.compute_digital_signature(msg);
 
     let flag = km.verify_signature(msg, &signature, &km.public_key_str);
     println!("verify suffcess {}", flag);
@@ -0,0 +1,212 @@
+/*  
+    merkle.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Merkle trees over the transactions of a block. The Merkle root in a block header commits to the transactions,
+//! and a Merkle proof shows that a transaction is included in a block without the other transactions.
+
+use crate::client_server_core::block_chain::{Block, Transaction, get_double_sha256};
+
+use serde::{Deserialize, Serialize};
+
+pub fn get_transaction_hash(transaction: &Transaction) -> String {
+    get_double_sha256(&transaction.to_string())
+}
+
+fn hash_pair(left: &str, right: &str) -> String {
+    get_double_sha256(&(left.to_string() + right))
+}
+
+fn get_next_level(level: &Vec<String>) -> Vec<String> {
+    //! hash pairs of a level. The last hash is paired with itself if the level has an odd number of hashes.
+    let mut next: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < level.len() {
+        let right = if i + 1 < level.len() { &level[i + 1] } else { &level[i] };
+        next.push(hash_pair(&level[i], right));
+        i += 2;
+    }
+    next
+}
+
+pub fn get_merkle_root(hashes: &Vec<String>) -> String {
+    //! return the Merkle root of hashes. It is empty for no hashes.
+    if hashes.len() == 0 {
+        return String::new();
+    }
+    let mut level = hashes.clone();
+    while level.len() > 1 {
+        level = get_next_level(&level);
+    }
+    level[0].clone()
+}
+
+pub fn get_merkle_root_of_transactions(transactions: &Vec<Transaction>) -> String {
+    let hashes: Vec<String> = transactions.iter().map(get_transaction_hash).collect();
+    get_merkle_root(&hashes)
+}
+
+/// A proof that a transaction is included in a block
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MerkleProof {
+    pub block_hash: String,
+    pub transaction: Transaction,
+    /// the position of the transaction in the block
+    pub index: usize,
+    /// the sibling hashes from the leaf to the root
+    pub branch: Vec<String>
+}
+
+impl MerkleProof {
+    pub fn create(block: &Block, index: usize) -> MerkleProof {
+        //! create the proof of the index-th transaction of block
+        let transactions = &block.transaction_pool.transactions;
+        let mut level: Vec<String> = transactions.iter().map(get_transaction_hash).collect();
+        let mut branch: Vec<String> = Vec::new();
+        let mut position = index;
+        while level.len() > 1 {
+            let sibling = position ^ 1;
+            if sibling < level.len() {
+                branch.push(level[sibling].clone());
+            }
+            else {
+                branch.push(level[position].clone());
+            }
+            level = get_next_level(&level);
+            position /= 2;
+        }
+
+        MerkleProof {
+            block_hash: block.get_hash(),
+            transaction: transactions[index].clone(),
+            index: index,
+            branch: branch
+        }
+    }
+
+    pub fn clone(&self) -> MerkleProof {
+        MerkleProof {
+            block_hash: self.block_hash.clone(),
+            transaction: self.transaction.clone(),
+            index: self.index,
+            branch: self.branch.clone()
+        }
+    }
+
+    pub fn get_root(&self) -> String {
+        //! compute the Merkle root from the transaction and the branch
+        let mut hash = get_transaction_hash(&self.transaction);
+        let mut position = self.index;
+        for sibling in self.branch.iter() {
+            if position.is_multiple_of(2) {
+                hash = hash_pair(&hash, sibling);
+            }
+            else {
+                hash = hash_pair(sibling, &hash);
+            }
+            position /= 2;
+        }
+        hash
+    }
+
+    pub fn verify(&self, merkle_root: &str) -> bool {
+        self.get_root() == merkle_root
+    }
+}
+
+pub fn get_merkle_proofs_for_address(blocks: &Vec<Block>, address: &str) -> Vec<MerkleProof> {
+    //! create the proofs of all transactions which pay to or spend from address
+    let mut proofs: Vec<MerkleProof> = Vec::new();
+    for block in blocks.iter() {
+        for (index, t) in block.transaction_pool.transactions.iter().enumerate() {
+            let mut relevant = false;
+            for o in t.outputs.iter() {
+                if o.recipient == address {
+                    relevant = true;
+                }
+            }
+            for i in t.inputs.iter() {
+                if i.transaction.outputs[i.output_index].recipient == address {
+                    relevant = true;
+                }
+            }
+            if relevant == true {
+                proofs.push(MerkleProof::create(block, index));
+            }
+        }
+    }
+    proofs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::block_chain::TransactionPool;
+
+    fn create_block(count: usize) -> Block {
+        //! a block of count transactions paying address-0, address-1, ...
+        let mut transaction_pool = TransactionPool::create();
+        for i in 0..count {
+            transaction_pool.push(Transaction::create_coinbase_transaction(&format!("address-{}", i), 30));
+        }
+        Block::create(transaction_pool, "previous".to_string())
+    }
+
+    #[test]
+    fn root_pairs_the_hashes_of_each_level() {
+        assert_eq!(get_merkle_root(&Vec::new()), "");
+        assert_eq!(get_merkle_root(&vec!["a".to_string()]), "a");
+        assert_eq!(get_merkle_root(&vec!["a".to_string(), "b".to_string()]), hash_pair("a", "b"));
+        // the last hash of an odd level is paired with itself
+        let three = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(get_merkle_root(&three), hash_pair(&hash_pair("a", "b"), &hash_pair("c", "c")));
+    }
+
+    #[test]
+    fn proof_of_every_transaction_verifies() {
+        for count in 1..8 {
+            let block = create_block(count);
+            for index in 0..count {
+                let proof = MerkleProof::create(&block, index);
+                assert!(proof.verify(&block.merkle_root), "{} of {}", index, count);
+            }
+        }
+    }
+
+    #[test]
+    fn proof_does_not_verify_for_another_transaction_or_position() {
+        let block = create_block(5);
+        let proof = MerkleProof::create(&block, 2);
+        let mut other_transaction = proof.clone();
+        other_transaction.transaction = block.transaction_pool.transactions[3].clone();
+        assert!(other_transaction.verify(&block.merkle_root) == false);
+        let mut other_index = proof.clone();
+        other_index.index = 3;
+        assert!(other_index.verify(&block.merkle_root) == false);
+    }
+
+    #[test]
+    fn proofs_are_created_for_the_transactions_of_an_address() {
+        let blocks = vec![create_block(3), create_block(5)];
+        let proofs = get_merkle_proofs_for_address(&blocks, "address-4");
+        assert_eq!(proofs.len(), 1);
+        assert_eq!(proofs[0].block_hash, blocks[1].get_hash());
+        assert_eq!(proofs[0].index, 4);
+        assert!(proofs[0].verify(&blocks[1].merkle_root));
+    }
+}
@@ -0,0 +1,167 @@
+/*  
+    spv.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Header chain of a light client (SPV). Edge nodes keep block headers only and verify Merkle proofs of
+//! their transactions against the heaviest valid header chain. Headers are checked by the consensus of the chain spec.
+
+use crate::client_server_core::block_chain::{Block, BlockHeader, DIFFICULTY};
+use crate::client_server_core::block_chain::merkle::MerkleProof;
+use crate::client_server_core::chain_spec::ChainSpec;
+use crate::client_server_core::consensus::pow::algorithm::{create_pow_algorithm, DoubleSha256, PowAlgorithm};
+use crate::client_server_core::consensus::verify_header_seal;
+
+pub struct HeaderChain {
+    pub headers: Vec<BlockHeader>,
+    /// the consensus of the chain spec: "pow" or "poa"
+    consensus: String,
+    algorithm: Box<dyn PowAlgorithm>,
+    /// the signers which may seal headers under proof of authority
+    authorities: Vec<String>
+}
+
+impl HeaderChain {
+    pub fn create() -> HeaderChain {
+        HeaderChain::create_with_algorithm(Box::new(DoubleSha256 {}))
+    }
+
+    pub fn create_with_algorithm(algorithm: Box<dyn PowAlgorithm>) -> HeaderChain {
+        //! start from the genesis header of a proof-of-work network. algorithm is its proof-of-work hash function.
+        HeaderChain {
+            headers: vec![Block::create_genesis_block().get_header()],
+            consensus: "pow".to_string(),
+            algorithm: algorithm,
+            authorities: Vec::new()
+        }
+    }
+
+    pub fn create_with_chain_spec(spec: &ChainSpec) -> Option<HeaderChain> {
+        //! start from the genesis header of the network of the chain spec. None for an unknown consensus or hash function,
+        //! and for proof of stake, whose leaders are drawn over stakes which a light client cannot replay. Anybody can
+        //! seal a header, so without the stakes a light client would follow any chain.
+        let algorithm = match spec.consensus.as_str() {
+            "pow" => create_pow_algorithm(&spec.pow_algorithm)?,
+            "poa" => Box::new(DoubleSha256 {}),
+            _ => return None,
+        };
+        let mut header_chain = HeaderChain::create_with_algorithm(algorithm);
+        header_chain.consensus = spec.consensus.clone();
+        header_chain.authorities = spec.authorities.clone();
+        Some(header_chain)
+    }
+
+    pub fn clone(&self) -> HeaderChain {
+        HeaderChain {
+            headers: self.headers.iter().map(|h| h.clone()).collect(),
+            consensus: self.consensus.clone(),
+            algorithm: create_pow_algorithm(&self.algorithm.name()).unwrap(),
+            authorities: self.authorities.clone()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn get_tip_hash(&self) -> String {
+        self.headers[self.headers.len() - 1].get_hash()
+    }
+
+    pub fn get_height(&self, block_hash: &str) -> Option<usize> {
+        self.headers.iter().position(|h| h.get_hash() == block_hash)
+    }
+
+    fn is_valid_header(&self, header: &BlockHeader, previous_block_hash: &str) -> bool {
+        //! check the link of a header and the proof of work or the seal which the consensus requires. A light client
+        //! cannot replay governance votes, so under proof of authority the sealer must be an authority of the chain spec.
+        if header.previous_block != previous_block_hash {
+            return false;
+        }
+        match self.consensus.as_str() {
+            "pow" => {
+                if header.sealer.len() > 0 || header.seal.len() > 0 {
+                    return false;
+                }
+                let suffix: String = "0".to_string().repeat(DIFFICULTY);
+                header.get_pow_hash(self.algorithm.as_ref()).ends_with(&suffix)
+            },
+            "poa" => self.authorities.contains(&header.sealer) == true && verify_header_seal(header),
+            _ => false,
+        }
+    }
+
+    pub fn add_headers(&mut self, headers: Vec<BlockHeader>) -> bool {
+        //! connect headers from a core node. The headers may extend the tip, fork from an ancestor or start at genesis.
+        //! The longer valid chain is kept, which is the heaviest one with a fixed difficulty.
+        if headers.len() == 0 {
+            return false;
+        }
+
+        let mut candidate: Vec<BlockHeader>;
+        if headers[0].previous_block.len() == 0 {
+            if headers[0].get_hash() != self.headers[0].get_hash() {
+                println!("headers of another genesis block");
+                return false;
+            }
+            candidate = headers;
+        }
+        else {
+            match self.get_height(&headers[0].previous_block) {
+                Some(height) => {
+                    candidate = self.headers[..height + 1].iter().map(|h| h.clone()).collect();
+                    candidate.extend(headers);
+                },
+                None => {
+                    println!("headers do not connect to the header chain");
+                    return false;
+                }
+            }
+        }
+
+        for i in 1..candidate.len() {
+            if self.is_valid_header(&candidate[i], &candidate[i - 1].get_hash()) == false {
+                println!("invalid header at {}", i);
+                return false;
+            }
+        }
+        if candidate.len() <= self.headers.len() {
+            println!("received headers are not heavier than the header chain");
+            return false;
+        }
+        println!("header chain is updated to height {}", candidate.len() - 1);
+        self.headers = candidate;
+        true
+    }
+
+    pub fn verify_merkle_proof(&self, proof: &MerkleProof) -> (usize, bool) {
+        //! verify a proof against the header chain. It returns the number of confirmations of the transaction.
+        match self.get_height(&proof.block_hash) {
+            Some(height) => {
+                if proof.verify(&self.headers[height].merkle_root) == false {
+                    println!("invalid merkle proof");
+                    return (0, false);
+                }
+                (self.headers.len() - height, true)
+            },
+            None => {
+                println!("merkle proof for an unknown block {}", proof.block_hash);
+                (0, false)
+            }
+        }
+    }
+}
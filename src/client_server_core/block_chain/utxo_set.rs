@@ -0,0 +1,398 @@
+/*  
+    utxo_set.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! The set of unspent transaction outputs of a chain. Core nodes apply blocks to it in order, which replaces
+//! scanning the whole chain for every input with a lookup of the spent outpoints. An input is valid only if
+//! its outpoint is in the set, and its value and owner are taken from the output stored there.
+//! The set keeps undo data of the latest blocks, so that a reorganization rolls it back to the fork point
+//! instead of replaying the chain, whose old blocks a pruned node does not have.
+
+use crate::client_server_core::block_chain::{Block, Transaction, TransactionOutput};
+use crate::client_server_core::block_chain::block_filter::get_outpoint;
+use crate::client_server_core::block_chain::merkle::get_transaction_hash;
+use crate::client_server_core::{verify_transaction_sig, MINING_REWARD};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// The number of latest blocks which the UTXO set can undo. A pruned node keeps fewer blocks than this.
+pub const MAX_UNDO_BLOCKS: usize = 1000;
+
+/// The changes of one block to the UTXO set
+struct BlockUndo {
+    /// the outputs which the block removed from the set
+    spent: Vec<(String, TransactionOutput)>,
+    /// the outpoints which the block added to the set
+    created: Vec<String>
+}
+
+impl BlockUndo {
+    fn clone(&self) -> BlockUndo {
+        BlockUndo {
+            spent: self.spent.iter().map(|(outpoint, output)| (outpoint.clone(), output.clone())).collect(),
+            created: self.created.clone()
+        }
+    }
+}
+
+pub struct UTXOSet {
+    /// the unspent outputs by outpoint
+    pub unspent: HashMap<String, TransactionOutput>,
+    /// the number of applied blocks including the genesis block
+    pub height: usize,
+    /// the undo data of the latest applied blocks from the oldest to the newest
+    undo: VecDeque<BlockUndo>
+}
+
+/// The changes of transactions on top of a UTXO set, which are written to the set only if all of them are valid
+pub struct UTXOView<'a> {
+    base: &'a UTXOSet,
+    /// the outputs created by the transactions and not spent yet
+    created: HashMap<String, TransactionOutput>,
+    /// the outpoints of the set spent by the transactions
+    spent: HashSet<String>
+}
+
+impl<'a> UTXOView<'a> {
+    pub fn create(base: &'a UTXOSet) -> UTXOView<'a> {
+        UTXOView {
+            base: base,
+            created: HashMap::new(),
+            spent: HashSet::new()
+        }
+    }
+
+    fn get(&self, outpoint: &str) -> Option<&TransactionOutput> {
+        if let Some(output) = self.created.get(outpoint) {
+            return Some(output);
+        }
+        if self.spent.contains(outpoint) == true {
+            return None;
+        }
+        self.base.unspent.get(outpoint)
+    }
+
+    pub fn check_transaction(&self, t: &Transaction, verify_signatures: bool) -> Option<i64> {
+        //! the fee of a transaction which spends unspent outputs of one owner and creates outputs of positive
+        //! values not exceeding its inputs. Coinbase transactions are checked with their block.
+        if t.tx_type == true {
+            println!("Coinbase Transaction is only for BlockBuilder!");
+            return None;
+        }
+        if t.outputs.iter().any(|o| o.value <= 0) {
+            println!("Transaction has an output without a positive value");
+            return None;
+        }
+        if t.inputs.len() == 0 {
+            // governance actions and evidence carry no value
+            if t.outputs.len() > 0 {
+                println!("Transaction without inputs creates coins");
+                return None;
+            }
+            return Some(0);
+        }
+
+        let mut outpoints: HashSet<String> = HashSet::new();
+        let mut used_outputs: Vec<TransactionOutput> = Vec::new();
+        let mut total_in: i64 = 0;
+        for i in t.inputs.iter() {
+            let outpoint = get_outpoint(&i.transaction, i.output_index);
+            let output = match self.get(&outpoint) {
+                Some(output) => output,
+                None => {
+                    println!("Input {} is not an unspent output", outpoint);
+                    return None;
+                }
+            };
+            if outpoints.insert(outpoint) == false {
+                println!("Transaction spends an output twice");
+                return None;
+            }
+            total_in = total_in.checked_add(output.value)?;
+            used_outputs.push(output.clone());
+        }
+        let total_out = t.outputs.iter().try_fold(0i64, |total, o| total.checked_add(o.value))?;
+        if total_in < total_out {
+            println!("Transaction spends more than its inputs");
+            return None;
+        }
+        if verify_signatures == true && verify_transaction_sig(t, &used_outputs) == false {
+            println!("signature verification error");
+            return None;
+        }
+        Some(total_in - total_out)
+    }
+
+    pub fn apply_transaction(&mut self, t: &Transaction, verify_signatures: bool) -> Option<i64> {
+        //! check a transaction and spend its inputs. It returns the fee of a valid transaction.
+        let fee = self.check_transaction(t, verify_signatures)?;
+        for i in t.inputs.iter() {
+            let outpoint = get_outpoint(&i.transaction, i.output_index);
+            if self.created.remove(&outpoint).is_none() {
+                self.spent.insert(outpoint);
+            }
+        }
+        self.add_outputs(t);
+        Some(fee)
+    }
+
+    fn add_outputs(&mut self, t: &Transaction) {
+        let transaction_hash = get_transaction_hash(t);
+        for (idx, o) in t.outputs.iter().enumerate() {
+            self.created.insert(transaction_hash.clone() + ":" + &idx.to_string(), o.clone());
+        }
+    }
+}
+
+impl UTXOSet {
+    pub fn create() -> UTXOSet {
+        UTXOSet {
+            unspent: HashMap::new(),
+            height: 0,
+            undo: VecDeque::new()
+        }
+    }
+
+    pub fn clone(&self) -> UTXOSet {
+        let mut unspent: HashMap<String, TransactionOutput> = HashMap::new();
+        for (outpoint, output) in self.unspent.iter() {
+            unspent.insert(outpoint.clone(), output.clone());
+        }
+        UTXOSet {
+            unspent: unspent,
+            height: self.height,
+            undo: self.undo.iter().map(|u| u.clone()).collect()
+        }
+    }
+
+    pub fn apply_block(&mut self, block: &Block, verify_signatures: bool) -> bool {
+        //! check the transactions of a block and update the set. Nothing is changed if the block is invalid.
+        let (created, spent) = {
+            let mut view = UTXOView::create(self);
+            let mut total_fee: i64 = 0;
+            let mut coinbase_value: Option<i64> = None;
+
+            for t in block.transaction_pool.transactions.iter() {
+                if t.tx_type == true {
+                    if coinbase_value.is_some() || t.inputs.len() > 0 {
+                        println!("Bad block. Coinbase Transaction is only for BlockBuilder!");
+                        return false;
+                    }
+                    if t.outputs.iter().any(|o| o.value <= 0) {
+                        println!("Bad block. Coinbase Transaction has an output without a positive value");
+                        return false;
+                    }
+                    coinbase_value = t.outputs.iter().try_fold(0i64, |total, o| total.checked_add(o.value));
+                    if coinbase_value.is_none() {
+                        println!("Bad block. The value of the Coinbase Transaction overflows");
+                        return false;
+                    }
+                    view.add_outputs(t);
+                    continue;
+                }
+                let fee = match view.apply_transaction(t, verify_signatures) {
+                    Some(fee) => fee,
+                    None => {
+                        println!("Bad block. Having invalid Transaction");
+                        return false;
+                    }
+                };
+                total_fee = match total_fee.checked_add(fee) {
+                    Some(total_fee) => total_fee,
+                    None => {
+                        println!("Bad block. The fees overflow");
+                        return false;
+                    }
+                };
+            }
+
+            if let Some(value) = coinbase_value {
+                if Some(value) != total_fee.checked_add(MINING_REWARD) {
+                    println!("Invalid value in fee for Coinbase Transaction {}", value);
+                    return false;
+                }
+            }
+            (view.created, view.spent)
+        };
+
+        let mut undo = BlockUndo { spent: Vec::new(), created: Vec::new() };
+        for outpoint in spent.into_iter() {
+            if let Some(output) = self.unspent.remove(&outpoint) {
+                undo.spent.push((outpoint, output));
+            }
+        }
+        for (outpoint, output) in created.into_iter() {
+            // an output replaced by a transaction with the same hash comes back on undo
+            if let Some(replaced) = self.unspent.insert(outpoint.clone(), output) {
+                undo.spent.push((outpoint.clone(), replaced));
+            }
+            undo.created.push(outpoint);
+        }
+        if self.undo.len() >= MAX_UNDO_BLOCKS {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(undo);
+        self.height += 1;
+        true
+    }
+
+    pub fn can_undo_to(&self, height: usize) -> bool {
+        //! true if the set can be rolled back to the first height blocks
+        height > 0 && height <= self.height && self.height - height <= self.undo.len()
+    }
+
+    pub fn undo_to(&mut self, height: usize) -> bool {
+        //! roll the set back to the first height blocks. Nothing is changed if the undo data does not reach height.
+        if self.can_undo_to(height) == false {
+            return false;
+        }
+        while self.height > height {
+            let undo = self.undo.pop_back().unwrap();
+            for outpoint in undo.created.iter() {
+                self.unspent.remove(outpoint);
+            }
+            for (outpoint, output) in undo.spent.into_iter() {
+                self.unspent.insert(outpoint, output);
+            }
+            self.height -= 1;
+        }
+        true
+    }
+
+    pub fn apply_blocks(&mut self, blocks: &[Block], assume_valid_height: Option<usize>) -> bool {
+        //! apply blocks following the applied ones. Signatures up to assume_valid_height are not checked.
+        for block in blocks.iter() {
+            let height = self.height;
+            let verify_signatures = match assume_valid_height {
+                Some(assume_valid_height) => height > assume_valid_height,
+                None => true,
+            };
+            if self.apply_block(block, verify_signatures) == false {
+                println!("invalid transactions in block at {}", height);
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn filter_transactions(&self, transactions: &[Transaction]) -> (Vec<Transaction>, i64) {
+        //! the transactions which are valid in order on top of the set, and their total fee. Coinbase transactions,
+        //! double spends of earlier transactions and transactions whose inputs were spent by a block are left out.
+        //! The signatures were verified when the transactions were pooled.
+        let mut view = UTXOView::create(self);
+        let mut valid_transactions: Vec<Transaction> = Vec::new();
+        let mut total_fee: i64 = 0;
+        for t in transactions.iter() {
+            if let Some(fee) = view.apply_transaction(t, false) {
+                if let Some(sum) = total_fee.checked_add(fee) {
+                    total_fee = sum;
+                    valid_transactions.push(t.clone());
+                }
+            }
+        }
+        (valid_transactions, total_fee)
+    }
+
+    pub fn get_balance(&self, address: &str) -> i64 {
+        self.unspent.values().filter(|o| o.recipient == address).map(|o| o.value).sum()
+    }
+
+    pub fn get_balances(&self) -> HashMap<String, i64> {
+        //! the balance of every address with unspent outputs
+        let mut balances: HashMap<String, i64> = HashMap::new();
+        for o in self.unspent.values() {
+            *balances.entry(o.recipient.clone()).or_insert(0) += o.value;
+        }
+        balances
+    }
+}
+
+pub fn run() {
+    //! apply a block with a signed payment, and try double spends, overspends, unknown inputs, outputs without
+    //! value and a transaction signed by one of two owners
+    use crate::client_server_core::block_chain::keymanager::KeyManager;
+    use crate::client_server_core::block_chain::{TransactionInput, TransactionPool};
+
+    fn sign(km: &KeyManager, mut t: Transaction) -> Transaction {
+        t.signature = String::new();
+        t.signature = km.compute_digital_signature(&t.to_string());
+        t
+    }
+
+    fn create_block(transactions: Vec<Transaction>, previous_block: &Block) -> Block {
+        let mut transaction_pool = TransactionPool::create();
+        for t in transactions.into_iter() {
+            transaction_pool.push(t);
+        }
+        Block::create(transaction_pool, previous_block.get_hash())
+    }
+
+    let alice = KeyManager::create(20);
+    let bob = KeyManager::create(20);
+    let genesis = Block::create_genesis_block();
+    let mut utxo_set = UTXOSet::create();
+    utxo_set.apply_block(&genesis, false);
+
+    let reward_alice = Transaction::create_coinbase_transaction(&alice.my_address(), MINING_REWARD);
+    let block1 = create_block(vec![reward_alice.clone()], &genesis);
+    println!("block with a reward: {}", utxo_set.apply_block(&block1, true));
+
+    let mut payment = Transaction::create();
+    payment.inputs.push(TransactionInput::create(reward_alice.clone(), 0));
+    payment.outputs.push(TransactionOutput::create(&bob.my_address(), 25));
+    let payment = sign(&alice, payment);
+
+    let mut view = UTXOView::create(&utxo_set);
+    println!("fee of the payment: {:?}", view.check_transaction(&payment, true));
+    view.apply_transaction(&payment, true);
+    println!("double spend of the pooled payment: {:?}", view.check_transaction(&payment, true));
+
+    let mut overspend = payment.clone();
+    overspend.outputs[0].value = MINING_REWARD + 1;
+    println!("overspend: {:?}", UTXOView::create(&utxo_set).check_transaction(&sign(&alice, overspend), true));
+    let mut zero = payment.clone();
+    zero.outputs.push(TransactionOutput::create(&bob.my_address(), 0));
+    println!("output without value: {:?}", UTXOView::create(&utxo_set).check_transaction(&sign(&alice, zero), true));
+    let mut forged_input = payment.clone();
+    forged_input.inputs[0].transaction.outputs[0].value = 1000;
+    forged_input.outputs[0].value = 900;
+    println!("input with a forged value: {:?}", UTXOView::create(&utxo_set).check_transaction(&sign(&alice, forged_input), true));
+    println!("coinbase: {:?}", UTXOView::create(&utxo_set).check_transaction(&reward_alice, true));
+
+    let fee = 5;
+    let block2 = create_block(vec![Transaction::create_coinbase_transaction(&bob.my_address(), MINING_REWARD + fee), payment.clone()], &block1);
+    println!("block with the payment: {}", utxo_set.apply_block(&block2, true));
+    println!("balances: {} {}", utxo_set.get_balance(&alice.my_address()), utxo_set.get_balance(&bob.my_address()));
+    let block3 = create_block(vec![payment.clone()], &block2);
+    println!("block spending the payment again: {}", utxo_set.apply_block(&block3, true));
+
+    // bob owns two outputs now. A transaction spending both of them and an output of alice is rejected.
+    let reward_bob = block2.transaction_pool.transactions[0].clone();
+    let reward_alice2 = Transaction::create_coinbase_transaction(&alice.my_address(), MINING_REWARD);
+    let block3 = create_block(vec![reward_alice2.clone()], &block2);
+    utxo_set.apply_block(&block3, true);
+    let mut mixed = Transaction::create();
+    mixed.inputs.push(TransactionInput::create(reward_bob, 0));
+    mixed.inputs.push(TransactionInput::create(reward_alice2, 0));
+    mixed.outputs.push(TransactionOutput::create(&bob.my_address(), 60));
+    println!("transaction of two owners signed by one: {:?}", UTXOView::create(&utxo_set).check_transaction(&sign(&bob, mixed), true));
+
+    let pooled = vec![payment.clone(), payment.clone()];
+    let (valid, total_fee) = utxo_set.filter_transactions(&pooled);
+    println!("pooled transactions valid after the blocks: {} with the fee {}", valid.len(), total_fee);
+}
@@ -0,0 +1,185 @@
+/*  
+    utxo_snapshot.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Snapshots of the UTXO set at a height. A new core node loads a snapshot whose commitment is listed in
+//! the chain spec, validates blocks from that height, and verifies the history up to it in the background.
+
+use crate::client_server_core::block_chain::{BlockChain, TransactionOutput, get_double_sha256};
+use crate::client_server_core::block_chain::utxo_set::UTXOSet;
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UTXOSnapshot {
+    /// the height of the last block applied to the set
+    pub height: usize,
+    pub block_hash: String,
+    /// the unspent outputs sorted by outpoint
+    pub unspent: Vec<(String, TransactionOutput)>,
+    /// the hash of the fields above
+    pub commitment: String
+}
+
+impl UTXOSnapshot {
+    pub fn create(utxo_set: &UTXOSet, block_hash: &str) -> UTXOSnapshot {
+        let mut unspent: Vec<(String, TransactionOutput)> = utxo_set.unspent.iter().map(|(k, o)| (k.clone(), o.clone())).collect();
+        unspent.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut snapshot = UTXOSnapshot {
+            height: utxo_set.height - 1,
+            block_hash: block_hash.to_string(),
+            unspent: unspent,
+            commitment: String::new()
+        };
+        snapshot.commitment = snapshot.compute_commitment();
+        snapshot
+    }
+
+    pub fn create_from_chain(bc: &BlockChain, height: usize) -> Option<UTXOSnapshot> {
+        //! replay the chain up to height. The chain is assumed to be validated.
+        if height >= bc.chain.len() {
+            return None;
+        }
+        let mut utxo_set = UTXOSet::create();
+        for block in bc.chain[..height + 1].iter() {
+            if utxo_set.apply_block(block, false) == false {
+                return None;
+            }
+        }
+        Some(UTXOSnapshot::create(&utxo_set, &bc.chain[height].get_hash()))
+    }
+
+    pub fn clone(&self) -> UTXOSnapshot {
+        UTXOSnapshot {
+            height: self.height,
+            block_hash: self.block_hash.clone(),
+            unspent: self.unspent.iter().map(|(k, o)| (k.clone(), o.clone())).collect(),
+            commitment: self.commitment.clone()
+        }
+    }
+
+    pub fn compute_commitment(&self) -> String {
+        let content = serde_json::to_string(&(self.height, &self.block_hash, &self.unspent)).unwrap();
+        get_double_sha256(&content)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.commitment == self.compute_commitment()
+    }
+
+    pub fn to_utxo_set(&self) -> UTXOSet {
+        let mut utxo_set = UTXOSet::create();
+        for (outpoint, output) in self.unspent.iter() {
+            utxo_set.unspent.insert(outpoint.clone(), output.clone());
+        }
+        utxo_set.height = self.height + 1;
+        utxo_set
+    }
+
+    pub fn save_to_file(&self, path: &str) -> bool {
+        match fs::write(path, serde_json::to_string(self).unwrap()) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("cannot write the snapshot to {}: {}", path, e);
+                false
+            }
+        }
+    }
+
+    pub fn load_from_file(path: &str) -> Option<UTXOSnapshot> {
+        let snapshot_str = match fs::read_to_string(path) {
+            Ok(snapshot_str) => snapshot_str,
+            Err(e) => {
+                println!("cannot read the snapshot from {}: {}", path, e);
+                return None;
+            }
+        };
+        match serde_json::from_str(&snapshot_str) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                println!("invalid snapshot: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::block_chain::{Block, Transaction, TransactionPool};
+
+    fn create_chain() -> BlockChain {
+        //! the genesis block and 3 blocks paying their rewards to miner0, miner1 and miner2
+        let mut bc = BlockChain::create();
+        bc.set_new_block(Block::create_genesis_block());
+        for i in 0..3 {
+            let mut transaction_pool = TransactionPool::create();
+            transaction_pool.push(Transaction::create_coinbase_transaction(&format!("miner{}", i), 30));
+            let previous_block = bc.chain[bc.chain.len() - 1].get_hash();
+            bc.set_new_block(Block::create(transaction_pool, previous_block));
+        }
+        bc
+    }
+
+    #[test]
+    fn snapshot_has_the_outputs_up_to_its_height() {
+        let bc = create_chain();
+        let snapshot = UTXOSnapshot::create_from_chain(&bc, 2).unwrap();
+        assert_eq!(snapshot.height, 2);
+        assert_eq!(snapshot.block_hash, bc.chain[2].get_hash());
+        assert!(snapshot.is_valid());
+        assert!(UTXOSnapshot::create_from_chain(&bc, 4).is_none());
+
+        // the set continues from the snapshot like a set replayed from the genesis block
+        let mut utxo_set = snapshot.to_utxo_set();
+        assert_eq!(utxo_set.height, 3);
+        assert_eq!(utxo_set.get_balance("miner1"), 30);
+        assert_eq!(utxo_set.get_balance("miner2"), 0);
+        assert!(utxo_set.apply_block(&bc.chain[3], false));
+        assert_eq!(UTXOSnapshot::create(&utxo_set, &bc.chain[3].get_hash()).commitment, UTXOSnapshot::create_from_chain(&bc, 3).unwrap().commitment);
+    }
+
+    #[test]
+    fn changed_snapshot_does_not_match_its_commitment() {
+        let snapshot = UTXOSnapshot::create_from_chain(&create_chain(), 3).unwrap();
+        let mut changed = snapshot.clone();
+        changed.unspent[0].1.value += 1;
+        assert!(changed.is_valid() == false);
+        let mut changed = snapshot.clone();
+        changed.height = 2;
+        assert!(changed.is_valid() == false);
+    }
+
+    #[test]
+    fn snapshot_is_saved_and_loaded() {
+        let snapshot = UTXOSnapshot::create_from_chain(&create_chain(), 3).unwrap();
+        let path = std::env::temp_dir().join(format!("utxo-snapshot-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(snapshot.save_to_file(path));
+        let loaded = UTXOSnapshot::load_from_file(path).unwrap();
+        assert_eq!(loaded.commitment, snapshot.commitment);
+        assert!(loaded.is_valid());
+        fs::write(path, "not a snapshot").unwrap();
+        assert!(UTXOSnapshot::load_from_file(path).is_none());
+        fs::remove_file(path).unwrap();
+    }
+}
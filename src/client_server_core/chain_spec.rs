@@ -0,0 +1,162 @@
+/*  
+    chain_spec.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Chain specification. It declares the consensus engine of a network and its parameters, and every node of the network has to share it.
+
+use crate::client_server_core::block_chain::{Block, BlockChain, get_double_sha256};
+use crate::client_server_core::consensus::pow::algorithm::create_pow_algorithm;
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChainSpec {
+    pub name: String,
+    /// "pow", "poa" or "pos"
+    pub consensus: String,
+    /// the hash function for proof of work: "sha256d" (default), "blake2b" or "scrypt"
+    #[serde(default)]
+    pub pow_algorithm: String,
+    /// the addresses of the initial signers under proof of authority
+    #[serde(default)]
+    pub authorities: Vec<String>,
+    /// the interval in seconds between blocks under proof of authority
+    #[serde(default)]
+    pub block_period: i64,
+    /// the initial stakes of (address, value) under proof of stake, added to the stakes in the UTXO set
+    #[serde(default)]
+    pub genesis_stakes: Vec<(String, i64)>,
+    /// the length of a slot in seconds under proof of stake
+    #[serde(default)]
+    pub slot_duration: i64,
+    /// the probability that a slot has at least one leader under proof of stake
+    #[serde(default)]
+    pub active_slot_coefficient: f64,
+    /// the hashes of blocks at (height, hash). Chains without them are rejected, so no reorg can go below them.
+    #[serde(default)]
+    pub checkpoints: Vec<(usize, String)>,
+    /// the hash of a block whose ancestors are assumed to have valid signatures
+    #[serde(default)]
+    pub assume_valid: String,
+    /// the commitments of trusted UTXO snapshots at (height, commitment)
+    #[serde(default)]
+    pub snapshots: Vec<(usize, String)>,
+    /// the (ip, port) of core nodes to join when neither the original contact nor a known peer answers
+    #[serde(default)]
+    pub seed_nodes: Vec<(String, String)>
+}
+
+impl ChainSpec {
+    pub fn create_default() -> ChainSpec {
+        //! the chain spec of the proof-of-work network
+        ChainSpec {
+            name: "blockchain-rs".to_string(),
+            consensus: "pow".to_string(),
+            pow_algorithm: "sha256d".to_string(),
+            authorities: Vec::new(),
+            block_period: 0,
+            genesis_stakes: Vec::new(),
+            slot_duration: 0,
+            active_slot_coefficient: 0.0,
+            checkpoints: Vec::new(),
+            assume_valid: String::new(),
+            snapshots: Vec::new(),
+            seed_nodes: Vec::new()
+        }
+    }
+
+    pub fn clone(&self) -> ChainSpec {
+        ChainSpec {
+            name: self.name.clone(),
+            consensus: self.consensus.clone(),
+            pow_algorithm: self.pow_algorithm.clone(),
+            authorities: self.authorities.clone(),
+            block_period: self.block_period,
+            genesis_stakes: self.genesis_stakes.clone(),
+            slot_duration: self.slot_duration,
+            active_slot_coefficient: self.active_slot_coefficient,
+            checkpoints: self.checkpoints.clone(),
+            assume_valid: self.assume_valid.clone(),
+            snapshots: self.snapshots.clone(),
+            seed_nodes: self.seed_nodes.clone()
+        }
+    }
+
+    pub fn is_trusted_snapshot(&self, height: usize, commitment: &str) -> bool {
+        self.snapshots.iter().any(|(h, c)| *h == height && c == commitment)
+    }
+
+    pub fn is_checkpoint_block(&self, height: usize, block_hash: &str) -> bool {
+        //! return false if a checkpoint at height has another hash
+        for (checkpoint_height, checkpoint_hash) in self.checkpoints.iter() {
+            if *checkpoint_height == height && checkpoint_hash != block_hash {
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn has_checkpoints(&self, bc: &BlockChain) -> bool {
+        //! return false if a block of the chain conflicts with a checkpoint
+        for (height, block) in bc.chain.iter().enumerate() {
+            if self.is_checkpoint_block(height, &block.get_hash()) == false {
+                println!("block at {} conflicts with a checkpoint", height);
+                return false;
+            }
+        }
+        true
+    }
+
+    pub fn get_assume_valid_height(&self, bc: &BlockChain) -> Option<usize> {
+        //! the height of the assume-valid block in the chain. Signatures up to it are not checked.
+        if self.assume_valid.len() == 0 {
+            return None;
+        }
+        bc.chain.iter().position(|b| b.get_hash() == self.assume_valid)
+    }
+
+    pub fn get_chain_id(&self) -> String {
+        //! the hash of the consensus rules and the genesis block. Nodes with another chain ID are refused in the handshake.
+        //! Checkpoints, snapshots and seed nodes are trusted by each node and are not a part of it.
+        let pow_algorithm = match self.consensus.as_str() {
+            "pow" => create_pow_algorithm(&self.pow_algorithm).map(|a| a.name()).unwrap_or(self.pow_algorithm.clone()),
+            _ => String::new(),
+        };
+        let rules = (&self.name, &self.consensus, pow_algorithm, &self.authorities, self.block_period, &self.genesis_stakes, self.slot_duration, self.active_slot_coefficient, Block::create_genesis_block().get_hash());
+        get_double_sha256(&serde_json::to_string(&rules).unwrap())
+    }
+
+    pub fn to_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_string(msg: &str) -> ChainSpec {
+        serde_json::from_str(msg).unwrap()
+    }
+
+    pub fn from_file(path: &str) -> ChainSpec {
+        let spec_str = fs::read_to_string(path).expect("cannot read the chain spec");
+        ChainSpec::from_string(&spec_str)
+    }
+
+    pub fn print(&self) {
+        println!("chain spec: {}", self.to_string());
+    }
+}
@@ -19,16 +19,33 @@
 
 
 pub mod message_manager;
-
-use std::net::{TcpListener, TcpStream};
-use std::net::UdpSocket;
-
-use std::collections::HashSet;
-use std::str::FromStr;
-use std::sync::mpsc;
-use std::io::{stdin, Write, BufRead, BufReader};
-use std::thread;
+pub mod frame;
+pub mod peer_connection;
+pub mod handshake;
+pub mod message;
+pub mod peer_db;
+pub mod ban_list;
+pub mod limits;
+pub mod noise;
+
+use self::peer_connection::PeerConnections;
+use self::handshake::PeerInfo;
+use self::message::{Message, MessageHandler, NodeEvent, Peer, ProtocolError};
+use self::peer_db::{PeerDB, MAX_ADDR_PER_MESSAGE};
+use self::limits::ResourceLimits;
+use self::noise::NodeKey;
+use self::ban_list::{BanEntry, BanList, BAN_THRESHOLD, DEFAULT_BAN_SECS, MISBEHAVIOUR_INVALID_MESSAGE};
+
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use chrono::Local;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// The maximum number of stored peers tried when a node joins the network
+pub const MAX_JOIN_CANDIDATES: usize = 8;
 
 pub struct ConnectionManager {
     my_ip: String, 
@@ -37,6 +54,18 @@ pub struct ConnectionManager {
     my_c_port: String, 
     core_node_set: HashSet<(String, String)>,
     edge_node_set: HashSet<(String, String)>, 
+    /// the core nodes which announced that they deleted the blocks below the height
+    pruned_node_set: HashMap<(String, String), usize>,
+    peers: Arc<PeerConnections>,
+    /// the known core nodes with their statistics, shared by the clones of the connection manager
+    peer_db: Arc<Mutex<PeerDB>>,
+    seed_nodes: Vec<(String, String)>,
+    /// the banned addresses and keys, shared by the clones of the connection manager and the connections
+    ban_list: Arc<Mutex<BanList>>,
+    /// the misbehaviour scores of the static keys of the peers which are not banned yet
+    misbehaviour: HashMap<String, u64>,
+    /// the time in seconds a misbehaving peer is banned for
+    ban_secs: i64
 }
 
 impl ConnectionManager {
@@ -45,13 +74,22 @@ impl ConnectionManager {
         core_node_set.insert((my_ip.to_string(), my_port.to_string()));
 
         let edge_node_set = HashSet::new();
+        let peers = Arc::new(PeerConnections::create(my_ip, my_port, handshake::NODE_FULL | handshake::NODE_MINER));
+        let ban_list = peers.get_ban_list();
         ConnectionManager {
             my_ip: my_ip.to_string(), 
             my_port: my_port.to_string(), 
             my_c_ip: my_c_ip.to_string(), 
             my_c_port: my_c_port.to_string(),
             core_node_set: core_node_set, 
-            edge_node_set: edge_node_set
+            edge_node_set: edge_node_set,
+            pruned_node_set: HashMap::new(),
+            peers: peers,
+            peer_db: Arc::new(Mutex::new(PeerDB::create(""))),
+            seed_nodes: Vec::new(),
+            ban_list: ban_list,
+            misbehaviour: HashMap::new(),
+            ban_secs: DEFAULT_BAN_SECS
         }
     }
 
@@ -62,7 +100,14 @@ impl ConnectionManager {
             my_c_ip: self.my_c_ip.clone(),
             my_c_port: self.my_c_port.clone(),
             core_node_set: self.core_node_set.clone(),
-            edge_node_set: self.core_node_set.clone()
+            edge_node_set: self.core_node_set.clone(),
+            pruned_node_set: self.pruned_node_set.clone(),
+            peers: Arc::clone(&self.peers),
+            peer_db: Arc::clone(&self.peer_db),
+            seed_nodes: self.seed_nodes.clone(),
+            ban_list: Arc::clone(&self.ban_list),
+            misbehaviour: self.misbehaviour.clone(),
+            ban_secs: self.ban_secs
         }
     }
 
@@ -77,6 +122,7 @@ impl ConnectionManager {
         if self.core_node_set.contains(&(ip.to_string(), port.to_string())) == true {
             println!("removing peer: {} {}", ip, port);
             self.core_node_set.remove(&(ip.to_string(), port.to_string()));
+            self.peers.disconnect(ip, port);
         }
     }
 
@@ -94,185 +140,371 @@ impl ConnectionManager {
         }
     }
 
-    pub fn handle_message(&mut self, msg: &str){
-        let res = message_manager::parse(&msg);
-
-        println!("received message: {} {} {} {} {} {}", res[0], res[1], res[2], res[3], res[4], res[5]);
-        let result: usize    = res[0].parse().unwrap();
-        let reason: usize    = res[1].parse().unwrap();
-        let cmd: usize       = res[2].parse().unwrap();
-        let ip      = &res[3];
-        let port    = &res[4];
-        let payload = &res[5];
+    pub async fn send_to_all_peer(&self, msg: &str) {
+        println!("send_to_all_peer was called!");
 
-        if      result == message_manager::ERROR && reason == message_manager::ERR_PROTOCOL_UNMATCH {
-            println!("Error: Protocol name is not matched");
+        for core_addr in self.core_node_set.iter() {
+            if self.my_ip.eq(&core_addr.0) == false || self.my_port.eq(&core_addr.1) == false {
+                self.send_msg(&core_addr.0, &core_addr.1, msg).await;
+            }
         }
-        else if result == message_manager::ERROR && reason == message_manager::ERR_VERSION_UNMATCH {
-            println!("Error: Protocol version is not matched");
+    }
+
+    pub async fn send_msg(&self, ip: &str, port: &str, msg: &str) -> bool {
+        //! send a message over the connection to the peer. Nothing is sent to banned peers.
+        if self.is_banned(ip, port) == true {
+            return false;
         }
-        else if result == message_manager::OK && reason == message_manager::OK_WITHOUT_PAYLOAD {
-            if cmd == message_manager::MSG_ADD {
-                println!("Add node request was received!");
-                println!("{} {}", ip, port);
-                println!("{} {}", self.my_ip, self.my_port);
-                if self.my_ip.eq(ip) == false || self.my_port.eq(port) == false {
-                    self.add_peer(&ip, &port);
-                    let cl = serde_json::to_string(&self.core_node_set).unwrap();
-                    let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &&cl);
-                    println!("{}", msg);
-                    self.send_to_all_peer(&msg);
-                }
-            }
-            else if cmd == message_manager::MSG_REMOVE {
-                println!("Remove request was received from {} {}", ip, port);
-                self.remove_peer(&ip, &port);
-                let cl = serde_json::to_string(&self.core_node_set).unwrap();
-                let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
-                self.send_to_all_peer(&msg);
-            }
-            else if cmd == message_manager::MSG_PING {
-                println!("MSG_PING pass");
-            }
-            else if cmd == message_manager::MSG_REQUEST_CORE_LIST {
-                println!("List for Core nodes was requested!");
-                let cl = serde_json::to_string(&self.core_node_set).unwrap();
-                let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
-                send_msg(&ip, &port, &msg);
+        self.peers.send(ip, port, msg).await
+    }
+
+    pub fn receiver(&self, client: TcpStream, tx: mpsc::UnboundedSender<NodeEvent>) {
+        //! keep the accepted connection and pass its messages to tx. Connections from banned hosts are closed.
+        if let Ok(addr) = client.peer_addr() {
+            if self.is_banned(&addr.ip().to_string(), "") == true {
+                println!("connection from banned host {} is closed", addr);
+                return;
             }
-            else if cmd == message_manager::MSG_ADD_AS_EDGE {
-                self.add_edge_node(&ip.to_string(), &port.to_string());
-                let cl = serde_json::to_string(&self.core_node_set).unwrap();
-                let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
-                send_msg(&ip, &port, &msg);
+        }
+        peer_connection::start_reader(Arc::clone(&self.peers), client, tx);
+    }
+
+    pub async fn join_network(&self) -> bool {
+        //! send MSG_ADD to the first core node which answers: the original contact, then the stored peers
+        //! by their statistics, then the seed nodes. The node asks the joined core node for more addresses.
+        println!("join_neftwork {} {}", self.my_c_ip, self.my_c_port);
+        let now = Local::now().timestamp();
+        let msg = message_manager::build(message_manager::MSG_ADD, &self.my_ip, &self.my_port, "");
+        for (ip, port) in self.get_join_candidates(now).iter() {
+            if self.is_banned(ip, port) == true {
+                continue;
             }
-            else if cmd == message_manager::MSG_REMOVE_EDGE {
-                self.remove_edge_node(&ip, &port);
+            if self.send_msg(ip, port, &msg).await == true {
+                println!("joined the network through {}:{}", ip, port);
+                let get_addr = message_manager::build(message_manager::MSG_GET_ADDR, &self.my_ip, &self.my_port, "");
+                self.send_msg(ip, port, &get_addr).await;
+                let mut peer_db = self.peer_db.lock().unwrap();
+                peer_db.mark_success(ip, port, now);
+                peer_db.save();
+                return true;
             }
-            else {
-   //             println!("received unknown command {}", cmd);
+            self.peer_db.lock().unwrap().mark_failure(ip, port, now);
+        }
+        self.peer_db.lock().unwrap().save();
+        false
+    }
+
+    fn get_join_candidates(&self, now: i64) -> Vec<(String, String)> {
+        let mut candidates: Vec<(String, String)> = Vec::new();
+        let mut exclude: HashSet<(String, String)> = HashSet::new();
+        exclude.insert((self.my_ip.clone(), self.my_port.clone()));
+        if self.my_c_ip.len() > 0 && self.my_c_port.len() > 0 {
+            candidates.push((self.my_c_ip.clone(), self.my_c_port.clone()));
+            exclude.insert((self.my_c_ip.clone(), self.my_c_port.clone()));
+        }
+        for peer in self.peer_db.lock().unwrap().select_peers(MAX_JOIN_CANDIDATES, &exclude, now) {
+            exclude.insert(peer.clone());
+            candidates.push(peer);
+        }
+        for seed in self.seed_nodes.iter() {
+            if exclude.contains(seed) == false {
+                exclude.insert(seed.clone());
+                candidates.push(seed.clone());
             }
         }
-        else if result == message_manager::OK && reason == message_manager::OK_WITH_PAYLOAD {
-            if cmd == message_manager::MSG_CORE_LIST {
-                println!("Refresh the core node list!");
+        candidates
+    }
 
-                let res = serde_json::from_str(&payload);
-                match res {
-                    Ok(result) => self.core_node_set = result,
-                    Err(msg) => println!("failure {}", msg),
-                }
+    pub fn load_peer_db(&mut self, path: &str) {
+        *self.peer_db.lock().unwrap() = PeerDB::load(path);
+    }
 
-                println!("new core node list");
-                for p in &self.core_node_set {
-                    println!("{} {}", p.0, p.1);
-                }
-            }
-            else {
-                println!("received unknown command {}", cmd);
+    pub fn set_seed_nodes(&mut self, seed_nodes: &Vec<(String, String)>) {
+        self.seed_nodes = seed_nodes.clone();
+    }
+
+    pub fn set_resource_limits(&self, limits: ResourceLimits) {
+        self.peers.set_limits(limits);
+    }
+
+    pub fn get_connection_counts(&self) -> (usize, usize) {
+        //! the numbers of inbound and outbound connections
+        (self.peers.inbound_len(), self.peers.len())
+    }
+
+    pub fn load_node_key(&self, path: &str) {
+        //! use the static key in the file at path for the encrypted connections
+        self.peers.set_node_key(NodeKey::load_or_create(path));
+    }
+
+    pub fn get_public_key(&self) -> String {
+        self.peers.get_public_key()
+    }
+
+    pub fn pin_node_key(&self, ip: &str, port: &str, public_key: &str) -> bool {
+        //! accept the core node at ip and port only with the static key. It returns false for an invalid key.
+        if noise::parse_key(public_key).is_none() {
+            println!("invalid key for {}:{}: {}", ip, port, public_key);
+            return false;
+        }
+        self.peers.pin_key(ip, port, public_key);
+        true
+    }
+
+    pub fn load_pinned_keys(&self, path: &str) -> usize {
+        //! pin the keys of the core nodes in the file at path. It returns the number of pinned keys.
+        let mut count = 0;
+        for pinned_key in noise::load_pinned_keys(path).iter() {
+            if self.pin_node_key(&pinned_key.ip, &pinned_key.port, &pinned_key.public_key) == true {
+                count += 1;
             }
         }
-        else {
-            println!("Unexpected status");
+        println!("{} core node keys are pinned", count);
+        count
+    }
+
+    pub fn get_my_peer(&self) -> Peer {
+        //! the identity of the node itself, the sender of its local messages
+        Peer {
+            ip: self.my_ip.clone(),
+            port: self.my_port.clone(),
+            key: self.peers.get_public_key()
         }
     }
 
-    pub fn send_to_all_peer(&self, msg: &str) {
-        println!("send_to_all_peer was called!");
+    pub fn load_ban_list(&mut self, path: &str) {
+        *self.ban_list.lock().unwrap() = BanList::load(path);
+    }
 
+    pub fn set_ban_secs(&mut self, ban_secs: i64) {
+        self.ban_secs = ban_secs;
+    }
+
+    pub fn is_banned(&self, ip: &str, port: &str) -> bool {
+        //! true if the host or the node is banned, or the key of the connection to the node
+        let key = match self.peers.get_peer_info(ip, port) {
+            Some(info) => info.public_key,
+            None => String::new(),
+        };
+        self.peers.is_banned(ip, port, &key)
+    }
+
+    pub fn mark_useful(&self, sender: &Peer) {
+        //! a peer whose block or transaction was accepted is kept when the inbound slots are full
+        self.peers.mark_useful(sender);
+    }
+
+    pub fn is_peer_banned(&self, peer: &Peer) -> bool {
+        //! true if the host, the node or the static key of the peer of a connection is banned
+        self.peers.is_banned(&peer.ip, &peer.port, &peer.key)
+    }
+
+    pub fn get_bans(&self) -> Vec<BanEntry> {
+        //! the active bans
+        let now = Local::now().timestamp();
+        let mut ban_list = self.ban_list.lock().unwrap();
+        if ban_list.remove_expired(now) > 0 {
+            ban_list.save();
+        }
+        ban_list.get_bans(now)
+    }
+
+    pub async fn misbehave(&mut self, sender: &Peer, points: u64, reason: &str) -> bool {
+        //! add misbehaviour points to the static key of the peer of a connection. A key reaching BAN_THRESHOLD
+        //! is banned and its connections are closed. The messages of the node itself are not scored.
+        if sender.key.len() == 0 || sender.key.eq(&self.peers.get_public_key()) == true {
+            return false;
+        }
+        let score = self.misbehaviour.entry(sender.key.clone()).or_insert(0);
+        *score = score.saturating_add(points);
+        println!("misbehaviour score of {}:{} is {}: {}", sender.ip, sender.port, score, reason);
+        if *score < BAN_THRESHOLD {
+            return false;
+        }
+        self.misbehaviour.remove(&sender.key);
+        let ban_secs = self.ban_secs;
+        self.ban_key(&sender.key, ban_secs, reason).await;
+        true
+    }
+
+    pub async fn ban_key(&mut self, key: &str, ban_secs: i64, reason: &str) {
+        //! ban the node with the static key and disconnect it
+        {
+            let mut ban_list = self.ban_list.lock().unwrap();
+            ban_list.ban(&ban_list::get_key_address(key), Local::now().timestamp().saturating_add(ban_secs), reason);
+            ban_list.save();
+        }
+        self.disconnect_banned().await;
+    }
+
+    pub fn unban_key(&mut self, key: &str) -> bool {
+        //! lift the ban of the static key
+        let mut ban_list = self.ban_list.lock().unwrap();
+        let unbanned = ban_list.unban(&ban_list::get_key_address(key));
+        if unbanned == true {
+            println!("unban {}", ban_list::get_key_address(key));
+            ban_list.save();
+        }
+        unbanned
+    }
+
+    pub async fn ban_peer(&mut self, ip: &str, port: &str, ban_secs: i64, reason: &str) {
+        //! ban the node at ip and port, or all nodes at ip if port is empty, and disconnect them
+        {
+            let mut ban_list = self.ban_list.lock().unwrap();
+            ban_list.ban(&ban_list::get_address(ip, port), Local::now().timestamp().saturating_add(ban_secs), reason);
+            ban_list.save();
+        }
+        self.disconnect_banned().await;
+    }
+
+    async fn disconnect_banned(&mut self) {
+        //! close the connections of the banned peers and forget them
+        self.peers.disconnect_banned();
+        let banned_cores: Vec<(String, String)> = self.core_node_set.iter().filter(|p| self.is_banned(&p.0, &p.1)).cloned().collect();
+        let banned_edges: Vec<(String, String)> = self.edge_node_set.iter().filter(|p| self.is_banned(&p.0, &p.1)).cloned().collect();
+        for p in banned_edges.iter() {
+            self.remove_edge_node(&p.0, &p.1);
+            self.peers.disconnect(&p.0, &p.1);
+        }
+        for p in banned_cores.iter() {
+            self.remove_peer(&p.0, &p.1);
+        }
+        if banned_cores.len() > 0 {
+            let cl = serde_json::to_string(&self.core_node_set).unwrap();
+            let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
+            self.send_to_all_peer(&msg).await;
+        }
+    }
+
+    pub fn unban_peer(&mut self, ip: &str, port: &str) -> bool {
+        //! lift the ban of the node at ip and port, or of the host at ip if port is empty
+        let mut ban_list = self.ban_list.lock().unwrap();
+        let unbanned = ban_list.unban(&ban_list::get_address(ip, port));
+        if unbanned == true {
+            println!("unban {}", ban_list::get_address(ip, port));
+            ban_list.save();
+        }
+        unbanned
+    }
+
+    pub async fn handle_protocol_error(&mut self, sender: &Peer, e: &ProtocolError) {
+        //! a message which cannot be parsed counts as misbehaviour of the peer of the connection. Messages of
+        //! other protocols and versions are ignored.
+        match e {
+            ProtocolError::UnknownCommand(_) | ProtocolError::InvalidPayload(_, _) => {},
+            _ => return,
+        }
+        self.misbehave(sender, MISBEHAVIOUR_INVALID_MESSAGE, &e.to_string()).await;
+    }
+
+    pub async fn send_to_all_full_node(&self, msg: &str) {
+        //! send a message to the peers which have not announced that they are pruned
         for core_addr in self.core_node_set.iter() {
+            if self.pruned_node_set.contains_key(core_addr) == true {
+                continue;
+            }
             if self.my_ip.eq(&core_addr.0) == false || self.my_port.eq(&core_addr.1) == false {
-                let server_addr = core_addr.0.clone() + &":" + &core_addr.1;
-                let mut socket = TcpStream::connect(&server_addr).expect("cannot connect to the server");
-                socket.set_nonblocking(true).expect("unable");
-                println!("connected to {}", &server_addr);
-    
-                let bytes = String::from(msg).into_bytes();
-                if let Err(e) = socket.write_all(&bytes) {
-                    println!("Send error: {}", e);
-                    continue;
-                }
+                self.send_msg(&core_addr.0, &core_addr.1, msg).await;
+            }
+        }
+    }
+
+    pub fn is_pruned(&self, ip: &str, port: &str) -> bool {
+        if self.pruned_node_set.contains_key(&(ip.to_string(), port.to_string())) == true {
+            return true;
+        }
+        match self.peers.get_peer_info(ip, port) {
+            Some(info) => info.has_service(handshake::NODE_PRUNED),
+            None => false,
+        }
+    }
+
+    pub async fn send_to_all_peer_with_feature(&self, feature: u64, msg: &str, fallback_msg: &str) {
+        //! send msg to the peers which negotiated the feature and fallback_msg to the others
+        for core_addr in self.core_node_set.iter() {
+            if self.my_ip.eq(&core_addr.0) == true && self.my_port.eq(&core_addr.1) == true {
+                continue;
+            }
+            // the handshake on connecting tells the features of the peer
+            if self.peers.connect(&core_addr.0, &core_addr.1).await == false {
+                continue;
+            }
+            let supported = match self.peers.get_peer_info(&core_addr.0, &core_addr.1) {
+                Some(info) => info.has_feature(feature),
+                None => false,
+            };
+            if supported == true {
+                self.send_msg(&core_addr.0, &core_addr.1, msg).await;
+            }
+            else {
+                self.send_msg(&core_addr.0, &core_addr.1, fallback_msg).await;
             }
         }
     }
 
+    pub fn get_peer_info(&self, ip: &str, port: &str) -> Option<PeerInfo> {
+        self.peers.get_peer_info(ip, port)
+    }
+
+    pub fn set_local_status(&self, services: u64, best_height: usize) {
+        self.peers.set_local_status(services, best_height);
+    }
+
+    pub fn set_chain_id(&self, chain_id: &str) {
+        self.peers.set_chain_id(chain_id);
+    }
+
     pub fn is_core(&self, ip: &str, port: &str) -> bool {
         self.core_node_set.contains(&(ip.to_string(), port.to_string()))
     }
 
-    pub fn check_peers_connection(&mut self, tx: mpsc::Sender<String>) {
+    pub async fn check_peers_connection(&mut self, _tx: mpsc::UnboundedSender<NodeEvent>) {
         println!("check_peers_connection");
         
+        let now = Local::now().timestamp();
         let mut new_core_node_set: HashSet<(String, String)> = HashSet::new();
         for core in self.core_node_set.iter() {
+            if self.my_ip.eq(&core.0) == true && self.my_port.eq(&core.1) == true {
+                new_core_node_set.insert((core.0.clone(), core.1.clone()));
+                continue;
+            }
             println!("ping to {} {}", core.0, core.1);
-            if ((self.my_ip.eq(&core.0) == true && self.my_port.eq(&core.1) == true)) || is_alive(&core.0, &core.1) == true {
+            if is_alive(&core.0, &core.1).await == true {
+                self.peer_db.lock().unwrap().mark_success(&core.0, &core.1, now);
                 new_core_node_set.insert((core.0.clone(), core.1.clone()));
             }
+            else {
+                self.peer_db.lock().unwrap().mark_failure(&core.0, &core.1, now);
+            }
         }
         if self.core_node_set.len() != new_core_node_set.len() {
             self.core_node_set = new_core_node_set;
             let cl = serde_json::to_string(&self.core_node_set).unwrap();
             let msg = get_message_text(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
-            self.send_to_all_peer(&msg);
+            self.send_to_all_peer(&msg).await;
         }
-    }
-}
-
-pub fn send_msg(ip: &str, port: &str, msg: &str) {
-    let server_addr = ip.to_string() + &":" + port;
-    let mut socket = TcpStream::connect(&server_addr).expect("cannot connect to the server");
-    socket.set_nonblocking(true).expect("unable");
-    println!("connected to {}", &server_addr);
-
-    let bytes = String::from(msg).into_bytes();
-    if let Err(e) = socket.write_all(&bytes) {
-        println!("Send error: {}", e);
-    }
-}
-
-pub fn join_network(my_ip: &str, my_port: &str, my_c_ip: &str, my_c_port: &str) {
-    println!("join_neftwork {} {}", my_c_ip, my_c_port);
-    if my_c_ip.len() > 0 && my_c_port.len() > 0 {
-       connect_to_p2pnw(my_ip, my_port, my_c_ip, my_c_port);
-   }
-}
-
-fn connect_to_p2pnw(my_ip: &str, my_port: &str, host: &str, port: &str) {
-    let server_addr = host.to_string() + &":" + &port;
-    let mut socket = TcpStream::connect(&server_addr).expect("cannot connect to the server");
-    socket.set_nonblocking(true).expect("unable");
-    let msg = message_manager::build(message_manager::MSG_ADD, my_ip, my_port, &"".to_string());
-    println!("connected to {}", &server_addr);
+        self.peer_db.lock().unwrap().save();
 
-    let bytes = String::from(msg).into_bytes();
-    if let Err(e) = socket.write_all(&bytes) {
-        println!("Send error: {}", e);
+        // a node which lost all of its peers joins again through the stored peers and the seed nodes
+        if self.core_node_set.len() <= 1 {
+            self.join_network().await;
+        }
     }
 }
 
-fn is_alive(ip: &str, port: &str) -> bool {
+async fn is_alive(ip: &str, port: &str) -> bool {
     println!("is_alive {} {}", ip, port);
-    let server_addr = ip.to_string() + &":" + port;
+    let server_addr = ip.to_string() + ":" + port;
 
-    let res = TcpStream::connect(&server_addr);
+    let res = tokio::time::timeout(Duration::from_millis(peer_connection::CONNECT_TIMEOUT_MS), TcpStream::connect(&server_addr)).await;
     match res {
-        Ok(socket) => { return true; },
-        Err(E) => { return false;}
+        Ok(Ok(_)) => { return true; },
+        _ => { return false; }
     }
 }
 
-pub fn receiver(client: TcpStream, tx: mpsc::Sender<String>) {
-    let mut reader = BufReader::new(client);
 
-    let mut msg = String::new();
-    if let Ok(n) = reader.read_line(&mut msg) {
-        if n > 0 { 
-            println!("received: {}", msg);
-            tx.send(msg).unwrap();
-        }
-    }
-}
 
 pub fn get_message_text(msg_type: usize, ip: &str, port: &str, payload: &str) -> String {
     let msgtxt = message_manager::build(msg_type, ip, port, payload);
@@ -285,7 +517,102 @@ pub struct ConnectionManager4Edge {
    pub port: String, 
    pub my_c_ip: String, 
    pub my_c_port: String, 
-   pub core_node_set: HashSet<(String, String)>
+   pub core_node_set: HashSet<(String, String)>,
+   peers: Arc<PeerConnections>
+}
+
+impl MessageHandler for ConnectionManager {
+    type Context = ();
+
+    async fn handle_message(&mut self, sender: &Peer, message: &Message, _context: &mut ()) {
+        let ip = &sender.ip;
+        let port = &sender.port;
+        println!("received message: {} {} {}", message.get_type(), ip, port);
+
+        match message {
+            Message::Add => {
+                println!("Add node request was received!");
+                println!("{} {}", ip, port);
+                println!("{} {}", self.my_ip, self.my_port);
+                if self.my_ip.eq(ip) == false || self.my_port.eq(port) == false {
+                    self.peer_db.lock().unwrap().mark_success(ip, port, Local::now().timestamp());
+                    self.add_peer(ip, port);
+                    let cl = serde_json::to_string(&self.core_node_set).unwrap();
+                    let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
+                    println!("{}", msg);
+                    self.send_to_all_peer(&msg).await;
+                }
+            },
+            Message::Remove => {
+                println!("Remove request was received from {} {}", ip, port);
+                self.remove_peer(ip, port);
+                let cl = serde_json::to_string(&self.core_node_set).unwrap();
+                let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
+                self.send_to_all_peer(&msg).await;
+            },
+            Message::Ping => {
+                println!("MSG_PING pass");
+            },
+            Message::RequestCoreList => {
+                println!("List for Core nodes was requested!");
+                let cl = serde_json::to_string(&self.core_node_set).unwrap();
+                let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
+                self.send_msg(ip, port, &msg).await;
+            },
+            Message::AddAsEdge => {
+                self.add_edge_node(&ip.to_string(), &port.to_string());
+                let cl = serde_json::to_string(&self.core_node_set).unwrap();
+                let msg = message_manager::build(message_manager::MSG_CORE_LIST, &self.my_ip, &self.my_port, &cl);
+                self.send_msg(ip, port, &msg).await;
+            },
+            Message::RemoveEdge => {
+                self.remove_edge_node(ip, port);
+            },
+            Message::CoreList(core_node_set) => {
+                println!("Refresh the core node list!");
+                self.core_node_set = core_node_set.iter().filter(|p| self.is_banned(&p.0, &p.1) == false).cloned().collect();
+
+                println!("new core node list");
+                for p in &self.core_node_set {
+                    println!("{} {}", p.0, p.1);
+                }
+                let core_node_set = &self.core_node_set;
+                self.pruned_node_set.retain(|k, _| core_node_set.contains(k));
+
+                let now = Local::now().timestamp();
+                let mut peer_db = self.peer_db.lock().unwrap();
+                for p in core_node_set.iter() {
+                    if self.my_ip.eq(&p.0) == false || self.my_port.eq(&p.1) == false {
+                        peer_db.add(&p.0, &p.1, now, ip);
+                    }
+                }
+            },
+            Message::GetAddr => {
+                let addresses = self.peer_db.lock().unwrap().get_addresses(Local::now().timestamp());
+                println!("Send {} addresses to : {}:{}", addresses.len(), ip, port);
+                let msg = message_manager::build(message_manager::MSG_ADDR, &self.my_ip, &self.my_port, &serde_json::to_string(&addresses).unwrap());
+                self.send_msg(ip, port, &msg).await;
+            },
+            Message::Addr(addresses) => {
+                // a peer cannot claim that an address was seen in the future or before 1970
+                let now = Local::now().timestamp();
+                let mut peer_db = self.peer_db.lock().unwrap();
+                for a in addresses.iter().take(MAX_ADDR_PER_MESSAGE) {
+                    if self.my_ip.eq(&a.ip) == false || self.my_port.eq(&a.port) == false {
+                        peer_db.add(&a.ip, &a.port, a.last_seen.max(0).min(now), ip);
+                    }
+                }
+                println!("{} addresses are known", peer_db.len());
+            },
+            Message::Pruned(pruned_height) => {
+                println!("{} {} is a pruned node without the blocks below {}", ip, port, pruned_height);
+                self.pruned_node_set.insert((ip.to_string(), port.to_string()), *pruned_height);
+            },
+            _ => {
+                // the other messages are for the node
+            }
+        }
+    }
 }
 
 impl ConnectionManager4Edge {
@@ -298,7 +625,8 @@ impl ConnectionManager4Edge {
             port: port.to_string(), 
             my_c_ip: my_c_ip.to_string(), 
             my_c_port: my_c_port.to_string(),
-            core_node_set: core_node_set
+            core_node_set: core_node_set,
+            peers: Arc::new(PeerConnections::create(ip, port, handshake::NODE_LIGHT))
         }
     }
 
@@ -308,7 +636,8 @@ impl ConnectionManager4Edge {
             port: self.port.clone(),
             my_c_ip: self.my_c_ip.clone(),
             my_c_port: self.my_c_port.clone(),
-            core_node_set: self.core_node_set.clone()
+            core_node_set: self.core_node_set.clone(),
+            peers: Arc::clone(&self.peers)
         }
     }
 
@@ -316,78 +645,33 @@ impl ConnectionManager4Edge {
         // to be implemented
     }
 
-    pub fn send_msg(&mut self, msg: &str) {
+    pub async fn send_msg(&mut self, msg: &str) {
         println!("Sending... {}", msg);
-        let server_addr = self.my_c_ip.clone() + &":" + &self.my_c_port;
-        let mut socket = TcpStream::connect(&server_addr).expect("cannot connect to the server");
-        socket.set_nonblocking(true).expect("unable");
-        let bytes = String::from(msg).into_bytes();
-
-        println!("Trying to connect into P2P network ...");
-        if let Err(e) = socket.write_all(&bytes) {
-            if self.core_node_set.len() != 0 {
-                for x in self.core_node_set.iter() {
-                    if x.0 != self.my_c_ip || x.1 != self.my_c_port {
-                        self.my_c_ip = x.0.clone();
-                        self.my_c_port = x.1.clone();
-                        break;
-                   }
-                }
-                self.send_msg(msg);
-            }
-            else {
-                println!("No core node found in our list ...");
-            }
+        if self.peers.send(&self.my_c_ip, &self.my_c_port, msg).await == true {
+            return;
         }
-    }
 
-    pub fn handle_message(&mut self, msg: &str) {
-        let res = message_manager::parse(&msg);
-    
-        println!("received message: {} {} {} {} {} {}", res[0], res[1], res[2], res[3], res[4], res[5]);
-        let result: usize    = res[0].parse().unwrap();
-        let reason: usize    = res[1].parse().unwrap();
-        let cmd: usize       = res[2].parse().unwrap();
-        let ip      = &res[3];
-        let port    = &res[4];
-        let payload = &res[5];
-    
-        if      result == message_manager::ERROR && reason == message_manager::ERR_PROTOCOL_UNMATCH {
-            println!("Error: Protocol name is not matched");
-        }
-        else if result == message_manager::ERROR && reason == message_manager::ERR_VERSION_UNMATCH {
-            println!("Error: Protocol version is not matched");
-        }
-        else if result == message_manager::OK && reason == message_manager::OK_WITHOUT_PAYLOAD {
-            if cmd == message_manager::MSG_PING {
-                // pass
-            }
-            else {
-                println!("Edge does not have functions for this message!");
-            }
+        println!("Trying to connect into P2P network ...");
+        let candidates: Vec<(String, String)> = self.core_node_set.iter()
+            .filter(|x| (x.0 != self.my_c_ip || x.1 != self.my_c_port) && (x.0 != self.ip || x.1 != self.port)).cloned()
+            .collect();
+        if candidates.len() == 0 {
+            println!("No core node found in our list ...");
+            return;
         }
-        else if result == message_manager::OK && reason == message_manager::OK_WITH_PAYLOAD {
-            if cmd == message_manager::MSG_CORE_LIST {
-                println!("Refresh the core node list!");
-                self.core_node_set = serde_json::from_str(&payload).unwrap();
-
-                println!("new core node list");
-                for p in &self.core_node_set {
-                    println!("{} {}", p.0, p.1);
-                }
-            }
-            else {
-                println!("received unknown command {}", cmd);
+        for (ip, port) in candidates {
+            if self.peers.send(&ip, &port, msg).await == true {
+                self.my_c_ip = ip;
+                self.my_c_port = port;
+                return;
             }
         }
-        else {
-            println!("Unexpected status");
-        }
+        println!("No core node in our list is reachable ...");
     }
 
-    pub fn send_ping(&mut self) {
+    pub async fn send_ping(&mut self) {
         println!("send ping from edge node!");
-        if is_alive(&self.my_c_ip, &self.my_c_port) == false {
+        if is_alive(&self.my_c_ip, &self.my_c_port).await == false {
             if self.core_node_set.len() == 0 {
                 println!("No core node found in our list");
                 return;
@@ -403,37 +687,74 @@ impl ConnectionManager4Edge {
         }
     }
 
-    pub fn connect_to_core_node_4edge(&self) {
-        self.connect_to_p2pnw_4edge();
+    pub async fn connect_to_core_node_4edge(&self) {
+        self.connect_to_p2pnw_4edge().await;
     }
     
-    fn connect_to_p2pnw_4edge(&self) {
-        let server_addr = self.my_c_ip.to_string() + &":" + &self.my_c_port.to_string();
-        let mut socket = TcpStream::connect(&server_addr).expect("cannot connect to the server");
-        socket.set_nonblocking(true).expect("unable");
-        let msg = message_manager::build(message_manager::MSG_ADD_AS_EDGE, &self.ip, &self.port, &"".to_string());
-        println!("connected to {}", &server_addr);
-    
-        let bytes = String::from(msg).into_bytes();
-        if let Err(e) = socket.write_all(&bytes) {
-            println!("Send error: {}", e);
+    async fn connect_to_p2pnw_4edge(&self) {
+        let msg = message_manager::build(message_manager::MSG_ADD_AS_EDGE, &self.ip, &self.port, "");
+        self.peers.send(&self.my_c_ip, &self.my_c_port, &msg).await;
+    }
+
+    pub fn receiver_4edge(&self, client: TcpStream, tx: mpsc::UnboundedSender<NodeEvent>) {
+        peer_connection::start_reader(Arc::clone(&self.peers), client, tx);
+    }
+
+    pub fn set_chain_id(&self, chain_id: &str) {
+        self.peers.set_chain_id(chain_id);
+    }
+
+    pub fn pin_core_node_key(&self, ip: &str, port: &str, public_key: &str) -> bool {
+        //! accept the core node at ip and port only with the static key. It returns false for an invalid key.
+        if noise::parse_key(public_key).is_none() {
+            println!("invalid key for {}:{}: {}", ip, port, public_key);
+            return false;
         }
+        self.peers.pin_key(ip, port, public_key);
+        true
     }
 
-    pub fn receiver_4edge(client: TcpStream, tx: mpsc::Sender<String>) {
-        let mut reader = BufReader::new(client);
-    
-        thread::spawn(move || {
-            let mut msg = String::new();
-            if let Ok(n) = reader.read_line(&mut msg) {
-                if n > 0 { 
-                    println!("{}", msg);
-                    tx.send(msg).unwrap();
-                }
+    pub fn load_pinned_keys(&self, path: &str) -> usize {
+        //! pin the keys of the core nodes in the file at path. It returns the number of pinned keys.
+        let mut count = 0;
+        for pinned_key in noise::load_pinned_keys(path).iter() {
+            if self.pin_core_node_key(&pinned_key.ip, &pinned_key.port, &pinned_key.public_key) == true {
+                count += 1;
             }
-            thread::sleep(Duration::from_millis(100));
-        });
+        }
+        println!("{} core node keys are pinned", count);
+        count
+    }
+
+    pub fn core_node_supports(&self, feature: u64) -> bool {
+        //! whether my core node negotiated the feature in the handshake
+        match self.peers.get_peer_info(&self.my_c_ip, &self.my_c_port) {
+            Some(info) => info.has_feature(feature),
+            None => false,
+        }
     }
 }
 
+impl MessageHandler for ConnectionManager4Edge {
+    type Context = ();
 
+    async fn handle_message(&mut self, _sender: &Peer, message: &Message, _context: &mut ()) {
+        match message {
+            Message::Ping => {
+                // pass
+            },
+            Message::CoreList(core_node_set) => {
+                println!("Refresh the core node list!");
+                self.core_node_set = core_node_set.clone();
+
+                println!("new core node list");
+                for p in &self.core_node_set {
+                    println!("{} {}", p.0, p.1);
+                }
+            },
+            _ => {
+                // the other messages are for the edge node
+            }
+        }
+    }
+}
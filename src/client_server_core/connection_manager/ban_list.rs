@@ -0,0 +1,216 @@
+/*  
+    ban_list.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! The banned peers. Validation failures add misbehaviour points to the static key of the connection which sent
+//! the message, and a key reaching BAN_THRESHOLD is disconnected and banned for a while. An address is either
+//! "ip" for all ports of a host, "ip:port" for one node or "key:" and a static key in hex for one peer wherever it
+//! connects from. The ban list is saved as JSON, so that bans survive restarts.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs;
+
+/// The misbehaviour score at which a peer is banned
+pub const BAN_THRESHOLD: u64 = 100;
+/// The default time in seconds a misbehaving peer is banned for
+pub const DEFAULT_BAN_SECS: i64 = 24 * 60 * 60;
+/// The points for a message which cannot be parsed or has an unknown command
+pub const MISBEHAVIOUR_INVALID_MESSAGE: u64 = 10;
+/// The points for a transaction whose signature does not match the outputs it spends
+pub const MISBEHAVIOUR_BAD_SIGNATURE: u64 = 50;
+/// The points for a longer chain which fails validation
+pub const MISBEHAVIOUR_INVALID_CHAIN: u64 = 50;
+/// The points for an invalid block on top of our chain
+pub const MISBEHAVIOUR_INVALID_BLOCK: u64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BanEntry {
+    /// "ip", "ip:port" or "key:" and a static key
+    pub address: String,
+    /// the time the ban ends
+    pub until: i64,
+    pub reason: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BanList {
+    /// the file the ban list is saved to. An empty path keeps the ban list in memory.
+    #[serde(skip)]
+    pub path: String,
+    /// the bans by address
+    pub bans: HashMap<String, BanEntry>
+}
+
+pub fn get_address(ip: &str, port: &str) -> String {
+    //! the address of a ban. An empty port bans all ports of the host.
+    if port.len() == 0 {
+        return ip.to_string();
+    }
+    ip.to_string() + ":" + port
+}
+
+pub fn get_key_address(key: &str) -> String {
+    //! the address of a ban of a static key
+    "key:".to_string() + key
+}
+
+impl BanList {
+    pub fn create(path: &str) -> BanList {
+        BanList {
+            path: path.to_string(),
+            bans: HashMap::new()
+        }
+    }
+
+    pub fn load(path: &str) -> BanList {
+        //! read the ban list from path. A missing or broken file gives an empty ban list.
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => {
+                println!("no ban list at {}", path);
+                return BanList::create(path);
+            }
+        };
+        match serde_json::from_str::<BanList>(&content) {
+            Ok(mut ban_list) => {
+                println!("{} bans are loaded from {}", ban_list.bans.len(), path);
+                ban_list.path = path.to_string();
+                ban_list
+            },
+            Err(e) => {
+                println!("invalid ban list {}: {}", path, e);
+                BanList::create(path)
+            }
+        }
+    }
+
+    pub fn save(&self) -> bool {
+        if self.path.len() == 0 {
+            return false;
+        }
+        match fs::write(&self.path, serde_json::to_string(self).unwrap()) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("cannot save the ban list to {}: {}", self.path, e);
+                false
+            }
+        }
+    }
+
+    pub fn ban(&mut self, address: &str, until: i64, reason: &str) {
+        //! ban an address until the time. A longer existing ban is kept.
+        if let Some(entry) = self.bans.get(address) {
+            if entry.until >= until {
+                return;
+            }
+        }
+        println!("ban {} until {}: {}", address, until, reason);
+        self.bans.insert(address.to_string(), BanEntry {
+            address: address.to_string(),
+            until: until,
+            reason: reason.to_string()
+        });
+    }
+
+    pub fn unban(&mut self, address: &str) -> bool {
+        self.bans.remove(address).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &str, port: &str, key: &str, now: i64) -> bool {
+        //! true if the host, the node or the static key is banned at now. An empty key is not checked.
+        let mut addresses = vec![ip.to_string(), get_address(ip, port)];
+        if key.len() > 0 {
+            addresses.push(get_key_address(key));
+        }
+        for address in addresses.iter() {
+            if let Some(entry) = self.bans.get(address) {
+                if entry.until > now {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    pub fn remove_expired(&mut self, now: i64) -> usize {
+        //! forget the bans which ended. It returns the number of removed bans.
+        let len = self.bans.len();
+        self.bans.retain(|_, entry| entry.until > now);
+        len - self.bans.len()
+    }
+
+    pub fn get_bans(&self, now: i64) -> Vec<BanEntry> {
+        //! the active bans, the ones ending first come first
+        let mut bans: Vec<BanEntry> = self.bans.values().filter(|entry| entry.until > now).cloned().collect();
+        bans.sort_by(|a, b| a.until.cmp(&b.until).then(a.address.cmp(&b.address)));
+        bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_000_000;
+
+    #[test]
+    fn bans_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("bans-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut ban_list = BanList::create(path);
+        ban_list.ban(&get_address("127.0.0.1", "50082"), NOW + 60, "invalid block");
+        ban_list.ban(&get_address("10.0.0.1", ""), NOW + DEFAULT_BAN_SECS, "banned by the operator");
+        ban_list.ban(&get_key_address("6f35f623"), NOW + 60, "invalid transaction signature");
+        assert!(ban_list.save());
+
+        let ban_list = BanList::load(path);
+        fs::remove_file(path).unwrap();
+        assert!(ban_list.is_banned("127.0.0.1", "50082", "", NOW));
+        assert!(ban_list.is_banned("127.0.0.1", "50083", "", NOW) == false);
+        assert!(ban_list.is_banned("10.0.0.1", "50082", "", NOW));
+        assert!(ban_list.is_banned("127.0.0.1", "50084", "6f35f623", NOW));
+        assert!(ban_list.is_banned("127.0.0.1", "50084", "", NOW) == false);
+    }
+
+    #[test]
+    fn ban_ends_at_its_time() {
+        let mut ban_list = BanList::create("");
+        ban_list.ban(&get_address("127.0.0.1", "50082"), NOW + 60, "invalid block");
+        ban_list.ban(&get_address("10.0.0.1", ""), NOW + DEFAULT_BAN_SECS, "banned by the operator");
+        assert!(ban_list.is_banned("127.0.0.1", "50082", "", NOW + 59));
+        assert!(ban_list.is_banned("127.0.0.1", "50082", "", NOW + 60) == false);
+        assert_eq!(ban_list.remove_expired(NOW + 60), 1);
+        let bans = ban_list.get_bans(NOW + 60);
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].address, "10.0.0.1");
+        assert!(ban_list.unban("10.0.0.1"));
+        assert!(ban_list.unban("10.0.0.1") == false);
+        assert!(ban_list.save() == false);
+    }
+
+    #[test]
+    fn longer_ban_is_kept() {
+        let mut ban_list = BanList::create("");
+        ban_list.ban("10.0.0.1", NOW + DEFAULT_BAN_SECS, "banned by the operator");
+        ban_list.ban("10.0.0.1", NOW + 60, "invalid block");
+        assert_eq!(ban_list.bans["10.0.0.1"].until, NOW + DEFAULT_BAN_SECS);
+        assert_eq!(ban_list.bans["10.0.0.1"].reason, "banned by the operator");
+    }
+}
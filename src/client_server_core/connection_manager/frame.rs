@@ -0,0 +1,161 @@
+/*  
+    frame.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Frames of the wire protocol. A frame is a 16-byte header of magic, command, payload length and
+//! checksum followed by the payload, so that a payload may contain any bytes including newlines.
+
+use crate::client_server_core::connection_manager::message_manager::*;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use crate::client_server_core::connection_manager::noise::SecureStream;
+
+use std::io;
+
+pub const MAGIC: [u8; 4] = [0xb1, 0x0c, 0xc4, 0x1a];
+/// magic (4 bytes), command (4 bytes), payload length (4 bytes) and checksum (4 bytes)
+pub const HEADER_SIZE: usize = 16;
+/// The maximum payload size. Core nodes sync in batches of blocks, and only edge nodes ask for a full chain.
+pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+/// The maximum payload size of a message carrying one block
+pub const MAX_BLOCK_PAYLOAD_SIZE: usize = 4 * 1024 * 1024;
+/// The maximum payload size of a transaction. Inputs carry the transactions they spend.
+pub const MAX_TRANSACTION_PAYLOAD_SIZE: usize = 1024 * 1024;
+/// The maximum payload size of a list of addresses
+pub const MAX_ADDRESS_PAYLOAD_SIZE: usize = 256 * 1024;
+/// The maximum payload size of requests and the other small messages
+pub const MAX_SMALL_PAYLOAD_SIZE: usize = 64 * 1024;
+
+pub fn get_max_payload_size(command: u32) -> usize {
+    //! the size cap of the payload of a command. Only responses with many blocks may use MAX_PAYLOAD_SIZE.
+    match command as usize {
+        RSP_FULL_CHAIN | RSP_BLOCKS | RSP_HEADERS | RSP_MERKLE_PROOFS | RSP_FILTERS => MAX_PAYLOAD_SIZE,
+        MSG_NEW_BLOCK | MSG_COMPACT_BLOCK | RSP_BLOCK_TRANSACTIONS => MAX_BLOCK_PAYLOAD_SIZE,
+        MSG_NEW_TRANSACTION => MAX_TRANSACTION_PAYLOAD_SIZE,
+        MSG_CORE_LIST | MSG_ADDR => MAX_ADDRESS_PAYLOAD_SIZE,
+        _ => MAX_SMALL_PAYLOAD_SIZE,
+    }
+}
+
+pub fn get_checksum(payload: &[u8]) -> [u8; 4] {
+    //! the first 4 bytes of the double SHA-256 of the payload
+    let mut hash = [0u8; 32];
+    let mut sha256 = Sha256::new();
+    sha256.input(payload);
+    sha256.result(&mut hash);
+    let mut sha256_2 = Sha256::new();
+    sha256_2.input(&hash);
+    sha256_2.result(&mut hash);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+pub fn encode_frame(command: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&command.to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&get_checksum(payload));
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub async fn read_frame(reader: &mut SecureStream) -> io::Result<(u32, Vec<u8>)> {
+    //! read one frame. read_exact waits for the rest of a frame split across several reads.
+    //! The length is checked against the cap of the command before the payload is allocated.
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    if header[0..4] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "magic is not matched"));
+    }
+    let command = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let length = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    if length > get_max_payload_size(command) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("payload of {} bytes is too large for command {}", length, command)));
+    }
+
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await?;
+    if header[12..16] != get_checksum(&payload) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "checksum is not matched"));
+    }
+    Ok((command, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::connection_manager::noise::{self, NodeKey};
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn create_pair() -> (SecureStream, SecureStream) {
+        //! the two ends of an encrypted connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (client_key, server_key) = (NodeKey::generate(), NodeKey::generate());
+        let (writer, reader) = tokio::join!(
+            noise::handshake_as_initiator(client.unwrap(), &client_key),
+            noise::handshake_as_responder(server.unwrap().0, &server_key)
+        );
+        (writer.unwrap(), reader.unwrap())
+    }
+
+    #[tokio::test]
+    async fn frame_split_into_pieces_is_read_whole() {
+        let (mut writer, mut reader) = create_pair().await;
+        let payload = b"a payload\nwith newlines\n";
+        let frame = encode_frame(MSG_PING as u32, payload);
+        assert_eq!(frame.len(), HEADER_SIZE + payload.len());
+        for piece in frame.chunks(7) {
+            writer.write_all(piece).await.unwrap();
+        }
+        let (command, received) = read_frame(&mut reader).await.unwrap();
+        assert_eq!(command, MSG_PING as u32);
+        assert_eq!(received, payload.to_vec());
+    }
+
+    #[tokio::test]
+    async fn invalid_frames_are_refused() {
+        let frame = encode_frame(MSG_PING as u32, b"payload");
+        let mut bad_magic = frame.clone();
+        bad_magic[0] ^= 1;
+        let mut bad_checksum = frame.clone();
+        bad_checksum[HEADER_SIZE] ^= 1;
+        // the length is refused before the payload is sent
+        let mut too_large = encode_frame(MSG_PING as u32, b"");
+        too_large[8..12].copy_from_slice(&((MAX_SMALL_PAYLOAD_SIZE + 1) as u32).to_le_bytes());
+        for bad in [bad_magic, bad_checksum, too_large].iter() {
+            let (mut writer, mut reader) = create_pair().await;
+            writer.write_all(bad).await.unwrap();
+            assert_eq!(read_frame(&mut reader).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn only_responses_with_many_blocks_are_large() {
+        assert_eq!(get_max_payload_size(RSP_BLOCKS as u32), MAX_PAYLOAD_SIZE);
+        assert_eq!(get_max_payload_size(MSG_NEW_BLOCK as u32), MAX_BLOCK_PAYLOAD_SIZE);
+        assert_eq!(get_max_payload_size(MSG_NEW_TRANSACTION as u32), MAX_TRANSACTION_PAYLOAD_SIZE);
+        assert_eq!(get_max_payload_size(MSG_ADDR as u32), MAX_ADDRESS_PAYLOAD_SIZE);
+        assert_eq!(get_max_payload_size(MSG_REQUEST_FULL_CHAIN as u32), MAX_SMALL_PAYLOAD_SIZE);
+        assert_eq!(get_max_payload_size(u32::MAX), MAX_SMALL_PAYLOAD_SIZE);
+    }
+}
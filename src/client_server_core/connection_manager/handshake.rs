@@ -0,0 +1,304 @@
+/*  
+    handshake.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! The version/verack handshake run on every new connection. The dialer sends its version, the
+//! acceptor answers with its version and a verack, and the dialer confirms with a verack. Both sides
+//! then use the highest protocol version they have in common and the features both of them support.
+//! Nodes of another chain, whose chain spec has other consensus rules, are refused.
+//! The handshake runs over the encrypted connection, so the peer info has the static key of the peer as well.
+
+use crate::client_server_core::chain_spec::ChainSpec;
+use crate::client_server_core::connection_manager::frame;
+use crate::client_server_core::connection_manager::message_manager;
+use crate::client_server_core::connection_manager::noise::{self, SecureStream};
+
+use serde::{Deserialize, Serialize};
+
+use std::io;
+
+/// Service flags. A node serving the full chain, a node without old blocks, a block producer and an edge node.
+pub const NODE_FULL: u64 = 1;
+pub const NODE_PRUNED: u64 = 2;
+pub const NODE_MINER: u64 = 4;
+pub const NODE_LIGHT: u64 = 8;
+
+/// Optional features which are used with a peer only if both sides support them
+pub const FEATURE_COMPACT_BLOCKS: u64 = 1;
+pub const FEATURE_BLOCK_FILTERS: u64 = 2;
+pub const ALL_FEATURES: u64 = FEATURE_COMPACT_BLOCKS | FEATURE_BLOCK_FILTERS;
+
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+pub const USER_AGENT: &str = "/blockchain-rs:0.3.0/";
+
+pub fn get_feature_version(feature: u64) -> u32 {
+    //! the lowest protocol version which has the feature
+    match feature {
+        FEATURE_COMPACT_BLOCKS => 2,
+        FEATURE_BLOCK_FILTERS => 2,
+        _ => message_manager::PROTOCOL_VERSION + 1,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VersionMessage {
+    pub min_version: u32,
+    pub max_version: u32,
+    pub services: u64,
+    pub features: u64,
+    pub best_height: usize,
+    pub user_agent: String,
+    /// the address the node listens at
+    pub ip: String,
+    pub port: String,
+    /// the chain ID of the chain spec of the node
+    #[serde(default)]
+    pub chain_id: String
+}
+
+impl VersionMessage {
+    pub fn create(ip: &str, port: &str, services: u64) -> VersionMessage {
+        VersionMessage {
+            min_version: message_manager::MIN_PROTOCOL_VERSION,
+            max_version: message_manager::PROTOCOL_VERSION,
+            services: services,
+            features: ALL_FEATURES,
+            best_height: 0,
+            user_agent: USER_AGENT.to_string(),
+            ip: ip.to_string(),
+            port: port.to_string(),
+            chain_id: ChainSpec::create_default().get_chain_id()
+        }
+    }
+
+    pub fn clone(&self) -> VersionMessage {
+        VersionMessage {
+            min_version: self.min_version,
+            max_version: self.max_version,
+            services: self.services,
+            features: self.features,
+            best_height: self.best_height,
+            user_agent: self.user_agent.clone(),
+            ip: self.ip.clone(),
+            port: self.port.clone(),
+            chain_id: self.chain_id.clone()
+        }
+    }
+}
+
+/// What a peer told in its version message and what was negotiated with it
+pub struct PeerInfo {
+    pub version: u32,
+    pub services: u64,
+    pub features: u64,
+    pub best_height: usize,
+    pub user_agent: String,
+    /// the static key of the peer in hex
+    pub public_key: String
+}
+
+impl PeerInfo {
+    pub fn clone(&self) -> PeerInfo {
+        PeerInfo {
+            version: self.version,
+            services: self.services,
+            features: self.features,
+            best_height: self.best_height,
+            user_agent: self.user_agent.clone(),
+            public_key: self.public_key.clone()
+        }
+    }
+
+    pub fn has_service(&self, service: u64) -> bool {
+        self.services & service != 0
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+}
+
+pub fn negotiate(local: &VersionMessage, remote: &VersionMessage) -> Option<PeerInfo> {
+    //! return the highest common version and the common features available in it. None for a node of another chain.
+    if local.chain_id != remote.chain_id {
+        return None;
+    }
+    let version = if local.max_version < remote.max_version { local.max_version } else { remote.max_version };
+    if version < local.min_version || version < remote.min_version {
+        return None;
+    }
+    let mut features: u64 = 0;
+    for feature in [FEATURE_COMPACT_BLOCKS, FEATURE_BLOCK_FILTERS].iter() {
+        if local.features & remote.features & feature != 0 && get_feature_version(*feature) <= version {
+            features |= feature;
+        }
+    }
+    Some(PeerInfo {
+        version: version,
+        services: remote.services,
+        features: features,
+        best_height: remote.best_height,
+        user_agent: remote.user_agent.clone(),
+        public_key: String::new()
+    })
+}
+
+async fn write_message(stream: &mut SecureStream, command: usize, payload: &str) -> io::Result<()> {
+    stream.write_all(&frame::encode_frame(command as u32, payload.as_bytes())).await
+}
+
+async fn read_message(stream: &mut SecureStream, command: usize) -> io::Result<String> {
+    let (received, payload) = frame::read_frame(stream).await?;
+    if received != command as u32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is expected but {} is received", command, received)));
+    }
+    String::from_utf8(payload).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "payload is not UTF-8"))
+}
+
+async fn read_version(stream: &mut SecureStream) -> io::Result<VersionMessage> {
+    let payload = read_message(stream, message_manager::MSG_VERSION).await?;
+    serde_json::from_str(&payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+fn cannot_negotiate(local: &VersionMessage, remote: &VersionMessage) -> io::Error {
+    if local.chain_id != remote.chain_id {
+        return io::Error::new(io::ErrorKind::InvalidData, format!("{} is on another chain {}", remote.user_agent, remote.chain_id));
+    }
+    io::Error::new(io::ErrorKind::InvalidData, format!("no common version with {}-{} of {}", remote.min_version, remote.max_version, remote.user_agent))
+}
+
+pub async fn handshake_as_dialer(stream: &mut SecureStream, local: &VersionMessage) -> io::Result<(VersionMessage, PeerInfo)> {
+    noise::with_timeout(HANDSHAKE_TIMEOUT_MS, async {
+        write_message(stream, message_manager::MSG_VERSION, &serde_json::to_string(local).unwrap()).await?;
+        let remote = read_version(stream).await?;
+        read_message(stream, message_manager::MSG_VERACK).await?;
+        let mut info = match negotiate(local, &remote) {
+            Some(info) => info,
+            None => return Err(cannot_negotiate(local, &remote)),
+        };
+        info.public_key = stream.get_remote_key();
+        write_message(stream, message_manager::MSG_VERACK, &info.version.to_string()).await?;
+        Ok((remote, info))
+    }).await
+}
+
+pub async fn handshake_as_acceptor(stream: &mut SecureStream, local: &VersionMessage) -> io::Result<(VersionMessage, PeerInfo)> {
+    noise::with_timeout(HANDSHAKE_TIMEOUT_MS, async {
+        let remote = read_version(stream).await?;
+        let mut info = match negotiate(local, &remote) {
+            Some(info) => info,
+            None => return Err(cannot_negotiate(local, &remote)),
+        };
+        info.public_key = stream.get_remote_key();
+        write_message(stream, message_manager::MSG_VERSION, &serde_json::to_string(local).unwrap()).await?;
+        write_message(stream, message_manager::MSG_VERACK, &info.version.to_string()).await?;
+        read_message(stream, message_manager::MSG_VERACK).await?;
+        Ok((remote, info))
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::connection_manager::noise::NodeKey;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn create_local() -> VersionMessage {
+        VersionMessage::create("127.0.0.1", "8880", NODE_FULL | NODE_MINER)
+    }
+
+    fn create_other_chain() -> VersionMessage {
+        let mut other_chain = VersionMessage::create("127.0.0.1", "8885", NODE_FULL);
+        let mut spec = ChainSpec::create_default();
+        spec.pow_algorithm = "scrypt".to_string();
+        other_chain.chain_id = spec.get_chain_id();
+        other_chain
+    }
+
+    #[test]
+    fn common_version_and_features_are_negotiated() {
+        let local = create_local();
+        let same = VersionMessage::create("127.0.0.1", "8884", NODE_PRUNED);
+        let info = negotiate(&local, &same).unwrap();
+        assert_eq!(info.version, message_manager::PROTOCOL_VERSION);
+        assert!(info.has_feature(FEATURE_COMPACT_BLOCKS) && info.has_feature(FEATURE_BLOCK_FILTERS));
+        assert!(info.has_service(NODE_PRUNED) && info.has_service(NODE_FULL) == false);
+
+        let mut newer = VersionMessage::create("127.0.0.1", "8882", NODE_LIGHT);
+        newer.max_version = message_manager::PROTOCOL_VERSION + 1;
+        newer.features = FEATURE_BLOCK_FILTERS;
+        let info = negotiate(&local, &newer).unwrap();
+        assert_eq!(info.version, message_manager::PROTOCOL_VERSION);
+        assert!(info.has_feature(FEATURE_COMPACT_BLOCKS) == false && info.has_feature(FEATURE_BLOCK_FILTERS));
+    }
+
+    #[test]
+    fn node_without_a_common_version_or_of_another_chain_is_refused() {
+        let local = create_local();
+        let mut older = VersionMessage::create("127.0.0.1", "8881", NODE_PRUNED);
+        older.min_version = 1;
+        older.max_version = message_manager::MIN_PROTOCOL_VERSION - 1;
+        let mut future = VersionMessage::create("127.0.0.1", "8883", NODE_FULL);
+        future.min_version = message_manager::PROTOCOL_VERSION + 1;
+        future.max_version = message_manager::PROTOCOL_VERSION + 1;
+        for remote in [older, future].iter() {
+            assert!(negotiate(&local, remote).is_none());
+            assert!(cannot_negotiate(&local, remote).to_string().starts_with("no common version"));
+        }
+        let other_chain = create_other_chain();
+        assert!(negotiate(&local, &other_chain).is_none());
+        assert!(cannot_negotiate(&local, &other_chain).to_string().contains("another chain"));
+    }
+
+    async fn run_handshakes(dialer: &VersionMessage, acceptor: &VersionMessage) -> (io::Result<(VersionMessage, PeerInfo)>, io::Result<(VersionMessage, PeerInfo)>, String, String) {
+        //! the results of the handshakes of both ends of an encrypted connection and the keys of the dialer and the acceptor
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (dialer_key, acceptor_key) = (NodeKey::generate(), NodeKey::generate());
+        let (client, server) = tokio::join!(
+            noise::handshake_as_initiator(client.unwrap(), &dialer_key),
+            noise::handshake_as_responder(server.unwrap().0, &acceptor_key)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let (dialer_result, acceptor_result) = tokio::join!(handshake_as_dialer(&mut client, dialer), handshake_as_acceptor(&mut server, acceptor));
+        (dialer_result, acceptor_result, dialer_key.get_public_key(), acceptor_key.get_public_key())
+    }
+
+    #[tokio::test]
+    async fn handshake_tells_the_address_and_the_key_of_the_peer() {
+        let dialer = VersionMessage::create("127.0.0.1", "8881", NODE_LIGHT);
+        let (dialer_result, acceptor_result, dialer_key, acceptor_key) = run_handshakes(&dialer, &create_local()).await;
+        let (remote, info) = dialer_result.unwrap();
+        assert_eq!(remote.port, "8880");
+        assert_eq!(info.public_key, acceptor_key);
+        assert!(info.has_service(NODE_MINER));
+        let (remote, info) = acceptor_result.unwrap();
+        assert_eq!(remote.port, "8881");
+        assert_eq!(info.public_key, dialer_key);
+        assert!(info.has_service(NODE_LIGHT));
+    }
+
+    #[tokio::test]
+    async fn handshake_with_another_chain_fails() {
+        let (dialer_result, acceptor_result, _, _) = run_handshakes(&create_other_chain(), &create_local()).await;
+        assert!(dialer_result.is_err());
+        assert!(matches!(acceptor_result, Err(e) if e.kind() == io::ErrorKind::InvalidData));
+    }
+}
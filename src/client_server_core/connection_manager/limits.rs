@@ -0,0 +1,220 @@
+/*  
+    limits.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! The resource limits of a node. Each inbound connection has a token bucket per message type, so that a peer
+//! cannot flood the node with transactions or expensive requests, and the numbers of inbound and outbound
+//! connections are limited by slots. When the inbound slots are full, the least useful inbound peer is evicted.
+
+use crate::client_server_core::connection_manager::message_manager::*;
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// The maximum number of connections accepted from other nodes
+pub const MAX_INBOUND_CONNECTIONS: usize = 117;
+/// The maximum number of connections to other nodes. Replies to edge nodes use outbound connections as well.
+pub const MAX_OUTBOUND_CONNECTIONS: usize = 125;
+/// The number of messages over the rate limits after which a connection is closed
+pub const MAX_DROPPED_MESSAGES: u64 = 500;
+/// The time after which an inbound connection without messages is closed. Peers ping their connections more often.
+pub const IDLE_TIMEOUT_SECS: u64 = 120;
+
+/// A rate of messages per second with the number of messages a peer may send at once
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64
+}
+
+#[derive(Debug, Clone)]
+pub struct ResourceLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    /// false if the messages are not rate limited
+    pub rate_limited: bool,
+    /// the rate limits by message type. The other message types use default_rate.
+    pub rates: HashMap<usize, RateLimit>,
+    pub default_rate: RateLimit,
+    pub max_dropped_messages: u64,
+    pub idle_timeout_secs: u64,
+    /// true if chains, headers and blocks which this node did not request are dropped
+    pub drop_unrequested: bool
+}
+
+impl ResourceLimits {
+    pub fn create() -> ResourceLimits {
+        //! the default limits. Requests which make the node send its chain are the most expensive.
+        let mut rates = HashMap::new();
+        rates.insert(MSG_REQUEST_FULL_CHAIN, RateLimit { rate: 0.1, burst: 5.0 });
+        rates.insert(RSP_FULL_CHAIN, RateLimit { rate: 0.5, burst: 5.0 });
+        rates.insert(MSG_REQUEST_BLOCKS, RateLimit { rate: 2.0, burst: 20.0 });
+        rates.insert(MSG_REQUEST_HEADERS, RateLimit { rate: 2.0, burst: 20.0 });
+        rates.insert(MSG_REQUEST_MERKLE_PROOFS, RateLimit { rate: 2.0, burst: 20.0 });
+        rates.insert(MSG_REQUEST_FILTERS, RateLimit { rate: 2.0, burst: 20.0 });
+        rates.insert(MSG_GET_ADDR, RateLimit { rate: 0.1, burst: 5.0 });
+        rates.insert(MSG_NEW_TRANSACTION, RateLimit { rate: 50.0, burst: 200.0 });
+        rates.insert(MSG_NEW_BLOCK, RateLimit { rate: 5.0, burst: 20.0 });
+        rates.insert(MSG_COMPACT_BLOCK, RateLimit { rate: 5.0, burst: 20.0 });
+        ResourceLimits {
+            max_inbound: MAX_INBOUND_CONNECTIONS,
+            max_outbound: MAX_OUTBOUND_CONNECTIONS,
+            rate_limited: true,
+            rates: rates,
+            default_rate: RateLimit { rate: 20.0, burst: 100.0 },
+            max_dropped_messages: MAX_DROPPED_MESSAGES,
+            idle_timeout_secs: IDLE_TIMEOUT_SECS,
+            drop_unrequested: true
+        }
+    }
+
+    pub fn unlimited() -> ResourceLimits {
+        //! no limits, for benchmarks and private networks
+        let mut limits = ResourceLimits::create();
+        limits.max_inbound = usize::MAX;
+        limits.max_outbound = usize::MAX;
+        limits.rate_limited = false;
+        limits
+    }
+
+    pub fn get_rate(&self, msg_type: usize) -> RateLimit {
+        match self.rates.get(&msg_type) {
+            Some(rate) => *rate,
+            None => self.default_rate,
+        }
+    }
+}
+
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant
+}
+
+impl TokenBucket {
+    pub fn create(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: limit.rate,
+            burst: limit.burst,
+            tokens: limit.burst,
+            last: now
+        }
+    }
+
+    pub fn take(&mut self, now: Instant) -> bool {
+        //! refill the bucket for the time passed and take a token if there is one
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The token buckets of one connection
+pub struct RateLimiter {
+    limits: ResourceLimits,
+    buckets: HashMap<usize, TokenBucket>,
+    /// the number of messages over the limits
+    pub dropped: u64
+}
+
+impl RateLimiter {
+    pub fn create(limits: &ResourceLimits) -> RateLimiter {
+        RateLimiter {
+            limits: limits.clone(),
+            buckets: HashMap::new(),
+            dropped: 0
+        }
+    }
+
+    pub fn allow(&mut self, msg_type: usize, now: Instant) -> bool {
+        //! true if a message of the type is within the limits. Messages over the limits are counted.
+        if self.limits.rate_limited == false {
+            return true;
+        }
+        let limit = self.limits.get_rate(msg_type);
+        let bucket = self.buckets.entry(msg_type).or_insert_with(|| TokenBucket::create(limit, now));
+        if bucket.take(now) == true {
+            return true;
+        }
+        self.dropped += 1;
+        false
+    }
+
+    pub fn is_abusive(&self) -> bool {
+        //! true if the peer kept sending over the limits
+        self.dropped > self.limits.max_dropped_messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_allows_a_burst_and_refills_at_its_rate() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::create(RateLimit { rate: 2.0, burst: 5.0 }, now);
+        assert_eq!((0..10).filter(|_| bucket.take(now) == true).count(), 5);
+        assert!(bucket.take(now + Duration::from_millis(400)) == false);
+        assert!(bucket.take(now + Duration::from_millis(500)));
+        // a long pause refills the bucket only up to the burst
+        let later = now + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.take(later) == true).count(), 5);
+    }
+
+    #[test]
+    fn message_types_have_their_own_buckets() {
+        let limits = ResourceLimits::create();
+        let mut limiter = RateLimiter::create(&limits);
+        let now = Instant::now();
+        let burst = limits.get_rate(MSG_REQUEST_FULL_CHAIN).burst as usize;
+        assert_eq!((0..100).filter(|_| limiter.allow(MSG_REQUEST_FULL_CHAIN, now) == true).count(), burst);
+        assert_eq!(limiter.dropped, 100 - burst as u64);
+        assert!(limiter.allow(MSG_NEW_TRANSACTION, now));
+        assert!(limiter.is_abusive() == false);
+    }
+
+    #[test]
+    fn peer_over_the_limits_is_abusive() {
+        let limits = ResourceLimits::create();
+        let mut limiter = RateLimiter::create(&limits);
+        let now = Instant::now();
+        let burst = limits.get_rate(MSG_GET_ADDR).burst as u64;
+        for _ in 0..(burst + limits.max_dropped_messages) {
+            limiter.allow(MSG_GET_ADDR, now);
+        }
+        assert!(limiter.is_abusive() == false);
+        assert!(limiter.allow(MSG_GET_ADDR, now) == false);
+        assert!(limiter.is_abusive());
+    }
+
+    #[test]
+    fn unlimited_node_allows_every_message() {
+        let mut limiter = RateLimiter::create(&ResourceLimits::unlimited());
+        let now = Instant::now();
+        assert!((0..1000).all(|_| limiter.allow(MSG_REQUEST_FULL_CHAIN, now) == true));
+        assert_eq!(limiter.dropped, 0);
+    }
+}
@@ -0,0 +1,265 @@
+/*  
+    message.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Typed protocol messages. decode turns a message built by message_manager::build into a Message with
+//! its payload parsed, and handlers of messages implement MessageHandler.
+
+use crate::client_server_core::block_chain::{Block, BlockChain, BlockHeader, Transaction};
+use crate::client_server_core::block_chain::block_filter::BlockFilter;
+use crate::client_server_core::block_chain::compact_block::{BlockTransactions, BlockTransactionsRequest, CompactBlock};
+use crate::client_server_core::block_chain::fee_estimator::FeeEstimate;
+use crate::client_server_core::block_chain::merkle::MerkleProof;
+use crate::client_server_core::connection_manager::handshake::VersionMessage;
+use crate::client_server_core::connection_manager::peer_db::PeerAddress;
+use crate::client_server_core::connection_manager::message_manager::*;
+
+use serde::de::DeserializeOwned;
+
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// the message is not an envelope built by message_manager::build
+    InvalidEnvelope(String),
+    ProtocolUnmatch(String),
+    VersionUnmatch(String),
+    UnknownCommand(usize),
+    /// the payload of the command cannot be parsed
+    InvalidPayload(usize, String)
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::InvalidEnvelope(e) => write!(f, "invalid message: {}", e),
+            ProtocolError::ProtocolUnmatch(protocol) => write!(f, "protocol name {} is not matched", protocol),
+            ProtocolError::VersionUnmatch(version) => write!(f, "protocol version {} is not matched", version),
+            ProtocolError::UnknownCommand(cmd) => write!(f, "unknown command {}", cmd),
+            ProtocolError::InvalidPayload(cmd, e) => write!(f, "invalid payload for command {}: {}", cmd, e),
+        }
+    }
+}
+
+/// The identity of the connection a message was read from: the address the peer listens at, which must not
+/// belong to a node with another key, and the static key the peer proved in the encrypted handshake
+#[derive(Debug)]
+pub struct Peer {
+    pub ip: String,
+    pub port: String,
+    pub key: String
+}
+
+impl Peer {
+    pub fn clone(&self) -> Peer {
+        Peer {
+            ip: self.ip.clone(),
+            port: self.port.clone(),
+            key: self.key.clone()
+        }
+    }
+}
+
+/// What the event loop of a node receives. The fields of the envelope of a message are never trusted,
+/// the sender of a remote message is the peer of the connection.
+#[derive(Debug)]
+pub enum NodeEvent {
+    /// a message read from a connection with the identity of the connection
+    Remote(Peer, String),
+    /// a command of the node itself
+    Local(LocalCommand)
+}
+
+/// Commands which the node gives itself, e.g. from its operator. They never travel over connections.
+#[derive(Debug)]
+pub enum LocalCommand {
+    /// a message of the node itself, e.g. a block of its producer
+    Message(String),
+    /// the height and the path of a UTXO snapshot
+    DumpSnapshot(usize, String),
+    /// the first height, the end height (exclusive) and the path of a bootstrap file
+    ExportChain(usize, usize, String),
+    ImportChain(String),
+    /// the ip, the port (empty for all ports) and the time in seconds of a ban
+    Ban(String, String, i64)
+}
+
+pub enum Message {
+    Add,
+    Remove,
+    CoreList(HashSet<(String, String)>),
+    RequestCoreList,
+    Ping,
+    AddAsEdge,
+    RemoveEdge,
+    NewTransaction(Transaction),
+    NewBlock(Block),
+    NewBlockToAll(Block),
+    RequestFullChain,
+    FullChain(BlockChain),
+    Enhanced(String),
+    /// the time the block producer finished
+    Unlocked(i64),
+    /// a message to be sent to all peers
+    SendMsgAllPeer(String),
+    /// the number of blocks to be confirmed in
+    RequestFeeEstimate(usize),
+    FeeEstimate(FeeEstimate),
+    /// the hash of the last known block
+    RequestHeaders(String),
+    Headers(Vec<BlockHeader>),
+    /// the address whose transactions are proven
+    RequestMerkleProofs(String),
+    MerkleProofs(Vec<MerkleProof>),
+    /// the height of the first filter
+    RequestFilters(usize),
+    Filters(Vec<BlockFilter>),
+    RequestBlocks(Vec<String>),
+    Blocks(Vec<Block>),
+    CompactBlock(CompactBlock),
+    RequestBlockTransactions(BlockTransactionsRequest),
+    BlockTransactions(BlockTransactions),
+    /// the height below which the sender has no blocks
+    Pruned(usize),
+    Version(VersionMessage),
+    /// the negotiated protocol version
+    Verack(u32),
+    GetAddr,
+    Addr(Vec<PeerAddress>)
+}
+
+impl Message {
+    pub fn get_type(&self) -> usize {
+        match self {
+            Message::Add => MSG_ADD,
+            Message::Remove => MSG_REMOVE,
+            Message::CoreList(_) => MSG_CORE_LIST,
+            Message::RequestCoreList => MSG_REQUEST_CORE_LIST,
+            Message::Ping => MSG_PING,
+            Message::AddAsEdge => MSG_ADD_AS_EDGE,
+            Message::RemoveEdge => MSG_REMOVE_EDGE,
+            Message::NewTransaction(_) => MSG_NEW_TRANSACTION,
+            Message::NewBlock(_) => MSG_NEW_BLOCK,
+            Message::NewBlockToAll(_) => MSG_NEW_BLOCK_TO_ALL,
+            Message::RequestFullChain => MSG_REQUEST_FULL_CHAIN,
+            Message::FullChain(_) => RSP_FULL_CHAIN,
+            Message::Enhanced(_) => MSG_ENHANCED,
+            Message::Unlocked(_) => MSG_UNLOCKED,
+            Message::SendMsgAllPeer(_) => MSG_SENDMSGALLPEAR,
+            Message::RequestFeeEstimate(_) => MSG_REQUEST_FEE_ESTIMATE,
+            Message::FeeEstimate(_) => RSP_FEE_ESTIMATE,
+            Message::RequestHeaders(_) => MSG_REQUEST_HEADERS,
+            Message::Headers(_) => RSP_HEADERS,
+            Message::RequestMerkleProofs(_) => MSG_REQUEST_MERKLE_PROOFS,
+            Message::MerkleProofs(_) => RSP_MERKLE_PROOFS,
+            Message::RequestFilters(_) => MSG_REQUEST_FILTERS,
+            Message::Filters(_) => RSP_FILTERS,
+            Message::RequestBlocks(_) => MSG_REQUEST_BLOCKS,
+            Message::Blocks(_) => RSP_BLOCKS,
+            Message::CompactBlock(_) => MSG_COMPACT_BLOCK,
+            Message::RequestBlockTransactions(_) => MSG_REQUEST_BLOCK_TRANSACTIONS,
+            Message::BlockTransactions(_) => RSP_BLOCK_TRANSACTIONS,
+            Message::Pruned(_) => MSG_PRUNED,
+            Message::Version(_) => MSG_VERSION,
+            Message::Verack(_) => MSG_VERACK,
+            Message::GetAddr => MSG_GET_ADDR,
+            Message::Addr(_) => MSG_ADDR,
+        }
+    }
+}
+
+fn from_json<T: DeserializeOwned>(cmd: usize, payload: &str) -> Result<T, ProtocolError> {
+    serde_json::from_str(payload).map_err(|e| ProtocolError::InvalidPayload(cmd, e.to_string()))
+}
+
+pub fn decode(msg: &str) -> Result<Message, ProtocolError> {
+    //! parse a message and its payload. The sender is not taken from the envelope but from the connection.
+    let mm: MessageManager = serde_json::from_str(msg).map_err(|e| ProtocolError::InvalidEnvelope(e.to_string()))?;
+    if mm.protocol != PROTOCOL_NAME {
+        return Err(ProtocolError::ProtocolUnmatch(mm.protocol));
+    }
+    if is_supported_version(&mm.version) == false {
+        return Err(ProtocolError::VersionUnmatch(mm.version));
+    }
+
+    let cmd = mm.msg_type;
+    let payload = &mm.payload;
+    let message = match cmd {
+        MSG_ADD => Message::Add,
+        MSG_REMOVE => Message::Remove,
+        MSG_CORE_LIST => Message::CoreList(from_json(cmd, payload)?),
+        MSG_REQUEST_CORE_LIST => Message::RequestCoreList,
+        MSG_PING => Message::Ping,
+        MSG_ADD_AS_EDGE => Message::AddAsEdge,
+        MSG_REMOVE_EDGE => Message::RemoveEdge,
+        MSG_NEW_TRANSACTION => Message::NewTransaction(from_json(cmd, payload)?),
+        MSG_NEW_BLOCK => Message::NewBlock(from_json(cmd, payload)?),
+        MSG_NEW_BLOCK_TO_ALL => Message::NewBlockToAll(from_json(cmd, payload)?),
+        MSG_REQUEST_FULL_CHAIN => Message::RequestFullChain,
+        RSP_FULL_CHAIN => Message::FullChain(from_json(cmd, payload)?),
+        MSG_ENHANCED => Message::Enhanced(payload.clone()),
+        MSG_UNLOCKED => Message::Unlocked(from_json(cmd, payload)?),
+        MSG_SENDMSGALLPEAR => Message::SendMsgAllPeer(payload.clone()),
+        MSG_REQUEST_FEE_ESTIMATE => Message::RequestFeeEstimate(from_json(cmd, payload)?),
+        RSP_FEE_ESTIMATE => Message::FeeEstimate(from_json(cmd, payload)?),
+        MSG_REQUEST_HEADERS => Message::RequestHeaders(payload.clone()),
+        RSP_HEADERS => Message::Headers(from_json(cmd, payload)?),
+        MSG_REQUEST_MERKLE_PROOFS => Message::RequestMerkleProofs(payload.clone()),
+        RSP_MERKLE_PROOFS => Message::MerkleProofs(from_json(cmd, payload)?),
+        MSG_REQUEST_FILTERS => Message::RequestFilters(from_json(cmd, payload)?),
+        RSP_FILTERS => Message::Filters(from_json(cmd, payload)?),
+        MSG_REQUEST_BLOCKS => Message::RequestBlocks(from_json(cmd, payload)?),
+        RSP_BLOCKS => Message::Blocks(from_json(cmd, payload)?),
+        MSG_COMPACT_BLOCK => Message::CompactBlock(from_json(cmd, payload)?),
+        MSG_REQUEST_BLOCK_TRANSACTIONS => Message::RequestBlockTransactions(from_json(cmd, payload)?),
+        RSP_BLOCK_TRANSACTIONS => Message::BlockTransactions(from_json(cmd, payload)?),
+        MSG_PRUNED => Message::Pruned(from_json(cmd, payload)?),
+        MSG_VERSION => Message::Version(from_json(cmd, payload)?),
+        MSG_VERACK => Message::Verack(from_json(cmd, payload)?),
+        MSG_GET_ADDR => Message::GetAddr,
+        MSG_ADDR => Message::Addr(from_json(cmd, payload)?),
+        _ => return Err(ProtocolError::UnknownCommand(cmd)),
+    };
+
+    // validation code indexes outputs by the inputs of transactions, so malformed transactions stop here
+    let well_formed = match &message {
+        Message::NewTransaction(t) => t.is_well_formed(),
+        Message::NewBlock(block) | Message::NewBlockToAll(block) => block.is_well_formed(),
+        Message::FullChain(bc) => bc.is_well_formed(),
+        Message::Blocks(blocks) => blocks.iter().all(|b| b.is_well_formed()),
+        Message::CompactBlock(compact_block) => compact_block.prefilled.iter().all(|(_, t)| t.is_well_formed()),
+        Message::BlockTransactions(response) => response.transactions.iter().all(|(_, t)| t.is_well_formed()),
+        Message::MerkleProofs(proofs) => proofs.iter().all(|p| p.transaction.is_well_formed()),
+        _ => true,
+    };
+    if well_formed == false {
+        return Err(ProtocolError::InvalidPayload(cmd, "malformed transaction".to_string()));
+    }
+
+    Ok(message)
+}
+
+/// Handlers of decoded messages. Context is the state of the caller which a handler may change,
+/// e.g. the block production of a core node.
+#[allow(async_fn_in_trait)]
+pub trait MessageHandler {
+    type Context;
+
+    async fn handle_message(&mut self, sender: &Peer, message: &Message, context: &mut Self::Context);
+}
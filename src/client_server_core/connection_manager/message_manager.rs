@@ -20,7 +20,11 @@
 use serde::{Serialize, Deserialize};
 
 pub const PROTOCOL_NAME: &str = "blockchain-rs_protocol";
-pub const MY_VERSION: &str = "0.1.0";
+pub const MY_VERSION: &str = "0.3.0";
+/// The protocol versions this node speaks. The minor number of a version string is its protocol version.
+/// Version 3 sends framed messages over encrypted connections, which nodes of earlier versions cannot read.
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
 
 pub const MSG_ADD: usize = 0;
 pub const MSG_REMOVE: usize = 1;
@@ -37,17 +41,27 @@ pub const RSP_FULL_CHAIN: usize = 11;
 pub const MSG_ENHANCED: usize = 12;
 pub const MSG_UNLOCKED: usize = 13;
 pub const MSG_SENDMSGALLPEAR: usize = 14;
+pub const MSG_REQUEST_FEE_ESTIMATE: usize = 15;
+pub const RSP_FEE_ESTIMATE: usize = 16;
+pub const MSG_REQUEST_HEADERS: usize = 17;
+pub const RSP_HEADERS: usize = 18;
+pub const MSG_REQUEST_MERKLE_PROOFS: usize = 19;
+pub const RSP_MERKLE_PROOFS: usize = 20;
+pub const MSG_REQUEST_FILTERS: usize = 21;
+pub const RSP_FILTERS: usize = 22;
+pub const MSG_REQUEST_BLOCKS: usize = 23;
+pub const RSP_BLOCKS: usize = 24;
+pub const MSG_COMPACT_BLOCK: usize = 25;
+pub const MSG_REQUEST_BLOCK_TRANSACTIONS: usize = 26;
+pub const RSP_BLOCK_TRANSACTIONS: usize = 27;
+pub const MSG_PRUNED: usize = 29;
+pub const MSG_VERSION: usize = 32;
+pub const MSG_VERACK: usize = 33;
+pub const MSG_GET_ADDR: usize = 34;
+pub const MSG_ADDR: usize = 35;
 
-pub const ERR_PROTOCOL_UNMATCH: usize = 0;
-pub const ERR_VERSION_UNMATCH: usize = 1;
-pub const OK_WITH_PAYLOAD: usize = 2;
-pub const OK_WITHOUT_PAYLOAD: usize = 3;
-
-pub const ERROR: usize = 0;
-pub const OK: usize = 1;
 pub const NONE: usize = 0;
 
-
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageManager {
     pub protocol: String, 
@@ -60,10 +74,15 @@ pub struct MessageManager {
 
 pub fn build(msg_type: usize, ip: &str, port: &str, payload: &str) -> String {
 //! It builds a message in String format with a given msg_type, ip, port and payload.    
+    build_with_version(msg_type, ip, port, payload, PROTOCOL_VERSION)
+}
+
+pub fn build_with_version(msg_type: usize, ip: &str, port: &str, payload: &str, version: u32) -> String {
+//! It builds a message of the protocol version negotiated with a peer.
 
     let mm = MessageManager {
         protocol: PROTOCOL_NAME.to_string(), 
-        version: MY_VERSION.to_string(),  
+        version: get_version_string(version),  
         msg_type: msg_type,
         ip: ip.to_string(), 
         port: port.to_string(), 
@@ -73,37 +92,66 @@ pub fn build(msg_type: usize, ip: &str, port: &str, payload: &str) -> String {
     serde_json::to_string(&mm).unwrap()
 }
 
-pub fn parse(msg: &str) -> Vec<String> {
-//! It parses a message build by the build function.
-
-    let mm: MessageManager = serde_json::from_str(msg).unwrap();
+pub fn get_version_string(version: u32) -> String {
+//! It returns the version string of a protocol version, such as "0.3.0" for 3.
+    format!("0.{}.0", version)
+}
 
-    println!("parsed message: {} {} {} {} {}", mm.version, mm.msg_type, mm.ip, mm.port, mm.payload);
-    println!("payload: {}", mm.payload);
+pub fn set_version(msg: &str, version: u32) -> Option<String> {
+//! It returns the message built by the build function with another protocol version.
+    let mut mm = serde_json::from_str::<MessageManager>(msg).ok()?;
+    mm.version = get_version_string(version);
+    Some(serde_json::to_string(&mm).unwrap())
+}
 
-    let mut res: Vec<String> = Vec::new();
-    if mm.protocol != PROTOCOL_NAME {
-        res = vec![ERROR.to_string(), ERR_PROTOCOL_UNMATCH.to_string(), NONE.to_string(), NONE.to_string(), NONE.to_string(), NONE.to_string()];
-    }
-    else if mm.version != MY_VERSION {
-        res = vec![ERROR.to_string(), ERR_VERSION_UNMATCH.to_string(), NONE.to_string(), NONE.to_string(), NONE.to_string(), NONE.to_string()];
+pub fn get_version_number(version: &str) -> Option<u32> {
+//! It returns the protocol version of a version string such as "0.2.0".
+    let numbers: Vec<&str> = version.split('.').collect();
+    if numbers.len() != 3 {
+        return None;
     }
-    else if mm.msg_type == MSG_CORE_LIST || mm.msg_type == MSG_NEW_TRANSACTION || mm.msg_type == MSG_NEW_BLOCK || mm.msg_type == MSG_NEW_BLOCK_TO_ALL  || mm.msg_type == RSP_FULL_CHAIN || mm.msg_type == MSG_ENHANCED {
-        res = vec![OK.to_string(), OK_WITH_PAYLOAD.to_string(), mm.msg_type.to_string(), mm.ip.to_string(), mm.port.to_string(), mm.payload.to_string()];
+    numbers[1].parse().ok()
+}
+
+pub fn is_supported_version(version: &str) -> bool {
+//! Messages of newer versions are accepted since the handshake decides what peers send to each other.
+    match get_version_number(version) {
+        Some(number) => number >= MIN_PROTOCOL_VERSION,
+        None => false,
     }
-    else {
-        res = vec![OK.to_string(), OK_WITHOUT_PAYLOAD.to_string(), mm.msg_type.to_string(), mm.ip.to_string(), mm.port.to_string(), NONE.to_string()];
+}
+
+pub fn is_local_command(msg_type: usize) -> bool {
+//! It returns true for the messages which a node only sends to itself. Peers must not send them.
+    matches!(msg_type, MSG_NEW_BLOCK_TO_ALL | MSG_UNLOCKED | MSG_SENDMSGALLPEAR)
+}
+
+pub fn get_response_type(msg_type: usize) -> Option<usize> {
+//! It returns the type of the response to a request for blocks. Such responses are accepted only after the request.
+    match msg_type {
+        MSG_REQUEST_FULL_CHAIN => Some(RSP_FULL_CHAIN),
+        MSG_REQUEST_HEADERS => Some(RSP_HEADERS),
+        MSG_REQUEST_BLOCKS => Some(RSP_BLOCKS),
+        _ => None,
     }
-    res
+}
 
+pub fn is_requested_response(msg_type: usize) -> bool {
+    matches!(msg_type, RSP_FULL_CHAIN | RSP_HEADERS | RSP_BLOCKS)
 }
 
-pub fn classify_msg(msg: &str) -> bool {
-    let res = parse(msg);
-    let msg_type = &res[2];
-    let msg_type_num: usize = msg_type.parse().unwrap();
-    if msg_type_num <= MSG_REMOVE_EDGE {
-        return true;
+pub fn get_msg_type(msg: &str) -> Option<usize> {
+//! It returns the type of a message build by the build function.
+    match serde_json::from_str::<MessageManager>(msg) {
+        Ok(mm) => Some(mm.msg_type),
+        Err(_) => None,
     }
-    return false;
-}
\ No newline at end of file
+}
+
+pub fn get_msg_type_and_version(msg: &str) -> Option<(usize, Option<u32>)> {
+//! It returns the type and the protocol version of a message build by the build function.
+    match serde_json::from_str::<MessageManager>(msg) {
+        Ok(mm) => Some((mm.msg_type, get_version_number(&mm.version))),
+        Err(_) => None,
+    }
+}
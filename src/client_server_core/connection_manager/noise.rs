@@ -0,0 +1,599 @@
+/*  
+    noise.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! The encrypted transport of the connections between nodes. Every connection starts with a handshake in the
+//! XX pattern of the Noise protocol framework, in which both sides exchange ephemeral keys and send their static
+//! node keys encrypted:
+//!
+//! ```text
+//! -> e
+//! <- e, ee, s, es
+//! -> s, se
+//! ```
+//!
+//! Afterwards both sides know the static key of the other side, and everything they send is encrypted and
+//! authenticated with keys derived from the handshake. The key agreement is X25519, the hash SHA-256 and the
+//! cipher the ChaCha20-Poly1305 of RFC 7539 with 96-bit nonces. It is built from the ChaCha20 and Poly1305 of
+//! rust-crypto, since the ChaCha20Poly1305 there is the earlier construction with 64-bit nonces.
+
+use crate::client_server_core::connection_manager::message_manager;
+
+use crypto::chacha20::ChaCha20;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::poly1305::Poly1305;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use crypto::util::fixed_time_eq;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub const NOISE_PROTOCOL_NAME: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+pub const KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+/// The maximum size of a Noise message. Longer writes are split into several messages.
+pub const MAX_NOISE_MESSAGE_SIZE: usize = 65535;
+pub const NOISE_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+/// A static X25519 key identifying a node
+pub struct NodeKey {
+    secret: [u8; KEY_SIZE],
+    public: [u8; KEY_SIZE]
+}
+
+impl NodeKey {
+    pub fn generate() -> NodeKey {
+        let mut secret = [0u8; KEY_SIZE];
+        rand::thread_rng().fill(&mut secret);
+        NodeKey::from_secret(secret)
+    }
+
+    pub fn from_secret(secret: [u8; KEY_SIZE]) -> NodeKey {
+        NodeKey {
+            secret: secret,
+            public: curve25519_base(&secret)
+        }
+    }
+
+    pub fn clone(&self) -> NodeKey {
+        NodeKey::from_secret(self.secret)
+    }
+
+    pub fn get_public_key(&self) -> String {
+        hex::encode(self.public)
+    }
+
+    pub fn load_or_create(path: &str) -> NodeKey {
+        //! read the secret key in hex from path, or generate one and save it there, so that the node keeps its key across restarts.
+        //! Only the owner may read the file.
+        if let Ok(content) = fs::read_to_string(path) {
+            if let Some(secret) = parse_key(content.trim()) {
+                restrict_permissions(path);
+                return NodeKey::from_secret(secret);
+            }
+            println!("invalid node key in {}", path);
+        }
+        let node_key = NodeKey::generate();
+        let saved = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+            .and_then(|mut file| {
+                restrict_permissions(path);
+                file.write_all(hex::encode(node_key.secret).as_bytes())
+            });
+        if let Err(e) = saved {
+            println!("cannot save the node key to {}: {}", path, e);
+        }
+        println!("node key {} is created", node_key.get_public_key());
+        node_key
+    }
+}
+
+fn restrict_permissions(path: &str) {
+    //! the mode of a new file is 0600, but an existing file keeps its mode
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            println!("the node key in {} is readable by others. Its mode is set to 0600.", path);
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+                println!("cannot set the mode of {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// A core node and the static key it must have, as written in the configuration of an edge node
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PinnedKey {
+    pub ip: String,
+    pub port: String,
+    /// the static key in hex
+    pub public_key: String
+}
+
+pub fn load_pinned_keys(path: &str) -> Vec<PinnedKey> {
+    //! read a JSON list of pinned keys. A missing or broken file pins nothing.
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => {
+            println!("no pinned keys at {}", path);
+            return Vec::new();
+        }
+    };
+    match serde_json::from_str::<Vec<PinnedKey>>(&content) {
+        Ok(pinned_keys) => pinned_keys,
+        Err(e) => {
+            println!("invalid pinned keys {}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn parse_key(key: &str) -> Option<[u8; KEY_SIZE]> {
+    //! a 32-byte key in hex
+    let bytes = hex::decode(key).ok()?;
+    if bytes.len() != KEY_SIZE {
+        return None;
+    }
+    let mut result = [0u8; KEY_SIZE];
+    result.copy_from_slice(&bytes);
+    Some(result)
+}
+
+fn invalid_data(e: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn dh(key: &NodeKey, public: &[u8]) -> io::Result<[u8; KEY_SIZE]> {
+    //! X25519. A low order point of a peer gives an all-zero secret, which is rejected.
+    let shared = curve25519(&key.secret, public);
+    if shared.iter().all(|b| *b == 0) {
+        return Err(invalid_data("invalid public key"));
+    }
+    Ok(shared)
+}
+
+fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    for d in data.iter() {
+        hmac.input(d);
+    }
+    let mut output = [0u8; 32];
+    hmac.raw_result(&mut output);
+    output
+}
+
+fn hkdf(chaining_key: &[u8], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
+    //! the HKDF of the Noise protocol with two outputs
+    let temp_key = hmac_sha256(chaining_key, &[input_key_material]);
+    let output1 = hmac_sha256(&temp_key, &[&[1u8]]);
+    let output2 = hmac_sha256(&temp_key, &[&output1, &[2u8]]);
+    (output1, output2)
+}
+
+struct CipherState {
+    key: Option<[u8; KEY_SIZE]>,
+    nonce: u64
+}
+
+fn get_nonce(counter: u64) -> [u8; 12] {
+    //! 32 bits of zeros and the little-endian counter
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn poly1305_tag(otk: &[u8], ad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE] {
+    //! the tag of RFC 7539 over the padded associated data, the padded ciphertext and both lengths
+    let padding = [0u8; 16];
+    let mut mac = Poly1305::new(otk);
+    mac.input(ad);
+    mac.input(&padding[..(16 - ad.len() % 16) % 16]);
+    mac.input(ciphertext);
+    mac.input(&padding[..(16 - ciphertext.len() % 16) % 16]);
+    mac.input(&(ad.len() as u64).to_le_bytes());
+    mac.input(&(ciphertext.len() as u64).to_le_bytes());
+    let mut tag = [0u8; TAG_SIZE];
+    mac.raw_result(&mut tag);
+    tag
+}
+
+fn chacha20_poly1305(key: &[u8], nonce: &[u8]) -> (ChaCha20, [u8; 32]) {
+    //! the cipher after the first block, whose first 32 bytes are the one-time Poly1305 key
+    let mut cipher = ChaCha20::new(key, nonce);
+    let mut block = [0u8; 64];
+    cipher.process(&[0u8; 64], &mut block);
+    let mut otk = [0u8; 32];
+    otk.copy_from_slice(&block[..32]);
+    (cipher, otk)
+}
+
+pub fn seal(key: &[u8], nonce: &[u8], ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    //! the ciphertext with the tag of the AEAD_CHACHA20_POLY1305 of RFC 7539
+    let (mut cipher, otk) = chacha20_poly1305(key, nonce);
+    let mut ciphertext = vec![0u8; plaintext.len()];
+    cipher.process(plaintext, &mut ciphertext);
+    let tag = poly1305_tag(&otk, ad, &ciphertext);
+    ciphertext.extend_from_slice(&tag);
+    ciphertext
+}
+
+pub fn open(key: &[u8], nonce: &[u8], ad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    //! the plaintext of a ciphertext sealed by seal, or None if the tag is not matched
+    if ciphertext.len() < TAG_SIZE {
+        return None;
+    }
+    let (input, tag) = ciphertext.split_at(ciphertext.len() - TAG_SIZE);
+    let (mut cipher, otk) = chacha20_poly1305(key, nonce);
+    if fixed_time_eq(&poly1305_tag(&otk, ad, input), tag) == false {
+        return None;
+    }
+    let mut plaintext = vec![0u8; input.len()];
+    cipher.process(input, &mut plaintext);
+    Some(plaintext)
+}
+
+impl CipherState {
+    fn create(key: Option<[u8; KEY_SIZE]>) -> CipherState {
+        CipherState {
+            key: key,
+            nonce: 0
+        }
+    }
+
+    fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        //! without a key the plaintext is sent as it is, as in the first message of a handshake
+        let key = match self.key {
+            Some(key) => key,
+            None => return Ok(plaintext.to_vec()),
+        };
+        if self.nonce == u64::MAX {
+            return Err(invalid_data("nonces are exhausted"));
+        }
+        let ciphertext = seal(&key, &get_nonce(self.nonce), ad, plaintext);
+        self.nonce += 1;
+        Ok(ciphertext)
+    }
+
+    fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let key = match self.key {
+            Some(key) => key,
+            None => return Ok(ciphertext.to_vec()),
+        };
+        if ciphertext.len() < TAG_SIZE {
+            return Err(invalid_data("message is shorter than a tag"));
+        }
+        if self.nonce == u64::MAX {
+            return Err(invalid_data("nonces are exhausted"));
+        }
+        let plaintext = match open(&key, &get_nonce(self.nonce), ad, ciphertext) {
+            Some(plaintext) => plaintext,
+            None => return Err(invalid_data("message is not authenticated")),
+        };
+        self.nonce += 1;
+        Ok(plaintext)
+    }
+}
+
+struct SymmetricState {
+    cipher_state: CipherState,
+    chaining_key: [u8; 32],
+    hash: [u8; 32]
+}
+
+impl SymmetricState {
+    fn initialize() -> SymmetricState {
+        //! the protocol name of at most 32 bytes is the initial hash. The prologue binds the handshake to our protocol.
+        let mut hash = [0u8; 32];
+        hash[..NOISE_PROTOCOL_NAME.len()].copy_from_slice(NOISE_PROTOCOL_NAME.as_bytes());
+        let mut symmetric_state = SymmetricState {
+            cipher_state: CipherState::create(None),
+            chaining_key: hash,
+            hash: hash
+        };
+        symmetric_state.mix_hash(message_manager::PROTOCOL_NAME.as_bytes());
+        symmetric_state
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (chaining_key, key) = hkdf(&self.chaining_key, input_key_material);
+        self.chaining_key = chaining_key;
+        self.cipher_state = CipherState::create(Some(key));
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut sha256 = Sha256::new();
+        sha256.input(&self.hash);
+        sha256.input(data);
+        sha256.result(&mut self.hash);
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let ciphertext = self.cipher_state.encrypt_with_ad(&self.hash, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let plaintext = self.cipher_state.decrypt_with_ad(&self.hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    fn split(&self) -> (CipherState, CipherState) {
+        //! the keys from the initiator to the responder and from the responder to the initiator
+        let (key1, key2) = hkdf(&self.chaining_key, &[]);
+        (CipherState::create(Some(key1)), CipherState::create(Some(key2)))
+    }
+}
+
+async fn write_noise_message(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    //! a Noise message with its length in 2 bytes
+    let mut data: Vec<u8> = Vec::with_capacity(2 + message.len());
+    data.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    data.extend_from_slice(message);
+    stream.write_all(&data).await
+}
+
+async fn read_noise_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).await?;
+    let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+pub async fn with_timeout<T, F: Future<Output = io::Result<T>>>(timeout_ms: u64, future: F) -> io::Result<T> {
+    //! the result of future, or a TimedOut error if it does not finish in time
+    match tokio::time::timeout(Duration::from_millis(timeout_ms), future).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer in {} ms", timeout_ms))),
+    }
+}
+
+/// A connection encrypted with the keys of a finished handshake
+pub struct SecureStream {
+    stream: TcpStream,
+    sender: CipherState,
+    receiver: CipherState,
+    /// the decrypted bytes of the last message which are not read yet
+    buffer: Vec<u8>,
+    position: usize,
+    remote_key: [u8; KEY_SIZE]
+}
+
+impl SecureStream {
+    pub fn get_remote_key(&self) -> String {
+        //! the static key of the other side
+        hex::encode(self.remote_key)
+    }
+
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        //! fill buf with decrypted bytes, reading as many Noise messages as needed
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.position == self.buffer.len() {
+                let message = read_noise_message(&mut self.stream).await?;
+                self.buffer = self.receiver.decrypt_with_ad(&[], &message)?;
+                self.position = 0;
+                continue;
+            }
+            let len = (buf.len() - filled).min(self.buffer.len() - self.position);
+            buf[filled..filled + len].copy_from_slice(&self.buffer[self.position..self.position + len]);
+            self.position += len;
+            filled += len;
+        }
+        Ok(())
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        //! encrypt data in Noise messages of at most MAX_NOISE_MESSAGE_SIZE bytes
+        for piece in data.chunks(MAX_NOISE_MESSAGE_SIZE - TAG_SIZE) {
+            let message = self.sender.encrypt_with_ad(&[], piece)?;
+            write_noise_message(&mut self.stream, &message).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+fn get_public_key(data: &[u8]) -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&data[..KEY_SIZE]);
+    key
+}
+
+pub async fn handshake_as_initiator(stream: TcpStream, node_key: &NodeKey) -> io::Result<SecureStream> {
+    //! the dialer of a connection is the initiator
+    with_timeout(NOISE_HANDSHAKE_TIMEOUT_MS, initiate(stream, node_key)).await
+}
+
+async fn initiate(mut stream: TcpStream, node_key: &NodeKey) -> io::Result<SecureStream> {
+    let mut symmetric_state = SymmetricState::initialize();
+    let ephemeral_key = NodeKey::generate();
+
+    // -> e
+    symmetric_state.mix_hash(&ephemeral_key.public);
+    let payload = symmetric_state.encrypt_and_hash(&[])?;
+    write_noise_message(&mut stream, &[&ephemeral_key.public[..], &payload[..]].concat()).await?;
+
+    // <- e, ee, s, es
+    let message = read_noise_message(&mut stream).await?;
+    if message.len() != KEY_SIZE + KEY_SIZE + TAG_SIZE + TAG_SIZE {
+        return Err(invalid_data("invalid handshake message"));
+    }
+    let remote_ephemeral = get_public_key(&message);
+    symmetric_state.mix_hash(&remote_ephemeral);
+    symmetric_state.mix_key(&dh(&ephemeral_key, &remote_ephemeral)?);
+    let remote_static = get_public_key(&symmetric_state.decrypt_and_hash(&message[KEY_SIZE..KEY_SIZE * 2 + TAG_SIZE])?);
+    symmetric_state.mix_key(&dh(&ephemeral_key, &remote_static)?);
+    symmetric_state.decrypt_and_hash(&message[KEY_SIZE * 2 + TAG_SIZE..])?;
+
+    // -> s, se
+    let encrypted_static = symmetric_state.encrypt_and_hash(&node_key.public)?;
+    symmetric_state.mix_key(&dh(node_key, &remote_ephemeral)?);
+    let payload = symmetric_state.encrypt_and_hash(&[])?;
+    write_noise_message(&mut stream, &[&encrypted_static[..], &payload[..]].concat()).await?;
+
+    let (sender, receiver) = symmetric_state.split();
+    Ok(SecureStream {
+        stream: stream,
+        sender: sender,
+        receiver: receiver,
+        buffer: Vec::new(),
+        position: 0,
+        remote_key: remote_static
+    })
+}
+
+pub async fn handshake_as_responder(stream: TcpStream, node_key: &NodeKey) -> io::Result<SecureStream> {
+    //! the acceptor of a connection is the responder
+    with_timeout(NOISE_HANDSHAKE_TIMEOUT_MS, respond(stream, node_key)).await
+}
+
+async fn respond(mut stream: TcpStream, node_key: &NodeKey) -> io::Result<SecureStream> {
+    let mut symmetric_state = SymmetricState::initialize();
+
+    // -> e
+    let message = read_noise_message(&mut stream).await?;
+    if message.len() != KEY_SIZE {
+        return Err(invalid_data("invalid handshake message"));
+    }
+    let remote_ephemeral = get_public_key(&message);
+    symmetric_state.mix_hash(&remote_ephemeral);
+    symmetric_state.decrypt_and_hash(&[])?;
+
+    // <- e, ee, s, es
+    let ephemeral_key = NodeKey::generate();
+    symmetric_state.mix_hash(&ephemeral_key.public);
+    symmetric_state.mix_key(&dh(&ephemeral_key, &remote_ephemeral)?);
+    let encrypted_static = symmetric_state.encrypt_and_hash(&node_key.public)?;
+    symmetric_state.mix_key(&dh(node_key, &remote_ephemeral)?);
+    let payload = symmetric_state.encrypt_and_hash(&[])?;
+    write_noise_message(&mut stream, &[&ephemeral_key.public[..], &encrypted_static[..], &payload[..]].concat()).await?;
+
+    // -> s, se
+    let message = read_noise_message(&mut stream).await?;
+    if message.len() != KEY_SIZE + TAG_SIZE + TAG_SIZE {
+        return Err(invalid_data("invalid handshake message"));
+    }
+    let remote_static = get_public_key(&symmetric_state.decrypt_and_hash(&message[..KEY_SIZE + TAG_SIZE])?);
+    symmetric_state.mix_key(&dh(&ephemeral_key, &remote_static)?);
+    symmetric_state.decrypt_and_hash(&message[KEY_SIZE + TAG_SIZE..])?;
+
+    let (receiver, sender) = symmetric_state.split();
+    Ok(SecureStream {
+        stream: stream,
+        sender: sender,
+        receiver: receiver,
+        buffer: Vec::new(),
+        position: 0,
+        remote_key: remote_static
+    })
+}
+
+pub async fn run() {
+    //! check the cipher with the test vector of RFC 7539 and the mode of a new key file, run the handshake over a
+    //! local connection, tamper with a message, and connect with a pinned key
+    use crate::client_server_core::connection_manager::handshake;
+    use crate::client_server_core::connection_manager::message::NodeEvent;
+    use crate::client_server_core::connection_manager::peer_connection::{self, PeerConnections};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    // RFC 7539 section 2.8.2
+    let key: Vec<u8> = (0x80..0xa0).collect();
+    let nonce = [0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+    let ad = [0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7];
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+    let sealed = seal(&key, &nonce, &ad, plaintext);
+    println!("tag of the RFC 7539 test vector: {}", hex::encode(&sealed[plaintext.len()..]) == "1ae10b594f09e26a7e902ecbd0600691");
+    println!("opened: {}", open(&key, &nonce, &ad, &sealed).map(|p| p == plaintext.to_vec()) == Some(true));
+
+    let key_path = "/tmp/blockchain-rs-node-key-test";
+    let _ = fs::remove_file(key_path);
+    let node_key = NodeKey::load_or_create(key_path);
+    println!("mode of the key file: {:o}", fs::metadata(key_path).unwrap().permissions().mode() & 0o777);
+    println!("key is loaded again: {}", NodeKey::load_or_create(key_path).get_public_key() == node_key.get_public_key());
+    fs::remove_file(key_path).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let responder_key = NodeKey::generate();
+    let responder_public_key = responder_key.get_public_key();
+    let (tx, mut rx) = mpsc::unbounded_channel::<(String, io::Result<Vec<u8>>)>();
+    tokio::spawn(async move {
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = handshake_as_responder(stream, &responder_key).await.unwrap();
+            let mut buf = vec![0u8; 5];
+            let result = stream.read_exact(&mut buf).await.map(|_| buf);
+            tx.send((stream.get_remote_key(), result)).unwrap();
+        }
+    });
+
+    let initiator_key = NodeKey::generate();
+    let stream = TcpStream::connect(&addr).await.unwrap();
+    let mut stream = handshake_as_initiator(stream, &initiator_key).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let (remote_key, result) = rx.recv().await.unwrap();
+    println!("initiator knows the responder key: {}", stream.get_remote_key() == responder_public_key);
+    println!("responder knows the initiator key: {}", remote_key == initiator_key.get_public_key());
+    println!("message: {:?}", result.map(|m| String::from_utf8(m).unwrap()));
+
+    // a flipped bit in the ciphertext fails the authentication
+    let stream = TcpStream::connect(&addr).await.unwrap();
+    let mut stream = handshake_as_initiator(stream, &initiator_key).await.unwrap();
+    let mut message = stream.sender.encrypt_with_ad(&[], b"hello").unwrap();
+    message[0] ^= 1;
+    write_noise_message(&mut stream.stream, &message).await.unwrap();
+    println!("tampered message: {:?}", rx.recv().await.unwrap().1.map_err(|e| e.to_string()));
+
+    // an edge node pinning the key of its core node connects only to the node with the key
+    let core_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let core_port = core_listener.local_addr().unwrap().port().to_string();
+    let core_peers = Arc::new(PeerConnections::create("127.0.0.1", &core_port, handshake::NODE_FULL));
+    let core_public_key = core_peers.get_public_key();
+    let (core_tx, mut core_rx) = mpsc::unbounded_channel::<NodeEvent>();
+    peer_connection::start_listener(Arc::clone(&core_peers), core_listener, core_tx);
+    // the core node answers the edge node at the address it claims
+    let edge_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let edge_port = edge_listener.local_addr().unwrap().port().to_string();
+    let edge_peers = Arc::new(PeerConnections::create("127.0.0.1", &edge_port, handshake::NODE_LIGHT));
+    let (edge_tx, _edge_rx) = mpsc::unbounded_channel::<NodeEvent>();
+    peer_connection::start_listener(Arc::clone(&edge_peers), edge_listener, edge_tx);
+    let msg = message_manager::build(message_manager::MSG_GET_ADDR, "127.0.0.1", &edge_port, "");
+    edge_peers.pin_key("127.0.0.1", &core_port, &NodeKey::generate().get_public_key());
+    println!("sent to a core node with another key: {}", edge_peers.send("127.0.0.1", &core_port, &msg).await);
+    edge_peers.pin_key("127.0.0.1", &core_port, &core_public_key);
+    println!("sent to the core node with the pinned key: {}", edge_peers.send("127.0.0.1", &core_port, &msg).await);
+    let received = tokio::time::timeout(Duration::from_secs(5), core_rx.recv()).await;
+    println!("received by the core node: {}", received.is_ok());
+}
@@ -0,0 +1,659 @@
+/*  
+    peer_connection.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Long-lived connections to peers on the async runtime. Each outgoing connection has a writer task fed by a
+//! bounded queue, and each accepted connection has a reader task which passes the received messages to the node.
+//! Both sides run the handshake before any message and remember what they negotiated with the peer.
+//! A peer is known by the static key it proved in the encrypted handshake and the address it claims to listen
+//! at on the host of the connection. The first key seen at an address is kept, so a peer cannot speak for another
+//! node which this node knows. Nothing is dialed while a connection is accepted.
+
+use crate::client_server_core::connection_manager::frame;
+use crate::client_server_core::connection_manager::handshake::{self, PeerInfo, VersionMessage};
+use crate::client_server_core::connection_manager::message_manager;
+use crate::client_server_core::connection_manager::message::{self, Message, NodeEvent, Peer};
+use crate::client_server_core::connection_manager::limits::{RateLimiter, ResourceLimits};
+use crate::client_server_core::connection_manager::noise::{self, NodeKey};
+use crate::client_server_core::connection_manager::ban_list::{self, BanList};
+
+use chrono::Local;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+
+/// The number of frames waiting for the writer task of a peer
+pub const MAX_QUEUED_FRAMES: usize = 256;
+/// The time to wait for a full queue before the peer is regarded as too slow and disconnected
+pub const SEND_QUEUE_TIMEOUT_MS: u64 = 1000;
+pub const CONNECT_TIMEOUT_MS: u64 = 3000;
+pub const WRITE_TIMEOUT_MS: u64 = 10000;
+/// The time after which an idle connection to a peer is pinged, so that the peer does not close it
+pub const PING_INTERVAL_SECS: u64 = 30;
+/// The number of responses of one type a peer may send to requests which are not answered yet
+pub const MAX_PENDING_REQUESTS: usize = 8;
+
+struct PeerConnection {
+    sender: mpsc::Sender<Vec<u8>>,
+    alive: Arc<AtomicBool>,
+    /// the result of the handshake over this connection
+    info: PeerInfo
+}
+
+struct InboundConnection {
+    /// notified to close the connection when it is evicted or banned
+    close: Arc<Notify>,
+    addr: String,
+    /// the ip of the socket, which host bans apply to
+    host: String,
+    /// the identity of the peer and the result of the handshake
+    peer: Option<(Peer, PeerInfo)>,
+    connected_at: Instant,
+    /// the last time the peer sent a block or a transaction
+    last_useful: Option<Instant>
+}
+
+pub struct PeerConnections {
+    peers: Mutex<HashMap<(String, String), PeerConnection>>,
+    /// held while a connection is made, so that a peer is not connected twice
+    connecting: tokio::sync::Mutex<()>,
+    /// the version message sent to new peers
+    local: Mutex<VersionMessage>,
+    /// the accepted connections by the ids given by next_inbound_id
+    inbound: Mutex<HashMap<usize, InboundConnection>>,
+    next_inbound_id: AtomicUsize,
+    limits: Mutex<ResourceLimits>,
+    /// the static key of the node for the encrypted connections
+    node_key: Mutex<NodeKey>,
+    /// the static keys in hex which the nodes at the addresses must have
+    pinned_keys: Mutex<HashMap<(String, String), String>>,
+    /// the keys of the nodes this node has connected to. A node keeps its key, so another key at the address is refused.
+    known_keys: Mutex<HashMap<(String, String), String>>,
+    /// the banned peers. Banned peers are closed after the handshake.
+    ban_list: Arc<Mutex<BanList>>,
+    /// the numbers of responses which the peers may send, by their addresses and the response types
+    requests: Mutex<HashMap<(String, String, usize), usize>>
+}
+
+impl PeerConnections {
+    pub fn create(my_ip: &str, my_port: &str, services: u64) -> PeerConnections {
+        PeerConnections {
+            peers: Mutex::new(HashMap::new()),
+            connecting: tokio::sync::Mutex::new(()),
+            local: Mutex::new(VersionMessage::create(my_ip, my_port, services)),
+            inbound: Mutex::new(HashMap::new()),
+            next_inbound_id: AtomicUsize::new(0),
+            limits: Mutex::new(ResourceLimits::create()),
+            node_key: Mutex::new(NodeKey::generate()),
+            pinned_keys: Mutex::new(HashMap::new()),
+            known_keys: Mutex::new(HashMap::new()),
+            ban_list: Arc::new(Mutex::new(BanList::create(""))),
+            requests: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn set_node_key(&self, node_key: NodeKey) {
+        //! the static key for the connections made from now on
+        println!("node key: {}", node_key.get_public_key());
+        *self.node_key.lock().unwrap() = node_key;
+    }
+
+    pub fn get_public_key(&self) -> String {
+        self.node_key.lock().unwrap().get_public_key()
+    }
+
+    pub fn get_node_key(&self) -> NodeKey {
+        self.node_key.lock().unwrap().clone()
+    }
+
+    pub fn pin_key(&self, ip: &str, port: &str, public_key: &str) {
+        //! connections to and from the node at ip and port are closed unless it has the key
+        self.pinned_keys.lock().unwrap().insert((ip.to_string(), port.to_string()), public_key.to_lowercase());
+        self.known_keys.lock().unwrap().remove(&(ip.to_string(), port.to_string()));
+    }
+
+    fn check_pinned_key(&self, ip: &str, port: &str, public_key: &str) -> io::Result<()> {
+        //! the key of a node must be its pinned key, or the key it had when this node first connected to it
+        let address = (ip.to_string(), port.to_string());
+        if let Some(pinned_key) = self.pinned_keys.lock().unwrap().get(&address) {
+            if pinned_key != public_key {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("key {} of {}:{} is not the pinned key", public_key, ip, port)));
+            }
+            return Ok(());
+        }
+        match self.known_keys.lock().unwrap().get(&address) {
+            Some(known_key) if known_key != public_key => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("key {} of {}:{} is not the key it had before", public_key, ip, port)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn check_identity(&self, host: &str, ip: &str, port: &str, public_key: &str) -> io::Result<()> {
+        //! check the address which the peer of an accepted connection from host claims to listen at against the
+        //! static key of the connection. The key is kept as the key of the address if it is the first one.
+        if host.len() > 0 && host != ip {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("peer from {} claims {}:{} of another host", host, ip, port)));
+        }
+        {
+            let local = self.local.lock().unwrap();
+            if local.ip == ip && local.port == port {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("peer claims our address {}:{}", ip, port)));
+            }
+        }
+        self.check_pinned_key(ip, port, public_key)?;
+        self.known_keys.lock().unwrap().entry((ip.to_string(), port.to_string())).or_insert(public_key.to_string());
+        Ok(())
+    }
+
+    pub fn get_ban_list(&self) -> Arc<Mutex<BanList>> {
+        Arc::clone(&self.ban_list)
+    }
+
+    pub fn is_banned(&self, ip: &str, port: &str, key: &str) -> bool {
+        self.ban_list.lock().unwrap().is_banned(ip, port, key, Local::now().timestamp())
+    }
+
+    pub fn disconnect_banned(&self) {
+        //! close the connections from and to the banned hosts, nodes and keys
+        let now = Local::now().timestamp();
+        let ban_list = self.ban_list.lock().unwrap();
+        self.inbound.lock().unwrap().retain(|_, connection| {
+            let banned = ban_list.is_banned(&connection.host, "", "", now) || match &connection.peer {
+                Some((peer, _)) => ban_list.is_banned(&peer.ip, &peer.port, &peer.key, now),
+                None => false,
+            };
+            if banned == true {
+                println!("close the inbound connection from banned peer {}", connection.addr);
+                connection.close.notify_one();
+            }
+            banned == false
+        });
+        let banned_outbound: Vec<(String, String)> = {
+            let peers = self.peers.lock().unwrap();
+            peers.iter().filter(|(address, peer)| ban_list.is_banned(&address.0, &address.1, &peer.info.public_key, now))
+                .map(|(address, _)| address.clone()).collect()
+        };
+        drop(ban_list);
+        for (ip, port) in banned_outbound.iter() {
+            println!("close the connection to banned peer {}:{}", ip, port);
+            self.disconnect(ip, port);
+        }
+    }
+
+    pub fn set_limits(&self, limits: ResourceLimits) {
+        //! the limits for the connections made from now on
+        *self.limits.lock().unwrap() = limits;
+    }
+
+    pub fn get_limits(&self) -> ResourceLimits {
+        self.limits.lock().unwrap().clone()
+    }
+
+    pub fn set_chain_id(&self, chain_id: &str) {
+        //! the chain ID told to the peers connecting from now on. Peers with another chain ID are refused.
+        self.local.lock().unwrap().chain_id = chain_id.to_string();
+    }
+
+    pub fn set_local_status(&self, services: u64, best_height: usize) {
+        //! update what is told to the peers connecting from now on
+        let mut local = self.local.lock().unwrap();
+        local.services = services;
+        local.best_height = best_height;
+    }
+
+    pub fn get_peer_info(&self, ip: &str, port: &str) -> Option<PeerInfo> {
+        //! the result of the handshake of the live connection to the peer
+        match self.peers.lock().unwrap().get(&(ip.to_string(), port.to_string())) {
+            Some(peer) if peer.alive.load(Ordering::Relaxed) == true => Some(peer.info.clone()),
+            _ => None,
+        }
+    }
+
+    pub async fn send(&self, ip: &str, port: &str, msg: &str) -> bool {
+        //! queue a message to the peer, connecting to it first if there is no live connection.
+        //! It waits while the queue is full, and drops the connection if the peer does not catch up.
+        let command = message_manager::get_msg_type(msg).unwrap_or(message_manager::NONE) as u32;
+        if msg.len() > frame::get_max_payload_size(command) {
+            println!("message of {} bytes is too large to send", msg.len());
+            return false;
+        }
+        let (sender, version) = match self.get_sender(ip, port).await {
+            Some(sender) => sender,
+            None => return false,
+        };
+        // the message is sent in the version negotiated with the peer
+        let data = if version == message_manager::PROTOCOL_VERSION {
+            frame::encode_frame(command, msg.as_bytes())
+        } else {
+            match message_manager::set_version(msg, version) {
+                Some(msg) => frame::encode_frame(command, msg.as_bytes()),
+                None => frame::encode_frame(command, msg.as_bytes()),
+            }
+        };
+
+        match tokio::time::timeout(Duration::from_millis(SEND_QUEUE_TIMEOUT_MS), sender.send(data)).await {
+            Ok(Ok(_)) => {
+                if let Some(response_type) = message_manager::get_response_type(command as usize) {
+                    self.add_request(ip, port, response_type);
+                }
+                true
+            },
+            Ok(Err(_)) => {
+                println!("connection to {}:{} is closed", ip, port);
+                self.disconnect(ip, port);
+                false
+            },
+            Err(_) => {
+                println!("{}:{} is too slow. the connection is dropped", ip, port);
+                self.disconnect(ip, port);
+                false
+            }
+        }
+    }
+
+    fn add_request(&self, ip: &str, port: &str, response_type: usize) {
+        //! allow one more response of the type from the peer. Requests which are never answered count only up to a limit.
+        let mut requests = self.requests.lock().unwrap();
+        let count = requests.entry((ip.to_string(), port.to_string(), response_type)).or_insert(0);
+        *count = (*count + 1).min(MAX_PENDING_REQUESTS);
+    }
+
+    fn take_request(&self, ip: &str, port: &str, response_type: usize) -> bool {
+        //! true if the response of the type from the peer was requested
+        let mut requests = self.requests.lock().unwrap();
+        let key = (ip.to_string(), port.to_string(), response_type);
+        match requests.get_mut(&key) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                true
+            },
+            Some(_) => {
+                requests.remove(&key);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub async fn connect(&self, ip: &str, port: &str) -> bool {
+        //! connect to the peer and run the handshake unless there is a live connection
+        self.get_sender(ip, port).await.is_some()
+    }
+
+    fn get_live_sender(&self, address: &(String, String)) -> Option<(mpsc::Sender<Vec<u8>>, u32)> {
+        match self.peers.lock().unwrap().get(address) {
+            Some(peer) if peer.alive.load(Ordering::Relaxed) == true => Some((peer.sender.clone(), peer.info.version)),
+            _ => None,
+        }
+    }
+
+    async fn get_sender(&self, ip: &str, port: &str) -> Option<(mpsc::Sender<Vec<u8>>, u32)> {
+        //! the queue of the live connection to the peer and the negotiated version
+        let key = (ip.to_string(), port.to_string());
+        if let Some(sender) = self.get_live_sender(&key) {
+            return Some(sender);
+        }
+        let _connecting = self.connecting.lock().await;
+        if let Some(sender) = self.get_live_sender(&key) {
+            return Some(sender);
+        }
+        {
+            let mut peers = self.peers.lock().unwrap();
+            peers.retain(|_, peer| peer.alive.load(Ordering::Relaxed) == true);
+            let max_outbound = self.limits.lock().unwrap().max_outbound;
+            if peers.len() >= max_outbound {
+                println!("no outbound slot for {}:{}", ip, port);
+                return None;
+            }
+        }
+        let local = self.local.lock().unwrap().clone();
+        let node_key = self.node_key.lock().unwrap().clone();
+        match connect(ip, port, &local, &node_key, self).await {
+            Ok(peer) => {
+                let info = &peer.info;
+                println!("handshake with {}:{} ({}): version {}, services {}, features {}, height {}, key {}", ip, port, info.user_agent, info.version, info.services, info.features, info.best_height, info.public_key);
+                self.known_keys.lock().unwrap().entry(key.clone()).or_insert(info.public_key.clone());
+                let sender = (peer.sender.clone(), peer.info.version);
+                self.peers.lock().unwrap().insert(key, peer);
+                Some(sender)
+            },
+            Err(e) => {
+                println!("cannot connect to {}:{}: {}", ip, port, e);
+                self.peers.lock().unwrap().remove(&key);
+                None
+            }
+        }
+    }
+
+    pub fn disconnect(&self, ip: &str, port: &str) {
+        //! dropping the queue stops the writer task, which closes the connection
+        self.peers.lock().unwrap().remove(&(ip.to_string(), port.to_string()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().values().filter(|p| p.alive.load(Ordering::Relaxed) == true).count()
+    }
+
+    pub fn inbound_len(&self) -> usize {
+        self.inbound.lock().unwrap().len()
+    }
+
+    fn add_inbound(&self, addr: &str, host: &str) -> Option<(usize, Arc<Notify>)> {
+        //! take an inbound slot for an accepted connection. When the slots are full, the least useful
+        //! inbound peer is evicted. It returns None if there is no slot.
+        let max_inbound = self.limits.lock().unwrap().max_inbound;
+        let mut inbound = self.inbound.lock().unwrap();
+        if inbound.len() >= max_inbound {
+            let id = select_eviction(&inbound)?;
+            let evicted = inbound.remove(&id).unwrap();
+            println!("evict the inbound connection from {}", evicted.addr);
+            evicted.close.notify_one();
+        }
+        let id = self.next_inbound_id.fetch_add(1, Ordering::Relaxed);
+        let close = Arc::new(Notify::new());
+        inbound.insert(id, InboundConnection {
+            close: Arc::clone(&close),
+            addr: addr.to_string(),
+            host: host.to_string(),
+            peer: None,
+            connected_at: Instant::now(),
+            last_useful: None
+        });
+        Some((id, close))
+    }
+
+    fn remove_inbound(&self, id: usize) {
+        self.inbound.lock().unwrap().remove(&id);
+    }
+
+    fn set_inbound_peer(&self, id: usize, peer: &Peer, info: &PeerInfo) {
+        if let Some(connection) = self.inbound.lock().unwrap().get_mut(&id) {
+            connection.peer = Some((peer.clone(), info.clone()));
+        }
+    }
+
+    pub fn mark_useful(&self, peer: &Peer) {
+        //! the inbound connections of a peer whose block or transaction was accepted
+        for connection in self.inbound.lock().unwrap().values_mut() {
+            if let Some((p, _)) = &connection.peer {
+                if p.key == peer.key {
+                    connection.last_useful = Some(Instant::now());
+                }
+            }
+        }
+    }
+}
+
+fn select_eviction(inbound: &HashMap<usize, InboundConnection>) -> Option<usize> {
+    //! the peer which sent a block or a transaction least recently, or never. Among the peers which never
+    //! did, the newest connection goes first, so that a flood of connections evicts itself.
+    inbound.iter()
+        .min_by(|(_, a), (_, b)| a.last_useful.cmp(&b.last_useful).then(b.connected_at.cmp(&a.connected_at)))
+        .map(|(id, _)| *id)
+}
+
+async fn connect(ip: &str, port: &str, local: &VersionMessage, node_key: &NodeKey, peers: &PeerConnections) -> io::Result<PeerConnection> {
+    let server_addr = ip.to_string() + ":" + port;
+    let stream = noise::with_timeout(CONNECT_TIMEOUT_MS, TcpStream::connect(&server_addr)).await?;
+    stream.set_nodelay(true)?;
+    let mut stream = noise::handshake_as_initiator(stream, node_key).await?;
+    peers.check_pinned_key(ip, port, &stream.get_remote_key())?;
+    let (_, info) = handshake::handshake_as_dialer(&mut stream, local).await?;
+    println!("connected to {}", &server_addr);
+
+    let ping = message_manager::build_with_version(message_manager::MSG_PING, &local.ip, &local.port, "", info.version);
+    let ping = frame::encode_frame(message_manager::MSG_PING as u32, ping.as_bytes());
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(MAX_QUEUED_FRAMES);
+    let alive = Arc::new(AtomicBool::new(true));
+    let alive_clone = Arc::clone(&alive);
+    tokio::spawn(async move {
+        loop {
+            let data = match tokio::time::timeout(Duration::from_secs(PING_INTERVAL_SECS), receiver.recv()).await {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(_) => ping.clone(),
+            };
+            if let Err(e) = noise::with_timeout(WRITE_TIMEOUT_MS, stream.write_all(&data)).await {
+                println!("Send error to {}: {}", server_addr, e);
+                break;
+            }
+        }
+        alive_clone.store(false, Ordering::Relaxed);
+        let _ = stream.shutdown().await;
+    });
+
+    Ok(PeerConnection {
+        sender: sender,
+        alive: alive,
+        info: info
+    })
+}
+
+pub fn start_reader(peers: Arc<PeerConnections>, stream: TcpStream, tx: mpsc::UnboundedSender<NodeEvent>) {
+    //! take an inbound slot for an accepted connection and read its messages in its own task
+    tokio::spawn(async move {
+        let (peer_addr, host) = match stream.peer_addr() {
+            Ok(addr) => (addr.to_string(), addr.ip().to_string()),
+            Err(_) => (String::new(), String::new()),
+        };
+        let (id, close) = match peers.add_inbound(&peer_addr, &host) {
+            Some(inbound) => inbound,
+            None => {
+                println!("no inbound slot for {}", peer_addr);
+                return;
+            }
+        };
+        // an evicted or banned connection is closed by dropping the reader
+        tokio::select! {
+            _ = close.notified() => {},
+            _ = read_messages(&peers, id, stream, &peer_addr, &host, &tx) => {},
+        }
+        peers.remove_inbound(id);
+    });
+}
+
+pub fn start_listener(peers: Arc<PeerConnections>, listener: TcpListener, tx: mpsc::UnboundedSender<NodeEvent>) {
+    //! accept connections in their own task and read each of them with start_reader
+    tokio::spawn(async move {
+        loop {
+            if let Ok((stream, _)) = listener.accept().await {
+                start_reader(Arc::clone(&peers), stream, tx.clone());
+            }
+        }
+    });
+}
+
+async fn read_messages(peers: &PeerConnections, id: usize, stream: TcpStream, peer_addr: &str, host: &str, tx: &mpsc::UnboundedSender<NodeEvent>) {
+    //! run the handshakes and read frames until the connection is closed, sends an invalid frame or keeps
+    //! sending over the rate limits. Messages over the rate limits are dropped. The messages are passed
+    //! with the identity of the connection.
+    let node_key = peers.node_key.lock().unwrap().clone();
+    let mut stream = match noise::handshake_as_responder(stream, &node_key).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                println!("encrypted handshake with {} failed: {}", peer_addr, e);
+            }
+            return;
+        }
+    };
+    let local = peers.local.lock().unwrap().clone();
+    let result = match handshake::handshake_as_acceptor(&mut stream, &local).await {
+        Ok((remote, info)) => peers.check_identity(host, &remote.ip, &remote.port, &info.public_key).map(|_| (remote, info)),
+        Err(e) => Err(e),
+    };
+    let (sender, info) = match result {
+        Ok((remote, info)) => {
+            println!("{} is {}:{} ({}) with key {}, version {}", peer_addr, remote.ip, remote.port, info.user_agent, info.public_key, info.version);
+            (Peer {
+                ip: remote.ip,
+                port: remote.port,
+                key: info.public_key.clone()
+            }, info)
+        },
+        Err(e) => {
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                println!("handshake with {} failed: {}", peer_addr, e);
+            }
+            return;
+        }
+    };
+    if peers.is_banned(&sender.ip, &sender.port, &sender.key) == true {
+        println!("connection from banned peer {}:{} is closed", sender.ip, sender.port);
+        return;
+    }
+    peers.set_inbound_peer(id, &sender, &info);
+    let limits = peers.get_limits();
+    let mut rate_limiter = RateLimiter::create(&limits);
+    loop {
+        // a peer pings its connections, so a connection without messages is dead or held open by an attacker
+        let (command, payload) = match noise::with_timeout(limits.idle_timeout_secs.saturating_mul(1000), frame::read_frame(&mut stream)).await {
+            Ok(result) => result,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::TimedOut {
+                    println!("drop the connection from {}: idle for {} seconds", peer_addr, limits.idle_timeout_secs);
+                }
+                else if e.kind() != io::ErrorKind::UnexpectedEof {
+                    println!("drop the connection from {}: {}", peer_addr, e);
+                }
+                break;
+            }
+        };
+        if rate_limiter.allow(command as usize, Instant::now()) == false {
+            if rate_limiter.is_abusive() == true {
+                println!("drop the connection from {}: too many messages over the rate limits", peer_addr);
+                break;
+            }
+            continue;
+        }
+        let msg = match String::from_utf8(payload) {
+            Ok(msg) => msg,
+            Err(_) => {
+                println!("drop the connection from {}: payload is not UTF-8", peer_addr);
+                break;
+            }
+        };
+        let version = match message_manager::get_msg_type_and_version(&msg) {
+            Some((msg_type, version)) if msg_type == command as usize => version,
+            _ => {
+                println!("drop the connection from {}: command is not matched", peer_addr);
+                break;
+            }
+        };
+        if version.map(|v| v > info.version) != Some(false) {
+            println!("message of version {:?} from {} is dropped: version {} is negotiated", version, peer_addr, info.version);
+            continue;
+        }
+        if message_manager::is_local_command(command as usize) == true {
+            println!("local command {} from {} is dropped", command, peer_addr);
+            continue;
+        }
+        if limits.drop_unrequested == true && message_manager::is_requested_response(command as usize) == true && peers.take_request(&sender.ip, &sender.port, command as usize) == false {
+            println!("response {} from {} was not requested and is dropped", command, peer_addr);
+            continue;
+        }
+        if command as usize == message_manager::MSG_PING {
+            // pings only keep the connection alive
+            continue;
+        }
+        if tx.send(NodeEvent::Remote(sender.clone(), msg)).is_err() {
+            break;
+        }
+    }
+}
+
+pub async fn run() {
+    //! send messages with newlines over one connection, a frame split into pieces, invalid frames and a
+    //! message from a connection which claims the address of another node. At last it bans the key of the sender.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().to_string();
+    let (tx, mut rx) = mpsc::unbounded_channel::<NodeEvent>();
+    let core = Arc::new(PeerConnections::create("127.0.0.1", &port, handshake::NODE_FULL));
+    start_listener(Arc::clone(&core), listener, tx.clone());
+
+    // the sender listens at the address it claims
+    let my_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let my_port = my_listener.local_addr().unwrap().port().to_string();
+    let peers = Arc::new(PeerConnections::create("127.0.0.1", &my_port, handshake::NODE_LIGHT));
+    let (my_tx, _my_rx) = mpsc::unbounded_channel::<NodeEvent>();
+    start_listener(Arc::clone(&peers), my_listener, my_tx);
+    let payload = "untrusted comment: minisign public key\nRWSlKRTQyvmLrnX0\n";
+    for i in 0..3 {
+        let msg = message_manager::build(message_manager::MSG_ENHANCED, "127.0.0.1", &i.to_string(), payload);
+        peers.send("127.0.0.1", &port, &msg).await;
+    }
+    for _ in 0..3 {
+        if let Ok(Some(NodeEvent::Remote(sender, msg))) = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await {
+            if let Ok(Message::Enhanced(payload)) = message::decode(&msg) {
+                println!("message from port {} with {} lines", sender.port, payload.lines().count());
+            }
+        }
+    }
+    println!("{} connection is used", peers.len());
+
+    // pings only keep a connection alive, so the demo sends a request for addresses
+    let msg = message_manager::build(message_manager::MSG_GET_ADDR, "127.0.0.1", "0", "");
+    let data = frame::encode_frame(message_manager::MSG_GET_ADDR as u32, msg.as_bytes());
+    let my_version = VersionMessage::create("127.0.0.1", &my_port, handshake::NODE_LIGHT);
+    let stream = TcpStream::connect("127.0.0.1:".to_string() + &port).await.unwrap();
+    let mut stream = noise::handshake_as_initiator(stream, &peers.get_node_key()).await.unwrap();
+    handshake::handshake_as_dialer(&mut stream, &my_version).await.unwrap();
+    for piece in data.chunks(7) {
+        stream.write_all(piece).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let received = match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await {
+        Ok(Some(NodeEvent::Remote(_, received))) => received == msg,
+        _ => false,
+    };
+    println!("split frame is received: {}", received);
+
+    let mut bad_checksum = data.clone();
+    bad_checksum[frame::HEADER_SIZE] ^= 1;
+    let mut too_large = frame::encode_frame(0, b"");
+    too_large[8..12].copy_from_slice(&((frame::MAX_PAYLOAD_SIZE + 1) as u32).to_le_bytes());
+    for bad in [bad_checksum, too_large, b"not a frame at all".to_vec()].iter() {
+        let stream = TcpStream::connect("127.0.0.1:".to_string() + &port).await.unwrap();
+        let mut stream = noise::handshake_as_initiator(stream, &peers.get_node_key()).await.unwrap();
+        handshake::handshake_as_dialer(&mut stream, &my_version).await.unwrap();
+        stream.write_all(bad).await.unwrap();
+        let _ = stream.write_all(&data).await;
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    println!("frames after invalid frames are dropped: {}", rx.try_recv().is_err());
+
+    let stream = TcpStream::connect("127.0.0.1:".to_string() + &port).await.unwrap();
+    let mut stream = noise::handshake_as_initiator(stream, &NodeKey::generate()).await.unwrap();
+    let spoofed = handshake::handshake_as_dialer(&mut stream, &my_version).await.is_ok() && stream.write_all(&data).await.is_ok();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    println!("message with the address of another node is dropped: {}", spoofed == false || rx.try_recv().is_err());
+
+    let now = Local::now().timestamp();
+    core.get_ban_list().lock().unwrap().ban(&ban_list::get_key_address(&peers.get_public_key()), now + 60, "misbehaviour");
+    core.disconnect_banned();
+    let inbound = core.inbound_len();
+    let sent = peers.send("127.0.0.1", &port, &msg).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    println!("inbound connections after the ban: {}, message of the banned key is dropped: {}", inbound, sent == false || rx.try_recv().is_err());
+}
@@ -0,0 +1,302 @@
+/*  
+    peer_db.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! The database of core node addresses. Addresses are learned from core lists, joining nodes and addr gossip,
+//! and each record keeps when the node was last seen and how often it answered, so that a node can select
+//! peers to connect to when it loses its original contact. The database is saved as JSON.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+/// The maximum number of addresses in the database
+pub const MAX_PEER_RECORDS: usize = 1000;
+/// The maximum number of addresses in an addr message
+pub const MAX_ADDR_PER_MESSAGE: usize = 100;
+/// Addresses not seen for this many seconds are not gossiped
+pub const ADDR_HORIZON_SECS: i64 = 3 * 24 * 60 * 60;
+/// The time in seconds before a failed address is tried again. It doubles with each consecutive failure.
+pub const RETRY_INTERVAL_SECS: i64 = 60;
+/// Addresses which never answered are forgotten after this many consecutive failures
+pub const MAX_FAILURES: u64 = 10;
+/// The maximum number of addresses which were told by the nodes at one IP address and never answered
+pub const MAX_ADDRESSES_PER_SOURCE: usize = 64;
+
+/// An address with the time it was last seen, as gossiped in addr messages
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerAddress {
+    pub ip: String,
+    pub port: String,
+    pub last_seen: i64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerRecord {
+    pub ip: String,
+    pub port: String,
+    /// the last time the node answered, or the time another node told us for an address which never answered
+    pub last_seen: i64,
+    /// the last time we tried to connect to the node
+    pub last_attempt: i64,
+    /// the number of times the node answered
+    pub successes: u64,
+    /// the number of consecutive failures to connect to the node
+    pub failures: u64,
+    /// the IP address of the node which told us about the address. It is empty once the node answered.
+    #[serde(default)]
+    pub source: String
+}
+
+impl PeerRecord {
+    pub fn is_retry_time(&self, now: i64) -> bool {
+        //! a failed address is retried after an exponential backoff
+        if self.failures == 0 {
+            return true;
+        }
+        let backoff = RETRY_INTERVAL_SECS << self.failures.min(6);
+        now.saturating_sub(self.last_attempt) >= backoff
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerDB {
+    /// the file the database is saved to. An empty path keeps the database in memory.
+    #[serde(skip)]
+    pub path: String,
+    /// the records by "ip:port"
+    pub peers: HashMap<String, PeerRecord>
+}
+
+fn get_key(ip: &str, port: &str) -> String {
+    ip.to_string() + ":" + port
+}
+
+impl PeerDB {
+    pub fn create(path: &str) -> PeerDB {
+        PeerDB {
+            path: path.to_string(),
+            peers: HashMap::new()
+        }
+    }
+
+    pub fn load(path: &str) -> PeerDB {
+        //! read the database from path. A missing or broken file gives an empty database.
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => {
+                println!("no peer database at {}", path);
+                return PeerDB::create(path);
+            }
+        };
+        match serde_json::from_str::<PeerDB>(&content) {
+            Ok(mut peer_db) => {
+                println!("{} peer addresses are loaded from {}", peer_db.peers.len(), path);
+                peer_db.path = path.to_string();
+                peer_db
+            },
+            Err(e) => {
+                println!("invalid peer database {}: {}", path, e);
+                PeerDB::create(path)
+            }
+        }
+    }
+
+    pub fn save(&self) -> bool {
+        if self.path.len() == 0 {
+            return false;
+        }
+        match fs::write(&self.path, serde_json::to_string(self).unwrap()) {
+            Ok(_) => true,
+            Err(e) => {
+                println!("cannot save the peer database to {}: {}", self.path, e);
+                false
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn get(&self, ip: &str, port: &str) -> Option<&PeerRecord> {
+        self.peers.get(&get_key(ip, port))
+    }
+
+    pub fn add(&mut self, ip: &str, port: &str, last_seen: i64, source: &str) -> bool {
+        //! add an address told by the node at the IP address source. Known addresses are not updated, since only
+        //! a node which answers is seen, and a source can add only MAX_ADDRESSES_PER_SOURCE addresses which never answered.
+        let key = get_key(ip, port);
+        if self.peers.contains_key(&key) == true {
+            return false;
+        }
+        if self.peers.values().filter(|r| r.source == source).count() >= MAX_ADDRESSES_PER_SOURCE {
+            return false;
+        }
+        self.insert(key, ip, port, last_seen, source);
+        true
+    }
+
+    fn insert(&mut self, key: String, ip: &str, port: &str, last_seen: i64, source: &str) {
+        if self.peers.len() >= MAX_PEER_RECORDS {
+            self.evict();
+        }
+        self.peers.insert(key, PeerRecord {
+            ip: ip.to_string(),
+            port: port.to_string(),
+            last_seen: last_seen,
+            last_attempt: 0,
+            successes: 0,
+            failures: 0,
+            source: source.to_string()
+        });
+    }
+
+    pub fn mark_success(&mut self, ip: &str, port: &str, now: i64) {
+        //! record that the node answered. It is the only update of when a node was seen.
+        let key = get_key(ip, port);
+        if self.peers.contains_key(&key) == false {
+            self.insert(key.clone(), ip, port, now, "");
+        }
+        if let Some(record) = self.peers.get_mut(&key) {
+            record.last_seen = now;
+            record.last_attempt = now;
+            record.successes += 1;
+            record.failures = 0;
+            record.source = String::new();
+        }
+    }
+
+    pub fn mark_failure(&mut self, ip: &str, port: &str, now: i64) {
+        //! record that a connection to the node failed. Addresses which never answered are forgotten after MAX_FAILURES.
+        let key = get_key(ip, port);
+        let forget = match self.peers.get_mut(&key) {
+            Some(record) => {
+                record.last_attempt = now;
+                record.failures += 1;
+                record.successes == 0 && record.failures >= MAX_FAILURES
+            },
+            None => false,
+        };
+        if forget == true {
+            self.peers.remove(&key);
+        }
+    }
+
+    fn evict(&mut self) {
+        //! remove the address with the most failures, and the oldest one among them
+        let worst = self.peers.iter()
+            .max_by_key(|(_, r)| (r.failures, -r.last_seen))
+            .map(|(k, _)| k.clone());
+        if let Some(key) = worst {
+            self.peers.remove(&key);
+        }
+    }
+
+    pub fn select_peers(&self, count: usize, exclude: &HashSet<(String, String)>, now: i64) -> Vec<(String, String)> {
+        //! select addresses to connect to. Nodes which answered most often come first, then the recently seen ones.
+        //! Addresses which failed recently are skipped until their backoff passes.
+        let mut candidates: Vec<&PeerRecord> = self.peers.values()
+            .filter(|r| exclude.contains(&(r.ip.clone(), r.port.clone())) == false && r.is_retry_time(now) == true)
+            .collect();
+        candidates.sort_by(|a, b| b.successes.cmp(&a.successes).then(b.last_seen.cmp(&a.last_seen)));
+        candidates.iter().take(count).map(|r| (r.ip.clone(), r.port.clone())).collect()
+    }
+
+    pub fn get_addresses(&self, now: i64) -> Vec<PeerAddress> {
+        //! the recently seen addresses for an addr message, newest first
+        let mut records: Vec<&PeerRecord> = self.peers.values().filter(|r| now.saturating_sub(r.last_seen) <= ADDR_HORIZON_SECS).collect();
+        records.sort_by_key(|r| std::cmp::Reverse(r.last_seen));
+        records.iter().take(MAX_ADDR_PER_MESSAGE).map(|r| PeerAddress {
+            ip: r.ip.clone(),
+            port: r.port.clone(),
+            last_seen: r.last_seen
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_000_000;
+
+    fn create_peer_db(path: &str) -> PeerDB {
+        //! 5 addresses told by one node, of which 50083 answered twice, 50084 once and 50080 failed
+        let mut peer_db = PeerDB::create(path);
+        for i in 0..5 {
+            assert!(peer_db.add("127.0.0.1", &(50080 + i).to_string(), NOW - i as i64 * 10, "127.0.0.2"));
+        }
+        peer_db.mark_success("127.0.0.1", "50083", NOW);
+        peer_db.mark_success("127.0.0.1", "50083", NOW + 1);
+        peer_db.mark_success("127.0.0.1", "50084", NOW);
+        peer_db.mark_failure("127.0.0.1", "50080", NOW);
+        peer_db
+    }
+
+    fn get_ports(selected: &Vec<(String, String)>) -> Vec<&str> {
+        selected.iter().map(|(_, port)| port.as_str()).collect()
+    }
+
+    #[test]
+    fn peers_are_selected_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("peers-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(create_peer_db(path).save());
+        let peer_db = PeerDB::load(path);
+        fs::remove_file(path).unwrap();
+        assert_eq!(peer_db.len(), 5);
+
+        // the nodes which answered come first, and the failed address waits for its backoff
+        let selected = peer_db.select_peers(3, &HashSet::new(), NOW + 10);
+        assert_eq!(get_ports(&selected), vec!["50083", "50084", "50081"]);
+        let selected = peer_db.select_peers(10, &HashSet::new(), NOW + RETRY_INTERVAL_SECS * 2);
+        assert_eq!(get_ports(&selected), vec!["50083", "50084", "50080", "50081", "50082"]);
+        let exclude: HashSet<(String, String)> = vec![("127.0.0.1".to_string(), "50083".to_string())].into_iter().collect();
+        assert_eq!(get_ports(&peer_db.select_peers(1, &exclude, NOW + 10)), vec!["50084"]);
+    }
+
+    #[test]
+    fn gossip_does_not_make_an_address_seen() {
+        let mut peer_db = create_peer_db("");
+        assert!(peer_db.add("127.0.0.1", "50080", NOW + 100, "127.0.0.3") == false);
+        assert_eq!(peer_db.get("127.0.0.1", "50080").unwrap().last_seen, NOW);
+        assert_eq!(peer_db.get_addresses(NOW).len(), 5);
+        assert_eq!(peer_db.get_addresses(NOW + ADDR_HORIZON_SECS).len(), 3);
+        assert!(peer_db.save() == false);
+    }
+
+    #[test]
+    fn one_source_cannot_fill_the_database() {
+        let mut peer_db = create_peer_db("");
+        let added = (0..200).filter(|i| peer_db.add("10.0.0.1", &i.to_string(), NOW, "127.0.0.3") == true).count();
+        assert_eq!(added, MAX_ADDRESSES_PER_SOURCE);
+    }
+
+    #[test]
+    fn address_which_never_answered_is_forgotten() {
+        let mut peer_db = create_peer_db("");
+        for i in 1..MAX_FAILURES {
+            peer_db.mark_failure("127.0.0.1", "50080", NOW + i as i64);
+            peer_db.mark_failure("127.0.0.1", "50083", NOW + i as i64);
+        }
+        assert!(peer_db.get("127.0.0.1", "50080").is_none());
+        assert_eq!(peer_db.get("127.0.0.1", "50083").unwrap().failures, MAX_FAILURES - 1);
+    }
+}
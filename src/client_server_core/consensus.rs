@@ -0,0 +1,134 @@
+/*  
+    consensus.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Consensus engines. A consensus engine decides when a node produces a block, seals it,
+//! validates blocks from other nodes and chooses between competing chains.
+
+pub mod pow;
+pub mod poa;
+pub mod pos;
+
+use crate::client_server_core::block_chain::{Block, BlockChain, BlockHeader, Transaction};
+use crate::client_server_core::block_chain::keymanager::KeyManager;
+use crate::client_server_core::chain_spec::ChainSpec;
+use crate::client_server_core::consensus::pow::algorithm::PowAlgorithm;
+use crate::client_server_core::verify_signature;
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+pub trait Consensus: Send + Sync {
+    fn name(&self) -> String;
+
+    /// It returns true if this node should produce a new block now.
+    /// last_block_time is the last time when this node produced a block or received a new one.
+    fn is_ready_to_produce(&self, bc: &BlockChain, km: &KeyManager, last_block_time: i64, now: i64) -> bool;
+
+    /// It seals a new block so that other nodes can validate it. It returns false if it was stopped or failed.
+    fn seal(&self, block: &mut Block, km: &KeyManager, to_stop: &Arc<AtomicBool>) -> bool;
+
+    /// It validates a block on top of its ancestors. ancestors[0] is the genesis block.
+    fn is_valid_block(&self, block: &Block, ancestors: &[Block]) -> bool;
+
+    /// It checks the proof of work or the seal of a header whose transactions are not known yet, e.g. of a
+    /// compact block. The parent of the header may be unknown, so the sealer is checked against the chain
+    /// of this node, and against its tip only if the header extends it.
+    fn is_valid_header(&self, header: &BlockHeader, ancestors: &[Block]) -> bool;
+
+    /// It returns true if the candidate chain should replace the current chain.
+    fn choose_fork(&self, current: &BlockChain, candidate: &BlockChain) -> bool;
+
+    /// It returns true if validating a block replays the transactions of its ancestors, so that their bodies cannot be pruned.
+    fn needs_block_bodies(&self) -> bool {
+        false
+    }
+
+    /// It returns true if a transaction without inputs carries a payload of this consensus, e.g. a governance vote
+    /// or equivocation evidence, which is valid on top of bc. Other transactions without inputs are not pooled.
+    fn is_valid_payload(&self, _transaction: &Transaction, _bc: &BlockChain) -> bool {
+        false
+    }
+
+    /// It returns the hash function of proof of work, or None for a consensus without proof of work.
+    fn get_pow_algorithm(&self) -> Option<Arc<dyn PowAlgorithm>> {
+        None
+    }
+
+    /// It checks that a chain starts at the genesis block and validates the blocks from fork_height. The blocks
+    /// below fork_height are in the chain of this node already, and a pruned node has only their headers, so
+    /// only the headers are checked.
+    fn is_valid_chain(&self, bc: &BlockChain, fork_height: usize) -> bool {
+        if bc.chain.len() == 0 {
+            return false;
+        }
+        if bc.chain[0].get_hash() != Block::create_genesis_block().get_hash() {
+            println!("chain starts at another genesis block");
+            return false;
+        }
+        for i in 1..fork_height.min(bc.chain.len()) {
+            if bc.chain[i].previous_block != bc.chain[i - 1].get_hash() || self.is_valid_header(&bc.chain[i].get_header(), &bc.chain[..i]) == false {
+                println!("invalid header at {} under {}", i, self.name());
+                return false;
+            }
+        }
+        for i in fork_height.max(1)..bc.chain.len() {
+            if self.is_valid_block(&bc.chain[i], &bc.chain[..i]) == false {
+                println!("invalid block at {} under {}", i, self.name());
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub fn create_consensus(spec: &ChainSpec) -> Option<Arc<dyn Consensus>> {
+    //! create the consensus engine declared by a chain spec
+    match spec.consensus.as_str() {
+        "pow" => {
+            let algorithm = pow::algorithm::create_pow_algorithm(&spec.pow_algorithm)?;
+            Some(Arc::new(pow::ProofOfWork::create_with_algorithm(algorithm)))
+        },
+        "poa" => Some(Arc::new(poa::ProofOfAuthority::create(&spec.authorities, spec.block_period))),
+        "pos" => Some(Arc::new(pos::ProofOfStake::create(&spec.genesis_stakes, spec.slot_duration, spec.active_slot_coefficient))),
+        _ => None,
+    }
+}
+
+pub fn sign_block(block: &mut Block, km: &KeyManager) {
+    //! set the sealer of a block and sign the block with an empty seal
+    block.sealer = km.my_address();
+    block.seal = String::new();
+    let to_be_signed = block.get_header().to_string();
+    block.seal = km.compute_digital_signature(&to_be_signed);
+}
+
+pub fn verify_block_seal(block: &Block) -> bool {
+    //! verify the seal of a block with the address of its sealer
+    verify_header_seal(&block.get_header())
+}
+
+pub fn verify_header_seal(header: &BlockHeader) -> bool {
+    //! verify the seal of a block header. Light clients check seals without the transactions.
+    if header.sealer.len() == 0 || header.seal.len() == 0 {
+        return false;
+    }
+    let mut unsealed = header.clone();
+    unsealed.seal = String::new();
+    verify_signature(&unsealed.to_string(), &header.seal, &header.sealer)
+}
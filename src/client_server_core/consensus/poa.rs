@@ -0,0 +1,457 @@
+/*  
+    poa.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Proof of authority. The signers listed in the chain spec take turns to seal blocks with their signature.
+//! If the signer in turn is offline, the next signers may seal the block out of turn after a delay. Such blocks
+//! weigh less, so the chain with the most blocks sealed in turn wins.
+//! A signer is added or removed by governance transactions once more than half of the current signers voted for it.
+//! Votes are cast for an epoch and count once, in a block of that epoch. Votes which did not pass expire with their epoch.
+
+use crate::client_server_core::block_chain::{Block, BlockChain, BlockHeader, Transaction, get_double_sha256};
+use crate::client_server_core::block_chain::keymanager::KeyManager;
+use crate::client_server_core::consensus::{Consensus, sign_block, verify_block_seal, verify_header_seal};
+use crate::client_server_core::verify_signature;
+
+use chrono::{DateTime, Utc};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub const GOVERNANCE_ADD_SIGNER: &str = "add";
+pub const GOVERNANCE_REMOVE_SIGNER: &str = "remove";
+/// The number of blocks in an epoch of governance votes
+pub const GOVERNANCE_EPOCH_LENGTH: usize = 100;
+
+/// The weight of a block sealed by the signer in turn
+pub const IN_TURN_WEIGHT: u64 = 2;
+/// The weight of a block sealed out of turn. A chain of signers in turn outweighs a chain of the same length.
+pub const OUT_OF_TURN_WEIGHT: u64 = 1;
+/// The number of seconds a block may be ahead of the clock of a node
+pub const MAX_FUTURE_BLOCK_SECS: i64 = 15;
+/// The number of governance states kept. The states at the start of an epoch are kept longest.
+pub const MAX_GOVERNANCE_STATES: usize = 4096;
+
+/// The governance of a chain after its last block: the signers for the next block, the votes of the current epoch,
+/// the recent sealers and the total weight of the chain
+struct GovernanceState {
+    height: usize,
+    authorities: Vec<String>,
+    votes: HashMap<(String, String), HashSet<String>>,
+    used_votes: HashSet<String>,
+    recent_sealers: VecDeque<String>,
+    weight: u64
+}
+
+impl GovernanceState {
+    fn create(authorities: &Vec<String>) -> GovernanceState {
+        GovernanceState {
+            height: 0,
+            authorities: authorities.clone(),
+            votes: HashMap::new(),
+            used_votes: HashSet::new(),
+            recent_sealers: VecDeque::new(),
+            weight: 0
+        }
+    }
+
+    fn clone(&self) -> GovernanceState {
+        GovernanceState {
+            height: self.height,
+            authorities: self.authorities.clone(),
+            votes: self.votes.clone(),
+            used_votes: self.used_votes.clone(),
+            recent_sealers: self.recent_sealers.clone(),
+            weight: self.weight
+        }
+    }
+
+    fn get_in_turn_signer(&self) -> String {
+        //! the signer whose turn is to seal the next block
+        if self.authorities.len() == 0 {
+            return String::new();
+        }
+        self.authorities[self.height % self.authorities.len()].clone()
+    }
+
+    fn get_out_of_turn_delay(&self, sealer: &str) -> Option<usize> {
+        //! the number of block periods after which a signer may seal the next block out of turn. The signers after
+        //! the one in turn wait one more period each, and a signer which sealed one of the last N/2 blocks waits
+        //! until it is in turn, so that no signer seals a chain alone. None if sealer is not a signer.
+        let position = self.authorities.iter().position(|a| a == sealer)?;
+        let distance = (position + self.authorities.len() - self.height % self.authorities.len()) % self.authorities.len();
+        let recent = self.authorities.len() / 2;
+        if distance > 0 && self.recent_sealers.iter().rev().take(recent).any(|s| s == sealer) == true {
+            return None;
+        }
+        Some(distance + 1)
+    }
+
+    fn apply_block(&mut self, block: &Block) {
+        //! count the seal and the governance votes of the next block
+        let height = self.height;
+        if height.is_multiple_of(GOVERNANCE_EPOCH_LENGTH) {
+            // votes count only in their epoch, so a vote of an earlier epoch cannot be replayed
+            self.votes.clear();
+            self.used_votes.clear();
+        }
+        if height > 0 {
+            self.weight += if block.sealer == self.get_in_turn_signer() { IN_TURN_WEIGHT } else { OUT_OF_TURN_WEIGHT };
+            self.recent_sealers.push_back(block.sealer.clone());
+            while self.recent_sealers.len() > self.authorities.len() {
+                self.recent_sealers.pop_front();
+            }
+        }
+
+        for t in block.transaction_pool.transactions.iter() {
+            if is_valid_governance_transaction(t, &self.authorities, height) == false {
+                continue;
+            }
+            // a vote copied into another block does not count again
+            if self.used_votes.insert(get_vote_id(t)) == false {
+                continue;
+            }
+            let g = t.governance.as_ref().unwrap();
+            let key = (g.action.clone(), g.signer.clone());
+            let voters = self.votes.entry(key.clone()).or_default();
+            voters.insert(g.proposer.clone());
+
+            if voters.len() * 2 > self.authorities.len() {
+                println!("governance: {} signer {}", g.action, g.signer);
+                if g.action == GOVERNANCE_ADD_SIGNER && self.authorities.contains(&g.signer) == false {
+                    self.authorities.push(g.signer.clone());
+                }
+                else if g.action == GOVERNANCE_REMOVE_SIGNER {
+                    self.authorities.retain(|a| a.ne(&g.signer));
+                    for v in self.votes.values_mut() {
+                        v.remove(&g.signer);
+                    }
+                }
+                self.votes.remove(&key);
+            }
+        }
+        self.height += 1;
+    }
+}
+
+pub struct ProofOfAuthority {
+    authorities: Vec<String>,
+    block_period: i64,
+    /// the governance states by the hash of the last block of their chain
+    states: Mutex<HashMap<String, GovernanceState>>
+}
+
+impl ProofOfAuthority {
+    pub fn create(authorities: &Vec<String>, block_period: i64) -> ProofOfAuthority {
+        ProofOfAuthority {
+            authorities: authorities.clone(),
+            block_period: block_period,
+            states: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn get_authorities(&self, ancestors: &[Block]) -> Vec<String> {
+        //! return the signers for the block on top of the ancestors
+        self.get_state(ancestors).authorities
+    }
+
+    fn get_state(&self, ancestors: &[Block]) -> GovernanceState {
+        //! return the governance after the ancestors. It replays the blocks after the last ancestor whose state is kept.
+        let mut states = self.states.lock().unwrap();
+        let mut hashes: Vec<String> = Vec::new();
+        let mut state = GovernanceState::create(&self.authorities);
+        for block in ancestors.iter().rev() {
+            let hash = block.get_hash();
+            if let Some(kept) = states.get(&hash) {
+                state = kept.clone();
+                break;
+            }
+            hashes.push(hash);
+        }
+
+        for block in ancestors[state.height..].iter() {
+            state.apply_block(block);
+            let hash = hashes.pop().unwrap();
+            if states.len() >= MAX_GOVERNANCE_STATES {
+                states.retain(|_, s| s.height.is_multiple_of(GOVERNANCE_EPOCH_LENGTH));
+                if states.len() >= MAX_GOVERNANCE_STATES {
+                    states.clear();
+                }
+            }
+            states.insert(hash, state.clone());
+        }
+        state
+    }
+
+    pub fn get_in_turn_signer(&self, ancestors: &[Block]) -> String {
+        //! return the signer whose turn is to seal the block on top of the ancestors
+        self.get_state(ancestors).get_in_turn_signer()
+    }
+
+    fn is_allowed_sealer(&self, state: &GovernanceState, parent: &Block, sealer: &str, timestamp: &str) -> bool {
+        //! the signer in turn may seal the next block at any time. The other signers may seal it after their delay,
+        //! in case the signer in turn is offline.
+        let delay = match state.get_out_of_turn_delay(sealer) {
+            Some(delay) => delay,
+            None => return false,
+        };
+        if delay == 1 {
+            return true;
+        }
+        let time = get_time(timestamp);
+        time >= get_time(&parent.timestamp) + self.block_period.max(1) * delay as i64 && time <= Utc::now().timestamp() + MAX_FUTURE_BLOCK_SECS
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn name(&self) -> String {
+        "poa".to_string()
+    }
+
+    fn is_ready_to_produce(&self, bc: &BlockChain, km: &KeyManager, last_block_time: i64, now: i64) -> bool {
+        if now - last_block_time < self.block_period || bc.chain.len() == 0 {
+            return false;
+        }
+        let state = self.get_state(&bc.chain);
+        let delay = match state.get_out_of_turn_delay(&km.my_address()) {
+            Some(delay) => delay,
+            None => return false,
+        };
+        delay == 1 || now >= get_time(&bc.chain[bc.chain.len() - 1].timestamp) + self.block_period.max(1) * delay as i64
+    }
+
+    fn seal(&self, block: &mut Block, km: &KeyManager, to_stop: &Arc<AtomicBool>) -> bool {
+        if to_stop.load(Ordering::Relaxed) {
+            println!("Receive stop signal for sealing");
+            return false;
+        }
+        sign_block(block, km);
+        true
+    }
+
+    fn is_valid_block(&self, block: &Block, ancestors: &[Block]) -> bool {
+        if ancestors.len() == 0 {
+            return false;
+        }
+        let parent = &ancestors[ancestors.len() - 1];
+        if block.previous_block.eq(&parent.get_hash()) == false {
+            println!("Invalid block (bad previous_block)");
+            return false;
+        }
+        if block.has_valid_merkle_root() == false {
+            println!("Invalid block (bad merkle_root)");
+            return false;
+        }
+
+        let state = self.get_state(ancestors);
+        if self.is_allowed_sealer(&state, parent, &block.sealer, &block.timestamp) == false {
+            println!("Invalid block (sealed out of turn by {})", block.sealer);
+            return false;
+        }
+
+        if verify_block_seal(block) == false {
+            println!("Invalid block (bad seal)");
+            return false;
+        }
+
+        let mut used_votes = if ancestors.len().is_multiple_of(GOVERNANCE_EPOCH_LENGTH) { HashSet::new() } else { state.used_votes };
+        for t in block.transaction_pool.transactions.iter() {
+            if t.governance.is_none() {
+                continue;
+            }
+            if is_valid_governance_transaction(t, &state.authorities, ancestors.len()) == false {
+                println!("Invalid block (bad governance transaction)");
+                return false;
+            }
+            if used_votes.insert(get_vote_id(t)) == false {
+                println!("Invalid block (replayed governance transaction)");
+                return false;
+            }
+        }
+        true
+    }
+
+    fn is_valid_header(&self, header: &BlockHeader, ancestors: &[Block]) -> bool {
+        if ancestors.len() == 0 {
+            return false;
+        }
+        let parent = &ancestors[ancestors.len() - 1];
+        let state = self.get_state(ancestors);
+        if header.previous_block == parent.get_hash() {
+            if self.is_allowed_sealer(&state, parent, &header.sealer, &header.timestamp) == false {
+                return false;
+            }
+        }
+        else if state.authorities.contains(&header.sealer) == false {
+            return false;
+        }
+        verify_header_seal(header)
+    }
+
+    fn choose_fork(&self, current: &BlockChain, candidate: &BlockChain) -> bool {
+        // blocks sealed in turn weigh more, so the chain with the most signers in turn wins
+        self.get_state(&candidate.chain).weight > self.get_state(&current.chain).weight
+    }
+
+    fn needs_block_bodies(&self) -> bool {
+        // the signers are replayed from the governance transactions
+        true
+    }
+
+    fn is_valid_payload(&self, transaction: &Transaction, bc: &BlockChain) -> bool {
+        // a vote is pooled for the next block
+        is_valid_governance_transaction(transaction, &self.get_authorities(&bc.chain), bc.chain.len())
+    }
+}
+
+fn get_time(timestamp: &str) -> i64 {
+    //! the unix time of a block timestamp. The genesis block has none.
+    match timestamp.parse::<DateTime<Utc>>() {
+        Ok(time) => time.timestamp(),
+        Err(_) => 0,
+    }
+}
+
+pub fn get_epoch(height: usize) -> u64 {
+    (height / GOVERNANCE_EPOCH_LENGTH) as u64
+}
+
+fn get_vote_id(transaction: &Transaction) -> String {
+    get_double_sha256(&transaction.to_string())
+}
+
+fn is_valid_governance_transaction(transaction: &Transaction, authorities: &Vec<String>, height: usize) -> bool {
+    //! a governance transaction in the block at height has to be cast in the epoch of the block and
+    //! signed by one of the current signers
+    let g = match &transaction.governance {
+        Some(g) => g,
+        None => return false,
+    };
+    if g.action != GOVERNANCE_ADD_SIGNER && g.action != GOVERNANCE_REMOVE_SIGNER {
+        return false;
+    }
+    if g.epoch != get_epoch(height) {
+        return false;
+    }
+    if authorities.contains(&g.proposer) == false {
+        return false;
+    }
+
+    let mut unsigned = transaction.clone();
+    unsigned.signature = String::new();
+    verify_signature(&unsigned.to_string(), &transaction.signature, &g.proposer)
+}
+
+pub fn create_signed_governance_transaction(action: &str, signer: &str, km: &KeyManager, height: usize) -> Transaction {
+    //! create a governance transaction proposed and signed by the owner of km for the block at height
+    let mut t = Transaction::create_governance_transaction(action, signer, &km.my_address(), get_epoch(height));
+    let to_be_signed = t.to_string();
+    t.signature = km.compute_digital_signature(&to_be_signed);
+    t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::block_chain::TransactionPool;
+    use chrono::TimeZone;
+
+    /// The block period of the tests in seconds
+    const PERIOD: i64 = 10;
+
+    fn create_poa() -> ProofOfAuthority {
+        ProofOfAuthority::create(&vec!["a".to_string(), "b".to_string(), "c".to_string()], PERIOD)
+    }
+
+    fn create_block(parent: &Block, sealer: &str, time: i64) -> Block {
+        //! an unsealed block of sealer at the unix time. Validating a sealer does not check the seal.
+        let mut block = Block::create(TransactionPool::create(), parent.get_hash());
+        block.timestamp = Utc.timestamp(time, 0).to_string();
+        block.sealer = sealer.to_string();
+        block
+    }
+
+    fn create_chain(sealers: &[&str], start_time: i64) -> Vec<Block> {
+        let mut chain = vec![Block::create_genesis_block()];
+        for (i, sealer) in sealers.iter().enumerate() {
+            let block = create_block(&chain[chain.len() - 1], sealer, start_time + i as i64 * PERIOD);
+            chain.push(block);
+        }
+        chain
+    }
+
+    #[test]
+    fn out_of_turn_sealer_waits_for_its_delay() {
+        let poa = create_poa();
+        let now = Utc::now().timestamp();
+        // b seals block 1 in turn, and c, which is in turn for block 2, is offline
+        let chain = create_chain(&["b"], now - PERIOD);
+        let state = poa.get_state(&chain);
+        assert_eq!(state.get_in_turn_signer(), "c");
+
+        let parent = &chain[1];
+        let early = create_block(parent, "a", now);
+        let late = create_block(parent, "a", now - PERIOD + 2 * PERIOD);
+        let future = create_block(parent, "a", now + 2 * MAX_FUTURE_BLOCK_SECS);
+        let in_turn = create_block(parent, "c", now);
+        assert!(poa.is_allowed_sealer(&state, parent, &early.sealer, &early.timestamp) == false);
+        assert!(poa.is_allowed_sealer(&state, parent, &late.sealer, &late.timestamp) == true);
+        assert!(poa.is_allowed_sealer(&state, parent, &future.sealer, &future.timestamp) == false);
+        assert!(poa.is_allowed_sealer(&state, parent, &in_turn.sealer, &in_turn.timestamp) == true);
+        assert!(poa.is_allowed_sealer(&state, parent, "d", &late.timestamp) == false);
+    }
+
+    #[test]
+    fn recent_sealer_cannot_seal_out_of_turn() {
+        let poa = create_poa();
+        let chain = create_chain(&["b"], 0);
+        let state = poa.get_state(&chain);
+        let late = create_block(&chain[1], "b", Utc::now().timestamp());
+        assert!(poa.is_allowed_sealer(&state, &chain[1], &late.sealer, &late.timestamp) == false);
+    }
+
+    #[test]
+    fn chain_sealed_in_turn_wins() {
+        let poa = create_poa();
+        let in_turn = BlockChain { chain: create_chain(&["b", "c", "a"], 0) };
+        let out_of_turn = BlockChain { chain: create_chain(&["a", "b", "c", "a"], 0) };
+        assert_eq!(poa.get_state(&in_turn.chain).weight, 3 * IN_TURN_WEIGHT);
+        assert_eq!(poa.get_state(&out_of_turn.chain).weight, 4 * OUT_OF_TURN_WEIGHT);
+        assert!(poa.choose_fork(&out_of_turn, &in_turn) == true);
+        assert!(poa.choose_fork(&in_turn, &out_of_turn) == false);
+    }
+
+    #[test]
+    fn governance_state_is_kept_per_block() {
+        let poa = create_poa();
+        let chain = create_chain(&["b", "c", "a", "b", "c"], 0);
+        for length in 1..chain.len() + 1 {
+            poa.get_state(&chain[..length]);
+        }
+        assert_eq!(poa.states.lock().unwrap().len(), chain.len());
+
+        // a fork replays only its own blocks on the state of the fork point
+        let mut fork: Vec<Block> = chain[..3].iter().map(|b| b.clone()).collect();
+        fork.push(create_block(&chain[2], "b", 100));
+        let state = poa.get_state(&fork);
+        assert_eq!(poa.states.lock().unwrap().len(), chain.len() + 1);
+        assert_eq!(state.weight, create_poa().get_state(&fork).weight);
+        assert_eq!(state.height, fork.len());
+    }
+}
@@ -0,0 +1,410 @@
+/*  
+    pos.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Proof of stake. Time is divided into slots, and the leaders of a slot are chosen by a stake-weighted lottery
+//! over the UTXO set. Each address draws its ticket with a VRF over the slot and the VRF output of the previous block,
+//! so nobody else learns the leaders in advance and anyone can verify the proof. The contents of a block do not change
+//! the lottery, but a leader can still bias the next lottery a little by withholding its block.
+//! The stakes are taken from a snapshot of the chain two epochs back, so stake moved to an address after its tickets
+//! of the coming slots became known does not count for them.
+//! A sealer which seals two blocks in one slot can be reported by an evidence transaction and loses its stake for the
+//! lottery once the evidence is in the snapshot.
+
+pub mod vrf;
+
+use crate::client_server_core::block_chain::{Block, BlockChain, BlockHeader, Transaction};
+use crate::client_server_core::block_chain::utxo_set::UTXOSet;
+use crate::client_server_core::block_chain::keymanager::KeyManager;
+use crate::client_server_core::consensus::{Consensus, sign_block, verify_block_seal, verify_header_seal};
+
+use chrono::Utc;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The length of a slot in seconds when the chain spec does not declare it
+pub const DEFAULT_SLOT_DURATION: i64 = 10;
+/// The active slot coefficient when the chain spec does not declare it
+pub const DEFAULT_ACTIVE_SLOT_COEFFICIENT: f64 = 0.5;
+/// The number of blocks in an epoch of stakes
+pub const STAKE_EPOCH_LENGTH: usize = 100;
+/// The number of epochs by which the stakes of the lottery lag behind the chain
+pub const STAKE_LAG_EPOCHS: usize = 2;
+/// The number of stake snapshots kept. It covers the snapshots of a few forks.
+pub const MAX_STAKE_SNAPSHOTS: usize = 16;
+
+/// The last lottery drawn by this node. The proof is reused by the seal.
+#[derive(Clone)]
+struct Draw {
+    previous_block: String,
+    slot: u64,
+    vrf_proof: String,
+    won: bool
+}
+
+/// The UTXO set and the offenders of a chain at the end of an epoch
+struct StakeSnapshot {
+    utxo_set: UTXOSet,
+    offenders: HashSet<String>
+}
+
+impl StakeSnapshot {
+    fn clone(&self) -> StakeSnapshot {
+        StakeSnapshot {
+            utxo_set: self.utxo_set.clone(),
+            offenders: self.offenders.clone()
+        }
+    }
+}
+
+pub struct ProofOfStake {
+    genesis_stakes: Vec<(String, i64)>,
+    slot_duration: i64,
+    active_slot_coefficient: f64,
+    draw: Mutex<Option<Draw>>,
+    /// the stake snapshots by the hash of their last block
+    snapshots: Mutex<HashMap<String, StakeSnapshot>>
+}
+
+impl ProofOfStake {
+    pub fn create(genesis_stakes: &Vec<(String, i64)>, slot_duration: i64, active_slot_coefficient: f64) -> ProofOfStake {
+        ProofOfStake {
+            genesis_stakes: genesis_stakes.clone(),
+            slot_duration: if slot_duration > 0 { slot_duration } else { DEFAULT_SLOT_DURATION },
+            active_slot_coefficient: if active_slot_coefficient > 0.0 && active_slot_coefficient <= 1.0 { active_slot_coefficient } else { DEFAULT_ACTIVE_SLOT_COEFFICIENT },
+            draw: Mutex::new(None),
+            snapshots: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn get_current_slot(&self, now: i64) -> u64 {
+        if now <= 0 {
+            return 0;
+        }
+        (now / self.slot_duration) as u64
+    }
+
+    pub fn get_stakes(&self, ancestors: &[Block]) -> HashMap<String, i64> {
+        //! return the stake of every address for a block on top of ancestors, which is its balance in the stake snapshot
+        //! plus its genesis stake. Sealers proven to have equivocated in the snapshot have no stake.
+        let mut stakes: HashMap<String, i64> = HashMap::new();
+        let mut offenders: HashSet<String> = HashSet::new();
+        if ancestors.len() > 0 {
+            let length = get_snapshot_length(ancestors.len());
+            let key = ancestors[length - 1].get_hash();
+            let mut snapshots = self.snapshots.lock().unwrap();
+            if snapshots.contains_key(&key) == false {
+                let snapshot = create_snapshot(&snapshots, &ancestors[..length]);
+                if snapshots.len() >= MAX_STAKE_SNAPSHOTS {
+                    snapshots.clear();
+                }
+                snapshots.insert(key.clone(), snapshot);
+            }
+            let snapshot = &snapshots[&key];
+            stakes = snapshot.utxo_set.get_balances();
+            offenders = snapshot.offenders.clone();
+        }
+
+        for (address, value) in self.genesis_stakes.iter() {
+            *stakes.entry(address.clone()).or_insert(0) += *value;
+        }
+        for offender in offenders.iter() {
+            stakes.remove(offender);
+        }
+        stakes
+    }
+
+    pub fn get_threshold(&self, ancestors: &[Block], address: &str) -> f64 {
+        //! an address with relative stake s wins a slot with probability 1 - (1 - f)^s
+        let stakes = self.get_stakes(ancestors);
+        let total_stake: i64 = stakes.values().filter(|v| **v > 0).sum();
+        let stake = match stakes.get(address) {
+            Some(stake) => *stake,
+            None => 0,
+        };
+        if total_stake <= 0 || stake <= 0 {
+            return 0.0;
+        }
+
+        let relative_stake = stake as f64 / total_stake as f64;
+        1.0 - (1.0 - self.active_slot_coefficient).powf(relative_stake)
+    }
+
+    pub fn is_slot_leader(&self, ancestors: &[Block], slot: u64, address: &str, vrf_proof: &str) -> bool {
+        //! check the VRF proof of address for the slot and whether its ticket is below the threshold of its stake
+        if ancestors.len() == 0 {
+            return false;
+        }
+        let alpha = get_lottery_input(&ancestors[ancestors.len() - 1], slot);
+        let public_key = match vrf::get_public_key(address) {
+            Some(public_key) => public_key,
+            None => return false,
+        };
+        let proof = match hex::decode(vrf_proof) {
+            Ok(proof) => proof,
+            Err(_) => return false,
+        };
+        match vrf::verify(&public_key, &proof, alpha.as_bytes()) {
+            Some(output) => get_lottery_value(&output) < self.get_threshold(ancestors, address),
+            None => false,
+        }
+    }
+}
+
+pub fn get_snapshot_length(length: usize) -> usize {
+    //! the number of blocks in the stake snapshot for a block on top of length blocks. It ends with an epoch
+    //! STAKE_LAG_EPOCHS epochs back, or is the genesis block.
+    let epochs = (length / STAKE_EPOCH_LENGTH).saturating_sub(STAKE_LAG_EPOCHS);
+    if epochs == 0 {
+        return 1;
+    }
+    epochs * STAKE_EPOCH_LENGTH
+}
+
+fn create_snapshot(snapshots: &HashMap<String, StakeSnapshot>, blocks: &[Block]) -> StakeSnapshot {
+    //! replay the blocks of the last epoch on the snapshot of the previous epoch if it is kept, or all of them
+    let previous_length = if blocks.len() > STAKE_EPOCH_LENGTH { blocks.len() - STAKE_EPOCH_LENGTH } else { 1 };
+    let previous = if blocks.len() > 1 { snapshots.get(&blocks[previous_length - 1].get_hash()) } else { None };
+    let (mut snapshot, start) = match previous {
+        Some(previous) => (previous.clone(), previous_length),
+        None => (StakeSnapshot { utxo_set: UTXOSet::create(), offenders: HashSet::new() }, 0),
+    };
+    if snapshot.utxo_set.apply_blocks(&blocks[start..], Some(blocks.len())) == false {
+        println!("stakes are counted up to block {}", snapshot.utxo_set.height);
+    }
+    snapshot.offenders.extend(get_offenders(&blocks[start..]));
+    snapshot
+}
+
+pub fn get_lottery_input(parent: &Block, slot: u64) -> String {
+    //! the input of the lottery of a slot. It chains the VRF outputs, which the sealers cannot choose,
+    //! instead of the block hashes, which they can grind by the contents. The genesis block has no proof.
+    let seed = match hex::decode(&parent.vrf_proof).ok().and_then(|proof| vrf::proof_to_hash(&proof)) {
+        Some(output) => hex::encode(output),
+        None => parent.get_hash(),
+    };
+    format!("{}{}", seed, slot)
+}
+
+pub fn get_lottery_value(output: &[u8]) -> f64 {
+    //! return a value in [0, 1) from a VRF output
+    let mut head: u64 = 0;
+    for b in output.iter().take(8) {
+        head = (head << 8) | *b as u64;
+    }
+    head as f64 / 18446744073709551616.0
+}
+
+impl Consensus for ProofOfStake {
+    fn name(&self) -> String {
+        "pos".to_string()
+    }
+
+    fn is_ready_to_produce(&self, bc: &BlockChain, km: &KeyManager, _last_block_time: i64, now: i64) -> bool {
+        if bc.chain.len() == 0 {
+            return false;
+        }
+        let parent = &bc.chain[bc.chain.len() - 1];
+        let slot = self.get_current_slot(now);
+        if slot <= parent.slot {
+            return false;
+        }
+        let previous_block = parent.get_hash();
+        if let Some(draw) = self.draw.lock().unwrap().as_ref() {
+            if draw.previous_block == previous_block && draw.slot == slot {
+                return draw.won;
+            }
+        }
+
+        // my own proof is valid, so only its output is needed
+        let vrf_proof = km.compute_vrf_proof(&get_lottery_input(parent, slot));
+        let won = match vrf::proof_to_hash(&hex::decode(&vrf_proof).unwrap()) {
+            Some(output) => get_lottery_value(&output) < self.get_threshold(&bc.chain, &km.my_address()),
+            None => false,
+        };
+        *self.draw.lock().unwrap() = Some(Draw {
+            previous_block: previous_block,
+            slot: slot,
+            vrf_proof: vrf_proof,
+            won: won
+        });
+        won
+    }
+
+    fn seal(&self, block: &mut Block, km: &KeyManager, to_stop: &Arc<AtomicBool>) -> bool {
+        if to_stop.load(Ordering::Relaxed) {
+            println!("Receive stop signal for sealing");
+            return false;
+        }
+        // the block is sealed in the slot won by the last draw
+        let draw = match self.draw.lock().unwrap().clone() {
+            Some(draw) if draw.won == true && draw.previous_block == block.previous_block => draw,
+            _ => {
+                println!("no slot is won on the previous block");
+                return false;
+            }
+        };
+        block.slot = draw.slot;
+        block.vrf_proof = draw.vrf_proof;
+        sign_block(block, km);
+        true
+    }
+
+    fn is_valid_block(&self, block: &Block, ancestors: &[Block]) -> bool {
+        if ancestors.len() == 0 {
+            return false;
+        }
+        let parent = &ancestors[ancestors.len() - 1];
+        if block.previous_block.eq(&parent.get_hash()) == false {
+            println!("Invalid block (bad previous_block)");
+            return false;
+        }
+        if block.has_valid_merkle_root() == false {
+            println!("Invalid block (bad merkle_root)");
+            return false;
+        }
+        if block.slot <= parent.slot || block.slot > self.get_current_slot(Utc::now().timestamp()) + 1 {
+            println!("Invalid block (bad slot {})", block.slot);
+            return false;
+        }
+        if self.is_slot_leader(ancestors, block.slot, &block.sealer, &block.vrf_proof) == false {
+            println!("Invalid block ({} is not a leader of slot {})", block.sealer, block.slot);
+            return false;
+        }
+        if verify_block_seal(block) == false {
+            println!("Invalid block (bad seal)");
+            return false;
+        }
+        for t in block.transaction_pool.transactions.iter() {
+            if t.evidence.is_some() && get_equivocator(t).is_none() {
+                println!("Invalid block (bad equivocation evidence)");
+                return false;
+            }
+        }
+        true
+    }
+
+    fn is_valid_header(&self, header: &BlockHeader, ancestors: &[Block]) -> bool {
+        if ancestors.len() == 0 || header.slot > self.get_current_slot(Utc::now().timestamp()) + 1 {
+            return false;
+        }
+        if header.previous_block == ancestors[ancestors.len() - 1].get_hash() {
+            if self.is_slot_leader(ancestors, header.slot, &header.sealer, &header.vrf_proof) == false {
+                return false;
+            }
+        }
+        else if self.get_stakes(ancestors).get(&header.sealer).map(|stake| *stake > 0) != Some(true) {
+            return false;
+        }
+        verify_header_seal(header)
+    }
+
+    fn choose_fork(&self, current: &BlockChain, candidate: &BlockChain) -> bool {
+        // the longest chain wins
+        candidate.chain.len() > current.chain.len()
+    }
+
+    fn needs_block_bodies(&self) -> bool {
+        // the stakes are replayed from the transactions
+        true
+    }
+
+    fn is_valid_payload(&self, transaction: &Transaction, _bc: &BlockChain) -> bool {
+        get_equivocator(transaction).is_some()
+    }
+}
+
+pub fn get_equivocator(transaction: &Transaction) -> Option<String> {
+    //! return the sealer proven to have equivocated by an evidence transaction
+    let evidence = match &transaction.evidence {
+        Some(evidence) => evidence,
+        None => return None,
+    };
+    let first: Block = match serde_json::from_str(&evidence.first) {
+        Ok(block) => block,
+        Err(_) => return None,
+    };
+    let second: Block = match serde_json::from_str(&evidence.second) {
+        Ok(block) => block,
+        Err(_) => return None,
+    };
+
+    if first.sealer.eq(&second.sealer) == false || first.slot != second.slot || first.slot == 0 {
+        return None;
+    }
+    if first.get_hash() == second.get_hash() {
+        return None;
+    }
+    if verify_block_seal(&first) == false || verify_block_seal(&second) == false {
+        return None;
+    }
+    Some(first.sealer.clone())
+}
+
+fn get_offenders(ancestors: &[Block]) -> HashSet<String> {
+    let mut offenders: HashSet<String> = HashSet::new();
+    for block in ancestors.iter() {
+        for t in block.transaction_pool.transactions.iter() {
+            if let Some(sealer) = get_equivocator(t) {
+                offenders.insert(sealer);
+            }
+        }
+    }
+    offenders
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::block_chain::TransactionPool;
+    use crate::client_server_core::MINING_REWARD;
+
+    fn create_chain(length: usize, address: &str) -> Vec<Block> {
+        //! a chain whose blocks pay their reward to address
+        let mut chain = vec![Block::create_genesis_block()];
+        while chain.len() < length {
+            let mut transaction_pool = TransactionPool::create();
+            transaction_pool.push(Transaction::create_coinbase_transaction(address, MINING_REWARD));
+            let block = Block::create(transaction_pool, chain[chain.len() - 1].get_hash());
+            chain.push(block);
+        }
+        chain
+    }
+
+    #[test]
+    fn stakes_lag_behind_the_chain() {
+        let pos = ProofOfStake::create(&vec![("genesis".to_string(), 100)], 0, 0.0);
+        let chain = create_chain(5 * STAKE_EPOCH_LENGTH, "miner");
+
+        // the first epochs draw with the genesis stakes only
+        let stakes = pos.get_stakes(&chain[..STAKE_LAG_EPOCHS * STAKE_EPOCH_LENGTH - 1]);
+        assert_eq!(stakes.get("genesis"), Some(&100));
+        assert_eq!(stakes.get("miner"), None);
+
+        // the snapshot ends two epochs back, so the rewards of the last two epochs do not count
+        for epochs in (STAKE_LAG_EPOCHS + 1)..5 {
+            let stakes = pos.get_stakes(&chain[..epochs * STAKE_EPOCH_LENGTH + 1]);
+            let rewarded_blocks = ((epochs - STAKE_LAG_EPOCHS) * STAKE_EPOCH_LENGTH - 1) as i64;
+            assert_eq!(stakes.get("miner"), Some(&(rewarded_blocks * MINING_REWARD)));
+        }
+        // the stakes change only at the end of an epoch
+        assert_eq!(pos.get_stakes(&chain[..5 * STAKE_EPOCH_LENGTH - 1]), pos.get_stakes(&chain[..4 * STAKE_EPOCH_LENGTH]));
+    }
+}
@@ -0,0 +1,388 @@
+/*  
+    vrf.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Verifiable random function ECVRF-EDWARDS25519-SHA512-TAI of RFC 9381. The keys are the Ed25519 keys of minisign,
+//! so the address of a sealer is also its VRF public key. Only the owner of a key can compute the output for an input,
+//! and everyone can check it with the proof. The output is unique for a key and an input, so it cannot be ground.
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha512;
+
+use minisign::{PublicKeyBox, SecretKeyBox};
+
+use num_bigint::BigUint;
+use num_traits::{One, Zero};
+
+/// The suite of ECVRF-EDWARDS25519-SHA512-TAI
+const SUITE_STRING: u8 = 0x03;
+/// The length of the challenge in a proof
+const C_LEN: usize = 16;
+/// The length of an encoded point or scalar
+const PT_LEN: usize = 32;
+/// The length of a proof: Gamma, c and s
+pub const PROOF_LEN: usize = PT_LEN + C_LEN + PT_LEN;
+/// The position of the Ed25519 seed in a serialized minisign secret key
+const MINISIGN_SEED_OFFSET: usize = 62;
+/// The position of the Ed25519 public key in a serialized minisign public key
+const MINISIGN_PUBLIC_KEY_OFFSET: usize = 10;
+
+struct Curve {
+    /// the field prime 2^255 - 19
+    p: BigUint,
+    /// the order of the base point 2^252 + 27742317777372353535851937790883648493
+    q: BigUint,
+    d: BigUint,
+    sqrt_m1: BigUint,
+    base: Point
+}
+
+/// A point of edwards25519 in extended coordinates (X:Y:Z:T) with x = X/Z, y = Y/Z and xy = T/Z
+#[derive(Clone)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+    t: BigUint
+}
+
+impl Curve {
+    fn create() -> Curve {
+        let p = (BigUint::one() << 255) - BigUint::from(19u32);
+        let q = (BigUint::one() << 252) + BigUint::parse_bytes(b"27742317777372353535851937790883648493", 10).unwrap();
+        let d = (&p - BigUint::from(121665u32)) * BigUint::from(121666u32).modpow(&(&p - BigUint::from(2u32)), &p) % &p;
+        let sqrt_m1 = BigUint::from(2u32).modpow(&((&p - BigUint::one()) >> 2), &p);
+        let mut curve = Curve {
+            p: p,
+            q: q,
+            d: d,
+            sqrt_m1: sqrt_m1,
+            base: Point { x: BigUint::zero(), y: BigUint::one(), z: BigUint::one(), t: BigUint::zero() }
+        };
+        let base_y = BigUint::from(4u32) * curve.inverse(&BigUint::from(5u32)) % &curve.p;
+        curve.base = curve.decode(&to_bytes(&base_y)).unwrap();
+        curve
+    }
+
+    fn inverse(&self, a: &BigUint) -> BigUint {
+        a.modpow(&(&self.p - BigUint::from(2u32)), &self.p)
+    }
+
+    fn sub(&self, a: &BigUint, b: &BigUint) -> BigUint {
+        (a + &self.p - b % &self.p) % &self.p
+    }
+
+    fn identity(&self) -> Point {
+        Point { x: BigUint::zero(), y: BigUint::one(), z: BigUint::one(), t: BigUint::zero() }
+    }
+
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        //! the unified addition of RFC 8032, which also doubles
+        let p = &self.p;
+        let pa = self.sub(&a.y, &a.x) * self.sub(&b.y, &b.x) % p;
+        let pb = (&a.y + &a.x) * (&b.y + &b.x) % p;
+        let pc = &a.t * BigUint::from(2u32) * &self.d % p * &b.t % p;
+        let pd = &a.z * BigUint::from(2u32) * &b.z % p;
+        let e = self.sub(&pb, &pa);
+        let f = self.sub(&pd, &pc);
+        let g = (&pd + &pc) % p;
+        let h = (&pb + &pa) % p;
+        Point {
+            x: &e * &f % p,
+            y: &g * &h % p,
+            t: &e * &h % p,
+            z: &f * &g % p
+        }
+    }
+
+    fn double(&self, a: &Point) -> Point {
+        let p = &self.p;
+        let pa = &a.x * &a.x % p;
+        let pb = &a.y * &a.y % p;
+        let pc = &a.z * &a.z * BigUint::from(2u32) % p;
+        let h = (&pa + &pb) % p;
+        let xy = &a.x + &a.y;
+        let e = self.sub(&h, &(&xy * &xy % p));
+        let g = self.sub(&pa, &pb);
+        let f = (&pc + &g) % p;
+        Point {
+            x: &e * &f % p,
+            y: &g * &h % p,
+            t: &e * &h % p,
+            z: &f * &g % p
+        }
+    }
+
+    fn negate(&self, a: &Point) -> Point {
+        Point {
+            x: self.sub(&BigUint::zero(), &a.x),
+            y: a.y.clone(),
+            z: a.z.clone(),
+            t: self.sub(&BigUint::zero(), &a.t)
+        }
+    }
+
+    fn multiply(&self, scalar: &BigUint, a: &Point) -> Point {
+        self.multiply_twice(scalar, a, &BigUint::zero(), a)
+    }
+
+    fn multiply_twice(&self, scalar_a: &BigUint, a: &Point, scalar_b: &BigUint, b: &Point) -> Point {
+        //! compute scalar_a * a + scalar_b * b with one chain of doublings
+        let bits_a = get_bits(scalar_a);
+        let bits_b = get_bits(scalar_b);
+        let sum = self.add(a, b);
+        let mut result = self.identity();
+        for i in (0..bits_a.len().max(bits_b.len())).rev() {
+            result = self.double(&result);
+            match (bits_a.get(i) == Some(&true), bits_b.get(i) == Some(&true)) {
+                (true, true) => result = self.add(&result, &sum),
+                (true, false) => result = self.add(&result, a),
+                (false, true) => result = self.add(&result, b),
+                (false, false) => {},
+            }
+        }
+        result
+    }
+
+    fn encode(&self, a: &Point) -> [u8; PT_LEN] {
+        let z_inv = self.inverse(&a.z);
+        let x = &a.x * &z_inv % &self.p;
+        let y = &a.y * &z_inv % &self.p;
+        let mut bytes = to_bytes(&y);
+        if &x & BigUint::one() == BigUint::one() {
+            bytes[PT_LEN - 1] |= 0x80;
+        }
+        bytes
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<Point> {
+        //! decode a point as in RFC 8032, or return None if the bytes are not a point
+        if bytes.len() != PT_LEN {
+            return None;
+        }
+        let sign = bytes[PT_LEN - 1] >> 7;
+        let mut y_bytes = bytes.to_vec();
+        y_bytes[PT_LEN - 1] &= 0x7f;
+        let y = BigUint::from_bytes_le(&y_bytes);
+        if y >= self.p {
+            return None;
+        }
+        let p = &self.p;
+        let y2 = &y * &y % p;
+        let u = self.sub(&y2, &BigUint::one());
+        let v = (&self.d * &y2 + BigUint::one()) % p;
+        let v3 = &v * &v % p * &v % p;
+        let v7 = &v3 * &v3 % p * &v % p;
+        let exponent = (p - BigUint::from(5u32)) >> 3;
+        let mut x = &u * &v3 % p * (&u * &v7 % p).modpow(&exponent, p) % p;
+        let vx2 = &v * &x % p * &x % p;
+        if vx2 == self.sub(&BigUint::zero(), &u) {
+            x = x * &self.sqrt_m1 % p;
+        }
+        else if vx2 != u {
+            return None;
+        }
+        if x.is_zero() == true && sign == 1 {
+            return None;
+        }
+        if (&x & BigUint::one()) != BigUint::from(sign) {
+            x = self.sub(&BigUint::zero(), &x);
+        }
+        let t = &x * &y % p;
+        Some(Point { x: x, y: y, z: BigUint::one(), t: t })
+    }
+
+    fn is_identity(&self, a: &Point) -> bool {
+        a.x.is_zero() == true && self.sub(&a.y, &a.z).is_zero() == true
+    }
+
+    fn multiply_by_cofactor(&self, a: &Point) -> Point {
+        let a2 = self.add(a, a);
+        let a4 = self.add(&a2, &a2);
+        self.add(&a4, &a4)
+    }
+
+    fn encode_to_curve(&self, public_key: &[u8], alpha: &[u8]) -> Point {
+        //! hash an input to a point by try and increment
+        let mut counter: u8 = 0;
+        loop {
+            let hash = hash(&[&[SUITE_STRING, 0x01], public_key, alpha, &[counter, 0x00]]);
+            if let Some(h) = self.decode(&hash[..PT_LEN]) {
+                return self.multiply_by_cofactor(&h);
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+
+    fn generate_challenge(&self, points: &[&Point]) -> BigUint {
+        let mut sha512 = Sha512::new();
+        sha512.input(&[SUITE_STRING, 0x02]);
+        for point in points.iter() {
+            sha512.input(&self.encode(point));
+        }
+        sha512.input(&[0x00]);
+        let mut digest = [0u8; 64];
+        sha512.result(&mut digest);
+        BigUint::from_bytes_le(&digest[..C_LEN])
+    }
+
+    fn proof_to_hash(&self, gamma: &Point) -> Vec<u8> {
+        hash(&[&[SUITE_STRING, 0x03], &self.encode(&self.multiply_by_cofactor(gamma)), &[0x00]])
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut sha512 = Sha512::new();
+    for part in parts.iter() {
+        sha512.input(part);
+    }
+    let mut digest = [0u8; 64];
+    sha512.result(&mut digest);
+    digest.to_vec()
+}
+
+fn get_bits(a: &BigUint) -> Vec<bool> {
+    //! the bits of a number from the lowest
+    let mut bits: Vec<bool> = Vec::new();
+    for b in a.to_bytes_le().iter() {
+        for i in 0..8 {
+            bits.push((b >> i) & 1 == 1);
+        }
+    }
+    bits
+}
+
+fn to_bytes(a: &BigUint) -> [u8; PT_LEN] {
+    let mut bytes = [0u8; PT_LEN];
+    for (i, b) in a.to_bytes_le().iter().take(PT_LEN).enumerate() {
+        bytes[i] = *b;
+    }
+    bytes
+}
+
+pub fn prove(seed: &[u8], alpha: &[u8]) -> Vec<u8> {
+    //! compute the proof for an input with the Ed25519 secret key seed
+    let curve = Curve::create();
+    let hashed_seed = hash(&[seed]);
+    let mut scalar = hashed_seed[..PT_LEN].to_vec();
+    scalar[0] &= 248;
+    scalar[PT_LEN - 1] &= 127;
+    scalar[PT_LEN - 1] |= 64;
+    let x = BigUint::from_bytes_le(&scalar);
+    let public_key = curve.encode(&curve.multiply(&x, &curve.base));
+
+    let h = curve.encode_to_curve(&public_key, alpha);
+    let gamma = curve.multiply(&x, &h);
+    let k = BigUint::from_bytes_le(&hash(&[&hashed_seed[PT_LEN..], &curve.encode(&h)])) % &curve.q;
+    let y = curve.decode(&public_key).unwrap();
+    let c = curve.generate_challenge(&[&y, &h, &gamma, &curve.multiply(&k, &curve.base), &curve.multiply(&k, &h)]);
+    let s = (&k + &c * &x) % &curve.q;
+
+    let mut proof = curve.encode(&gamma).to_vec();
+    let mut c_bytes = c.to_bytes_le();
+    c_bytes.resize(C_LEN, 0);
+    proof.extend(c_bytes);
+    proof.extend(to_bytes(&s).iter());
+    proof
+}
+
+pub fn verify(public_key: &[u8], proof: &[u8], alpha: &[u8]) -> Option<Vec<u8>> {
+    //! return the output for an input if the proof is valid for the public key
+    let curve = Curve::create();
+    let y = curve.decode(public_key)?;
+    if curve.is_identity(&curve.multiply_by_cofactor(&y)) == true {
+        return None;
+    }
+    if proof.len() != PROOF_LEN {
+        return None;
+    }
+    let gamma = curve.decode(&proof[..PT_LEN])?;
+    let c = BigUint::from_bytes_le(&proof[PT_LEN..PT_LEN + C_LEN]);
+    let s = BigUint::from_bytes_le(&proof[PT_LEN + C_LEN..]);
+    if s >= curve.q {
+        return None;
+    }
+
+    let h = curve.encode_to_curve(public_key, alpha);
+    let u = curve.multiply_twice(&s, &curve.base, &c, &curve.negate(&y));
+    let v = curve.multiply_twice(&s, &h, &c, &curve.negate(&gamma));
+    if curve.generate_challenge(&[&y, &h, &gamma, &u, &v]) != c {
+        return None;
+    }
+    Some(curve.proof_to_hash(&gamma))
+}
+
+pub fn proof_to_hash(proof: &[u8]) -> Option<Vec<u8>> {
+    //! the output of a proof which was verified before
+    if proof.len() != PROOF_LEN {
+        return None;
+    }
+    let curve = Curve::create();
+    let gamma = curve.decode(&proof[..PT_LEN])?;
+    Some(curve.proof_to_hash(&gamma))
+}
+
+pub fn get_seed(secret_key_box_str: &str, password: &str) -> Option<Vec<u8>> {
+    //! the Ed25519 seed of a minisign secret key
+    let sk = SecretKeyBox::from_string(secret_key_box_str).ok()?.into_secret_key(Some(password.to_string())).ok()?;
+    let bytes = sk.to_bytes();
+    Some(bytes.get(MINISIGN_SEED_OFFSET..MINISIGN_SEED_OFFSET + PT_LEN)?.to_vec())
+}
+
+pub fn get_public_key(public_key_box_str: &str) -> Option<Vec<u8>> {
+    //! the Ed25519 public key of a minisign public key, which is an address
+    let pk = PublicKeyBox::from_string(public_key_box_str).ok()?.into_public_key().ok()?;
+    let bytes = pk.to_bytes();
+    Some(bytes.get(MINISIGN_PUBLIC_KEY_OFFSET..MINISIGN_PUBLIC_KEY_OFFSET + PT_LEN)?.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test vector of RFC 9381 (Example 16) for ECVRF-EDWARDS25519-SHA512-TAI with an empty input
+    const SECRET_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const PROOF: &str = "8657106690b5526245a92b003bb079ccd1a92130477671f6fc01ad16f26f723f26f8a57ccaed74ee1b190bed1f479d9727d2d0f9b005a6e456a35d4fb0daab1268a1b0db10836d9826a528ca76567805";
+    const BETA: &str = "90cf1df3b703cce59e2a35b925d411164068269d7b2d29f3301c03dd757876ff66b71dda49d2de59d03450451af026798e8f81cd2e333de5cdf4f3e140fdd8ae";
+
+    #[test]
+    fn proof_matches_the_rfc_vector() {
+        let seed = hex::decode(SECRET_KEY).unwrap();
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        let proof = prove(&seed, b"");
+        assert_eq!(hex::encode(&proof), PROOF);
+        assert_eq!(proof_to_hash(&proof).map(hex::encode), Some(BETA.to_string()));
+        assert_eq!(verify(&public_key, &proof, b"").map(hex::encode), Some(BETA.to_string()));
+    }
+
+    #[test]
+    fn proof_does_not_verify_for_other_inputs_or_keys() {
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        let proof = hex::decode(PROOF).unwrap();
+        assert!(verify(&public_key, &proof, b"x").is_none());
+
+        let mut tampered = proof.clone();
+        tampered[PT_LEN] ^= 1;
+        assert!(verify(&public_key, &tampered, b"").is_none());
+
+        let other_key = hex::decode("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c").unwrap();
+        assert!(verify(&other_key, &proof, b"").is_none());
+        assert!(verify(&public_key, &proof[..proof.len() - 1], b"").is_none());
+    }
+}
@@ -0,0 +1,106 @@
+/*  
+    pow.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Proof of work. Blocks are sealed by searching a nonce whose proof-of-work hash ends with DIFFICULTY zeros.
+
+pub mod algorithm;
+pub mod miner;
+
+use crate::client_server_core::block_chain::{Block, BlockChain, BlockHeader, DIFFICULTY};
+use crate::client_server_core::block_chain::keymanager::KeyManager;
+use crate::client_server_core::consensus::Consensus;
+use crate::client_server_core::MINING_INTERVAL;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use self::algorithm::PowAlgorithm;
+
+pub struct ProofOfWork {
+    algorithm: Arc<dyn PowAlgorithm>,
+    threads: usize,
+    /// the hash rate of the last sealing in hashes per second
+    last_hash_rate: AtomicU64
+}
+
+impl ProofOfWork {
+    pub fn create() -> ProofOfWork {
+        ProofOfWork::create_with_algorithm(Box::new(algorithm::DoubleSha256 {}))
+    }
+
+    pub fn create_with_algorithm(algorithm: Box<dyn PowAlgorithm>) -> ProofOfWork {
+        //! create a miner which uses every CPU core
+        ProofOfWork::create_with_threads(algorithm, miner::get_default_threads())
+    }
+
+    pub fn create_with_threads(algorithm: Box<dyn PowAlgorithm>, threads: usize) -> ProofOfWork {
+        ProofOfWork {
+            algorithm: Arc::from(algorithm),
+            threads: threads,
+            last_hash_rate: AtomicU64::new(0)
+        }
+    }
+
+    pub fn get_hash_rate(&self) -> u64 {
+        self.last_hash_rate.load(Ordering::Relaxed)
+    }
+}
+
+impl Consensus for ProofOfWork {
+    fn name(&self) -> String {
+        "pow-".to_string() + &self.algorithm.name()
+    }
+
+    fn is_ready_to_produce(&self, _bc: &BlockChain, _km: &KeyManager, last_block_time: i64, now: i64) -> bool {
+        now - last_block_time > MINING_INTERVAL
+    }
+
+    fn seal(&self, block: &mut Block, _km: &KeyManager, to_stop: &Arc<AtomicBool>) -> bool {
+        if self.threads <= 1 {
+            return block.compute_nonce_for_pow_with(self.algorithm.as_ref(), to_stop);
+        }
+        let (flag, hash_rate) = miner::mine_parallel(block, self.algorithm.as_ref(), self.threads, to_stop);
+        self.last_hash_rate.store(hash_rate as u64, Ordering::Relaxed);
+        flag
+    }
+
+    fn is_valid_block(&self, block: &Block, ancestors: &[Block]) -> bool {
+        if ancestors.len() == 0 {
+            return false;
+        }
+        let previous_block_hash = ancestors[ancestors.len() - 1].get_hash();
+        block.is_valid_block_with(&previous_block_hash, self.algorithm.as_ref())
+    }
+
+    fn is_valid_header(&self, header: &BlockHeader, _ancestors: &[Block]) -> bool {
+        if header.sealer.len() > 0 || header.seal.len() > 0 {
+            return false;
+        }
+        let suffix: String = "0".to_string().repeat(DIFFICULTY);
+        header.get_pow_hash(self.algorithm.as_ref()).ends_with(&suffix)
+    }
+
+    fn choose_fork(&self, current: &BlockChain, candidate: &BlockChain) -> bool {
+        // the longest chain wins
+        candidate.chain.len() > current.chain.len()
+    }
+    fn get_pow_algorithm(&self) -> Option<Arc<dyn PowAlgorithm>> {
+        Some(Arc::clone(&self.algorithm))
+    }
+}
@@ -0,0 +1,109 @@
+/*  
+    algorithm.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Hash functions for proof of work. The chain spec declares which one the network uses.
+//! Block hashes which link blocks are always computed by get_double_sha256.
+
+use crate::client_server_core::block_chain::get_double_sha256;
+
+extern crate crypto;
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest;
+use crypto::scrypt::{scrypt, ScryptParams};
+
+use std::time::Instant;
+
+/// scrypt parameters (N = 2^10, r = 1, p = 1). It needs 128 KiB of memory for each hash.
+pub const SCRYPT_LOG_N: u8 = 10;
+pub const SCRYPT_R: u32 = 1;
+pub const SCRYPT_P: u32 = 1;
+
+pub trait PowAlgorithm: Send + Sync {
+    fn name(&self) -> String;
+
+    /// It returns the hash of a message as a hex string.
+    fn hash(&self, msg: &str) -> String;
+}
+
+pub struct DoubleSha256 {}
+
+impl PowAlgorithm for DoubleSha256 {
+    fn name(&self) -> String {
+        "sha256d".to_string()
+    }
+
+    fn hash(&self, msg: &str) -> String {
+        get_double_sha256(msg)
+    }
+}
+
+pub struct Blake2b256 {}
+
+impl PowAlgorithm for Blake2b256 {
+    fn name(&self) -> String {
+        "blake2b".to_string()
+    }
+
+    fn hash(&self, msg: &str) -> String {
+        let mut blake2b = Blake2b::new(32);
+        blake2b.input_str(msg);
+        blake2b.result_str()
+    }
+}
+
+/// A memory-hard hash function. It is much slower than the others, so it is expensive to validate as well.
+pub struct Scrypt {}
+
+impl PowAlgorithm for Scrypt {
+    fn name(&self) -> String {
+        "scrypt".to_string()
+    }
+
+    fn hash(&self, msg: &str) -> String {
+        let params = ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P);
+        let mut output = [0u8; 32];
+        scrypt(msg.as_bytes(), msg.as_bytes(), &params, &mut output);
+        hex::encode(output)
+    }
+}
+
+pub fn create_pow_algorithm(name: &str) -> Option<Box<dyn PowAlgorithm>> {
+    //! create a hash function by its name. An empty name means sha256d.
+    match name {
+        "" | "sha256d" => Some(Box::new(DoubleSha256 {})),
+        "blake2b" => Some(Box::new(Blake2b256 {})),
+        "scrypt" => Some(Box::new(Scrypt {})),
+        _ => None,
+    }
+}
+
+pub fn run() {
+    //! measure the hash rate of each hash function on a block-sized message
+    let msg = "0".repeat(1000);
+    for name in ["sha256d", "blake2b", "scrypt"].iter() {
+        let algorithm = create_pow_algorithm(name).unwrap();
+        let iterations = 200;
+        let start = Instant::now();
+        for i in 0..iterations {
+            algorithm.hash(&format!("{}{}", msg, i));
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        println!("{}: {:.0} hashes/s", algorithm.name(), iterations as f64 / elapsed);
+    }
+}
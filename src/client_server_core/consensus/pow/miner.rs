@@ -0,0 +1,149 @@
+/*  
+    miner.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Parallel miner. It splits the nonce space of a block across worker threads. A worker which exhausted its range
+//! takes a new extra nonce for the coinbase transaction and searches the whole nonce space of the changed block.
+
+use crate::client_server_core::block_chain::{Block, DIFFICULTY};
+use crate::client_server_core::consensus::pow::algorithm::PowAlgorithm;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// The largest nonce of a block. Like a 32-bit header nonce, it can be exhausted by fast miners.
+pub const MAX_NONCE: u64 = u32::MAX as u64;
+
+pub fn get_default_threads() -> usize {
+    //! return the number of CPU cores
+    match thread::available_parallelism() {
+        Ok(n) => n.get(),
+        Err(_) => 1,
+    }
+}
+
+pub fn mine_parallel(block: &mut Block, algorithm: &dyn PowAlgorithm, threads: usize, to_stop: &Arc<AtomicBool>) -> (bool, f64) {
+    //! search a nonce with the given number of worker threads. It returns whether a nonce was found and the hash rate.
+    mine_parallel_with_difficulty(block, algorithm, threads, DIFFICULTY, to_stop)
+}
+
+pub fn mine_parallel_with_difficulty(block: &mut Block, algorithm: &dyn PowAlgorithm, threads: usize, difficulty: usize, to_stop: &Arc<AtomicBool>) -> (bool, f64) {
+    //! search a nonce whose hash ends with difficulty zeros, e.g. a share of a mining pool
+    let threads = if threads == 0 { 1 } else { threads };
+    println!("start computing nonce with {} threads by {}", threads, algorithm.name());
+
+    let suffix: String = "0".to_string().repeat(difficulty);
+    let span = MAX_NONCE / threads as u64 + 1;
+    let found = AtomicBool::new(false);
+    let next_extra_nonce = AtomicU64::new(get_extra_nonce(block) + 1);
+    let total_hashes = AtomicU64::new(0);
+    let result: Mutex<Option<Block>> = Mutex::new(None);
+    let start_time = Instant::now();
+
+    thread::scope(|s| {
+        for i in 0..threads {
+            let mut candidate = block.clone();
+            let suffix = &suffix;
+            let found = &found;
+            let next_extra_nonce = &next_extra_nonce;
+            let total_hashes = &total_hashes;
+            let result = &result;
+
+            s.spawn(move || {
+                let mut start = i as u64 * span;
+                let mut end = if start + span > MAX_NONCE { MAX_NONCE } else { start + span - 1 };
+                let mut hashes: u64 = 0;
+
+                'search: loop {
+                    let mut nonce = start;
+                    loop {
+                        if to_stop.load(Ordering::Relaxed) || found.load(Ordering::Relaxed) {
+                            break 'search;
+                        }
+                        candidate.nonce = nonce;
+                        hashes += 1;
+                        if candidate.get_pow_hash(algorithm).ends_with(suffix) {
+                            if found.swap(true, Ordering::Relaxed) == false {
+                                *result.lock().unwrap() = Some(candidate.clone());
+                            }
+                            break 'search;
+                        }
+                        if nonce == end {
+                            break;
+                        }
+                        nonce += 1;
+                    }
+
+                    // the range is exhausted. change the coinbase and search the whole nonce space again.
+                    let extra_nonce = next_extra_nonce.fetch_add(1, Ordering::Relaxed);
+                    if set_extra_nonce(&mut candidate, extra_nonce) == false {
+                        println!("no coinbase transaction to change the extra nonce");
+                        break;
+                    }
+                    start = 0;
+                    end = MAX_NONCE;
+                }
+                total_hashes.fetch_add(hashes, Ordering::Relaxed);
+            });
+        }
+    });
+
+    let elapsed = start_time.elapsed().as_secs_f64();
+    let hashes = total_hashes.load(Ordering::Relaxed);
+    let hash_rate = if elapsed > 0.0 { hashes as f64 / elapsed } else { 0.0 };
+    println!("{} hashes in {:.1} s ({:.0} hashes/s)", hashes, elapsed, hash_rate);
+
+    let mined = result.lock().unwrap().take();
+    match mined {
+        Some(mined) => {
+            *block = mined;
+            (true, hash_rate)
+        },
+        None => {
+            println!("Receive stop signal for pow");
+            (false, hash_rate)
+        }
+    }
+}
+
+pub fn get_extra_nonce(block: &Block) -> u64 {
+    //! return the extra nonce of the coinbase transaction
+    for t in block.transaction_pool.transactions.iter() {
+        if t.tx_type == true {
+            return t.extra_nonce;
+        }
+    }
+    0
+}
+
+fn set_extra_nonce(block: &mut Block, extra_nonce: u64) -> bool {
+    let mut found = false;
+    for t in block.transaction_pool.transactions.iter_mut() {
+        if t.tx_type == true {
+            t.extra_nonce = extra_nonce;
+            found = true;
+            break;
+        }
+    }
+    if found == true {
+        block.update_merkle_root();
+    }
+    found
+}
@@ -0,0 +1,243 @@
+/*  
+    fuzz.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! A fuzz-style harness for the input handling of nodes. Messages of every kind are mutated at random and fed
+//! to message::decode, the handlers of core and edge nodes and the validation of transactions and blocks.
+//! A bad message must cost one dropped message, so the tests fail on any panic. They run as the node itself and
+//! as another node, which also covers the checks of core nodes and the bans.
+
+use crate::client_server_core::{ClientCore, ProducerState, ServerCore, Worker, verify_sbc_transaction_sig};
+use crate::client_server_core::block_chain::{Block, BlockChain, Transaction, TransactionInput, TransactionOutput, TransactionPool};
+use crate::client_server_core::block_chain::compact_block::CompactBlock;
+use crate::client_server_core::block_chain::merkle::MerkleProof;
+use crate::client_server_core::block_chain::utxo_set::UTXOSet;
+use crate::client_server_core::connection_manager::ConnectionManager;
+use crate::client_server_core::connection_manager::noise::NodeKey;
+use crate::client_server_core::connection_manager::message::{self, Message, MessageHandler, Peer};
+use crate::client_server_core::connection_manager::message_manager::*;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+
+/// The number of mutated messages
+pub const FUZZ_ITERATIONS: usize = 2000;
+/// The seed of the mutations, so that a panic can be reproduced
+pub const FUZZ_SEED: u64 = 2022;
+/// The port of the nodes under test. Nothing listens on it, so their replies fail immediately.
+const FUZZ_PORT: &str = "0";
+/// The port of another node sending the messages. Nothing listens on it either.
+const FUZZ_OTHER_PORT: &str = "1";
+/// Values which are written over numbers of messages
+const HOSTILE_VALUES: [&str; 6] = ["18446744073709551615", "9223372036854775807", "-9223372036854775808", "-1", "1e999", "null"];
+
+fn get_seed_messages() -> Vec<String> {
+    //! valid messages of every kind with payloads, and hostile ones which used to panic a node
+    let coinbase = Transaction::create_coinbase_transaction("address-0", 30);
+    let mut t = Transaction::create();
+    t.inputs.push(TransactionInput::create(coinbase.clone(), 0));
+    t.outputs.push(TransactionOutput::create("address-1", 20));
+    t.signature = "untrusted comment: signature from minisign secret key\nnot a signature".to_string();
+
+    let mut transaction_pool = TransactionPool::create();
+    transaction_pool.push(coinbase.clone());
+    transaction_pool.push(t.clone());
+    let genesis = Block::create_genesis_block();
+    let block = Block::create(transaction_pool, genesis.get_hash());
+    let mut bc = BlockChain::create();
+    bc.set_new_block(genesis.clone());
+    bc.set_new_block(block.clone());
+
+    let mut unknown_output = t.clone();
+    unknown_output.inputs[0].output_index = 5;
+    let mut overflow = t.clone();
+    overflow.outputs.push(TransactionOutput::create("address-2", i64::MAX));
+
+    let payloads: Vec<(usize, String)> = vec![
+        (MSG_ADD, String::new()),
+        (MSG_REMOVE, String::new()),
+        (MSG_CORE_LIST, r#"[["127.0.0.1","0"],["not an address","port"]]"#.to_string()),
+        (MSG_REQUEST_CORE_LIST, String::new()),
+        (MSG_PING, String::new()),
+        (MSG_NEW_TRANSACTION, t.to_string()),
+        (MSG_NEW_TRANSACTION, unknown_output.to_string()),
+        (MSG_NEW_TRANSACTION, overflow.to_string()),
+        (MSG_NEW_BLOCK_TO_ALL, block.to_string()),
+        (MSG_REQUEST_FULL_CHAIN, String::new()),
+        (RSP_FULL_CHAIN, bc.to_string()),
+        (RSP_FULL_CHAIN, r#"{"chain":[]}"#.to_string()),
+        (MSG_UNLOCKED, "0".to_string()),
+        (MSG_REQUEST_FEE_ESTIMATE, "6".to_string()),
+        (RSP_FEE_ESTIMATE, r#"{"target_blocks":6,"fee_rate":1.0}"#.to_string()),
+        (MSG_REQUEST_HEADERS, genesis.get_hash()),
+        (RSP_HEADERS, serde_json::to_string(&bc.get_headers_after(&genesis.get_hash())).unwrap()),
+        (MSG_REQUEST_MERKLE_PROOFS, "address-1".to_string()),
+        (RSP_MERKLE_PROOFS, serde_json::to_string(&vec![MerkleProof::create(&block, 1)]).unwrap()),
+        (MSG_REQUEST_FILTERS, "0".to_string()),
+        (MSG_REQUEST_BLOCKS, serde_json::to_string(&vec![block.get_hash()]).unwrap()),
+        (RSP_BLOCKS, serde_json::to_string(&vec![&block]).unwrap()),
+        (MSG_COMPACT_BLOCK, CompactBlock::create(&block).to_string()),
+        (MSG_REQUEST_BLOCK_TRANSACTIONS, format!(r#"{{"block_hash":"{}","indexes":[0,18446744073709551615]}}"#, block.get_hash())),
+        (RSP_BLOCK_TRANSACTIONS, format!(r#"{{"block_hash":"{}","transactions":[[9,{}]]}}"#, block.get_hash(), t.to_string())),
+        (MSG_PRUNED, "3".to_string()),
+        (MSG_GET_ADDR, String::new()),
+        (MSG_ADDR, r#"[{"ip":"127.0.0.1","port":"1","last_seen":1600000000}]"#.to_string()),
+    ];
+    payloads.iter().map(|(msg_type, payload)| build(*msg_type, "127.0.0.1", FUZZ_PORT, payload)).collect()
+}
+
+fn mutate(rng: &mut StdRng, msg: &str) -> String {
+    //! apply a few random byte flips, truncations, insertions, duplications and hostile numbers
+    let mut bytes = msg.as_bytes().to_vec();
+    for _ in 0..rng.gen_range(1, 4) {
+        if bytes.len() == 0 {
+            break;
+        }
+        let pos = rng.gen_range(0, bytes.len());
+        match rng.gen_range(0, 5) {
+            0 => bytes[pos] ^= 1 << rng.gen_range(0, 8),
+            1 => bytes.truncate(pos),
+            2 => {
+                let junk: Vec<u8> = (0..rng.gen_range(1, 16)).map(|_| rng.gen::<u8>()).collect();
+                bytes.splice(pos..pos, junk);
+            },
+            3 => {
+                let end = rng.gen_range(pos, bytes.len());
+                let piece: Vec<u8> = bytes[pos..end].to_vec();
+                bytes.splice(pos..pos, piece);
+            },
+            _ => {
+                let digits: Vec<usize> = (0..bytes.len()).filter(|i| bytes[*i].is_ascii_digit()).collect();
+                if digits.len() > 0 {
+                    let digit = digits[rng.gen_range(0, digits.len())];
+                    let value = HOSTILE_VALUES[rng.gen_range(0, HOSTILE_VALUES.len())];
+                    bytes.splice(digit..digit + 1, value.bytes());
+                }
+            },
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn check_validation(message: &Message) {
+    //! run the validation which transactions and blocks from peers reach behind consensus checks
+    let mut transactions: Vec<&Transaction> = Vec::new();
+    let mut blocks: Vec<&Block> = Vec::new();
+    match message {
+        Message::NewTransaction(t) => transactions.push(t),
+        Message::NewBlock(block) | Message::NewBlockToAll(block) => blocks.push(block),
+        Message::FullChain(bc) => blocks.extend(bc.chain.iter()),
+        Message::Blocks(received) => blocks.extend(received.iter()),
+        _ => {},
+    }
+    for block in blocks.iter() {
+        UTXOSet::create().apply_block(block, true);
+        transactions.extend(block.transaction_pool.transactions.iter());
+    }
+    for t in transactions.iter() {
+        verify_sbc_transaction_sig(t);
+        t.get_fee();
+    }
+}
+
+fn fuzz(sender_is_node: bool) -> (Vec<String>, bool) {
+    //! feed the seed messages and their mutations to the nodes, count decoded and rejected messages, and return
+    //! the messages which panicked and whether the sender is banned. The sender is the node itself, which passes the checks of known core nodes,
+    //! or another node, which is added as a core node by MSG_ADD, scored for its bad messages and banned.
+    let mut rng = StdRng::seed_from_u64(FUZZ_SEED);
+    let seeds = get_seed_messages();
+    // the handlers are async. A panic is caught around each message, so they run on a runtime of this thread.
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+    let mut server_core = ServerCore::create("127.0.0.1", FUZZ_PORT, "", "");
+    let mut producer = ProducerState {
+        locked: false,
+        cur_time: 0,
+        w: Worker::new()
+    };
+    let mut cm = ConnectionManager::create("127.0.0.1", FUZZ_PORT, "", "");
+    let mut client_core = ClientCore::create("127.0.0.1", FUZZ_PORT, "127.0.0.1", FUZZ_PORT);
+    let (mut tx, rx) = mpsc::channel::<String>();
+    let sender = if sender_is_node == true {
+        server_core.connection_manager.get_my_peer()
+    } else {
+        Peer {
+            ip: "127.0.0.1".to_string(),
+            port: FUZZ_OTHER_PORT.to_string(),
+            key: NodeKey::generate().get_public_key()
+        }
+    };
+
+    let mut decoded = 0;
+    let mut rejected = 0;
+    let mut panics: Vec<String> = Vec::new();
+    for i in 0..seeds.len() + FUZZ_ITERATIONS {
+        let msg = if i < seeds.len() { seeds[i].clone() } else {
+            let seed = &seeds[rng.gen_range(0, seeds.len())];
+            mutate(&mut rng, seed)
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| rt.block_on(async {
+            let message = match message::decode(&msg) {
+                Ok(message) => message,
+                Err(e) => {
+                    println!("{}", e);
+                    cm.handle_protocol_error(&sender, &e).await;
+                    server_core.connection_manager.handle_protocol_error(&sender, &e).await;
+                    return false;
+                }
+            };
+            check_validation(&message);
+            cm.handle_message(&sender, &message, &mut ()).await;
+            client_core.cm.handle_message(&sender, &message, &mut ()).await;
+            client_core.handle_message(&sender, &message, &mut tx).await;
+            server_core.handle_message(&sender, &message, &mut producer).await;
+            true
+        })));
+        match result {
+            Ok(true) => decoded += 1,
+            Ok(false) => rejected += 1,
+            Err(_) => panics.push(msg),
+        }
+        while rx.try_recv().is_ok() {}
+    }
+
+    for msg in panics.iter() {
+        println!("panicked on: {}", msg);
+    }
+    println!("{} messages: {} decoded, {} rejected, {} panics", seeds.len() + FUZZ_ITERATIONS, decoded, rejected, panics.len());
+    let banned = server_core.connection_manager.is_peer_banned(&sender);
+    println!("the sender is banned: {}", banned);
+    (panics, banned)
+}
+
+#[test]
+fn fuzz_messages_of_the_node_itself() {
+    let (panics, banned) = fuzz(true);
+    assert!(panics.is_empty());
+    assert!(banned == false);
+}
+
+#[test]
+fn fuzz_messages_of_another_node() {
+    let (panics, banned) = fuzz(false);
+    assert!(panics.is_empty());
+    assert!(banned == true);
+}
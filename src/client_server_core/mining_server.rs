@@ -0,0 +1,566 @@
+/*  
+    mining_server.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Mining server for external miners. A core node serves block templates and accepts solutions over a local TCP connection.
+//! Each request and response is a JSON object in a line, similar to getblocktemplate/submitblock.
+
+use crate::client_server_core::block_chain::{Block, Transaction, TransactionPool, DIFFICULTY, get_double_sha256};
+use crate::client_server_core::block_chain::keymanager::KeyManager;
+use crate::client_server_core::consensus::Consensus;
+use crate::client_server_core::consensus::pow::algorithm::PowAlgorithm;
+use crate::client_server_core::connection_manager;
+use crate::client_server_core::connection_manager::message_manager;
+use crate::client_server_core::connection_manager::message::{LocalCommand, NodeEvent};
+use crate::client_server_core::{verify_signature, MINING_REWARD};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::UnboundedSender;
+
+pub mod pool;
+
+use self::pool::MiningPool;
+
+use rand::Rng;
+
+pub const MINING_METHOD_GET_BLOCK_TEMPLATE: &str = "getblocktemplate";
+pub const MINING_METHOD_SUBMIT_BLOCK: &str = "submitblock";
+pub const MINING_METHOD_GET_CHALLENGE: &str = "getchallenge";
+pub const MINING_METHOD_REGISTER: &str = "register";
+pub const MINING_METHOD_SUBMIT_SHARE: &str = "submitshare";
+
+/// The number of templates kept for accepting solutions on a connection. A miner asking for more loses its oldest templates.
+pub const MAX_TEMPLATES: usize = 16;
+/// The number of blocks found by the pool which are kept until the node accepts them
+pub const MAX_FOUND_BLOCKS: usize = 100;
+/// The maximum length in bytes of a request of a miner including the newline. The connection is closed on a longer request.
+pub const MAX_REQUEST_LENGTH: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockTemplate {
+    pub template_id: String,
+    pub height: usize,
+    pub previous_block: String,
+    pub timestamp: String,
+    /// the selected transactions without the coinbase transaction
+    pub transactions: Vec<Transaction>,
+    /// the coinbase transaction. Miners may change its extra_nonce.
+    pub coinbase: Transaction,
+    /// the number of zeros at the end of the proof-of-work hash
+    pub difficulty: usize,
+    /// the proof-of-work hash function
+    pub algorithm: String,
+    /// the number of zeros at the end of the hash of a share (0 if the node is not a pool)
+    #[serde(default)]
+    pub share_difficulty: usize
+}
+
+impl BlockTemplate {
+    pub fn clone(&self) -> BlockTemplate {
+        BlockTemplate {
+            template_id: self.template_id.clone(),
+            height: self.height,
+            previous_block: self.previous_block.clone(),
+            timestamp: self.timestamp.clone(),
+            transactions: self.transactions.iter().map(|t| t.clone()).collect(),
+            coinbase: self.coinbase.clone(),
+            difficulty: self.difficulty,
+            algorithm: self.algorithm.clone(),
+            share_difficulty: self.share_difficulty
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_string(msg: &str) -> Option<BlockTemplate> {
+        serde_json::from_str(msg).ok()
+    }
+
+    pub fn create_block(&self, nonce: u64, extra_nonce: u64) -> Block {
+        //! build the block of this template with a nonce and an extra nonce
+        let mut transaction_pool = TransactionPool::create();
+        for t in self.transactions.iter() {
+            transaction_pool.push(t.clone());
+        }
+        let mut coinbase = self.coinbase.clone();
+        coinbase.extra_nonce = extra_nonce;
+        transaction_pool.push(coinbase);
+
+        let mut block = Block::create(transaction_pool, self.previous_block.clone());
+        block.timestamp = self.timestamp.clone();
+        block.nonce = nonce;
+        block
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockSubmission {
+    pub template_id: String,
+    pub nonce: u64,
+    pub extra_nonce: u64
+}
+
+/// The params of MINING_METHOD_REGISTER
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MinerRegistration {
+    pub address: String,
+    /// the signature of the challenge of the connection by the key of address
+    pub signature: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MiningRequest {
+    pub method: String,
+    pub params: String
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MiningResponse {
+    pub result: String,
+    pub error: String
+}
+
+/// The state of a core node which templates are built from. The node loop keeps it up to date.
+pub struct MiningState {
+    pub tp: TransactionPool,
+    /// the total fee of the transactions in tp
+    pub total_fee: i64,
+    pub tip: Block,
+    pub height: usize,
+    pub reward_address: String,
+    /// the mining pool if the node runs a pool
+    pub pool: Option<MiningPool>,
+    /// (block hash, payouts of the coinbase) of the blocks found by the pool which the node has not accepted yet
+    found_blocks: VecDeque<(String, Vec<(String, i64)>)>
+}
+
+impl MiningState {
+    pub fn create(reward_address: &str) -> MiningState {
+        MiningState {
+            tp: TransactionPool::create(),
+            total_fee: 0,
+            tip: Block::create_genesis_block(),
+            height: 1,
+            reward_address: reward_address.to_string(),
+            pool: None,
+            found_blocks: VecDeque::new()
+        }
+    }
+
+    pub fn block_accepted(&mut self, block_hash: &str) {
+        //! settle the balances paid by a block of the pool once the node accepted it
+        let position = match self.found_blocks.iter().position(|(hash, _)| hash == block_hash) {
+            Some(position) => position,
+            None => return,
+        };
+        let (_, payouts) = self.found_blocks.remove(position).unwrap();
+        if let Some(pool) = self.pool.as_mut() {
+            pool.block_found(&payouts);
+        }
+    }
+}
+
+pub fn create_block_template(state: &MiningState, address: &str, algorithm: &str) -> BlockTemplate {
+    //! create a template on top of the current tip paying the reward and the fees to address
+    let mut transactions: Vec<Transaction> = Vec::new();
+    for t in state.tp.transactions.iter() {
+        if t.tx_type == false {
+            transactions.push(t.clone());
+        }
+    }
+    let total_fee = state.total_fee + MINING_REWARD;
+    let recipient = if address.len() > 0 { address } else { &state.reward_address };
+    let (coinbase, share_difficulty) = match &state.pool {
+        Some(pool) => (Transaction::create_coinbase_transaction_with_outputs(&pool.get_payouts(total_fee, &state.reward_address)), pool.share_difficulty),
+        None => (Transaction::create_coinbase_transaction(recipient, total_fee), 0),
+    };
+
+    let mut template = BlockTemplate {
+        template_id: String::new(),
+        height: state.height,
+        previous_block: state.tip.get_hash(),
+        timestamp: chrono::Utc::now().to_string(),
+        transactions: transactions,
+        coinbase: coinbase,
+        difficulty: DIFFICULTY,
+        algorithm: algorithm.to_string(),
+        share_difficulty: share_difficulty
+    };
+    template.template_id = get_double_sha256(&template.to_string());
+    template
+}
+
+/// A connection with a miner. A template can only be solved on the connection which got it,
+/// and shares are credited to the address which the miner proved to own on the connection.
+struct MinerSession {
+    challenge: String,
+    /// empty until the miner registers
+    address: String,
+    /// the templates on the current tip from the oldest to the newest
+    templates: VecDeque<BlockTemplate>
+}
+
+/// Everything a connection with a miner needs
+struct MiningContext {
+    state: Arc<Mutex<MiningState>>,
+    consensus: Arc<dyn Consensus>,
+    algorithm: Arc<dyn PowAlgorithm>,
+    tx: UnboundedSender<NodeEvent>,
+    my_ip: String,
+    my_port: String
+}
+
+impl MiningContext {
+    fn clone(&self) -> MiningContext {
+        MiningContext {
+            state: Arc::clone(&self.state),
+            consensus: Arc::clone(&self.consensus),
+            algorithm: Arc::clone(&self.algorithm),
+            tx: self.tx.clone(),
+            my_ip: self.my_ip.clone(),
+            my_port: self.my_port.clone()
+        }
+    }
+}
+
+pub async fn start_mining_server(addr: &str, state: Arc<Mutex<MiningState>>, consensus: Arc<dyn Consensus>, tx: UnboundedSender<NodeEvent>, my_ip: &str, my_port: &str) {
+    //! accept external miners at addr, each in its own task. Accepted blocks are passed to the node loop as MSG_NEW_BLOCK_TO_ALL.
+    let algorithm = match consensus.get_pow_algorithm() {
+        Some(algorithm) => algorithm,
+        None => {
+            println!("external mining is only for proof of work");
+            return;
+        }
+    };
+
+    println!("starting mining server at {}", addr);
+    let server = tokio::net::TcpListener::bind(addr).await.expect("Failure in starting mining server");
+    let context = MiningContext {
+        state: state,
+        consensus: consensus,
+        algorithm: algorithm,
+        tx: tx,
+        my_ip: my_ip.to_string(),
+        my_port: my_port.to_string()
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match server.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    println!("mining connection error: {}", e);
+                    continue;
+                }
+            };
+            println!("miner connected");
+            let context = context.clone();
+            tokio::spawn(async move {
+                handle_miner(stream, &context).await;
+            });
+        }
+    });
+}
+
+async fn handle_miner(stream: tokio::net::TcpStream, context: &MiningContext) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill(&mut challenge);
+    let mut session = MinerSession {
+        challenge: hex::encode(challenge),
+        address: String::new(),
+        templates: VecDeque::new()
+    };
+
+    loop {
+        let line = match read_request(&mut reader).await {
+            Some(line) => line,
+            None => break,
+        };
+        let response = match serde_json::from_str::<MiningRequest>(&line) {
+            Ok(request) => handle_mining_request(&request, &mut session, context),
+            Err(e) => MiningResponse { result: String::new(), error: format!("invalid request: {}", e) },
+        };
+        let response_str = serde_json::to_string(&response).unwrap() + "\n";
+        if writer.write_all(response_str.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    println!("miner disconnected");
+}
+
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Option<String> {
+    //! read a line of at most MAX_REQUEST_LENGTH bytes. It returns None when the connection is closed or the line is longer.
+    let mut line = Vec::new();
+    let mut limited = (&mut *reader).take(MAX_REQUEST_LENGTH as u64);
+    match limited.read_until(b'\n', &mut line).await {
+        Ok(0) | Err(_) => return None,
+        Ok(_) => {}
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() >= MAX_REQUEST_LENGTH {
+            println!("request of a miner is too long");
+        }
+        return None;
+    }
+    String::from_utf8(line).ok()
+}
+
+fn handle_mining_request(request: &MiningRequest, session: &mut MinerSession, context: &MiningContext) -> MiningResponse {
+    if request.method == MINING_METHOD_GET_BLOCK_TEMPLATE {
+        // the coinbase of a pool pays the miners of the pool whatever the address is
+        let state = context.state.lock().unwrap();
+        let algorithm_name = context.algorithm.name();
+        let template = create_block_template(&state, &request.params, &algorithm_name);
+
+        session.templates.retain(|t| t.previous_block == template.previous_block);
+        // the oldest template goes first, so that the miner can still submit solutions of recent templates
+        while session.templates.len() >= MAX_TEMPLATES {
+            session.templates.pop_front();
+        }
+        session.templates.push_back(template.clone());
+        return MiningResponse { result: template.to_string(), error: String::new() };
+    }
+    else if request.method == MINING_METHOD_GET_CHALLENGE {
+        return MiningResponse { result: session.challenge.clone(), error: String::new() };
+    }
+    else if request.method == MINING_METHOD_REGISTER {
+        let registration: MinerRegistration = match serde_json::from_str(&request.params) {
+            Ok(registration) => registration,
+            Err(e) => return MiningResponse { result: String::new(), error: format!("invalid registration: {}", e) },
+        };
+        if verify_signature(&session.challenge, &registration.signature, &registration.address) == false {
+            return MiningResponse { result: String::new(), error: "the challenge is not signed by the key of the address".to_string() };
+        }
+        let mut state = context.state.lock().unwrap();
+        return match state.pool.as_mut() {
+            Some(pool) => {
+                if pool.register_miner(&registration.address) == true {
+                    session.address = registration.address;
+                    MiningResponse { result: "registered".to_string(), error: String::new() }
+                }
+                else {
+                    MiningResponse { result: String::new(), error: "invalid address".to_string() }
+                }
+            },
+            None => MiningResponse { result: String::new(), error: "the node is not a pool".to_string() },
+        };
+    }
+    else if request.method == MINING_METHOD_SUBMIT_BLOCK || request.method == MINING_METHOD_SUBMIT_SHARE {
+        let submission: BlockSubmission = match serde_json::from_str(&request.params) {
+            Ok(submission) => submission,
+            Err(e) => return MiningResponse { result: String::new(), error: format!("invalid submission: {}", e) },
+        };
+        let template = match session.templates.iter().find(|t| t.template_id == submission.template_id) {
+            Some(template) => template.clone(),
+            None => return MiningResponse { result: String::new(), error: "unknown or stale template".to_string() },
+        };
+
+        let block = template.create_block(submission.nonce, submission.extra_nonce);
+        let mut state = context.state.lock().unwrap();
+        let tip = state.tip.clone();
+
+        if request.method == MINING_METHOD_SUBMIT_SHARE {
+            if session.address.len() == 0 {
+                return MiningResponse { result: String::new(), error: "unregistered miner".to_string() };
+            }
+            if template.previous_block != tip.get_hash() {
+                return MiningResponse { result: String::new(), error: "stale share".to_string() };
+            }
+            let pool = match state.pool.as_mut() {
+                Some(pool) => pool,
+                None => return MiningResponse { result: String::new(), error: "the node is not a pool".to_string() },
+            };
+            let hash = block.get_pow_hash(context.algorithm.as_ref());
+            let (msg, flag) = pool.submit_share(&session.address, &hash, &template.previous_block);
+            if flag == false {
+                return MiningResponse { result: String::new(), error: msg };
+            }
+            if context.consensus.is_valid_block(&block, &[tip]) == false {
+                return MiningResponse { result: "share".to_string(), error: String::new() };
+            }
+            // the balances are settled when the node accepts the block
+            let payouts: Vec<(String, i64)> = template.coinbase.outputs.iter().map(|o| (o.recipient.clone(), o.value)).collect();
+            while state.found_blocks.len() >= MAX_FOUND_BLOCKS {
+                state.found_blocks.pop_front();
+            }
+            state.found_blocks.push_back((block.get_hash(), payouts));
+        }
+        else if context.consensus.is_valid_block(&block, &[tip]) == false {
+            return MiningResponse { result: String::new(), error: "invalid or stale block".to_string() };
+        }
+
+        println!("block from an external miner is accepted");
+        let msg = connection_manager::get_message_text(message_manager::MSG_NEW_BLOCK_TO_ALL, &context.my_ip, &context.my_port, &block.to_string());
+        context.tx.send(NodeEvent::Local(LocalCommand::Message(msg))).unwrap();
+        if request.method == MINING_METHOD_SUBMIT_SHARE {
+            return MiningResponse { result: "block ".to_string() + &block.get_hash(), error: String::new() };
+        }
+        return MiningResponse { result: block.get_hash(), error: String::new() };
+    }
+    MiningResponse { result: String::new(), error: format!("unknown method {}", request.method) }
+}
+
+pub struct MiningClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream
+}
+
+impl MiningClient {
+    pub fn connect(addr: &str) -> Option<MiningClient> {
+        let stream = match TcpStream::connect(addr) {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("cannot connect to the mining server {}: {}", addr, e);
+                return None;
+            }
+        };
+        let writer = stream.try_clone().ok()?;
+        Some(MiningClient {
+            reader: BufReader::new(stream),
+            writer: writer
+        })
+    }
+
+    fn request(&mut self, method: &str, params: &str) -> MiningResponse {
+        //! a failure of the connection or an invalid response is returned as the error of the response
+        let request = MiningRequest { method: method.to_string(), params: params.to_string() };
+        let request_str = serde_json::to_string(&request).unwrap() + "\n";
+        if let Err(e) = self.writer.write_all(request_str.as_bytes()) {
+            return MiningResponse { result: String::new(), error: format!("cannot send the request: {}", e) };
+        }
+
+        let mut line = String::new();
+        if let Err(e) = self.reader.read_line(&mut line) {
+            return MiningResponse { result: String::new(), error: format!("cannot read the response: {}", e) };
+        }
+        match serde_json::from_str(&line) {
+            Ok(response) => response,
+            Err(e) => MiningResponse { result: String::new(), error: format!("invalid response: {}", e) },
+        }
+    }
+
+    pub fn get_block_template(&mut self, address: &str) -> Option<BlockTemplate> {
+        //! get a template paying to address. An empty address means the address of the core node.
+        let response = self.request(MINING_METHOD_GET_BLOCK_TEMPLATE, address);
+        if response.error.len() > 0 {
+            println!("getblocktemplate error: {}", response.error);
+            return None;
+        }
+        BlockTemplate::from_string(&response.result)
+    }
+
+    pub fn submit_block(&mut self, submission: &BlockSubmission) -> (String, bool) {
+        //! submit a solution. It returns the block hash if accepted, otherwise the error.
+        let response = self.request(MINING_METHOD_SUBMIT_BLOCK, &serde_json::to_string(submission).unwrap());
+        if response.error.len() > 0 {
+            return (response.error, false);
+        }
+        (response.result, true)
+    }
+
+    pub fn register(&mut self, km: &KeyManager) -> (String, bool) {
+        //! register the address of km as the miner of this connection by signing its challenge
+        let response = self.request(MINING_METHOD_GET_CHALLENGE, "");
+        if response.error.len() > 0 {
+            return (response.error, false);
+        }
+        let registration = MinerRegistration {
+            address: km.my_address(),
+            signature: km.compute_digital_signature(&response.result)
+        };
+        let response = self.request(MINING_METHOD_REGISTER, &serde_json::to_string(&registration).unwrap());
+        if response.error.len() > 0 {
+            return (response.error, false);
+        }
+        (response.result, true)
+    }
+
+    pub fn submit_share(&mut self, submission: &BlockSubmission) -> (String, bool) {
+        //! submit a share to the pool for the registered address. The result is "block <hash>" if the share is also a block.
+        let response = self.request(MINING_METHOD_SUBMIT_SHARE, &serde_json::to_string(submission).unwrap());
+        if response.error.len() > 0 {
+            return (response.error, false);
+        }
+        (response.result, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_server_core::consensus::pow::ProofOfWork;
+
+    fn create_context() -> MiningContext {
+        let consensus: Arc<dyn Consensus> = Arc::new(ProofOfWork::create());
+        let (tx, _) = tokio::sync::mpsc::unbounded_channel();
+        MiningContext {
+            state: Arc::new(Mutex::new(MiningState::create("miner"))),
+            algorithm: consensus.get_pow_algorithm().unwrap(),
+            consensus: consensus,
+            tx: tx,
+            my_ip: "127.0.0.1".to_string(),
+            my_port: "0".to_string()
+        }
+    }
+
+    fn get_template(session: &mut MinerSession, context: &MiningContext) -> BlockTemplate {
+        let request = MiningRequest { method: MINING_METHOD_GET_BLOCK_TEMPLATE.to_string(), params: String::new() };
+        let response = handle_mining_request(&request, session, context);
+        BlockTemplate::from_string(&response.result).unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_longer_than_the_limit_closes_the_connection() {
+        let mut reader: &[u8] = b"{\"method\":\"getchallenge\",\"params\":\"\"}\n";
+        assert_eq!(read_request(&mut reader).await.unwrap(), "{\"method\":\"getchallenge\",\"params\":\"\"}\n");
+        assert!(read_request(&mut reader).await.is_none());
+
+        let long = "a".repeat(MAX_REQUEST_LENGTH) + "\n";
+        let mut reader = long.as_bytes();
+        assert!(read_request(&mut reader).await.is_none());
+    }
+
+    #[test]
+    fn templates_are_kept_per_session() {
+        let context = create_context();
+        let mut session = MinerSession { challenge: String::new(), address: String::new(), templates: VecDeque::new() };
+        let mut other = MinerSession { challenge: String::new(), address: String::new(), templates: VecDeque::new() };
+        let first = get_template(&mut session, &context);
+        for _ in 0..MAX_TEMPLATES * 2 {
+            get_template(&mut other, &context);
+        }
+
+        // another miner asking for many templates does not evict the templates of the session
+        assert_eq!(other.templates.len(), MAX_TEMPLATES);
+        assert_eq!(session.templates.len(), 1);
+        assert_eq!(session.templates[0].template_id, first.template_id);
+        let submission = BlockSubmission { template_id: first.template_id.clone(), nonce: 0, extra_nonce: 0 };
+        let request = MiningRequest { method: MINING_METHOD_SUBMIT_BLOCK.to_string(), params: serde_json::to_string(&submission).unwrap() };
+        assert_eq!(handle_mining_request(&request, &mut other, &context).error, "unknown or stale template");
+    }
+}
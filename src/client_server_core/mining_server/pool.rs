@@ -0,0 +1,204 @@
+/*  
+    pool.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Mining pool. Registered miners submit shares whose hash meets a lower difficulty than a block,
+//! and the coinbase transaction of the pool splits the block reward among them by PPLNS or PPS.
+
+use crate::client_server_core::block_chain::DIFFICULTY;
+use crate::client_server_core::MINING_REWARD;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Pay per last N shares. The reward of a block is split in proportion to the last PPLNS_WINDOW shares.
+pub const PAYOUT_PPLNS: &str = "pplns";
+/// Pay per share. Every share earns a fixed value and the pool operator takes the variance of finding blocks.
+pub const PAYOUT_PPS: &str = "pps";
+
+/// The number of zeros at the end of the hash of a share
+pub const SHARE_DIFFICULTY: usize = DIFFICULTY - 1;
+/// The number of recent shares which a block reward is split among under PPLNS
+pub const PPLNS_WINDOW: usize = 1000;
+
+pub struct MiningPool {
+    pub payout_scheme: String,
+    pub share_difficulty: usize,
+    miners: HashSet<String>,
+    /// the miner addresses of the recent shares (PPLNS)
+    shares: VecDeque<String>,
+    /// the number of accepted shares of each miner
+    share_counts: HashMap<String, usize>,
+    /// the unpaid values of each miner (PPS)
+    balances: HashMap<String, i64>,
+    /// the hashes of shares on top of the current tip for rejecting duplicates
+    seen_shares: HashSet<String>,
+    tip: String
+}
+
+impl MiningPool {
+    pub fn create(payout_scheme: &str) -> MiningPool {
+        MiningPool {
+            payout_scheme: payout_scheme.to_string(),
+            share_difficulty: SHARE_DIFFICULTY,
+            miners: HashSet::new(),
+            shares: VecDeque::new(),
+            share_counts: HashMap::new(),
+            balances: HashMap::new(),
+            seen_shares: HashSet::new(),
+            tip: String::new()
+        }
+    }
+
+    pub fn register_miner(&mut self, address: &str) -> bool {
+        if address.len() == 0 {
+            return false;
+        }
+        self.miners.insert(address.to_string());
+        println!("miner is registered to the pool: {}", address);
+        true
+    }
+
+    pub fn is_registered(&self, address: &str) -> bool {
+        self.miners.contains(address)
+    }
+
+    pub fn get_share_value(&self) -> i64 {
+        //! the value of a share under PPS. A block is expected every 16^(DIFFICULTY - share_difficulty) shares.
+        let expected_shares = 16_i64.pow((DIFFICULTY - self.share_difficulty) as u32);
+        let value = MINING_REWARD / expected_shares;
+        if value > 0 { value } else { 1 }
+    }
+
+    pub fn submit_share(&mut self, address: &str, hash: &str, previous_block: &str) -> (String, bool) {
+        //! credit a share of address. It returns the reason of rejection if the share is not accepted.
+        if self.is_registered(address) == false {
+            return ("unregistered miner".to_string(), false);
+        }
+        let suffix: String = "0".to_string().repeat(self.share_difficulty);
+        if hash.ends_with(&suffix) == false {
+            return ("share does not meet the share difficulty".to_string(), false);
+        }
+        if self.tip != previous_block {
+            self.tip = previous_block.to_string();
+            self.seen_shares.clear();
+        }
+        if self.seen_shares.insert(hash.to_string()) == false {
+            return ("duplicate share".to_string(), false);
+        }
+
+        self.shares.push_back(address.to_string());
+        while self.shares.len() > PPLNS_WINDOW {
+            self.shares.pop_front();
+        }
+        *self.share_counts.entry(address.to_string()).or_insert(0) += 1;
+        if self.payout_scheme == PAYOUT_PPS {
+            let value = self.get_share_value();
+            *self.balances.entry(address.to_string()).or_insert(0) += value;
+        }
+        ("share is accepted".to_string(), true)
+    }
+
+    pub fn get_payouts(&self, total_reward: i64, operator: &str) -> Vec<(String, i64)> {
+        //! split total_reward into (recipient, value). The rest after paying miners goes to operator.
+        let mut owed: HashMap<String, i64> = HashMap::new();
+        if self.payout_scheme == PAYOUT_PPS {
+            for (address, balance) in self.balances.iter() {
+                owed.insert(address.clone(), *balance);
+            }
+        }
+        else {
+            let mut counts: HashMap<String, i64> = HashMap::new();
+            for address in self.shares.iter() {
+                *counts.entry(address.clone()).or_insert(0) += 1;
+            }
+            let window = self.shares.len() as i64;
+            for (address, count) in counts.iter() {
+                owed.insert(address.clone(), total_reward * count / window);
+            }
+        }
+
+        // sort by address so that every template of the same state has the same coinbase
+        let mut addresses: Vec<&String> = owed.keys().collect();
+        addresses.sort();
+
+        let mut payouts: Vec<(String, i64)> = Vec::new();
+        let mut remaining = total_reward;
+        for address in addresses {
+            let value = if owed[address] < remaining { owed[address] } else { remaining };
+            if value > 0 {
+                payouts.push((address.clone(), value));
+                remaining -= value;
+            }
+        }
+        if remaining > 0 || payouts.len() == 0 {
+            payouts.push((operator.to_string(), remaining));
+        }
+        payouts
+    }
+
+    pub fn block_found(&mut self, payouts: &Vec<(String, i64)>) {
+        //! settle the balances paid by the coinbase transaction of a found block
+        if self.payout_scheme != PAYOUT_PPS {
+            return;
+        }
+        for (address, value) in payouts.iter() {
+            if let Some(balance) = self.balances.get_mut(address) {
+                *balance -= if *value < *balance { *value } else { *balance };
+            }
+        }
+        self.balances.retain(|_, balance| *balance > 0);
+    }
+
+    pub fn print(&self) {
+        println!("mining pool ({}, share difficulty {})", self.payout_scheme, self.share_difficulty);
+        for address in self.miners.iter() {
+            let count = self.share_counts.get(address).unwrap_or(&0);
+            let balance = self.balances.get(address).unwrap_or(&0);
+            println!("{} shares: {} unpaid: {}", address, count, balance);
+        }
+    }
+}
+
+pub fn run() {
+    //! simulate three miners with different hash rates and print the coinbase payouts
+    for scheme in [PAYOUT_PPLNS, PAYOUT_PPS].iter() {
+        let mut pool = MiningPool::create(scheme);
+        let miners = ["miner-a", "miner-b", "miner-c"];
+        for m in miners.iter() {
+            pool.register_miner(m);
+        }
+        let zeros: String = "0".to_string().repeat(SHARE_DIFFICULTY);
+        let mut n = 0;
+        for (i, m) in miners.iter().enumerate() {
+            for _ in 0..(i + 1) * 4 {
+                n += 1;
+                let hash = format!("{:x}{}", n, zeros);
+                pool.submit_share(m, &hash, "tip");
+            }
+        }
+        let (msg, flag) = pool.submit_share("miner-a", &format!("{:x}{}", 1, zeros), "tip");
+        println!("duplicate share: {} {}", flag, msg);
+        let (msg, flag) = pool.submit_share("unknown", &format!("{:x}{}", 100, zeros), "tip");
+        println!("unregistered share: {} {}", flag, msg);
+
+        let payouts = pool.get_payouts(MINING_REWARD + 5, "operator");
+        println!("{} payouts: {:?}", scheme, payouts);
+        pool.block_found(&payouts);
+        pool.print();
+    }
+}
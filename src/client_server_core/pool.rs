@@ -0,0 +1,101 @@
+/*  
+    pool.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+//! Tests of the transactions which core nodes pool and relay. Transactions without inputs are pooled only
+//! with a valid payload of the consensus, the others have to pay the minimum fee rate, and a full pool keeps
+//! the transactions paying the most.
+
+use crate::client_server_core::{ProducerState, ServerCore, Worker, MAX_POOL_TRANSACTIONS};
+use crate::client_server_core::block_chain::{Block, Transaction, TransactionInput, TransactionOutput, TransactionPool};
+use crate::client_server_core::block_chain::keymanager::KeyManager;
+use crate::client_server_core::connection_manager::message::{Message, MessageHandler, Peer};
+use crate::client_server_core::connection_manager::noise::NodeKey;
+
+/// The port of the node under test. Nothing listens on it, so its relays fail immediately.
+const POOL_PORT: &str = "0";
+
+fn create_node() -> (ServerCore, Transaction) {
+    //! a node whose chain pays a coinbase of 30 coins to the node itself. Creating keys is slow, so its key pays.
+    let mut server_core = ServerCore::create("127.0.0.1", POOL_PORT, "", "");
+    let coinbase = Transaction::create_coinbase_transaction(&server_core.km.my_address(), 30);
+    let mut transaction_pool = TransactionPool::create();
+    transaction_pool.push(coinbase.clone());
+    let block = Block::create(transaction_pool, server_core.bc.chain[0].get_hash());
+    assert!(server_core.utxo_set.apply_block(&block, false));
+    server_core.bc.set_new_block(block);
+    (server_core, coinbase)
+}
+
+fn create_payment(km: &KeyManager, coinbase: &Transaction, fee: i64) -> Transaction {
+    //! a signed transaction spending the coinbase with fee
+    let mut t = Transaction::create();
+    t.inputs.push(TransactionInput::create(coinbase.clone(), 0));
+    t.outputs.push(TransactionOutput::create("recipient", 30 - fee));
+    t.signature = km.compute_digital_signature(&t.to_string());
+    t
+}
+
+async fn receive(server_core: &mut ServerCore, t: &Transaction) {
+    let sender = Peer {
+        ip: "127.0.0.1".to_string(),
+        port: "1".to_string(),
+        key: NodeKey::generate().get_public_key()
+    };
+    let mut producer = ProducerState {
+        locked: false,
+        cur_time: 0,
+        w: Worker::new()
+    };
+    server_core.handle_message(&sender, &Message::NewTransaction(t.clone()), &mut producer).await;
+}
+
+#[tokio::test]
+async fn transaction_without_inputs_is_not_pooled() {
+    let (mut server_core, _) = create_node();
+    receive(&mut server_core, &Transaction::create()).await;
+    let vote = Transaction::create_governance_transaction("add", "signer", &server_core.km.my_address(), 0);
+    receive(&mut server_core, &vote).await;
+    assert_eq!(server_core.tp.len(), 0);
+}
+
+#[tokio::test]
+async fn transaction_below_the_minimum_fee_rate_is_not_pooled() {
+    let (mut server_core, coinbase) = create_node();
+    let t = create_payment(&server_core.km, &coinbase, 0);
+    receive(&mut server_core, &t).await;
+    assert_eq!(server_core.tp.len(), 0);
+    let t = create_payment(&server_core.km, &coinbase, 5);
+    receive(&mut server_core, &t).await;
+    assert_eq!(server_core.tp.len(), 1);
+}
+
+#[tokio::test]
+async fn full_pool_evicts_the_lowest_fee_rate() {
+    let (mut server_core, coinbase) = create_node();
+    // transactions without inputs pay no fee, so they are evicted first
+    let mut free = Transaction::create();
+    free.timestamp = "free".to_string();
+    for _ in 0..MAX_POOL_TRANSACTIONS {
+        server_core.tp.push(free.clone());
+    }
+    let t = create_payment(&server_core.km, &coinbase, 5);
+    receive(&mut server_core, &t).await;
+    assert_eq!(server_core.tp.len(), MAX_POOL_TRANSACTIONS);
+    assert!(server_core.tp.contain(&t));
+}
@@ -0,0 +1,75 @@
+/*  
+    reorg.rs
+    Copyright (c) 2022 Yasuo Tabei
+ 
+    Released under the GNU General Public License version 3.0
+    see https://www.gnu.org/licenses/gpl-3.0.en.html
+
+    The GNU General Public License is a free, copyleft license for software and other kinds of works.
+    The licenses for most software and other practical works are designed to take away your freedom to share and change the works. By contrast, the GNU General Public License is intended to guarantee your freedom to share and change all versions of a program--to make sure it remains free software for all its users. We, the Free Software Foundation, use the GNU General Public License for most of our software; it applies also to any other work released this way by its authors. You can apply it to your programs, too.
+    When we speak of free software, we are referring to freedom, not price. Our General Public Licenses are designed to make sure that you have the freedom to distribute copies of free software (and charge for them if you wish), that you receive source code or can get it if you want it, that you can change the software or use pieces of it in new free programs, and that you know you can do these things.
+    To protect your rights, we need to prevent others from denying you these rights or asking you to surrender the rights. Therefore, you have certain responsibilities if you distribute copies of the software, or if you modify it: responsibilities to respect the freedom of others.
+    For example, if you distribute copies of such a program, whether gratis or for a fee, you must pass on to the recipients the same freedoms that you received. You must make sure that they, too, receive or can get the source code. And you must show them these terms so they know their rights.
+    Developers that use the GNU GPL protect your rights with two steps: (1) assert copyright on the software, and (2) offer you this License giving you legal permission to copy, distribute and/or modify it.
+    For the developers' and authors' protection, the GPL clearly explains that there is no warranty for this free software. For both users' and authors' sake, the GPL requires that modified versions be marked as changed, so that their problems will not be attributed erroneously to authors of previous versions.
+    Some devices are designed to deny users access to install or run modified versions of the software inside them, although the manufacturer can do so. This is fundamentally incompatible with the aim of protecting users' freedom to change the software. The systematic pattern of such abuse occurs in the area of products for individuals to use, which is precisely where it is most unacceptable. Therefore, we have designed this version of the GPL to prohibit the practice for those products. If such problems arise substantially in other domains, we stand ready to extend this provision to those domains in future versions of the GPL, as needed to protect the freedom of users.
+    Finally, every program is threatened constantly by software patents. States should not allow patents to restrict development and use of software on general-purpose computers, but in those that do, we wish to avoid the special danger that patents applied to a free program could make it effectively proprietary. To prevent this, the GPL assures that patents cannot be used to render the program non-free.
+    The precise terms and conditions for copying, distribution and modification follow.
+ */
+
+
+//! Tests of the reorganizations of a core node. A pruned node has only the headers of its old blocks, so it
+//! validates a fork from the fork point and rolls its UTXO set back there instead of replaying the chain.
+
+use crate::client_server_core::{ServerCore, MINING_REWARD, MIN_BLOCKS_TO_KEEP};
+use crate::client_server_core::block_chain::{Block, BlockChain, Transaction, TransactionPool};
+
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// The number of blocks of the chain of the node before the fork
+const CHAIN_BLOCKS: usize = 20;
+/// The height of the first block of the fork
+const FORK_HEIGHT: usize = 16;
+/// The number of blocks of the fork, which is longer than the chain of the node
+const FORK_BLOCKS: usize = 8;
+
+fn mine_block(previous_block: &Block, recipient: &str) -> Block {
+    //! a proof-of-work block paying the reward to recipient
+    let mut transaction_pool = TransactionPool::create();
+    transaction_pool.push(Transaction::create_coinbase_transaction(recipient, MINING_REWARD));
+    let mut block = Block::create(transaction_pool, previous_block.get_hash());
+    assert!(block.compute_nonce_for_pow(&Arc::new(AtomicBool::new(false))));
+    block
+}
+
+#[tokio::test]
+async fn pruned_node_switches_to_a_longer_fork() {
+    let mut server_core = ServerCore::create("127.0.0.1", "0", "", "");
+    assert!(server_core.enable_pruning(MIN_BLOCKS_TO_KEEP));
+    for i in 1..=CHAIN_BLOCKS {
+        let block = mine_block(&server_core.bc.chain[i - 1], &format!("main{}", i));
+        assert!(server_core.add_new_block(block).await);
+    }
+    assert!(server_core.bc.get_pruned_height() > 0);
+
+    let mut fork = BlockChain { chain: server_core.bc.chain[..FORK_HEIGHT].iter().map(|b| b.clone()).collect() };
+    for i in 0..FORK_BLOCKS {
+        let block = mine_block(&fork.chain[fork.chain.len() - 1], &format!("fork{}", i));
+        fork.set_new_block(block);
+    }
+    let tip = fork.chain[fork.chain.len() - 1].get_hash();
+    assert!(server_core.replace_chain(fork));
+
+    // the rewards of the orphaned blocks are gone and the rewards of the fork are unspent
+    assert_eq!(server_core.previous_block_hash, tip);
+    assert_eq!(server_core.utxo_set.height, FORK_HEIGHT + FORK_BLOCKS);
+    for i in 1..=CHAIN_BLOCKS {
+        let expected = if i < FORK_HEIGHT { MINING_REWARD } else { 0 };
+        assert_eq!(server_core.utxo_set.get_balance(&format!("main{}", i)), expected);
+    }
+    for i in 0..FORK_BLOCKS {
+        assert_eq!(server_core.utxo_set.get_balance(&format!("fork{}", i)), MINING_REWARD);
+    }
+    assert_eq!(server_core.bc.get_pruned_height(), FORK_HEIGHT + FORK_BLOCKS - MIN_BLOCKS_TO_KEEP);
+}
@@ -17,16 +17,33 @@
     The precise terms and conditions for copying, distribution and modification follow.
  */
 
-mod client_server_core;
+// the code base compares booleans with `== true`, spells out struct fields, returns
+// explicitly and gives its types `create()`/`clone()`/`to_string()` methods
+#![allow(clippy::bool_comparison, clippy::redundant_field_names, clippy::len_zero, clippy::needless_return)]
+#![allow(clippy::should_implement_trait, clippy::inherent_to_string, clippy::len_without_is_empty)]
+#![allow(clippy::ptr_arg, clippy::too_many_arguments)]
 
-use std::thread;
-use std::time::Duration;
+use blockchain_rs::client_server_core;
+
+use std::time::{Duration, Instant};
 
 extern crate rand;
 use rand::Rng;
 
 use std::sync::mpsc;
-use crate::client_server_core::block_chain::UTXOManager;
+use client_server_core::block_chain::UTXOManager;
+use client_server_core::block_chain::coin_selection;
+use client_server_core::block_chain::coin_selection::CoinSelection;
+use client_server_core::block_chain::fee_estimator;
+use client_server_core::connection_manager::message;
+use client_server_core::connection_manager::message::Message;
+
+/// The estimated length of a signature box in a serialized transaction
+pub const SIGNATURE_SIZE_ESTIMATE: usize = 320;
+/// How long a wallet waits for the fee estimate of its core node before building a transaction
+pub const FEE_ESTIMATE_WAIT_MS: u64 = 5000;
+/// How often a wallet checks for the fee estimate while waiting
+pub const FEE_ESTIMATE_POLL_MS: u64 = 100;
 
 /// Wallet for edge nodes. It manages coins to be sent and received to/from other edge nodes.
 pub struct Wallet {
@@ -34,7 +51,12 @@ pub struct Wallet {
     um: UTXOManager,
     client_core: client_server_core::ClientCore,
     tx: mpsc::Sender<String>,
-    rx: mpsc::Receiver<String>
+    rx: mpsc::Receiver<String>,
+    fee_rate: f64,
+    /// the height of the next block filter to request
+    filter_height: usize,
+    /// the hash of the tip of the header chain. The headers after it are requested.
+    header_tip: String
 }
 
 impl Wallet {
@@ -52,7 +74,10 @@ impl Wallet {
             um: um,
             client_core: client_server_core::ClientCore::create(my_ip, my_port, my_core_ip, my_core_port),
             tx: tx,
-            rx: rx
+            rx: rx,
+            fee_rate: fee_estimator::MIN_FEE_RATE,
+            filter_height: 0,
+            header_tip: String::new()
         }
     }
 
@@ -64,10 +89,7 @@ impl Wallet {
         let t2 = client_server_core::block_chain::Transaction::create_coinbase_transaction(&self.km.my_address(), 30);
         let t3 = client_server_core::block_chain::Transaction::create_coinbase_transaction(&self.km.my_address(), 30);
         
-        let mut transactions: Vec<client_server_core::block_chain::Transaction> = Vec::new();
-        transactions.push(t1);
-        transactions.push(t2);
-        transactions.push(t3);
+        let transactions: Vec<client_server_core::block_chain::Transaction> = vec![t1, t2, t3];
 
         um.extract_utxo(&transactions);
         println!("my_address: {}", my_address);
@@ -75,23 +97,120 @@ impl Wallet {
         self.um = um;
     }
 
-    pub fn update_wallet(&mut self) {
-        self.update_block_chain();
-        if let Ok(msg) = self.rx.try_recv() {
-            self.client_core.bc = serde_json::from_str(&msg).unwrap();
-            let tx = self.client_core.bc.get_stored_transactions_from_bc();
-            self.um.extract_utxo(&tx);
-            println!("my_address: {}", self.km.my_address());
-            println!("my_balance: {}", self.um.my_balance);
+    pub async fn update_wallet(&mut self) {
+        self.update_block_chain().await;
+        while let Ok(msg) = self.rx.try_recv() {
+            let message = match message::decode(&msg) {
+                Ok(message) => message,
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            };
+            self.handle_message(message).await;
+        }
+    }
+
+    async fn update_fee_rate(&mut self) {
+        //! ask the core node for the fee rate right before building a transaction and wait for the answer.
+        //! The last fee rate is kept if the core node does not answer in time.
+        self.client_core.send_req_fee_estimate_to_my_core_node(fee_estimator::DEFAULT_CONFIRM_TARGET).await;
+        let deadline = Instant::now() + Duration::from_millis(FEE_ESTIMATE_WAIT_MS);
+        while Instant::now() < deadline {
+            while let Ok(msg) = self.rx.try_recv() {
+                let message = match message::decode(&msg) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                };
+                let is_fee_estimate = matches!(message, Message::FeeEstimate(_));
+                self.handle_message(message).await;
+                if is_fee_estimate == true {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(FEE_ESTIMATE_POLL_MS)).await;
+        }
+        println!("no fee estimate from the core node, keep fee rate {}", self.fee_rate);
+    }
+
+    async fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::FullChain(bc) => {
+                self.client_core.bc = bc;
+                let tx = self.client_core.bc.get_stored_transactions_from_bc();
+                self.um.extract_utxo(&tx);
+                println!("my_address: {}", self.km.my_address());
+                println!("my_balance: {}", self.um.my_balance);
+            },
+            Message::Headers(headers) => {
+                if let Some(tip) = headers.last() {
+                    self.header_tip = tip.get_hash();
+                }
+            },
+            Message::MerkleProofs(proofs) => {
+                let tx: Vec<client_server_core::block_chain::Transaction> = proofs.iter().map(|p| p.transaction.clone()).collect();
+                self.um.extract_utxo(&tx);
+                println!("my_address: {}", self.km.my_address());
+                println!("my_balance: {}", self.um.my_balance);
+            },
+            Message::Filters(filters) => {
+                let block_hashes = self.um.get_matched_blocks(&filters);
+                println!("{} of {} block filters matched", block_hashes.len(), filters.len());
+                if let Some(last) = filters.last() {
+                    self.filter_height = last.height + 1;
+                }
+                if block_hashes.len() > 0 {
+                    self.client_core.send_req_blocks_to_my_core_node(&block_hashes).await;
+                }
+            },
+            Message::Blocks(blocks) => {
+                let mut tx: Vec<client_server_core::block_chain::Transaction> = Vec::new();
+                for block in blocks.iter() {
+                    for t in block.transaction_pool.transactions.iter() {
+                        tx.push(t.clone());
+                    }
+                }
+                self.um.extract_utxo(&tx);
+                println!("my_address: {}", self.km.my_address());
+                println!("my_balance: {}", self.um.my_balance);
+            },
+            Message::FeeEstimate(fee_estimate) => {
+                self.fee_rate = fee_estimate.fee_rate;
+                println!("fee rate for {} blocks: {}", fee_estimate.target_blocks, self.fee_rate);
+            },
+            _ => {},
         }
     }
 
-    fn update_block_chain(&mut self) {
-        self.client_core.send_req_full_chain_to_my_core_node();
+    async fn update_block_chain(&mut self) {
+        if self.client_core.spv == true {
+            // block filters instead of Merkle proofs for my address so that the core node does not learn it,
+            // unless the core node did not negotiate block filters
+            let header_tip = self.header_tip.clone();
+            self.client_core.send_req_headers_to_my_core_node(&header_tip).await;
+            if self.client_core.core_node_supports(client_server_core::connection_manager::handshake::FEATURE_BLOCK_FILTERS) == true {
+                self.client_core.send_req_filters_to_my_core_node(self.filter_height).await;
+            }
+            else {
+                let my_address = self.km.my_address();
+                self.client_core.send_req_merkle_proofs_to_my_core_node(&my_address).await;
+            }
+        }
+        else {
+            self.client_core.send_req_full_chain_to_my_core_node().await;
+        }
     }
 
-    pub fn start(&mut self) {
-        self.client_core.start(&self.tx);
+    pub fn enable_spv(&mut self) {
+        //! sync with headers and Merkle proofs instead of the full chain
+        self.client_core.enable_spv();
+    }
+
+    pub async fn start(&mut self) {
+        self.client_core.start(&self.tx).await;
     }
 
     pub fn show_my_block_chain(&self) {
@@ -99,7 +218,56 @@ impl Wallet {
         self.client_core.bc.print();
     }
 
-    pub fn send(&mut self, recipient: &str, amount: i64, sendfee: i64) {
+    pub async fn send(&mut self, recipient: &str, amount: i64, sendfee: i64) {
+        let strategy = coin_selection::LargestFirst::create();
+        self.send_with_coin_selection(recipient, amount, sendfee, &strategy).await;
+    }
+
+    pub async fn send_with_default_fee(&mut self, recipient: &str, amount: i64, strategy: &dyn CoinSelection) {
+        self.update_fee_rate().await;
+        let sendfee = self.compute_default_fee(recipient, amount, strategy);
+        println!("default fee: {} (fee rate {})", sendfee, self.fee_rate);
+        self.send_with_coin_selection(recipient, amount, sendfee, strategy).await;
+    }
+
+    pub fn compute_default_fee(&self, recipient: &str, amount: i64, strategy: &dyn CoinSelection) -> i64 {
+        //! compute the fee for sending amount to recipient from the fee rate estimated by the core node.
+        //! The size of the transaction depends on the selected inputs, so the selection is repeated until the fee is stable.
+        let mut sendfee: i64 = 1;
+        for _ in 0..3 {
+            let (selected, total_in, flag) = strategy.select(&self.um, amount + sendfee);
+            if flag == false {
+                break;
+            }
+            let t = self.build_transaction(recipient, amount, sendfee, &selected, total_in);
+            let needed = fee_estimator::get_fee_for_size(self.fee_rate, t.to_string().len() + SIGNATURE_SIZE_ESTIMATE);
+            if needed <= sendfee {
+                break;
+            }
+            sendfee = needed;
+        }
+        sendfee
+    }
+
+    fn build_transaction(&self, recipient: &str, amount: i64, sendfee: i64, selected: &Vec<usize>, total_in: i64) -> client_server_core::block_chain::Transaction {
+        //! build an unsigned transaction spending the selected UTXOs
+        let mut t = client_server_core::block_chain::Transaction::create();
+        for idx in selected.iter() {
+            let (utxo, output_index) = &self.um.utxo_txs[*idx];
+            t.inputs.push(client_server_core::block_chain::TransactionInput::create(utxo.clone(), *output_index));
+        }
+        t.outputs.push(client_server_core::block_chain::TransactionOutput::create(recipient, amount));
+
+        let change = total_in - amount - sendfee;
+        println!("change: {}", change);
+        if change > 0 {
+            let t_out = client_server_core::block_chain::TransactionOutput::create(&self.km.my_address(), change);
+            t.outputs.push(t_out);
+        }
+        t
+    }
+
+    pub async fn send_with_coin_selection(&mut self, recipient: &str, amount: i64, sendfee: i64, strategy: &dyn CoinSelection) {
         println!("my_balance: {}", self.um.my_balance);
 
         if recipient.len() == 0 {
@@ -126,69 +294,45 @@ impl Wallet {
             return;
         }
 
-        let (utxo, idx) = self.um.get_utxo_tx(0);
-
-        let mut t = client_server_core::block_chain::Transaction::create();
-
-        let t_in   = client_server_core::block_chain::TransactionInput::create(utxo.clone(), *idx);
-        let t_out = client_server_core::block_chain::TransactionOutput::create(recipient, amount);
-
-        t.inputs.push(t_in);
-        t.outputs.push(t_out);
-
-        let mut counter = 1;
-        while t.is_enough_inputs(sendfee) == false {
-            let (new_uxto, new_idx) = self.um.get_utxo_tx(counter);
-            t.inputs.push(client_server_core::block_chain::TransactionInput::create(new_uxto.clone(), *new_idx));
-            counter += 1;
-            if counter >= utxo_len {
-                println!("Short of Coin. Not enough coin to be sent");
-                break;
-            }
+        let (selected, total_in, flag) = strategy.select(&self.um, amount + sendfee);
+        println!("coin selection {}: {:?}", strategy.name(), selected);
+        if flag == false {
+            println!("Short of Coin. Not enough coin to be sent");
+            return;
         }
 
-        if t.is_enough_inputs(sendfee) == true {
-            let change = t.compute_change(sendfee);
-            println!("change: {}", change);
-            let t_out = client_server_core::block_chain::TransactionOutput::create(&self.km.my_address(), change);
-            t.outputs.push(t_out);
-            t.signature = "".to_string();
-            let to_be_signed = serde_json::to_string(&t).unwrap();
-            let signed = self.km.compute_digital_signature(&to_be_signed);
-            t.signature = signed;
-            let tx_string = serde_json::to_string(&t).unwrap();
-            self.client_core.send_message_to_my_core_node(client_server_core::connection_manager::message_manager::MSG_NEW_TRANSACTION, &tx_string);
-            println!("signed new_tx: {}", tx_string);
-            self.um.put_utxo_tx(&t);
-
-            let mut to_be_deleted = 0;
-            let mut del_list_idx = Vec::new();
-            while to_be_deleted < counter {
-                let del_tx = self.um.get_utxo_tx(to_be_deleted);
-                del_list_idx.push(del_tx.1);
-                to_be_deleted += 1;
-            }
-            for idx in del_list_idx.iter() {
-                self.um.remove_utxo_tx(*idx);
-            }
-        }   
+        let mut t = self.build_transaction(recipient, amount, sendfee, &selected, total_in);
+        t.signature = "".to_string();
+        let to_be_signed = serde_json::to_string(&t).unwrap();
+        let signed = self.km.compute_digital_signature(&to_be_signed);
+        t.signature = signed;
+        let tx_string = serde_json::to_string(&t).unwrap();
+        self.client_core.send_message_to_my_core_node(client_server_core::connection_manager::message_manager::MSG_NEW_TRANSACTION, &tx_string).await;
+        println!("signed new_tx: {}", tx_string);
+        self.um.put_utxo_tx(&t);
+
+        // the new transaction is appended at the end, so the selected indices are still valid
+        let mut del_list_idx = selected.clone();
+        del_list_idx.sort();
+        for idx in del_list_idx.iter().rev() {
+            self.um.remove_utxo_tx(*idx);
+        }
         println!("my updated balance: {}", self.um.my_balance);
     }
 }
 
-fn start_server1() {
+async fn start_server1() {
     println!("start server1");
     let ip = "127.0.0.1";
     let port = "8880";
 
-    let mut my_p2p_server = client_server_core::ServerCore::create(&ip.to_string(), &port.to_string(), &"".to_string(), &"".to_string());
-    my_p2p_server.start();
+    let mut my_p2p_server = client_server_core::ServerCore::create(ip, port, "", "");
+    my_p2p_server.start().await;
 
-    loop {
-    }
+    std::future::pending::<()>().await;
 }
 
-fn start_server2() {
+async fn start_server2() {
     println!("start server2");
     let ip = "127.0.0.1";
     let port = "50090";
@@ -196,15 +340,14 @@ fn start_server2() {
     let ip_core = "127.0.0.1";
     let port_core = "8880";
 
-    let mut my_p2p_server = client_server_core::ServerCore::create(&ip.to_string(), &port.to_string(), &ip_core.to_string(), &port_core.to_string());
-    my_p2p_server.start();
-    my_p2p_server.join_network();
+    let mut my_p2p_server = client_server_core::ServerCore::create(ip, port, ip_core, port_core);
+    my_p2p_server.start().await;
+    my_p2p_server.join_network().await;
 
-    loop {
-    }
+    std::future::pending::<()>().await;
 }
 
-fn start_client1() {
+async fn start_client1() {
     let my_ip = "127.0.0.1";
     let my_port = "50092";
 
@@ -214,15 +357,15 @@ fn start_client1() {
     let mut wallet = Wallet::create(my_ip, my_port, my_core_ip, my_core_port);
 
     println!("wallet start");
-    wallet.start();
+    wallet.start().await;
 
     loop {
-        wallet.update_wallet();
-        thread::sleep(Duration::from_millis(50000));
+        wallet.update_wallet().await;
+        tokio::time::sleep(Duration::from_millis(50000)).await;
     }
 }
 
-fn start_client2() {
+async fn start_client2() {
     let my_ip = "127.0.0.1";
     let my_port = "50093";
 
@@ -234,22 +377,27 @@ fn start_client2() {
 
     println!("wallet start");
 
-    wallet.start();
+    wallet.start().await;
 
     let recipient = "untrusted comment: minisign public key: AE8BF9CAD01429A5\nRWSlKRTQyvmLrnX0rvRivOpEWl8zN2+0eEtmLDw8Vsq8Snudkyf4DYMZ\n";
 
-    wallet.send(recipient, 30, 5); // send 30 coins with 5 fee to the recipient
+    let strategy = coin_selection::create_coin_selection("branch-and-bound").unwrap();
+    wallet.send_with_default_fee(recipient, 30, strategy.as_ref()).await; // send 30 coins to the recipient with the estimated fee
 
     loop {
-        wallet.update_wallet();
-        thread::sleep(Duration::from_millis(50000));
+        wallet.update_wallet().await;
+        tokio::time::sleep(Duration::from_millis(50000)).await;
     }
 }
 
-fn main() {
-//   start_server1();
-//   start_server2();
-
-//    start_client1();
-    start_client2();
+#[tokio::main]
+async fn main() {
+    // the first argument picks the demo to run, the second wallet is the default
+    let demo = std::env::args().nth(1).unwrap_or("client2".to_string());
+    match demo.as_str() {
+        "server1" => start_server1().await,
+        "server2" => start_server2().await,
+        "client1" => start_client1().await,
+        _ => start_client2().await,
+    }
 }
\ No newline at end of file